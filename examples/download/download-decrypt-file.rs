@@ -17,10 +17,11 @@ async fn main() -> Result<()> {
     }
 }
 
-async fn download(url: &String, path: &String) -> Result<()> {
+async fn download(url: &str, path: &str) -> Result<()> {
     let file = OpenOptions::new()
         .write(true)
         .append(false)
+        .truncate(true)
         .create(true)
         .open(path)?;
 
@@ -33,7 +34,7 @@ async fn download(url: &String, path: &String) -> Result<()> {
         cipher,
     );
     downloader
-        .download(path.clone(), url.as_str(), None)
+        .download(path.to_owned(), url, None)
         .await?;
 
     println!("file downloaded to path:{path}");
@@ -16,7 +16,7 @@ async fn main() -> Result<()> {
     }
 }
 
-async fn download(url: &String, path: &String) -> Result<()> {
+async fn download(url: &str, path: &str) -> Result<()> {
     let file = OpenOptions::new()
         .read(true)
         .append(true)
@@ -36,7 +36,7 @@ async fn download(url: &String, path: &String) -> Result<()> {
         file,
     );
     downloader
-        .download(path.clone(), url.as_str(), start_offset)
+        .download(path.to_owned(), url, start_offset)
         .await?;
 
     println!("file downloaded to path:{path}");
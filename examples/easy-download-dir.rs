@@ -1,7 +1,7 @@
 use anyhow::Result;
 use std::env;
 use std::sync::{Arc, Mutex};
-use w3s::helper;
+use w3s::helper::{self, DownloadDirOptions};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -13,7 +13,7 @@ async fn main() -> Result<()> {
     }
 }
 
-async fn download(url: &String, path: &String) -> Result<()> {
+async fn download(url: &str, path: &str) -> Result<()> {
     helper::download_dir(
         url,
         path,
@@ -21,8 +21,12 @@ async fn download(url: &String, path: &String) -> Result<()> {
         Some(Arc::new(Mutex::new(|name, _, pos, total| {
             println!("name: {name} {pos}/{total}");
         }))),
-        None,
-        false,
+        &DownloadDirOptions {
+            with_metadata: true,
+            with_symlinks: true,
+            with_verification: true,
+            ..Default::default()
+        },
     )
     .await?;
 
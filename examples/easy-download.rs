@@ -2,7 +2,7 @@ use anyhow::Result;
 use std::env;
 use std::fs::OpenOptions;
 use std::sync::{Arc, Mutex};
-use w3s::helper;
+use w3s::helper::{self, DownloadOptions};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -16,10 +16,11 @@ async fn main() -> Result<()> {
     }
 }
 
-async fn download(url: &String, path: &String) -> Result<()> {
+async fn download(url: &str, path: &str) -> Result<()> {
     let mut file = OpenOptions::new()
         .write(true)
         .append(false)
+        .truncate(true)
         .create(true)
         .open(path)?;
 
@@ -32,9 +33,12 @@ async fn download(url: &String, path: &String) -> Result<()> {
         Some(Arc::new(Mutex::new(|name, _, pos, total| {
             println!("name: {name} {pos}/{total}");
         }))),
-        None,
-        Some(b"abcd1234".to_vec()),
-        true,
+        &DownloadOptions {
+            with_decryption: Some(b"abcd1234".to_vec()),
+            with_decompression: true,
+            with_verification: true,
+            ..Default::default()
+        },
     )
     .await?;
 
@@ -0,0 +1,32 @@
+use anyhow::Result;
+use std::env;
+use std::sync::{Arc, Mutex};
+use w3s::helper;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = env::args().collect::<Vec<_>>();
+
+    match args.as_slice() {
+        [_, path, auth_token] => upload(path, auth_token).await,
+        _ => panic!(
+            "\n\nPlease input [the_path_to_the_car_file] and [web3.storage_auth_token(eyJhbG......MHlq0)]\n\n"
+        ),
+    }
+}
+
+async fn upload(path: &str, auth_token: &str) -> Result<()> {
+    let results = helper::upload_car(
+        path,
+        auth_token,
+        2,
+        Some(Arc::new(Mutex::new(|name, part, pos, total| {
+            println!("name: {name} part:{part} {pos}/{total}");
+        }))),
+    )
+    .await?;
+
+    println!("results: {:?}", results);
+
+    Ok(())
+}
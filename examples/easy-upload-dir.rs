@@ -1,7 +1,9 @@
 use anyhow::Result;
 use std::env;
 use std::sync::{Arc, Mutex};
-use w3s::helper;
+use w3s::helper::{self, UploadDirOptions};
+use w3s::writer::car_util::SymlinkPolicy;
+use w3s::writer::parallel;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -15,17 +17,20 @@ async fn main() -> Result<()> {
     }
 }
 
-async fn upload(path: &String, auth_token: &String) -> Result<()> {
+async fn upload(path: &str, auth_token: &str) -> Result<()> {
     let results = helper::upload_dir(
         path,
-        None,
         auth_token.to_owned(),
         1,
         Some(Arc::new(Mutex::new(|name, part, pos, total| {
             println!("name: {name} part:{part} {pos}/{total}");
         }))),
-        None,
-        None,
+        &UploadDirOptions {
+            with_metadata: true,
+            symlink_policy: SymlinkPolicy::Store,
+            threads: Some(parallel::available_threads()),
+            ..Default::default()
+        },
     )
     .await?;
 
@@ -1,7 +1,7 @@
 use anyhow::Result;
 use std::env;
 use std::sync::{Arc, Mutex};
-use w3s::helper;
+use w3s::helper::{self, UploadOptions};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -15,7 +15,7 @@ async fn main() -> Result<()> {
     }
 }
 
-async fn upload(path: &String, auth_token: &String) -> Result<()> {
+async fn upload(path: &str, auth_token: &str) -> Result<()> {
     let results = helper::upload(
         path,
         auth_token,
@@ -23,9 +23,12 @@ async fn upload(path: &String, auth_token: &String) -> Result<()> {
         Some(Arc::new(Mutex::new(|name, part, pos, total| {
             println!("name: {name} part:{part} {pos}/{total}");
         }))),
-        Some(None),
-        Some(b"abcd1234".to_vec()),
-        Some(None),
+        &UploadOptions {
+            with_car: Some(None),
+            with_encryption: Some(b"abcd1234".to_vec()),
+            with_compression: Some(None),
+            ..Default::default()
+        },
     )
     .await?;
 
@@ -0,0 +1,32 @@
+use anyhow::Result;
+use std::env;
+use w3s::car::{self, BlockStore, TreeEntry};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = env::args().collect::<Vec<_>>();
+
+    match args.as_slice() {
+        [_, car_path] => inspect(car_path, false).await,
+        [_, car_path, json] if json == "--json" => inspect(car_path, true).await,
+        _ => panic!("\n\nPlease input [car_file_path] and optional [--json]\n\n"),
+    }
+}
+
+async fn inspect(car_path: &str, json: bool) -> Result<()> {
+    let store = BlockStore::open(car_path)?;
+    let root = *store.roots().first().ok_or(car::Error::NoRoot)?;
+    let tree = TreeEntry::from_block_store(&store, &root)?;
+
+    if json {
+        println!("{}", tree.to_json());
+        return Ok(());
+    }
+
+    for block in car::list_blocks(tokio::fs::File::open(car_path).await?).await? {
+        println!("{}", block);
+    }
+    println!("\n{}", tree);
+
+    Ok(())
+}
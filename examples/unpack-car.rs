@@ -0,0 +1,33 @@
+use anyhow::Result;
+use std::env;
+use w3s::car::{self, BlockStore, UnpackOptions};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = env::args().collect::<Vec<_>>();
+
+    match args.as_slice() {
+        [_, car_path, path] => unpack(car_path, path).await,
+        _ => panic!("\n\nPlease input [car_file_path] and the [path_to_save_dir]\n\n"),
+    }
+}
+
+async fn unpack(car_path: &str, path: &str) -> Result<()> {
+    // only the offsets of the blocks are loaded, and the blocks are read when they are unpacked
+    let store = BlockStore::open(car_path)?;
+    let root = *store.roots().first().ok_or(car::Error::NoRoot)?;
+
+    car::unpack_block_store(
+        &store,
+        &root,
+        path,
+        &UnpackOptions {
+            with_metadata: true,
+            ..Default::default()
+        },
+    )?;
+
+    println!("{root} unpacked to path: {path}");
+
+    Ok(())
+}
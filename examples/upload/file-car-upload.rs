@@ -6,11 +6,11 @@ use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 use w3s::writer::{car, uploader, ChainWrite};
 
-fn get_file_name(path: &String) -> Option<String> {
+fn get_file_name(path: &str) -> Option<String> {
     let path = std::path::Path::new(path);
     path.file_name()
         .and_then(|name| name.to_str())
-        .and_then(|x| Some(x.to_owned()))
+        .map(|x| x.to_owned())
 }
 
 #[tokio::main]
@@ -25,12 +25,12 @@ async fn main() -> Result<()> {
     }
 }
 
-async fn upload(path: &String, auth_token: &String) -> Result<()> {
+async fn upload(path: &str, auth_token: &str) -> Result<()> {
     let mut file = File::open(path)?;
     let filename = get_file_name(path).unwrap();
 
     let uploader = uploader::Uploader::new(
-        auth_token.clone(),
+        auth_token.to_owned(),
         filename.clone(),
         uploader::UploadType::Car,
         2,
@@ -44,6 +44,8 @@ async fn upload(path: &String, auth_token: &String) -> Result<()> {
         Rc::new(vec![car::single_file_to_directory_item(&filename, None)]),
         None,
         None,
+        None,
+        None,
         uploader,
     );
 
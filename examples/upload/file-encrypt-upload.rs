@@ -5,11 +5,11 @@ use std::io::{self, Write};
 use std::sync::{Arc, Mutex};
 use w3s::writer::{cipher::Cipher, splitter, uploader, ChainWrite};
 
-fn get_file_name(path: &String) -> Option<String> {
+fn get_file_name(path: &str) -> Option<String> {
     let path = std::path::Path::new(path);
     path.file_name()
         .and_then(|name| name.to_str())
-        .and_then(|x| Some(x.to_owned()))
+        .map(|x| x.to_owned())
 }
 
 #[tokio::main]
@@ -24,12 +24,12 @@ async fn main() -> Result<()> {
     }
 }
 
-async fn upload(path: &String, auth_token: &String) -> Result<()> {
+async fn upload(path: &str, auth_token: &str) -> Result<()> {
     let mut file = File::open(path)?;
     let filename = get_file_name(path).unwrap();
 
     let uploader = uploader::Uploader::new(
-        auth_token.clone(),
+        auth_token.to_owned(),
         filename.clone(),
         uploader::UploadType::Upload,
         2,
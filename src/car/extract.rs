@@ -0,0 +1,81 @@
+use std::collections::HashSet;
+use std::io::Write;
+
+use cid::Cid;
+use unixfs_v1::PBNode;
+
+use super::iroh_car::{CarHeader, CarWriter};
+use super::*;
+
+/// Resolves a path like `photos/2023/a.jpg` from `root` through UnixFS directories and HAMT shards.
+pub fn resolve_path(store: &BlockStore, root: &Cid, path: &str) -> Result<Cid, Error> {
+    let mut cid = *root;
+
+    for segment in path.split('/').filter(|x| !x.is_empty()) {
+        cid = dir_entries(store, &cid)?
+            .into_iter()
+            .find_map(|(name, child)| (name == segment).then_some(child))
+            .ok_or_else(|| Error::PathNotFound(path.to_owned()))?;
+    }
+
+    Ok(cid)
+}
+
+/// Streams the content of the file at `path` under `root` into `writer`.
+///
+/// With a store of `BlockStore::open`, only the blocks on the path and of the file are read.
+pub fn extract_file(
+    store: &BlockStore,
+    root: &Cid,
+    path: &str,
+    writer: impl Write,
+    options: &UnpackOptions,
+) -> Result<Cid, Error> {
+    let cid = resolve_path(store, root, path)?;
+    write_content(store, &cid, writer, options)?;
+    Ok(cid)
+}
+
+/// Writes a minimal CARv1 with only the blocks of the subgraph at `path` under `root`.
+///
+/// The resolved CID becomes the root of the new CAR and is returned.
+pub fn extract_car(
+    store: &BlockStore,
+    root: &Cid,
+    path: &str,
+    writer: impl Write + Send + Unpin,
+) -> Result<Cid, Error> {
+    let cid = resolve_path(store, root, path)?;
+
+    let mut visited = HashSet::new();
+    let mut pending = vec![cid];
+    let mut blocks = vec![];
+    while let Some(x) = pending.pop() {
+        if !visited.insert(x) {
+            continue;
+        }
+
+        let data = store.get(&x)?;
+        if x.codec() == DAG_PB {
+            let node =
+                PBNode::try_from(&*data).map_err(|e| Error::InvalidNode(x, e.to_string()))?;
+            for link in node.Links.iter() {
+                pending.push(link_cid(&x, link)?);
+            }
+        }
+
+        // a CAR can't be empty, so an inlined root is kept
+        if x.hash().code() != IDENTITY || x == cid {
+            blocks.push((x, data.to_vec()));
+        }
+    }
+
+    let mut car = CarWriter::new(CarHeader::new(vec![cid]), writer);
+    // the root goes last like the CAR files generated by `car_util`
+    for (x, data) in blocks.into_iter().rev() {
+        car.write(x, data)?;
+    }
+    car.flush()?;
+
+    Ok(cid)
+}
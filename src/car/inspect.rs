@@ -0,0 +1,335 @@
+use std::fmt::{self, Display};
+
+use cid::Cid;
+use serde::{Serialize, Serializer};
+use tokio::io::AsyncRead;
+use unixfs_v1::UnixFsType;
+
+use super::*;
+
+/// Type of a decoded block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeKind {
+    Raw,
+    Directory,
+    HAMTShard,
+    File,
+    Symlink,
+    Metadata,
+}
+
+impl NodeKind {
+    fn is_dir(&self) -> bool {
+        matches!(self, NodeKind::Directory | NodeKind::HAMTShard)
+    }
+}
+
+impl From<UnixFsType> for NodeKind {
+    fn from(x: UnixFsType) -> Self {
+        match x {
+            UnixFsType::Raw => NodeKind::Raw,
+            UnixFsType::Directory => NodeKind::Directory,
+            UnixFsType::File => NodeKind::File,
+            UnixFsType::Metadata => NodeKind::Metadata,
+            UnixFsType::Symlink => NodeKind::Symlink,
+            UnixFsType::HAMTShard => NodeKind::HAMTShard,
+        }
+    }
+}
+
+/// A link of a dag-pb node
+#[derive(Debug, Clone, Serialize)]
+pub struct LinkInfo {
+    pub name: Option<String>,
+    #[serde(serialize_with = "serialize_cid")]
+    pub cid: Cid,
+    pub tsize: Option<u64>,
+}
+
+/// A decoded raw leaf or dag-pb UnixFS node
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeInfo {
+    pub kind: NodeKind,
+    /// size of the file content under this node
+    pub filesize: Option<u64>,
+    pub links: Vec<LinkInfo>,
+}
+
+impl NodeInfo {
+    /// Decodes a raw or dag-pb block.
+    pub fn decode(cid: &Cid, block: &[u8]) -> Result<Self, Error> {
+        let node = match decode_node(cid, block)? {
+            Node::Raw(data) => {
+                return Ok(NodeInfo {
+                    kind: NodeKind::Raw,
+                    filesize: Some(data.len() as u64),
+                    links: vec![],
+                })
+            }
+            Node::UnixFs(x) => x,
+        };
+
+        let filesize = node.data.filesize.or_else(|| {
+            node.data
+                .Data
+                .as_ref()
+                .map(|x| x.len() as u64 + node.data.blocksizes.iter().sum::<u64>())
+        });
+
+        let links = node
+            .links
+            .iter()
+            .map(|x| {
+                Ok(LinkInfo {
+                    name: x.Name.as_ref().map(|x| x.to_string()),
+                    cid: link_cid(cid, x)?,
+                    tsize: x.Tsize,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(NodeInfo {
+            kind: node.data.Type.into(),
+            filesize,
+            links,
+        })
+    }
+}
+
+/// A block listed from a CAR file
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockInfo {
+    #[serde(serialize_with = "serialize_cid")]
+    pub cid: Cid,
+    pub codec: u64,
+    pub size: usize,
+    /// `None` if the block is neither raw nor dag-pb UnixFS
+    pub node: Option<NodeInfo>,
+}
+
+impl Display for BlockInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "cid: {} codec: {:#x} size: {}",
+            self.cid, self.codec, self.size
+        )?;
+        if let Some(node) = self.node.as_ref() {
+            write!(f, " | {:?}", node.kind)?;
+            if let Some(filesize) = node.filesize {
+                write!(f, " filesize: {}", filesize)?;
+            }
+            for link in node.links.iter() {
+                write!(
+                    f,
+                    "\n  -> {} {}",
+                    link.cid,
+                    link.name.as_deref().unwrap_or_default()
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Lists all the blocks of a CARv1 or CARv2 in the order they are stored.
+pub async fn list_blocks<R: AsyncRead + Send + Unpin>(reader: R) -> Result<Vec<BlockInfo>, Error> {
+    let mut car = CarReader::new(reader).await?;
+    let mut ret = vec![];
+
+    while let Some((cid, data)) = car.next_block().await? {
+        ret.push(BlockInfo {
+            cid,
+            codec: cid.codec(),
+            size: data.len(),
+            node: NodeInfo::decode(&cid, &data).ok(),
+        });
+    }
+
+    Ok(ret)
+}
+
+/// An entry of the UnixFS path tree. HAMT shards are flattened into directories.
+#[derive(Debug, Clone, Serialize)]
+pub struct TreeEntry {
+    pub name: String,
+    #[serde(serialize_with = "serialize_cid")]
+    pub cid: Cid,
+    pub kind: NodeKind,
+    /// file size, or the total size of the files under a directory
+    pub size: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<TreeEntry>,
+}
+
+impl TreeEntry {
+    /// Builds the path tree from `root` with loaded blocks.
+    pub fn from_block_store(store: &BlockStore, root: &Cid) -> Result<Self, Error> {
+        Self::build(store, root.to_string(), root)
+    }
+
+    fn build(store: &BlockStore, name: String, cid: &Cid) -> Result<Self, Error> {
+        let node = NodeInfo::decode(cid, &store.get(cid)?)?;
+
+        let children = if node.kind.is_dir() {
+            dir_entries(store, cid)?
+                .into_iter()
+                .map(|(name, child)| Self::build(store, name, &child))
+                .collect::<Result<Vec<_>, Error>>()?
+        } else {
+            vec![]
+        };
+
+        let size = if node.kind.is_dir() {
+            children.iter().map(|x| x.size).sum()
+        } else {
+            node.filesize.unwrap_or_default()
+        };
+
+        Ok(TreeEntry {
+            name,
+            cid: *cid,
+            kind: if node.kind.is_dir() {
+                NodeKind::Directory
+            } else {
+                node.kind
+            },
+            size,
+            children,
+        })
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    fn fmt_with_indent(&self, f: &mut fmt::Formatter<'_>, indent: usize) -> fmt::Result {
+        let suffix = if self.kind == NodeKind::Directory {
+            "/"
+        } else {
+            ""
+        };
+        writeln!(
+            f,
+            "{:indent$}{}{} ({}, {} bytes)",
+            "",
+            self.name,
+            suffix,
+            self.cid,
+            self.size,
+            indent = indent
+        )?;
+        for child in self.children.iter() {
+            child.fmt_with_indent(f, indent + 2)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for TreeEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_with_indent(f, 0)
+    }
+}
+
+/// Reads a CARv1 or CARv2 and builds the path tree from the first root of its header.
+pub async fn inspect_tree<R: AsyncRead + Send + Unpin>(reader: R) -> Result<TreeEntry, Error> {
+    let store = BlockStore::from_reader(reader).await?;
+    let root = *store.roots().first().ok_or(Error::NoRoot)?;
+
+    TreeEntry::from_block_store(&store, &root)
+}
+
+fn serialize_cid<S: Serializer>(cid: &Cid, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(cid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::car_util::{
+        gen_blocks, gen_car_by_data, gen_dir, gen_pbnode_from_blocks, DagConfig, FileMeta,
+    };
+
+    /// A CAR of `root/a` with 3 leaves of 10 bytes and the empty directory `root/sub`
+    fn dir_car() -> (Vec<u8>, Cid) {
+        let (config, meta) = (DagConfig::default(), FileMeta::default());
+        let mut blocks = gen_blocks((0..30).collect(), 10, &config);
+        let file = gen_pbnode_from_blocks("a".to_owned(), &blocks, &meta, &config);
+        let sub = gen_dir(Some("sub".to_owned()), &[], &meta, &config);
+        let root = gen_dir(None, &[file.clone(), sub.clone()], &meta, &config);
+        blocks.extend([file, sub]);
+
+        let cid = root.cid();
+        let data = blocks.iter_mut().map(|x| x.rip_data_with_cid()).collect();
+        (gen_car_by_data(data, Some(root), &config).unwrap(), cid)
+    }
+
+    #[tokio::test]
+    async fn list_dir_blocks() {
+        let (car, root) = dir_car();
+        let blocks = list_blocks(car.as_slice()).await.unwrap();
+
+        let kinds: Vec<_> = blocks
+            .iter()
+            .map(|x| x.node.as_ref().unwrap().kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                NodeKind::Raw,
+                NodeKind::Raw,
+                NodeKind::Raw,
+                NodeKind::File,
+                NodeKind::Directory,
+                NodeKind::Directory
+            ]
+        );
+        assert_eq!(blocks[0].codec, RAW);
+        assert_eq!(blocks[0].size, 10);
+
+        let file = blocks[3].node.as_ref().unwrap();
+        assert_eq!(file.filesize, Some(30));
+        assert_eq!(file.links.len(), 3);
+
+        let last = blocks.last().unwrap();
+        assert_eq!(last.cid, root);
+        let names: Vec<_> = last
+            .node
+            .as_ref()
+            .unwrap()
+            .links
+            .iter()
+            .map(|x| x.name.as_deref())
+            .collect();
+        assert_eq!(names, [Some("a"), Some("sub")]);
+    }
+
+    #[tokio::test]
+    async fn dir_tree() {
+        let (car, root) = dir_car();
+        let tree = inspect_tree(car.as_slice()).await.unwrap();
+
+        assert_eq!(
+            (tree.name.clone(), tree.cid, tree.kind, tree.size),
+            (root.to_string(), root, NodeKind::Directory, 30)
+        );
+        let children: Vec<_> = tree
+            .children
+            .iter()
+            .map(|x| (x.name.as_str(), x.kind, x.size))
+            .collect();
+        assert_eq!(
+            children,
+            [("a", NodeKind::File, 30), ("sub", NodeKind::Directory, 0)]
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&tree.to_json()).unwrap();
+        assert_eq!(json["cid"], root.to_string());
+        assert_eq!(json["children"][0]["kind"], "file");
+        // an empty directory has no `children`
+        assert!(json["children"][1].get("children").is_none());
+        assert!(tree.to_string().contains("  sub/ ("));
+    }
+}
@@ -0,0 +1,288 @@
+//! Local CAR file utilities which work without any IPFS gateway
+//!
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use cid::Cid;
+use integer_encoding::VarIntReader;
+use thiserror::Error;
+use tokio::io::AsyncRead;
+use unixfs_v1::{FlatUnixFs, PBLink, UnixFsType};
+
+use super::helper;
+use super::iroh_car::{self, CarHeader, CarReader, MultihashIndexSorted};
+use super::writer::manifest;
+
+mod extract;
+mod inspect;
+mod pack;
+mod unpack;
+mod verify;
+pub use extract::*;
+pub use inspect::*;
+pub use pack::*;
+pub use unpack::*;
+pub use verify::*;
+
+const IDENTITY: u64 = 0x00;
+const RAW: u64 = 0x55;
+const DAG_PB: u64 = 0x70;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("IO error")]
+    IoError(#[from] io::Error),
+    #[error("CAR error: {0}")]
+    CarError(#[from] iroh_car::Error),
+    #[error("Helper error")]
+    HelperError(#[from] helper::Error),
+    #[error("Manifest error: {0}")]
+    ManifestError(#[from] manifest::Error),
+    #[error("Block not found: {0}")]
+    BlockNotFound(Cid),
+    #[error("Invalid node {0}: {1}")]
+    InvalidNode(Cid, String),
+    #[error("Path not found: {0}")]
+    PathNotFound(String),
+    #[error("The CAR file has no root")]
+    NoRoot,
+}
+
+/// Blocks of CAR files kept in memory and indexed by CID,
+/// or read on demand from a seekable CAR through the offsets of its blocks
+#[derive(Debug, Clone, Default)]
+pub struct BlockStore {
+    roots: Vec<Cid>,
+    blocks: HashMap<Cid, Vec<u8>>,
+    indexed: Option<Arc<IndexedCar>>,
+}
+
+#[derive(Debug)]
+struct IndexedCar {
+    reader: Mutex<Box<dyn SeekRead>>,
+    data_offset: u64,
+    index: MultihashIndexSorted,
+}
+
+trait SeekRead: Read + Seek + Send + Debug {}
+
+impl<T: Read + Seek + Send + Debug> SeekRead for T {}
+
+impl BlockStore {
+    /// Reads all the blocks of a CARv1 or CARv2 into memory. Every block is re-hashed and checked against its CID.
+    ///
+    /// For a file or another seekable reader, `open` or `from_seekable` only keeps the offsets of the blocks.
+    pub async fn from_reader<R: AsyncRead + Send + Unpin>(reader: R) -> Result<Self, Error> {
+        let mut car = CarReader::new(reader).await?;
+        let mut store = BlockStore {
+            roots: car.header().roots().to_vec(),
+            blocks: HashMap::new(),
+            indexed: None,
+        };
+
+        while let Some((cid, data)) = car.next_block().await? {
+            check_hash(&cid, &data)?;
+            store.blocks.insert(cid, data);
+        }
+
+        Ok(store)
+    }
+
+    /// Opens a CARv1 or CARv2 file, see `from_seekable`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::from_seekable(BufReader::new(File::open(path)?))
+    }
+
+    /// Reads a CARv1 or CARv2 from a seekable reader. Only the offsets of the blocks are loaded,
+    /// from the index of a CARv2 or by one pass over the sections otherwise,
+    /// and the blocks are read and checked against their CIDs when they are used.
+    pub fn from_seekable<R: Read + Seek + Send + Debug + 'static>(
+        mut reader: R,
+    ) -> Result<Self, Error> {
+        reader.seek(SeekFrom::Start(0))?;
+        let (data_offset, index) = match iroh_car::read_header_v2(&mut reader) {
+            Ok(header) if header.index_offset != 0 => {
+                (header.data_offset, iroh_car::read_index_v2(&mut reader)?)
+            }
+            Ok(header) => (
+                header.data_offset,
+                MultihashIndexSorted::from_v1_reader(
+                    &mut reader,
+                    header.data_offset,
+                    header.data_size,
+                )?,
+            ),
+            // not a CARv2, so the whole file is the payload
+            Err(_) => {
+                let size = reader.seek(SeekFrom::End(0))?;
+                (
+                    0,
+                    MultihashIndexSorted::from_v1_reader(&mut reader, 0, size)?,
+                )
+            }
+        };
+
+        reader.seek(SeekFrom::Start(data_offset))?;
+        let len: u64 = reader.read_varint()?;
+        let mut buf = vec![];
+        reader.by_ref().take(len).read_to_end(&mut buf)?;
+
+        Ok(BlockStore {
+            roots: CarHeader::decode(&buf)?.roots().to_vec(),
+            blocks: HashMap::new(),
+            indexed: Some(Arc::new(IndexedCar {
+                reader: Mutex::new(Box::new(reader)),
+                data_offset,
+                index,
+            })),
+        })
+    }
+
+    pub fn roots(&self) -> &[Cid] {
+        &self.roots
+    }
+
+    /// Returns the data of a block. Inlined blocks are read from their identity CIDs.
+    pub fn get<'a>(&'a self, cid: &'a Cid) -> Result<Cow<'a, [u8]>, Error> {
+        if cid.hash().code() == IDENTITY {
+            return Ok(Cow::Borrowed(cid.hash().digest()));
+        }
+        if let Some(x) = self.blocks.get(cid) {
+            return Ok(Cow::Borrowed(x));
+        }
+
+        match &self.indexed {
+            Some(car) => {
+                let offset = car.index.get(cid).ok_or(Error::BlockNotFound(*cid))?;
+                let mut reader = car.reader.lock().unwrap();
+                let data = iroh_car::read_block_at(&mut *reader, car.data_offset + offset, cid)?;
+                check_hash(cid, &data)?;
+                Ok(Cow::Owned(data))
+            }
+            None => Err(Error::BlockNotFound(*cid)),
+        }
+    }
+}
+
+/// Returns `Error::InvalidNode` if the data of a block doesn't match its CID.
+///
+/// Since a block can't link to a CID hashed from itself, the DAG of checked blocks has no cycle.
+fn check_hash(cid: &Cid, data: &[u8]) -> Result<(), Error> {
+    if !is_hash_matched(cid, data) {
+        return Err(Error::InvalidNode(
+            *cid,
+            "the data doesn't match the CID".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// A decoded block of a UnixFS DAG
+pub(crate) enum Node<'a> {
+    Raw(&'a [u8]),
+    UnixFs(FlatUnixFs<'a>),
+}
+
+pub(crate) fn decode_node<'a>(cid: &Cid, block: &'a [u8]) -> Result<Node<'a>, Error> {
+    match cid.codec() {
+        RAW => Ok(Node::Raw(block)),
+        DAG_PB => FlatUnixFs::try_from(block)
+            .map(Node::UnixFs)
+            .map_err(|e| Error::InvalidNode(*cid, e.to_string())),
+        x => Err(Error::InvalidNode(
+            *cid,
+            format!("unsupported codec: {:#x}", x),
+        )),
+    }
+}
+
+pub(crate) fn link_cid(cid: &Cid, link: &PBLink) -> Result<Cid, Error> {
+    link.Hash
+        .as_deref()
+        .and_then(|x| Cid::try_from(x).ok())
+        .ok_or_else(|| Error::InvalidNode(*cid, "invalid link hash".to_string()))
+}
+
+/// Lists the named entries of a directory node. Entries of HAMT shards are collected from all the sub shards.
+pub(crate) fn dir_entries(store: &BlockStore, cid: &Cid) -> Result<Vec<(String, Cid)>, Error> {
+    let block = store.get(cid)?;
+    let node = match decode_node(cid, &block)? {
+        Node::UnixFs(x) => x,
+        Node::Raw(_) => return Err(Error::InvalidNode(*cid, "not a directory".to_string())),
+    };
+
+    let prefix_len = match node.data.Type {
+        UnixFsType::Directory => 0,
+        UnixFsType::HAMTShard => {
+            let fanout = node.data.fanout.unwrap_or(256).max(2);
+            format!("{:X}", fanout - 1).len()
+        }
+        _ => return Err(Error::InvalidNode(*cid, "not a directory".to_string())),
+    };
+
+    let mut ret = vec![];
+    for link in node.links.iter() {
+        let child = link_cid(cid, link)?;
+        let name = link.Name.as_deref().unwrap_or_default();
+
+        if prefix_len > 0 && name.len() == prefix_len {
+            ret.extend(dir_entries(store, &child)?);
+            continue;
+        }
+
+        let name = name.get(prefix_len..).unwrap_or_default();
+        if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+            return Err(Error::InvalidNode(
+                *cid,
+                format!("invalid entry name: {:?}", name),
+            ));
+        }
+        ret.push((name.to_owned(), child));
+    }
+
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use multihash::{Code, MultihashDigest};
+
+    use super::*;
+    use crate::iroh_car::CarWriter;
+
+    fn raw_cid(data: &[u8]) -> Cid {
+        Cid::new_v1(RAW, Code::Sha2_256.digest(data))
+    }
+
+    #[test]
+    fn seekable_car() {
+        let (a, b, forged) = (raw_cid(b"a"), raw_cid(b"b"), raw_cid(b"c"));
+        let mut car = CarWriter::new(CarHeader::new(vec![a]), vec![]);
+        car.write(a, b"a").unwrap();
+        car.write(b, b"b").unwrap();
+        car.write(forged, b"not c").unwrap();
+        let v1 = car.finish().unwrap();
+        let v2 = iroh_car::write_v2(vec![a], &v1, vec![]).unwrap();
+
+        for car in [v1.clone(), v2] {
+            let store = BlockStore::from_seekable(Cursor::new(car)).unwrap();
+            assert_eq!(store.roots(), &[a]);
+            assert_eq!(&*store.get(&a).unwrap(), b"a");
+            assert_eq!(&*store.get(&b).unwrap(), b"b");
+            let missing = raw_cid(b"d");
+            assert!(matches!(store.get(&missing), Err(Error::BlockNotFound(x)) if x == missing));
+            assert!(matches!(store.get(&forged), Err(Error::InvalidNode(x, _)) if x == forged));
+        }
+
+        // a section running past the end of the file
+        let truncated = v1[..v1.len() - 1].to_vec();
+        assert!(BlockStore::from_seekable(Cursor::new(truncated)).is_err());
+    }
+}
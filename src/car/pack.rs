@@ -0,0 +1,297 @@
+use std::cell::RefCell;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use cid::Cid;
+
+use super::*;
+use crate::writer::car::Car;
+use crate::writer::car_stream::UploadBody;
+use crate::writer::car_util::{DagConfig, DirectoryItem, SymlinkPolicy, MAX_CAR_SIZE};
+use crate::writer::envelope::Argon2Params;
+use crate::writer::raw_key::RawKey;
+use crate::writer::CarWrite;
+
+/// Options of `pack_dir_to_car`
+#[derive(Debug, Clone, Default)]
+pub struct PackOptions {
+    /// bypasses the files or directories which return `false`
+    pub file_filter: Option<fn(name: &str, is_file: bool) -> bool>,
+    /// encrypts the files with the password
+    pub with_encryption: Option<Vec<u8>>,
+    /// Argon2id costs to derive the key from the password, `None` means the default costs
+    pub argon2_params: Option<Argon2Params>,
+    /// encrypts the files for X25519 recipients like `w3s-x25519-...` instead of a password
+    pub with_recipients: Option<Vec<String>>,
+    /// encrypts the files with a raw 256-bit key instead of a password
+    pub with_key: Option<RawKey>,
+    /// replaces the names with opaque IDs kept in an encrypted manifest, see `writer::manifest`
+    pub with_hidden_names: bool,
+    /// encrypts the files by AES-256-GCM instead of XChaCha20-Poly1305, and derives the key of a password by PBKDF2
+    /// instead of Argon2id, which requires the feature `aes` and can't be used with `with_recipients`
+    pub with_aes_gcm: bool,
+    /// signs the directory by the Ed25519 key of the key file, which requires the feature `signing`,
+    /// see `writer::signature`
+    pub with_signing_key: Option<String>,
+    /// compresses the files with zstd level, `Some(None)` means level 10
+    pub with_compression: Option<Option<i32>>,
+    /// keeps the POSIX mode and mtime of files and directories
+    pub with_metadata: bool,
+    pub symlink_policy: SymlinkPolicy,
+    /// `None` means the default config
+    pub dag_config: Option<DagConfig>,
+    /// `None` means 256K
+    pub block_size: Option<usize>,
+    /// the bytes of CAR shards kept in memory before spilling to temporary files
+    pub memory_budget: Option<usize>,
+    /// the largest size of every CAR file, `None` means `MAX_CAR_SIZE` of web3.storage
+    pub max_car_size: Option<usize>,
+    /// processes the files on worker threads with the same result, `None` keeps everything on the current thread
+    pub threads: Option<usize>,
+}
+
+/// Saves every CAR generated by `Car` as a file.
+struct CarFiles {
+    output: PathBuf,
+    count: usize,
+}
+
+impl CarFiles {
+    /// `name.car` turns into `name.0.car`, `name.1.car`...
+    fn shard_path(&self, index: usize) -> PathBuf {
+        let stem = self
+            .output
+            .file_stem()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let name = match self.output.extension() {
+            Some(ext) => format!("{}.{}.{}", stem, index, ext.to_string_lossy()),
+            None => format!("{}.{}", stem, index),
+        };
+        self.output.with_file_name(name)
+    }
+
+    fn next_path(&mut self) -> io::Result<PathBuf> {
+        let path = match self.count {
+            0 => self.output.clone(),
+            1 => {
+                // the first CAR is renamed once there is more than one
+                fs::rename(&self.output, self.shard_path(0))?;
+                self.shard_path(1)
+            }
+            x => self.shard_path(x),
+        };
+        self.count += 1;
+        Ok(path)
+    }
+}
+
+impl Write for CarFiles {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        fs::write(self.next_path()?, buf)?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl CarWrite for CarFiles {
+    fn write_car(&mut self, car: UploadBody) -> io::Result<usize> {
+        let mut writer = BufWriter::new(File::create(self.next_path()?)?);
+        car.write_to(&mut writer)?;
+        writer.flush()?;
+        Ok(car.len())
+    }
+}
+
+/// Packs a directory into CAR files under `PackOptions::max_car_size` without any uploading, and returns the root CID.
+///
+/// Files are streamed with optional compression and encryption, the same as `helper::upload_dir`.
+/// A single CAR is saved as `output`. When there are more, `dir/name.car` turns into `dir/name.0.car`,
+/// `dir/name.1.car`... in the order they are written, and the last one declares the root CID.
+/// An `output` without extension turns into `dir/name.0`, `dir/name.1`...
+pub fn pack_dir_to_car(
+    path: &str,
+    output: impl AsRef<Path>,
+    options: &PackOptions,
+) -> Result<Cid, Error> {
+    let encryption_key = helper::encryption_key(
+        options.with_encryption.clone(),
+        options.argon2_params,
+        options.with_recipients.clone(),
+        options.with_key.clone(),
+        options.with_aes_gcm,
+    )?;
+
+    let (dir_items, count) = DirectoryItem::from_path(
+        path,
+        options.file_filter,
+        options.with_metadata,
+        options.symlink_policy,
+    )?;
+    // the manifest file is kept until the files are written
+    let (dir_items, count, _manifest_file) = helper::hide_names(
+        dir_items,
+        count,
+        options.with_hidden_names,
+        encryption_key.is_some(),
+    )?;
+    let dir_items = Rc::new(dir_items);
+    let curr_file_id = Rc::new(RefCell::new(0));
+
+    let car = Car::new(
+        count as usize,
+        dir_items.clone(),
+        Some(curr_file_id.clone()),
+        options.block_size,
+        options.dag_config,
+        options.memory_budget,
+        CarFiles {
+            output: output.as_ref().to_path_buf(),
+            count: 0,
+        },
+    )
+    .with_max_car_size(options.max_car_size.unwrap_or(MAX_CAR_SIZE))
+    .with_root_meta(helper::root_meta(
+        path,
+        options.with_metadata,
+        options.with_hidden_names,
+    )?);
+    let encoded = encryption_key.is_some() || options.with_compression.is_some();
+    let mut car = helper::sign_dir(
+        car,
+        encoded,
+        options.with_signing_key.as_deref(),
+        options.with_hidden_names,
+    )?;
+
+    // the final flush is only triggered by files, so an empty directory is flushed here
+    if count == 0 {
+        car.flush()?;
+    }
+
+    let car = helper::write_dir_to_car(
+        curr_file_id,
+        &dir_items,
+        car,
+        encryption_key,
+        options.with_compression,
+        options.threads,
+    )?;
+
+    car.root().ok_or(Error::NoRoot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::car_util::FileMeta;
+
+    /// Packs `dir/a` and `dir/sub/b` into `root.car` in a temporary folder, and returns the folder.
+    fn pack(name: &str, options: &PackOptions) -> (PathBuf, Cid) {
+        let root = std::env::temp_dir().join(format!("w3s-pack-{}-{}", name, std::process::id()));
+        fs::create_dir_all(root.join("dir/sub")).unwrap();
+        fs::write(root.join("dir/a"), vec![1; 5000]).unwrap();
+        fs::write(root.join("dir/sub/b"), vec![2; 3000]).unwrap();
+
+        let cid = pack_dir_to_car(
+            root.join("dir").to_str().unwrap(),
+            root.join("root.car"),
+            options,
+        )
+        .unwrap();
+        (root, cid)
+    }
+
+    #[tokio::test]
+    async fn single_car() {
+        let (root, cid) = pack("single", &PackOptions::default());
+        assert!(!root.join("root.0.car").exists());
+
+        let car = fs::read(root.join("root.car")).unwrap();
+        let report = verify_car([car.as_slice()]).await.unwrap();
+        assert!(report.is_valid());
+        assert_eq!(report.roots, vec![cid]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn numbered_shards() {
+        let options = PackOptions {
+            block_size: Some(1024),
+            max_car_size: Some(4096),
+            ..Default::default()
+        };
+        let (root, cid) = pack("shards", &options);
+        assert!(!root.join("root.car").exists());
+
+        let mut cars = vec![];
+        while let Ok(x) = fs::read(root.join(format!("root.{}.car", cars.len()))) {
+            assert!(x.len() <= 4096);
+            cars.push(x);
+        }
+        assert!(cars.len() > 1);
+
+        // the shards are one DAG, whose root is declared by the last shard
+        let report = verify_car(cars.iter().map(|x| x.as_slice())).await.unwrap();
+        assert!(report.is_valid());
+        let last = BlockStore::from_reader(cars.last().unwrap().as_slice())
+            .await
+            .unwrap();
+        assert_eq!(last.roots(), &[cid]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn restore_metadata() {
+        let root = std::env::temp_dir().join(format!("w3s-pack-meta-{}", std::process::id()));
+        let dir = root.join("dir");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a"), vec![1; 5000]).unwrap();
+        fs::write(dir.join("sub/b"), vec![2; 3000]).unwrap();
+
+        let metas = [
+            ("a", 0o640, 1_500_000_000),
+            ("sub/b", 0o600, 1_500_000_100),
+            ("sub", 0o750, 1_500_000_200),
+            ("", 0o755, 1_500_000_300),
+        ];
+        for (path, mode, secs) in metas {
+            let meta = FileMeta {
+                mode: Some(mode),
+                mtime: Some((secs, 0)),
+            };
+            meta.apply(dir.join(path)).unwrap();
+        }
+
+        let options = PackOptions {
+            with_metadata: true,
+            ..Default::default()
+        };
+        pack_dir_to_car(dir.to_str().unwrap(), root.join("root.car"), &options).unwrap();
+
+        // the directories are restored after their children, which would change their mtimes
+        let car = fs::read(root.join("root.car")).unwrap();
+        let options = UnpackOptions {
+            with_metadata: true,
+            ..Default::default()
+        };
+        unpack_car(car.as_slice(), root.join("out"), &options)
+            .await
+            .unwrap();
+
+        for (path, mode, secs) in metas {
+            let path = root.join("out").join(path);
+            let restored = FileMeta::from_fs(&fs::metadata(&path).unwrap());
+            assert_eq!(restored.mtime, Some((secs, 0)), "{:?}", path);
+            #[cfg(unix)]
+            assert_eq!(restored.mode, Some(mode), "{:?}", path);
+            #[cfg(not(unix))]
+            let _ = mode;
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,286 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+use cid::Cid;
+use tokio::io::AsyncRead;
+use unixfs_v1::UnixFsType;
+
+use super::*;
+use crate::writer::car_util::{FileMeta, SIGNATURE_NAME};
+use crate::writer::envelope::{Argon2Params, Opener, Secret};
+use crate::writer::manifest::{Manifest, MANIFEST_NAME};
+use crate::writer::raw_key::RawKey;
+#[cfg(feature = "encryption")]
+use crate::writer::recipient::Identity;
+
+/// Options of `unpack_car` and `extract_file`
+#[derive(Debug, Clone, Default)]
+pub struct UnpackOptions {
+    /// decrypts the files with the password
+    pub with_decryption: Option<Vec<u8>>,
+    /// decrypts the files encrypted for recipients, which is used instead of `with_decryption`
+    #[cfg(feature = "encryption")]
+    pub with_identities: Option<Vec<Identity>>,
+    /// decrypts the files encrypted with raw keys, which is used instead of `with_decryption`
+    pub with_keys: Option<Vec<RawKey>>,
+    /// decompresses the files with zstd, only for the files without the envelope header
+    pub with_decompression: bool,
+    /// restores the POSIX mode and mtime stored in the UnixFS nodes
+    pub with_metadata: bool,
+    /// the largest Argon2 costs accepted from the encrypted files, see `helper::DownloadOptions`
+    pub argon2_limits: Option<Argon2Params>,
+}
+
+impl UnpackOptions {
+    fn secret(&self) -> Option<Secret> {
+        if let Some(keys) = self.with_keys.clone() {
+            return Some(Secret::Keys(keys));
+        }
+        #[cfg(feature = "encryption")]
+        if let Some(identities) = self.with_identities.clone() {
+            return Some(Secret::Identities(identities));
+        }
+        self.with_decryption.clone().map(Secret::Password)
+    }
+}
+
+/// Unpacks a CARv1 or CARv2 into a local directory by walking from the first root of its header.
+///
+/// A root directory is unpacked into `dest_dir` itself, while a root file is saved as `dest_dir/<root cid>`.
+/// Directories packed with hidden names are restored with the names and metadata of their manifests.
+/// Returns the root CID.
+pub async fn unpack_car<R: AsyncRead + Send + Unpin>(
+    reader: R,
+    dest_dir: impl AsRef<Path>,
+    options: &UnpackOptions,
+) -> Result<Cid, Error> {
+    let store = BlockStore::from_reader(reader).await?;
+    let root = *store.roots().first().ok_or(Error::NoRoot)?;
+
+    unpack_block_store(&store, &root, dest_dir, options)?;
+    Ok(root)
+}
+
+/// Unpacks the DAG under `root` from loaded blocks, the same way as `unpack_car`.
+pub fn unpack_block_store(
+    store: &BlockStore,
+    root: &Cid,
+    dest_dir: impl AsRef<Path>,
+    options: &UnpackOptions,
+) -> Result<(), Error> {
+    let dest_dir = dest_dir.as_ref();
+    fs::create_dir_all(dest_dir)?;
+
+    let is_dir = match decode_node(root, &store.get(root)?)? {
+        Node::UnixFs(x) => matches!(x.data.Type, UnixFsType::Directory | UnixFsType::HAMTShard),
+        Node::Raw(_) => false,
+    };
+
+    if !is_dir {
+        return unpack_node(
+            store,
+            root,
+            &dest_dir.join(root.to_string()),
+            "",
+            dest_dir,
+            None,
+            options,
+        );
+    }
+
+    let manifest = match dir_entries(store, root)?
+        .into_iter()
+        .find(|(name, _)| name == MANIFEST_NAME)
+    {
+        Some((_, cid)) => {
+            let mut buf = vec![];
+            write_content(store, &cid, &mut buf, options)?;
+            Some(Manifest::from_slice(&buf)?)
+        }
+        None => None,
+    };
+    unpack_node(
+        store,
+        root,
+        dest_dir,
+        "",
+        dest_dir,
+        manifest.as_ref(),
+        options,
+    )?;
+
+    // the links of hidden names are only kept in the manifest
+    for entry in manifest.iter().flat_map(|x| x.symlinks()) {
+        let path = dest_dir.join(&entry.path);
+        helper::create_symlink(entry.target.as_deref().unwrap_or_default(), &path, dest_dir)?;
+    }
+    Ok(())
+}
+
+/// * `opaque_path`: the path under the root in the DAG, which is mapped to the real one by `manifest`,
+///   the manifest of hidden names.
+/// * `root`: the directory everything is unpacked into, which the symlinks can't point out of.
+fn unpack_node(
+    store: &BlockStore,
+    cid: &Cid,
+    path: &Path,
+    opaque_path: &str,
+    root: &Path,
+    manifest: Option<&Manifest>,
+    options: &UnpackOptions,
+) -> Result<(), Error> {
+    let block = store.get(cid)?;
+    let node = match decode_node(cid, &block)? {
+        Node::Raw(_) => return write_file(store, cid, path, root, options),
+        Node::UnixFs(x) => x,
+    };
+
+    match node.data.Type {
+        UnixFsType::Directory | UnixFsType::HAMTShard => {
+            helper::check_no_symlink(path, root)?;
+            fs::create_dir_all(path)?;
+            for (name, child) in dir_entries(store, cid)? {
+                let child_opaque = match opaque_path {
+                    "" => name.clone(),
+                    x => format!("{}/{}", x, name),
+                };
+                let child_path = match manifest {
+                    Some(_) if child_opaque == MANIFEST_NAME => continue,
+                    Some(manifest) => match manifest.entry(&child_opaque) {
+                        Some(entry) => root.join(&entry.path),
+                        None => path.join(&name),
+                    },
+                    None => path.join(&name),
+                };
+                if child_opaque == SIGNATURE_NAME {
+                    helper::check_no_symlink(&child_path, root)?;
+                    write_leaves(store, &child, File::create(&child_path)?)?;
+                    continue;
+                }
+                unpack_node(
+                    store,
+                    &child,
+                    &child_path,
+                    &child_opaque,
+                    root,
+                    manifest,
+                    options,
+                )?;
+            }
+        }
+        UnixFsType::File | UnixFsType::Raw => write_file(store, cid, path, root, options)?,
+        UnixFsType::Symlink => {
+            let target = node.data.Data.unwrap_or_default();
+            helper::create_symlink(&String::from_utf8_lossy(&target), path, root)?;
+            // the metadata of a link would be applied to its target
+            return Ok(());
+        }
+        x => {
+            return Err(Error::InvalidNode(
+                *cid,
+                format!("unsupported UnixFS type: {:?}", x),
+            ))
+        }
+    }
+
+    // applied after the children are written since they change the directory mtime
+    if options.with_metadata {
+        let entry = manifest.and_then(|x| x.entry(opaque_path));
+        if let Some(meta) = entry
+            .map(|x| x.meta)
+            .or_else(|| FileMeta::from_block(&block))
+        {
+            meta.apply(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_file(
+    store: &BlockStore,
+    cid: &Cid,
+    path: &Path,
+    root: &Path,
+    options: &UnpackOptions,
+) -> Result<(), Error> {
+    helper::check_no_symlink(path, root)?;
+    write_content(store, cid, File::create(path)?, options)
+}
+
+/// Writes the content of a file node through the optional decryption and decompression.
+pub(super) fn write_content(
+    store: &BlockStore,
+    cid: &Cid,
+    writer: impl Write,
+    options: &UnpackOptions,
+) -> Result<(), Error> {
+    let mut opener = Opener::new(options.secret(), options.with_decompression, writer);
+    if let Some(max) = options.argon2_limits {
+        opener = opener.with_max_params(max);
+    }
+    write_leaves(store, cid, opener)
+}
+
+fn write_leaves(store: &BlockStore, cid: &Cid, mut writer: impl Write) -> Result<(), Error> {
+    write_node_data(store, cid, &mut writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes the data of a file node and then its children in order.
+fn write_node_data<W: Write>(store: &BlockStore, cid: &Cid, writer: &mut W) -> Result<(), Error> {
+    let block = store.get(cid)?;
+    match decode_node(cid, &block)? {
+        Node::Raw(data) => writer.write_all(data)?,
+        Node::UnixFs(node) => {
+            if let Some(data) = node.data.Data.as_deref() {
+                writer.write_all(data)?;
+            }
+            for link in node.links.iter() {
+                write_node_data(store, &link_cid(cid, link)?, writer)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use multihash::{Code, MultihashDigest};
+    use quick_protobuf::{MessageWrite, Writer};
+    use unixfs_v1::{PBLink, PBNode};
+
+    use super::*;
+    use crate::iroh_car::CarWriter;
+
+    #[tokio::test]
+    async fn cyclic_car() {
+        // a directory claiming the CID it links to, which can't be its real hash
+        let cid = Cid::new_v1(DAG_PB, Code::Sha2_256.digest(b"loop"));
+        let node = PBNode {
+            Links: vec![PBLink {
+                Hash: Some(Cow::from(cid.to_bytes())),
+                Name: Some(Cow::from("loop")),
+                Tsize: Some(0),
+            }],
+            // UnixFS `Type: Directory`
+            Data: Some(Cow::from(&[0x08, 0x01][..])),
+        };
+        let mut block = vec![];
+        node.write_message(&mut Writer::new(&mut block)).unwrap();
+
+        let mut car = CarWriter::new(CarHeader::new(vec![cid]), vec![]);
+        car.write(cid, &block).unwrap();
+        let car = car.finish().unwrap();
+
+        let dest = std::env::temp_dir().join(format!("w3s-cyclic-{}", std::process::id()));
+        let ret = unpack_car(car.as_slice(), &dest, &UnpackOptions::default()).await;
+        assert!(matches!(ret, Err(Error::InvalidNode(x, _)) if x == cid));
+        assert!(!dest.exists());
+    }
+}
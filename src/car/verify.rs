@@ -0,0 +1,217 @@
+//! Integrity checks of CAR files, which re-hash every block and walk the DAG from the header roots
+//!
+use std::collections::{HashMap, HashSet};
+
+use cid::Cid;
+use multihash::{Code, MultihashDigest};
+use tokio::io::AsyncRead;
+use unixfs_v1::PBNode;
+
+use super::*;
+
+/// The result of `verify_car`
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    /// roots from the headers of all the CAR files
+    pub roots: Vec<Cid>,
+    /// count of the blocks read
+    pub blocks: usize,
+    /// linked blocks which can not be found
+    pub missing: Vec<Cid>,
+    /// blocks which can not be reached from the roots
+    pub extra: Vec<Cid>,
+    /// blocks whose data doesn't match the CID or can't be decoded
+    pub corrupt: Vec<Cid>,
+    /// blocks with identity CIDs or hashed by a function unsupported by this build, whose data isn't checked
+    pub unsupported: Vec<Cid>,
+    /// total size of the blocks reachable from the roots
+    pub dag_size: u64,
+}
+
+impl VerifyReport {
+    /// Whether nothing is missing, extra or corrupt. The blocks in `unsupported` are not checked.
+    pub fn is_valid(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.corrupt.is_empty()
+    }
+}
+
+/// Streams one or more CAR files of the same DAG, re-hashes every block and checks that
+/// all the links reachable from the header roots exist.
+///
+/// Only the links and sizes of the blocks are kept in memory.
+pub async fn verify_car<R: AsyncRead + Send + Unpin>(
+    readers: impl IntoIterator<Item = R>,
+) -> Result<VerifyReport, Error> {
+    let mut report = VerifyReport::default();
+    let mut blocks: HashMap<Cid, (u64, Vec<Cid>)> = HashMap::new();
+
+    for reader in readers {
+        let mut car = CarReader::new(reader).await?;
+        for root in car.header().roots() {
+            if !report.roots.contains(root) {
+                report.roots.push(*root);
+            }
+        }
+
+        while let Some((cid, data)) = car.next_block().await? {
+            report.blocks += 1;
+
+            let is_supported = is_hash_supported(&cid);
+            if !is_supported {
+                report.unsupported.push(cid);
+            }
+            let links = match block_links(&cid, &data) {
+                Some(x) if !is_supported || is_hash_matched(&cid, &data) => x,
+                _ => {
+                    report.corrupt.push(cid);
+                    vec![]
+                }
+            };
+            blocks.insert(cid, (data.len() as u64, links));
+        }
+    }
+
+    let mut visited = HashSet::new();
+    let mut pending = report.roots.clone();
+    while let Some(cid) = pending.pop() {
+        if cid.hash().code() == IDENTITY || !visited.insert(cid) {
+            continue;
+        }
+
+        match blocks.get(&cid) {
+            Some((size, links)) => {
+                report.dag_size += size;
+                pending.extend(links);
+            }
+            None => report.missing.push(cid),
+        }
+    }
+
+    report.extra = blocks
+        .into_keys()
+        .filter(|x| !visited.contains(x))
+        .collect();
+    report.extra.sort();
+
+    Ok(report)
+}
+
+/// Whether the data of a block can be checked against its CID. Identity CIDs hold the data themselves.
+fn is_hash_supported(cid: &Cid) -> bool {
+    let code = cid.hash().code();
+    code != IDENTITY && Code::try_from(code).is_ok()
+}
+
+pub(crate) fn is_hash_matched(cid: &Cid, data: &[u8]) -> bool {
+    let expected = cid.hash();
+    // the whole multihash is compared, so a truncated digest never matches
+    match Code::try_from(expected.code()) {
+        Ok(code) => code.digest(data) == *expected,
+        Err(_) => false,
+    }
+}
+
+/// Returns the links of a dag-pb block. Other codecs are treated as leaves.
+fn block_links(cid: &Cid, data: &[u8]) -> Option<Vec<Cid>> {
+    if cid.codec() != DAG_PB {
+        return Some(vec![]);
+    }
+
+    PBNode::try_from(data)
+        .ok()?
+        .Links
+        .iter()
+        .map(|x| link_cid(cid, x).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use multihash::Multihash;
+    use quick_protobuf::{MessageWrite, Writer};
+    use unixfs_v1::PBLink;
+
+    use super::*;
+    use crate::iroh_car::CarWriter;
+
+    fn raw_cid(data: &[u8]) -> Cid {
+        Cid::new_v1(RAW, Code::Sha2_256.digest(data))
+    }
+
+    /// A CAR of a raw block as the root and the given other blocks
+    fn car(blocks: &[(Cid, &[u8])]) -> Vec<u8> {
+        let root = raw_cid(b"root");
+        let mut car = CarWriter::new(CarHeader::new(vec![root]), vec![]);
+        car.write(root, b"root").unwrap();
+        for (cid, data) in blocks {
+            car.write(*cid, data).unwrap();
+        }
+        car.finish().unwrap()
+    }
+
+    async fn verify(car: &[u8]) -> VerifyReport {
+        verify_car([car]).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn valid_car() {
+        let report = verify(&car(&[])).await;
+        assert!(report.is_valid());
+        assert_eq!((report.blocks, report.dag_size), (1, 4));
+    }
+
+    #[tokio::test]
+    async fn corrupt_block() {
+        let root = raw_cid(b"root");
+        let mut car = CarWriter::new(CarHeader::new(vec![root]), vec![]);
+        car.write(root, b"rooT").unwrap();
+        let report = verify(&car.finish().unwrap()).await;
+        assert_eq!(report.corrupt, vec![root]);
+        assert!(!report.is_valid());
+    }
+
+    #[tokio::test]
+    async fn missing_block() {
+        let child = raw_cid(b"child");
+        let node = PBNode {
+            Links: vec![PBLink {
+                Hash: Some(Cow::from(child.to_bytes())),
+                Name: Some(Cow::from("child")),
+                Tsize: Some(5),
+            }],
+            // UnixFS `Type: Directory`
+            Data: Some(Cow::from(&[0x08, 0x01][..])),
+        };
+        let mut block = vec![];
+        node.write_message(&mut Writer::new(&mut block)).unwrap();
+        let root = Cid::new_v1(DAG_PB, Code::Sha2_256.digest(&block));
+
+        let mut car = CarWriter::new(CarHeader::new(vec![root]), vec![]);
+        car.write(root, &block).unwrap();
+        let report = verify(&car.finish().unwrap()).await;
+        assert_eq!(report.missing, vec![child]);
+        assert!(!report.is_valid());
+    }
+
+    #[tokio::test]
+    async fn extra_blocks() {
+        let (a, b) = (raw_cid(b"a"), raw_cid(b"b"));
+        let report = verify(&car(&[(b, b"b"), (a, b"a")])).await;
+        let mut expected = vec![a, b];
+        expected.sort();
+        assert_eq!(report.extra, expected);
+        assert!(!report.is_valid());
+    }
+
+    #[tokio::test]
+    async fn unsupported_hash() {
+        // an unknown hash code and an identity CID are reported apart from corruption
+        let unknown = Cid::new_v1(RAW, Multihash::wrap(0x300000, &[0; 32]).unwrap());
+        let identity = Cid::new_v1(RAW, Multihash::wrap(IDENTITY, b"x").unwrap());
+        let report = verify(&car(&[(unknown, b"a"), (identity, b"x")])).await;
+        assert_eq!(report.unsupported, vec![unknown, identity]);
+        assert!(report.corrupt.is_empty());
+    }
+}
@@ -1,6 +1,7 @@
 //! IPFS gateway utilities
 //!
 
+use crate::writer::car_util::FileMeta;
 use futures::{future, future::select_all, FutureExt, TryFutureExt};
 use reqwest::Client;
 use std::time::{Duration, Instant};
@@ -196,6 +197,39 @@ static GATEWAYS: [&str; 92] = [
     "https://ipfs.litnet.work/ipfs/",
 ];
 
+/// Fetches the raw dag-pb block behind a gateway url and reads its UnixFS mode and mtime.
+/// Returns `None` if the gateway doesn't serve raw blocks or the node carries no metadata.
+pub async fn fetch_unixfs_meta(url: &str) -> Option<FileMeta> {
+    FileMeta::from_block(&fetch_raw_block(url).await?)
+}
+
+/// Fetches the raw block behind a gateway url. Returns `None` if the gateway doesn't serve raw blocks.
+pub async fn fetch_raw_block(url: &str) -> Option<Vec<u8>> {
+    let resp = Client::new()
+        .get(url)
+        .header("accept", "application/vnd.ipld.raw")
+        .send()
+        .await
+        .ok()?;
+
+    let is_raw = resp
+        .headers()
+        .get("content-type")
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.starts_with("application/vnd.ipld.raw"))
+        .unwrap_or(false);
+
+    if resp.status().as_u16() != 200 || !is_raw {
+        return None;
+    }
+
+    Some(resp.bytes().await.ok()?.to_vec())
+}
+
+/// Called with the URL and the status code of every checked path.
+/// It is an alias since `async_recursion` can't take the elided lifetime of a `fn` argument.
+pub type CheckListener = fn(&str, u16);
+
 #[derive(Debug)]
 pub enum GatewayStruct {
     Unknown(String),
@@ -207,7 +241,7 @@ pub enum GatewayStruct {
 pub async fn cid_url_check(
     domain: &str,
     path: &str,
-    progress_listener: Option<fn(&str, u16)>,
+    progress_listener: Option<CheckListener>,
 ) -> GatewayStruct {
     let path_string = path.strip_prefix("/").unwrap_or(path).to_owned();
     let url = format!("{}{}", domain, path);
@@ -250,7 +284,7 @@ pub async fn cid_url_check(
 pub async fn gateway_page_parse(
     domain: &str,
     path: &str,
-    progress_listener: Option<fn(&str, u16)>,
+    progress_listener: Option<CheckListener>,
 ) -> Option<Vec<GatewayStruct>> {
     let url = format!("{}{}", domain, path);
 
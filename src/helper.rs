@@ -3,15 +3,20 @@
 use cid::Cid;
 use thiserror::Error;
 
-use crate::writer::car_util::DirectoryItem;
+use crate::iroh_car::{self, CarHeader, CarReader};
+use crate::writer::car_util::{
+    read_symlink_block, DagConfig, DirectoryItem, FileMeta, SymlinkPolicy, MAX_CAR_SIZE,
+    SIGNATURE_NAME,
+};
 
 use super::gateway::*;
 use super::writer::*;
 use std::cell::RefCell;
+use std::cmp;
 use std::fs;
 use std::fs::File;
 use std::io::{self, Write};
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
 use std::rc::Rc;
 
 #[derive(Error, Debug)]
@@ -24,18 +29,65 @@ pub enum Error {
     #[cfg(feature = "encryption")]
     #[error("Cipher error")]
     CipherError(#[from] cipher::Error),
+    #[cfg(feature = "encryption")]
+    #[error("Recipient error: {0}")]
+    RecipientError(#[from] recipient::Error),
+    #[cfg(feature = "age")]
+    #[error("Age error: {0}")]
+    AgeError(#[from] age::Error),
+    #[cfg(feature = "signing")]
+    #[error("Signature error: {0}")]
+    SignatureError(#[from] signature::Error),
 
     #[error("Get filename error: {0}")]
     FilenameError(String),
 
     #[error("Download error")]
     DownloadError(#[from] downloader::Error),
+    #[error("CAR error: {0}")]
+    CarError(#[from] iroh_car::Error),
+    #[error("Envelope error: {0}")]
+    EnvelopeError(#[from] envelope::Error),
+    #[error("The uploaded root {1} isn't any of the declared roots {0:?}")]
+    RootMismatch(Vec<Cid>, Box<Cid>),
+    #[error("The header root {0} isn't returned by the service")]
+    RootNotUploaded(Box<Cid>),
+    #[error("Raw key error: {0}")]
+    RawKeyError(#[from] raw_key::Error),
+    #[error("Files can be encrypted with only one of a password, recipients or a raw key.")]
+    MultipleKeys,
+    #[error("Manifest error: {0}")]
+    ManifestError(#[from] manifest::Error),
+    #[error("Names can only be hidden with encryption.")]
+    HiddenNamesWithoutEncryption,
+    #[error("Files in the age format can only be encrypted by ChaCha20-Poly1305.")]
+    AesGcmOfAge,
+    #[error("Files in the age format need a password or recipients to be encrypted with.")]
+    AgeWithoutSecret,
+    #[error("Files for X25519 recipients can't be encrypted by AES-256-GCM, since X25519 isn't FIPS-approved.")]
+    AesGcmOfRecipients,
+    #[error("Directories with hidden names can't be signed, since the digests list the names.")]
+    SigningWithHiddenNames,
+    #[error("The gateway doesn't serve the raw root block of {0}")]
+    NoRootBlock(String),
+    #[error("The symlink {0} points out of the directory: {1}")]
+    UnsafeSymlink(String, String),
+    #[error("The path {0} goes through a symbolic link")]
+    SymlinkInPath(String),
+    #[error("Symbolic links aren't supported on this platform.")]
+    SymlinkUnsupported,
     #[error("The feature:\"encryption\" is required.")]
     FeatureNoCipher,
     #[error("The feature:\"zstd\" is required.")]
     FeatureNoZstd,
     #[error("The features:\"encryption zstd\" are required.")]
     FeatureNoCipherAndZstd,
+    #[error("The feature:\"age\" is required.")]
+    FeatureNoAge,
+    #[error("The feature:\"aes\" is required.")]
+    FeatureNoAes,
+    #[error("The feature:\"signing\" is required.")]
+    FeatureNoSigning,
 }
 
 fn gen_single_file_uploader(
@@ -65,6 +117,8 @@ fn gen_single_file_uploader(
             Rc::new(vec![dir_item]),
             None,
             custom_block_size,
+            None,
+            None,
             uploader,
         ))
     } else {
@@ -72,28 +126,133 @@ fn gen_single_file_uploader(
     }
 }
 
+/// How the key of encrypted files is obtained
+#[cfg_attr(not(feature = "encryption"), allow(dead_code))]
+pub(crate) enum KeySource {
+    /// derived by Argon2id from the password, or by PBKDF2 for AES-256-GCM
+    Password(Vec<u8>, envelope::Argon2Params),
+    /// a random file key wrapped for the X25519 recipients like `w3s-x25519-...`
+    Recipients(Vec<String>),
+    /// a raw key used directly, whose ID is recorded in the envelope header
+    Raw(raw_key::RawKey),
+}
+
+/// The key of encrypted files and the algorithm which seals them
+#[cfg_attr(not(feature = "encryption"), allow(dead_code))]
+pub(crate) struct EncryptionKey {
+    source: KeySource,
+    encryption: envelope::Encryption,
+}
+
+pub(crate) fn encryption_key(
+    with_encryption: Option<Vec<u8>>,
+    argon2_params: Option<envelope::Argon2Params>,
+    with_recipients: Option<Vec<String>>,
+    with_key: Option<raw_key::RawKey>,
+    with_aes_gcm: bool,
+) -> Result<Option<EncryptionKey>, Error> {
+    if with_aes_gcm && !cfg!(feature = "aes") {
+        return Err(Error::FeatureNoAes);
+    }
+
+    let source = match (with_encryption, with_recipients, with_key) {
+        (Some(password), None, None) => {
+            KeySource::Password(password, argon2_params.unwrap_or_default())
+        }
+        (None, Some(_), None) if with_aes_gcm => return Err(Error::AesGcmOfRecipients),
+        (None, Some(recipients), None) => KeySource::Recipients(recipients),
+        (None, None, Some(key)) => KeySource::Raw(key),
+        (None, None, None) => return Ok(None),
+        _ => return Err(Error::MultipleKeys),
+    };
+    Ok(Some(EncryptionKey {
+        source,
+        encryption: if with_aes_gcm {
+            envelope::Encryption::Aes256Gcm
+        } else {
+            envelope::Encryption::XChaCha20Poly1305
+        },
+    }))
+}
+
+/// Creates the cipher which writes the envelope header before every file.
+#[cfg(feature = "encryption")]
+fn gen_cipher<W: io::Write>(
+    key: EncryptionKey,
+    is_compressed: bool,
+    next_writer: W,
+) -> Result<cipher::Cipher<envelope::Envelope<W>>, Error> {
+    let encryption = key.encryption;
+    let gen_header = |kdf| envelope::Header {
+        encryption,
+        ..envelope::Header::new(is_compressed, kdf)
+    };
+
+    let mut cipher = match key.source {
+        #[cfg(feature = "aes")]
+        KeySource::Password(mut password, _) if encryption == envelope::Encryption::Aes256Gcm => {
+            let iterations = cipher::PBKDF2_ITERATIONS;
+            let header = gen_header(envelope::Kdf::Pbkdf2 { iterations });
+            let envelope = envelope::Envelope::new(header, next_writer);
+            cipher::Cipher::new_with_pbkdf2(&mut password, iterations, envelope)?
+        }
+        KeySource::Password(mut password, params) => {
+            let header = gen_header(envelope::Kdf::Argon2id(params));
+            let envelope = envelope::Envelope::new(header, next_writer);
+            cipher::Cipher::new_with_params(&mut password, params, envelope)?
+        }
+        KeySource::Recipients(recipients) => {
+            let recipients = recipients
+                .iter()
+                .map(|x| x.parse())
+                .collect::<Result<Vec<recipient::Recipient>, _>>()?;
+            let file_key = recipient::gen_file_key();
+            let stanzas = recipient::wrap(&file_key, &recipients)?;
+
+            let kdf = envelope::Kdf::X25519 {
+                recipients: recipients.len() as u32,
+            };
+            let envelope = envelope::Envelope::with_stanzas(gen_header(kdf), stanzas, next_writer);
+            cipher::Cipher::new_with_key(file_key, envelope)?
+        }
+        KeySource::Raw(key) => {
+            let kdf = envelope::Kdf::RawKey { key_id: key.id() };
+            let envelope = envelope::Envelope::new(gen_header(kdf), next_writer);
+            cipher::Cipher::new_with_key(*key.as_bytes(), envelope)?
+        }
+    };
+
+    // the envelope header is authenticated with every chunk
+    let head = cipher.next_mut().head();
+    let cipher = cipher.with_envelope(head);
+    match encryption {
+        #[cfg(feature = "aes")]
+        envelope::Encryption::Aes256Gcm => Ok(cipher.with_aes_gcm()),
+        _ => Ok(cipher),
+    }
+}
+
 #[cfg(all(feature = "zstd", feature = "encryption"))]
-async fn upload_dir_compress_then_encrypt(
+fn write_dir_compress_then_encrypt<W: CarWrite>(
     curr_file_id: Rc<RefCell<u64>>,
     dir_items: &[DirectoryItem],
-    car: car::Car<uploader::Uploader>,
+    car: car::Car<W>,
     level: Option<i32>,
-    mut password: Vec<u8>,
-) -> Result<Vec<Cid>, Error> {
-    let cipher = cipher::Cipher::new(&mut password, car)?;
+    key: EncryptionKey,
+) -> Result<car::Car<W>, Error> {
+    let cipher = gen_cipher(key, true, car)?;
     let mut dir = dir::Dir::new(curr_file_id, cipher);
     dir.walk_write_with_compression(dir_items, level)?;
-    let result = dir.next().next().next().finish_results().await?;
-    Ok(result)
+    Ok(dir.next().next().next())
 }
 #[cfg(not(all(feature = "zstd", feature = "encryption")))]
-async fn upload_dir_compress_then_encrypt(
+fn write_dir_compress_then_encrypt<W: CarWrite>(
     _: Rc<RefCell<u64>>,
     _: &[DirectoryItem],
-    _: car::Car<uploader::Uploader>,
+    _: car::Car<W>,
     _: Option<i32>,
-    _: Vec<u8>,
-) -> Result<Vec<Cid>, Error> {
+    _: EncryptionKey,
+) -> Result<car::Car<W>, Error> {
     Err(Error::FeatureNoCipherAndZstd)
 }
 
@@ -102,14 +261,14 @@ async fn compress_then_encrypt(
     reader: &mut impl io::Read,
     writer: Box<dyn ChainWrite<uploader::Uploader>>,
     level: Option<i32>,
-    mut password: Vec<u8>,
+    key: EncryptionKey,
 ) -> Result<Vec<Cid>, Error> {
-    let cipher = cipher::Cipher::new(&mut password, writer)?;
+    let cipher = gen_cipher(key, true, writer)?;
     let mut compressor = zstd::stream::Encoder::new(cipher, level.unwrap_or(10))?;
     io::copy(reader, &mut compressor)?;
     let mut cipher = compressor.finish()?;
     cipher.flush()?;
-    let ret = cipher.next_mut().next_mut().finish_results().await?;
+    let ret = cipher.next_mut().next_mut().next_mut().finish_results().await?;
     Ok(ret)
 }
 #[cfg(not(all(feature = "zstd", feature = "encryption")))]
@@ -117,30 +276,30 @@ async fn compress_then_encrypt(
     _: &mut impl io::Read,
     _: Box<dyn ChainWrite<uploader::Uploader>>,
     _: Option<i32>,
-    _: Vec<u8>,
+    _: EncryptionKey,
 ) -> Result<Vec<Cid>, Error> {
     Err(Error::FeatureNoCipherAndZstd)
 }
 
 #[cfg(feature = "zstd")]
-async fn upload_dir_compress(
+fn write_dir_compress<W: CarWrite>(
     curr_file_id: Rc<RefCell<u64>>,
     dir_items: &[DirectoryItem],
-    car: car::Car<uploader::Uploader>,
+    car: car::Car<W>,
     level: Option<i32>,
-) -> Result<Vec<Cid>, Error> {
-    let mut dir = dir::Dir::new(curr_file_id, car);
+) -> Result<car::Car<W>, Error> {
+    let envelope = envelope::Envelope::new(envelope::Header::new(true, envelope::Kdf::None), car);
+    let mut dir = dir::Dir::new(curr_file_id, envelope);
     dir.walk_write_with_compression(dir_items, level)?;
-    let result = dir.next().next().finish_results().await?;
-    Ok(result)
+    Ok(dir.next().next())
 }
 #[cfg(not(feature = "zstd"))]
-async fn upload_dir_compress(
+fn write_dir_compress<W: CarWrite>(
     _: Rc<RefCell<u64>>,
     _: &[DirectoryItem],
-    _: car::Car<uploader::Uploader>,
+    _: car::Car<W>,
     _: Option<i32>,
-) -> Result<Vec<Cid>, Error> {
+) -> Result<car::Car<W>, Error> {
     Err(Error::FeatureNoZstd)
 }
 
@@ -150,11 +309,13 @@ async fn compress(
     writer: Box<dyn ChainWrite<uploader::Uploader>>,
     level: Option<i32>,
 ) -> Result<Vec<Cid>, Error> {
-    let mut compressor = zstd::stream::Encoder::new(writer, level.unwrap_or(10))?;
+    let envelope =
+        envelope::Envelope::new(envelope::Header::new(true, envelope::Kdf::None), writer);
+    let mut compressor = zstd::stream::Encoder::new(envelope, level.unwrap_or(10))?;
     io::copy(reader, &mut compressor)?;
-    let mut writer = compressor.finish()?;
-    writer.flush()?;
-    let ret = writer.next_mut().finish_results().await?;
+    let mut envelope = compressor.finish()?;
+    envelope.flush()?;
+    let ret = envelope.next_mut().next_mut().finish_results().await?;
     Ok(ret)
 }
 #[cfg(not(feature = "zstd"))]
@@ -166,59 +327,320 @@ async fn compress(
     Err(Error::FeatureNoZstd)
 }
 #[cfg(feature = "encryption")]
-async fn upload_dir_encrypt(
+fn write_dir_encrypt<W: CarWrite>(
     curr_file_id: Rc<RefCell<u64>>,
     dir_items: &[DirectoryItem],
-    car: car::Car<uploader::Uploader>,
-    mut password: Vec<u8>,
-) -> Result<Vec<Cid>, Error> {
-    let cipher = cipher::Cipher::new(&mut password, car)?;
+    car: car::Car<W>,
+    key: EncryptionKey,
+) -> Result<car::Car<W>, Error> {
+    let cipher = gen_cipher(key, false, car)?;
     let mut dir = dir::Dir::new(curr_file_id, cipher);
     dir.walk_write(dir_items)?;
-    let result = dir.next().next().next().finish_results().await?;
-    Ok(result)
+    Ok(dir.next().next().next())
 }
 #[cfg(not(feature = "encryption"))]
-async fn upload_dir_encrypt(
+fn write_dir_encrypt<W: CarWrite>(
     _: Rc<RefCell<u64>>,
     _: &[DirectoryItem],
-    _: car::Car<uploader::Uploader>,
-    _: Vec<u8>,
-) -> Result<Vec<Cid>, Error> {
+    _: car::Car<W>,
+    _: EncryptionKey,
+) -> Result<car::Car<W>, Error> {
     Err(Error::FeatureNoCipher)
 }
 
+fn write_dir_parallel<W: CarWrite>(
+    dir_items: &[DirectoryItem],
+    mut car: car::Car<W>,
+    with_encryption: Option<EncryptionKey>,
+    with_compression: Option<Option<i32>>,
+    threads: usize,
+) -> Result<car::Car<W>, Error> {
+    let is_compressed = with_compression.is_some();
+    match (is_compressed, with_encryption.is_some()) {
+        (true, true) if !cfg!(all(feature = "zstd", feature = "encryption")) => {
+            return Err(Error::FeatureNoCipherAndZstd)
+        }
+        (true, _) if !cfg!(feature = "zstd") => return Err(Error::FeatureNoZstd),
+        (_, true) if !cfg!(feature = "encryption") => return Err(Error::FeatureNoCipher),
+        _ => {}
+    }
+
+    let mut encoding = parallel::FileEncoding {
+        compression: with_compression,
+        ..Default::default()
+    };
+    match with_encryption {
+        #[cfg(feature = "encryption")]
+        Some(key) => {
+            let cipher = gen_cipher(key, is_compressed, io::sink())?;
+            encoding.encryption = Some(cipher.fork(io::sink()));
+            encoding.envelope = Some(cipher.next());
+        }
+        #[cfg(not(feature = "encryption"))]
+        Some(_) => return Err(Error::FeatureNoCipher),
+        None if is_compressed => {
+            let header = envelope::Header::new(true, envelope::Kdf::None);
+            encoding.envelope = Some(envelope::Envelope::new(header, io::sink()));
+        }
+        None => {}
+    }
+
+    parallel::write_dir_parallel(dir_items, &mut car, &encoding, threads)?;
+    Ok(car)
+}
+
+/// Replaces the names with opaque IDs, and adds the manifest of them as one more file if `with_hidden_names`.
+///
+/// Returns the items, the count of files and the manifest file which should be kept until the files are written.
+pub(crate) fn hide_names(
+    dir_items: Vec<DirectoryItem>,
+    count: u64,
+    with_hidden_names: bool,
+    is_encrypted: bool,
+) -> Result<(Vec<DirectoryItem>, u64, Option<car_stream::TempFile>), Error> {
+    if !with_hidden_names {
+        return Ok((dir_items, count, None));
+    }
+    if !is_encrypted {
+        return Err(Error::HiddenNamesWithoutEncryption);
+    }
+
+    let (dir_items, temp) = manifest::Manifest::hide(dir_items, count + 1)?;
+    Ok((dir_items, count + 1, Some(temp)))
+}
+
+/// Captures the mode and mtime of the root directory `path` with `with_metadata`.
+/// Hidden names remove the metadata from the DAG, so nothing is captured with them.
+pub(crate) fn root_meta(
+    path: &str,
+    with_metadata: bool,
+    with_hidden_names: bool,
+) -> Result<FileMeta, Error> {
+    if !with_metadata || with_hidden_names {
+        return Ok(FileMeta::default());
+    }
+    Ok(FileMeta::from_fs(&fs::metadata(path)?))
+}
+
+/// Signs the directory by the first key of the key file `with_signing_key` at the final flush of `car`,
+/// see `writer::signature`. `encoded` tells that the files are compressed or encrypted.
+#[cfg(feature = "signing")]
+pub(crate) fn sign_dir<W: CarWrite>(
+    car: car::Car<W>,
+    encoded: bool,
+    with_signing_key: Option<&str>,
+    with_hidden_names: bool,
+) -> Result<car::Car<W>, Error> {
+    let path = match with_signing_key {
+        Some(x) => x,
+        None => return Ok(car),
+    };
+    if with_hidden_names {
+        return Err(Error::SigningWithHiddenNames);
+    }
+
+    let key = signature::SigningKey::from_file(path)?;
+    Ok(car.with_signature(key, encoded))
+}
+#[cfg(not(feature = "signing"))]
+pub(crate) fn sign_dir<W: CarWrite>(
+    car: car::Car<W>,
+    _: bool,
+    with_signing_key: Option<&str>,
+    _: bool,
+) -> Result<car::Car<W>, Error> {
+    match with_signing_key {
+        Some(_) => Err(Error::FeatureNoSigning),
+        None => Ok(car),
+    }
+}
+
+/// Walks the directory items into a `Car` with optional compression and encryption of every file.
+///
+/// * `threads`: processes the files on worker threads, `None` keeps everything on the current thread.
+pub(crate) fn write_dir_to_car<W: CarWrite>(
+    curr_file_id: Rc<RefCell<u64>>,
+    dir_items: &[DirectoryItem],
+    car: car::Car<W>,
+    with_encryption: Option<EncryptionKey>,
+    with_compression: Option<Option<i32>>,
+    threads: Option<usize>,
+) -> Result<car::Car<W>, Error> {
+    if let Some(threads) = threads {
+        return write_dir_parallel(dir_items, car, with_encryption, with_compression, threads);
+    }
+
+    match (with_compression, with_encryption) {
+        (Some(level), Some(key)) => {
+            write_dir_compress_then_encrypt(curr_file_id, dir_items, car, level, key)
+        }
+        (Some(level), None) => write_dir_compress(curr_file_id, dir_items, car, level),
+        (None, Some(key)) => write_dir_encrypt(curr_file_id, dir_items, car, key),
+        _ => {
+            let mut dir = dir::Dir::new(curr_file_id, car);
+            dir.walk_write(dir_items)?;
+            Ok(dir.next())
+        }
+    }
+}
+
 #[cfg(feature = "encryption")]
 async fn encrypt(
     reader: &mut impl io::Read,
     writer: Box<dyn ChainWrite<uploader::Uploader>>,
-    mut password: Vec<u8>,
+    key: EncryptionKey,
 ) -> Result<Vec<Cid>, Error> {
-    let mut cipher = cipher::Cipher::new(&mut password, writer)?;
+    let mut cipher = gen_cipher(key, false, writer)?;
     io::copy(reader, &mut cipher)?;
     cipher.flush()?;
-    let ret = cipher.next_mut().next_mut().finish_results().await?;
+    let ret = cipher.next_mut().next_mut().next_mut().finish_results().await?;
     Ok(ret)
 }
 #[cfg(not(feature = "encryption"))]
 async fn encrypt(
     _: &mut impl io::Read,
     _: Box<dyn ChainWrite<uploader::Uploader>>,
-    _: Vec<u8>,
+    _: EncryptionKey,
 ) -> Result<Vec<Cid>, Error> {
     Err(Error::FeatureNoCipher)
 }
 
+/// Creates the encryption stage of the age format, which uses the password as the passphrase.
+#[cfg(feature = "age")]
+fn gen_age<W: io::Write>(key: EncryptionKey, next_writer: W) -> Result<age::Age<W>, Error> {
+    if key.encryption == envelope::Encryption::Aes256Gcm {
+        return Err(Error::AesGcmOfAge);
+    }
+
+    let age = match key.source {
+        KeySource::Password(password, _) => {
+            age::Age::new_with_passphrase(password, age::DEFAULT_WORK_FACTOR, next_writer)?
+        }
+        KeySource::Recipients(recipients) => {
+            let recipients = recipients
+                .iter()
+                .map(|x| x.parse())
+                .collect::<Result<Vec<recipient::Recipient>, _>>()?;
+            age::Age::new(recipients, next_writer)?
+        }
+        KeySource::Raw(_) => return Err(envelope::Error::RawKeyOfAge.into()),
+    };
+    Ok(age)
+}
+
+/// Writes the optionally compressed file through the age stage, and ends the stream.
+#[cfg(feature = "age")]
+fn write_age<W: io::Write>(
+    reader: &mut impl io::Read,
+    mut age: age::Age<W>,
+    level: Option<Option<i32>>,
+) -> Result<age::Age<W>, Error> {
+    match level {
+        #[cfg(feature = "zstd")]
+        Some(level) => {
+            let mut compressor = zstd::stream::Encoder::new(age, level.unwrap_or(10))?;
+            io::copy(reader, &mut compressor)?;
+            age = compressor.finish()?;
+        }
+        #[cfg(not(feature = "zstd"))]
+        Some(_) => return Err(Error::FeatureNoZstd),
+        None => {
+            io::copy(reader, &mut age)?;
+        }
+    }
+    age.flush()?;
+    Ok(age)
+}
+
+#[cfg(feature = "age")]
+async fn encrypt_age(
+    reader: &mut impl io::Read,
+    writer: Box<dyn ChainWrite<uploader::Uploader>>,
+    level: Option<Option<i32>>,
+    key: EncryptionKey,
+    is_armored: bool,
+) -> Result<Vec<Cid>, Error> {
+    let ret = if is_armored {
+        let mut age = write_age(reader, gen_age(key, age::Armor::new(writer))?, level)?;
+        age.next_mut()
+            .next_mut()
+            .next_mut()
+            .finish_results()
+            .await?
+    } else {
+        let mut age = write_age(reader, gen_age(key, writer)?, level)?;
+        age.next_mut().next_mut().finish_results().await?
+    };
+    Ok(ret)
+}
+#[cfg(not(feature = "age"))]
+async fn encrypt_age(
+    _: &mut impl io::Read,
+    _: Box<dyn ChainWrite<uploader::Uploader>>,
+    _: Option<Option<i32>>,
+    _: EncryptionKey,
+    _: bool,
+) -> Result<Vec<Cid>, Error> {
+    Err(Error::FeatureNoAge)
+}
+
+/// Options of `upload_dir`
+#[derive(Debug, Clone, Default)]
+pub struct UploadDirOptions {
+    /// bypasses the files or directories which return `false`
+    pub file_filter: Option<fn(name: &str, is_file: bool) -> bool>,
+    /// encrypts the files with the password
+    pub with_encryption: Option<Vec<u8>>,
+    /// compresses the files with zstd level, `Some(None)` means level 10
+    pub with_compression: Option<Option<i32>>,
+    /// keeps the POSIX mode and mtime of files and directories in the UnixFS nodes
+    pub with_metadata: bool,
+    /// skips, follows or stores symbolic links
+    pub symlink_policy: SymlinkPolicy,
+    /// controls the hash function, CID version, inlining and leaf types of the generated DAG,
+    /// `None` means the default config
+    pub dag_config: Option<DagConfig>,
+    /// reads, compresses, encrypts and hashes files on worker threads with the same result.
+    /// `None` keeps everything on the current thread, see `writer::parallel::available_threads`
+    pub threads: Option<usize>,
+    /// Argon2id costs to derive the key from the password, which are recorded in the encrypted files
+    /// for decryption. `None` means the default costs
+    pub argon2_params: Option<envelope::Argon2Params>,
+    /// encrypts the files for X25519 recipients like `w3s-x25519-...` instead of a password,
+    /// so any of their identities can decrypt them, see `writer::recipient`
+    pub with_recipients: Option<Vec<String>>,
+    /// encrypts the files with a raw 256-bit key instead of a password, without any key derivation.
+    /// The key ID is recorded in the envelope header, see `writer::raw_key`
+    pub with_key: Option<raw_key::RawKey>,
+    /// replaces the names with opaque IDs and removes the metadata from the DAG, which are kept in
+    /// an encrypted manifest for `download_dir`, see `writer::manifest`. Encryption is required
+    pub with_hidden_names: bool,
+    /// encrypts the files by AES-256-GCM instead of XChaCha20-Poly1305, which is recorded in the envelope header
+    /// for decryption. A password is derived by PBKDF2-HMAC-SHA256 instead of Argon2id, so `argon2_params` is
+    /// ignored, and it can't be used with `with_recipients`. The feature `aes` is required
+    pub with_aes_gcm: bool,
+    /// the path of a key file with an Ed25519 signing key, which signs the root directory and the digests of
+    /// the files into a signature file of the root directory for `verify`, see `writer::signature`.
+    /// The feature `signing` is required, and it can't be used with `with_hidden_names`
+    pub with_signing_key: Option<String>,
+}
+
 /// Uploads a entire directory recursively with optional encryption and compression
 pub async fn upload_dir(
     dir_path: &str,
-    file_filter: Option<fn(name: &str, is_file: bool) -> bool>,
     auth_token: String,
     max_upload_concurrent: usize,
     progress_listener: Option<uploader::ProgressListener>,
-    with_encryption: Option<Vec<u8>>,
-    with_compression: Option<Option<i32>>,
+    options: &UploadDirOptions,
 ) -> Result<Vec<Cid>, Error> {
+    let encryption_key = encryption_key(
+        options.with_encryption.clone(),
+        options.argon2_params,
+        options.with_recipients.clone(),
+        options.with_key.clone(),
+        options.with_aes_gcm,
+    )?;
+
     let uploader = uploader::Uploader::new(
         auth_token,
         dir_path.to_owned(),
@@ -227,7 +649,19 @@ pub async fn upload_dir(
         progress_listener,
     );
 
-    let (dir_items, count) = DirectoryItem::from_path(dir_path, file_filter)?;
+    let (dir_items, count) = DirectoryItem::from_path(
+        dir_path,
+        options.file_filter,
+        options.with_metadata,
+        options.symlink_policy,
+    )?;
+    // the manifest file is kept until the upload finishes
+    let (dir_items, count, _manifest_file) = hide_names(
+        dir_items,
+        count,
+        options.with_hidden_names,
+        encryption_key.is_some(),
+    )?;
     let dir_items_rc = Rc::new(dir_items);
 
     let curr_file_id = Rc::new(RefCell::new(0));
@@ -237,24 +671,32 @@ pub async fn upload_dir(
         dir_items_rc.clone(),
         Some(curr_file_id.clone()),
         None,
+        options.dag_config,
+        None,
         uploader,
-    );
+    )
+    .with_root_meta(root_meta(
+        dir_path,
+        options.with_metadata,
+        options.with_hidden_names,
+    )?);
+    let encoded = encryption_key.is_some() || options.with_compression.is_some();
+    let car = sign_dir(
+        car,
+        encoded,
+        options.with_signing_key.as_deref(),
+        options.with_hidden_names,
+    )?;
 
-    let results = match (with_compression, with_encryption) {
-        (Some(level), Some(password)) => {
-            upload_dir_compress_then_encrypt(curr_file_id, &dir_items_rc, car, level, password)
-                .await?
-        }
-        (Some(level), None) => upload_dir_compress(curr_file_id, &dir_items_rc, car, level).await?,
-        (None, Some(password)) => {
-            upload_dir_encrypt(curr_file_id, &dir_items_rc, car, password).await?
-        }
-        _ => {
-            let mut dir = dir::Dir::new(curr_file_id, car);
-            dir.walk_write(&dir_items_rc)?;
-            dir.next().next().finish_results().await?
-        }
-    };
+    let mut car = write_dir_to_car(
+        curr_file_id,
+        &dir_items_rc,
+        car,
+        encryption_key,
+        options.with_compression,
+        options.threads,
+    )?;
+    let results = car.next_mut().finish_results().await?;
 
     Ok(results)
 }
@@ -266,16 +708,54 @@ fn get_file_name(path: &str) -> Option<String> {
         .map(|x| x.to_owned())
 }
 
+/// Options of `upload`
+#[derive(Debug, Clone, Default)]
+pub struct UploadOptions {
+    /// packs the file in a CAR with the block size, `Some(None)` means the default 256K block size
+    pub with_car: Option<Option<usize>>,
+    /// encrypts the file with the password
+    pub with_encryption: Option<Vec<u8>>,
+    /// compresses the file with zstd level, `Some(None)` means level 10
+    pub with_compression: Option<Option<i32>>,
+    /// Argon2id costs to derive the key from the password, `None` means the default costs
+    pub argon2_params: Option<envelope::Argon2Params>,
+    /// encrypts the file for X25519 recipients like `w3s-x25519-...` instead of a password,
+    /// so any of their identities can decrypt it, see `writer::recipient`
+    pub with_recipients: Option<Vec<String>>,
+    /// writes the standard age format instead of the envelope, so the `age` CLI can decrypt the file
+    /// with the password as the passphrase or an identity of the recipients. `Some(true)` armors it in PEM.
+    /// A compressed file should be downloaded with `with_decompression`, since the age format doesn't record it.
+    /// A password or recipients are required, otherwise `Error::AgeWithoutSecret` is returned
+    pub with_age: Option<bool>,
+    /// encrypts the file with a raw 256-bit key instead of a password, without any key derivation.
+    /// The key ID is recorded in the envelope header, see `writer::raw_key`
+    pub with_key: Option<raw_key::RawKey>,
+    /// encrypts the file by AES-256-GCM instead of XChaCha20-Poly1305, which is recorded in the envelope header
+    /// for decryption. A password is derived by PBKDF2-HMAC-SHA256 instead of Argon2id, so `argon2_params` is
+    /// ignored. The feature `aes` is required, and it can't be used with `with_age` or `with_recipients`
+    pub with_aes_gcm: bool,
+}
+
 /// Uploads a single file with optional encryption and compression
 pub async fn upload(
     path: &str,
     auth_token: impl AsRef<str>,
     max_upload_concurrent: usize,
     progress_listener: Option<uploader::ProgressListener>,
-    with_car: Option<Option<usize>>,
-    with_encryption: Option<Vec<u8>>,
-    with_compression: Option<Option<i32>>,
+    options: &UploadOptions,
 ) -> Result<Vec<Cid>, Error> {
+    let encryption_key = encryption_key(
+        options.with_encryption.clone(),
+        options.argon2_params,
+        options.with_recipients.clone(),
+        options.with_key.clone(),
+        options.with_aes_gcm,
+    )?;
+    let with_age = options.with_age;
+    // the age format is always encrypted, so the file is never uploaded in plaintext instead
+    if with_age.is_some() && encryption_key.is_none() {
+        return Err(Error::AgeWithoutSecret);
+    }
     let mut reader = File::open(path)?;
     let name = get_file_name(path).unwrap_or_default();
 
@@ -284,15 +764,16 @@ pub async fn upload(
         name,
         max_upload_concurrent,
         progress_listener,
-        with_car,
+        options.with_car,
     );
 
-    let results = match (with_compression, with_encryption) {
-        (Some(level), Some(password)) => {
-            compress_then_encrypt(&mut reader, writer, level, password).await?
+    let results = match (options.with_compression, encryption_key) {
+        (level, Some(key)) if with_age.is_some() => {
+            encrypt_age(&mut reader, writer, level, key, with_age == Some(true)).await?
         }
+        (Some(level), Some(key)) => compress_then_encrypt(&mut reader, writer, level, key).await?,
         (Some(level), None) => compress(&mut reader, writer, level).await?,
-        (None, Some(password)) => encrypt(&mut reader, writer, password).await?,
+        (None, Some(key)) => encrypt(&mut reader, writer, key).await?,
         _ => {
             io::copy(&mut reader, &mut writer)?;
             writer.flush()?;
@@ -303,41 +784,260 @@ pub async fn upload(
     Ok(results)
 }
 
-#[cfg(all(feature = "zstd", feature = "encryption"))]
-fn decrypt_then_decompress<'a>(
-    writer: impl io::Write,
-    password: Vec<u8>,
-) -> Result<cipher::Cipher<decompressor::Decompressor<'a, impl io::Write>>, Error> {
-    let decompressor = decompressor::Decompressor::new(writer)?;
-    let cipher = cipher::Cipher::new_decryption(password, decompressor)?;
-    Ok(cipher)
+/// Writes the shard, which declares the header `roots` in it followed by the roots of its complete subtrees.
+/// Returns the declared roots.
+fn write_car_shard(
+    writer: &mut impl CarWrite,
+    roots: Vec<Cid>,
+    shard: &mut car_stream::CarShard,
+) -> Result<Vec<Cid>, Error> {
+    let roots = shard.declared_roots(roots, &DagConfig::default())?;
+    if writer.write_car(shard.finish_with_roots(roots.clone())?)? == 0 {
+        writer.flush()?;
+    }
+    Ok(roots)
 }
-#[cfg(not(all(feature = "zstd", feature = "encryption")))]
-fn decrypt_then_decompress<W: io::Write>(_: W, _: Vec<u8>) -> Result<W, Error> {
-    Err(Error::FeatureNoCipherAndZstd)
+
+/// Re-shards the blocks of a CAR in their original order into CARs of at most `max_size` bytes.
+/// Returns the declared roots of every shard.
+async fn reshard_car<R: tokio::io::AsyncRead + Send + Unpin>(
+    reader: &mut CarReader<R>,
+    max_size: usize,
+    writer: &mut impl CarWrite,
+) -> Result<Vec<Vec<Cid>>, Error> {
+    let header_roots = reader.header().roots().to_vec();
+    // room for the header roots besides the subtree roots, which is reserved by the shard
+    let header_size = CarHeader::new(header_roots.clone()).encode()?.len();
+
+    let mut ret = vec![];
+    let mut roots = vec![];
+    let mut shard = car_stream::CarShard::new(car_stream::MemoryBudget::default());
+    while let Some((cid, data)) = reader.next_block().await? {
+        if !shard.is_empty()
+            && (shard.has_max_roots()
+                || shard.len_with_block(&cid, data.len()) + header_size > max_size)
+        {
+            let shard_roots = std::mem::take(&mut roots);
+            ret.push(write_car_shard(writer, shard_roots, &mut shard)?);
+        }
+        if header_roots.contains(&cid) && !roots.contains(&cid) {
+            roots.push(cid);
+        }
+        shard.write_block(cid, &data)?;
+    }
+    if !shard.is_empty() {
+        ret.push(write_car_shard(writer, roots, &mut shard)?);
+    }
+
+    Ok(ret)
+}
+
+/// Whether the service returned `root`, which may turn a CIDv0 into CIDv1 of the same codec and multihash
+fn is_same_root(root: &Cid, returned: &Cid) -> bool {
+    root.codec() == returned.codec() && root.hash() == returned.hash()
+}
+
+/// Uploads a CARv1 or CARv2 file of any size
+///
+/// The blocks are re-sharded in their original order under the size limit of web3.storage.
+/// Every shard declares the roots of the original header which are in it,
+/// followed by the roots of its complete subtrees.
+/// Returns `Error::RootMismatch` if the service returns a root which a shard doesn't declare,
+/// or `Error::RootNotUploaded` if a root of the header is not returned.
+pub async fn upload_car(
+    path: &str,
+    auth_token: impl AsRef<str>,
+    max_upload_concurrent: usize,
+    progress_listener: Option<uploader::ProgressListener>,
+) -> Result<Vec<Cid>, Error> {
+    let file = tokio::fs::File::open(path).await?;
+    let mut reader = CarReader::new(tokio::io::BufReader::new(file)).await?;
+    let roots = reader.header().roots().to_vec();
+    if roots.is_empty() {
+        return Err(iroh_car::Error::InvalidFile("The CAR file has no root".to_string()).into());
+    }
+
+    let mut uploader = uploader::Uploader::new(
+        auth_token.as_ref().to_owned(),
+        get_file_name(path).unwrap_or_default(),
+        uploader::UploadType::Car,
+        max_upload_concurrent,
+        progress_listener,
+    );
+
+    let declared = reshard_car(&mut reader, MAX_CAR_SIZE, &mut uploader).await?;
+
+    // every shard returns one of its declared roots
+    let results = uploader.finish_results().await?;
+    for (x, shard_roots) in results.iter().zip(declared) {
+        if !shard_roots.iter().any(|root| is_same_root(root, x)) {
+            return Err(Error::RootMismatch(shard_roots, Box::new(*x)));
+        }
+    }
+    if let Some(x) = roots
+        .iter()
+        .find(|root| !results.iter().any(|x| is_same_root(root, x)))
+    {
+        return Err(Error::RootNotUploaded(Box::new(*x)));
+    }
+
+    Ok(results)
 }
+
+/// Fetches the headers of an encrypted file, and returns the cipher with the offset to download from.
 #[cfg(feature = "encryption")]
-fn decrypt(
+async fn decrypt_from(
     writer: impl io::Write,
-    password: Vec<u8>,
-) -> Result<cipher::Cipher<impl io::Write>, Error> {
-    let cipher = cipher::Cipher::new_decryption(password, writer)?;
-    Ok(cipher)
+    secret: envelope::Secret,
+    url: &str,
+    offset: u64,
+    max_params: Option<envelope::Argon2Params>,
+) -> Result<(cipher::Cipher<impl io::Write>, u64), Error> {
+    let len = envelope::HEADER_SIZE + cipher::MAX_HEADER_SIZE;
+    let mut head = downloader::fetch_range(url, 0, len as u64).await?;
+    if envelope::age_format(&head).is_some() {
+        return Err(envelope::Error::OffsetOfAge.into());
+    }
+    let header = envelope::Header::parse(&head)?;
+
+    // files uploaded without the envelope start with the cipher header
+    let start = match header {
+        Some(x) if x.compression != envelope::Compression::None => {
+            return Err(envelope::Error::OffsetOfCompressed.into())
+        }
+        Some(x) => envelope::HEADER_SIZE + x.stanzas_size()?,
+        None => 0,
+    };
+    if start + cipher::MAX_HEADER_SIZE > len {
+        let len = start + cipher::MAX_HEADER_SIZE;
+        head = downloader::fetch_range(url, 0, len as u64).await?;
+    }
+
+    let envelope_head = head
+        .get(..start)
+        .ok_or(envelope::Error::TooShortForHeader)?;
+    let version = *head.get(start).ok_or(cipher::Error::TooShortForHeader)?;
+    let cipher_header = head
+        .get(start..start + cipher::header_size(version)?)
+        .ok_or(cipher::Error::TooShortForHeader)?;
+
+    let mut cipher =
+        envelope::decryption_cipher(header, envelope_head, secret, offset, max_params, writer)?;
+    cipher.write_all(cipher_header)?;
+    Ok((
+        cipher,
+        start as u64 + cipher::encrypted_offset(version, offset)?,
+    ))
 }
 #[cfg(not(feature = "encryption"))]
-fn decrypt<W: io::Write>(_: W, _: Vec<u8>) -> Result<W, Error> {
+async fn decrypt_from<W: io::Write>(
+    _: W,
+    _: envelope::Secret,
+    _: &str,
+    _: u64,
+    _: Option<envelope::Argon2Params>,
+) -> Result<(W, u64), Error> {
     Err(Error::FeatureNoCipher)
 }
-#[cfg(feature = "zstd")]
-fn decompress<'a>(
-    writer: impl io::Write,
-) -> Result<decompressor::Decompressor<'a, impl io::Write>, Error> {
-    let decompressor = decompressor::Decompressor::new(writer)?;
-    Ok(decompressor)
+
+#[cfg(feature = "encryption")]
+fn read_identities(path: &str) -> Result<envelope::Secret, Error> {
+    let identities = recipient::Identity::from_file(path)?;
+    Ok(envelope::Secret::Identities(identities))
 }
-#[cfg(not(feature = "zstd"))]
-fn decompress<W: io::Write>(_: W) -> Result<W, Error> {
-    Err(Error::FeatureNoZstd)
+#[cfg(not(feature = "encryption"))]
+fn read_identities(_: &str) -> Result<envelope::Secret, Error> {
+    Err(Error::FeatureNoCipher)
+}
+
+/// Returns the secret of the key file if any, or the password.
+fn read_secret(
+    with_decryption: Option<Vec<u8>>,
+    with_key_file: Option<&str>,
+) -> Result<Option<envelope::Secret>, Error> {
+    match with_key_file {
+        Some(path) => Ok(Some(read_key_file(path)?)),
+        None => Ok(with_decryption.map(envelope::Secret::Password)),
+    }
+}
+
+/// Reads the raw keys of a key file, or the X25519 identities if it has no raw key.
+fn read_key_file(path: &str) -> Result<envelope::Secret, Error> {
+    let text = fs::read_to_string(path)?;
+    if text
+        .lines()
+        .any(|x| x.trim().starts_with(raw_key::KEY_PREFIX))
+    {
+        return Ok(envelope::Secret::Keys(raw_key::RawKey::parse_lines(&text)?));
+    }
+    read_identities(path)
+}
+
+/// Creates a symbolic link at `path` inside the directory `root`.
+/// The target comes from an untrusted DAG or manifest, so it must be relative and stay inside `root`.
+///
+/// The target is normalized before the link is created, like `sub/../a` to `a`, so its parent components
+/// never go through another link. Links can't be created through the links created before either.
+pub(crate) fn create_symlink(target: &str, path: &Path, root: &Path) -> Result<(), Error> {
+    let unsafe_link = || Error::UnsafeSymlink(path.display().to_string(), target.to_owned());
+
+    // the depth of the directory holding the link under `root`
+    let parent = path.parent().ok_or_else(unsafe_link)?;
+    check_no_symlink(parent, root).map_err(|_| unsafe_link())?;
+    let depth = parent
+        .strip_prefix(root)
+        .map_err(|_| unsafe_link())?
+        .components()
+        .count();
+
+    let (mut ups, mut normals) = (0, vec![]);
+    for component in Path::new(target).components() {
+        match component {
+            Component::Normal(x) => normals.push(x),
+            Component::CurDir => {}
+            Component::ParentDir if normals.pop().is_some() => {}
+            Component::ParentDir if ups < depth => ups += 1,
+            _ => return Err(unsafe_link()),
+        }
+    }
+
+    let mut normalized = PathBuf::new();
+    for _ in 0..ups {
+        normalized.push(Component::ParentDir);
+    }
+    normalized.extend(normals);
+    if normalized.as_os_str().is_empty() {
+        normalized.push(Component::CurDir);
+    }
+    symlink(&normalized, path)
+}
+
+/// Refuses `path` under `root` if it or any of its parents under `root` is a symbolic link,
+/// so nothing is written out of `root` through the links created before.
+pub(crate) fn check_no_symlink(path: &Path, root: &Path) -> Result<(), Error> {
+    let through_link = || Error::SymlinkInPath(path.display().to_string());
+
+    let relative = path.strip_prefix(root).map_err(|_| through_link())?;
+    let mut current = root.to_path_buf();
+    for component in relative.components() {
+        current.push(component);
+        if matches!(fs::symlink_metadata(&current), Ok(x) if x.file_type().is_symlink()) {
+            return Err(through_link());
+        }
+    }
+    Ok(())
+}
+#[cfg(unix)]
+fn symlink(target: &Path, path: &Path) -> Result<(), Error> {
+    Ok(std::os::unix::fs::symlink(target, path)?)
+}
+#[cfg(windows)]
+fn symlink(target: &Path, path: &Path) -> Result<(), Error> {
+    Ok(std::os::windows::fs::symlink_file(target, path)?)
+}
+#[cfg(not(any(unix, windows)))]
+fn symlink(_: &Path, _: &Path) -> Result<(), Error> {
+    Err(Error::SymlinkUnsupported)
 }
 
 #[async_recursion::async_recursion(?Send)]
@@ -346,34 +1046,78 @@ async fn rec_download(
     root: &Path,
     url: &str,
     progress_listener: Option<uploader::ProgressListener>,
-    with_decryption: Option<Vec<u8>>,
-    with_decompression: bool,
+    secret: Option<envelope::Secret>,
+    options: &DownloadDirOptions,
+    manifest: Option<Rc<manifest::Manifest>>,
 ) -> Result<(), Error> {
     match gs {
+        GatewayStruct::File(path) if manifest.is_some() && path == manifest::MANIFEST_NAME => {}
         GatewayStruct::File(path) => {
-            let f_path = root.join(&path);
-            if !f_path.exists() {
-                let f = File::create(&f_path)?;
+            let entry = manifest.as_ref().and_then(|x| x.entry(&path));
+            let f_path = root.join(entry.map_or(&path, |x| &x.path));
+            if fs::symlink_metadata(&f_path).is_err() {
+                check_no_symlink(&f_path, root)?;
+                let file_url = format!("{}{}", url, path);
+                // the raw node costs a request per file, and hidden names keep everything in the manifest
+                let block = if entry.is_none() && (options.with_symlinks || options.with_metadata) {
+                    fetch_raw_block(&file_url).await
+                } else {
+                    None
+                };
+
+                if let Some(target) = block.as_deref().and_then(read_symlink_block) {
+                    create_symlink(&target, &f_path, root)?;
+                    return Ok(());
+                }
+
                 let name = f_path
                     .file_name()
                     .and_then(|x| x.to_str())
                     .ok_or_else(|| Error::FilenameError(path.clone()))?;
+                // the signature file is never encrypted or compressed
+                let decoding = if path == SIGNATURE_NAME {
+                    Decoding::default()
+                } else {
+                    Decoding {
+                        secret,
+                        with_decompression: options.with_decompression,
+                        argon2_limits: options.argon2_limits,
+                        ..Default::default()
+                    }
+                };
 
-                download(
-                    format!("{}{}", url, path),
-                    name,
-                    f,
-                    progress_listener,
-                    None,
-                    with_decryption,
-                    with_decompression,
-                )
-                .await?;
+                if options.with_verification {
+                    // the file is only created after the content is verified
+                    let mut staging = downloader::Staging::new_in(f_path.parent().unwrap_or(root))?;
+                    download_decoded(&file_url, name, &mut staging, progress_listener, decoding)
+                        .await?;
+                    staging.persist(&f_path)?;
+                } else {
+                    download_decoded(
+                        &file_url,
+                        name,
+                        File::create(&f_path)?,
+                        progress_listener,
+                        decoding,
+                    )
+                    .await?;
+                }
+
+                if options.with_metadata {
+                    if let Some(meta) = entry
+                        .map(|x| x.meta)
+                        .or_else(|| block.as_deref().and_then(FileMeta::from_block))
+                    {
+                        meta.apply(&f_path)?;
+                    }
+                }
             }
         }
         GatewayStruct::Directory(path, Some(sub_items)) => {
-            let dir_path = root.join(path);
-            fs::create_dir_all(dir_path)?;
+            let entry = manifest.as_ref().and_then(|x| x.entry(&path));
+            let dir_path = root.join(entry.map_or(&path, |x| &x.path));
+            check_no_symlink(&dir_path, root)?;
+            fs::create_dir_all(&dir_path)?;
 
             for item in sub_items {
                 rec_download(
@@ -381,11 +1125,21 @@ async fn rec_download(
                     root,
                     url,
                     progress_listener.clone(),
-                    with_decryption.clone(),
-                    with_decompression,
+                    secret.clone(),
+                    options,
+                    manifest.clone(),
                 )
                 .await?
             }
+
+            // applied after the children are written since they change the directory mtime
+            if let (true, Some(entry)) = (options.with_metadata, entry) {
+                entry.meta.apply(&dir_path)?;
+            } else if options.with_metadata {
+                if let Some(meta) = fetch_unixfs_meta(&format!("{}{}", url, path)).await {
+                    meta.apply(&dir_path)?;
+                }
+            }
         }
         _ => {}
     }
@@ -393,15 +1147,40 @@ async fn rec_download(
     Ok(())
 }
 
+/// Options of `download_dir`
+#[derive(Debug, Clone, Default)]
+pub struct DownloadDirOptions {
+    /// decrypts the files with the password
+    pub with_decryption: Option<Vec<u8>>,
+    /// decompresses the files without the envelope header, see `download`
+    pub with_decompression: bool,
+    /// the path of a key file to decrypt the files with instead of `with_decryption`, see `DownloadOptions`
+    pub with_key_file: Option<String>,
+    /// restores the POSIX mode and mtime when the gateway serves the raw UnixFS nodes
+    pub with_metadata: bool,
+    /// recreates UnixFS symlinks as local symbolic links when the gateway serves the raw UnixFS nodes.
+    /// Both this and `with_metadata` fetch the raw node of every file. Symlinks pointing out of the folder are refused
+    pub with_symlinks: bool,
+    /// creates every file only after its whole content is decrypted and verified
+    pub with_verification: bool,
+    /// the largest Argon2 costs accepted from the encrypted files, see `DownloadOptions`
+    pub argon2_limits: Option<envelope::Argon2Params>,
+}
+
 /// Download the entire cid structure as local directory with optional decryption and decompression
+///
+/// Directories uploaded with hidden names are restored with the names, metadata and symlinks of their manifests.
 pub async fn download_dir(
     url: &str,
     save_to_folder: &str,
     check_progress_listener: Option<fn(&str, u16)>,
     progress_listener: Option<uploader::ProgressListener>,
-    with_decryption: Option<Vec<u8>>,
-    with_decompression: bool,
+    options: &DownloadDirOptions,
 ) -> Result<(), Error> {
+    let secret = read_secret(
+        options.with_decryption.clone(),
+        options.with_key_file.as_deref(),
+    )?;
     let url = format!("{}{}", url, if url.ends_with("/") { "" } else { "/" });
     let cid_struct = cid_url_check(&url, "", check_progress_listener).await;
 
@@ -409,55 +1188,372 @@ pub async fn download_dir(
 
     let root = Path::new(save_to_folder);
 
+    let manifest = match &cid_struct {
+        GatewayStruct::Directory(_, Some(items))
+            if items
+                .iter()
+                .any(|x| matches!(x, GatewayStruct::File(x) if x == manifest::MANIFEST_NAME)) =>
+        {
+            let mut buf = vec![];
+            download_decoded(
+                &format!("{}{}", url, manifest::MANIFEST_NAME),
+                manifest::MANIFEST_NAME,
+                &mut buf,
+                None,
+                Decoding {
+                    secret: secret.clone(),
+                    with_decompression: options.with_decompression,
+                    argon2_limits: options.argon2_limits,
+                    ..Default::default()
+                },
+            )
+            .await?;
+            Some(Rc::new(manifest::Manifest::from_slice(&buf)?))
+        }
+        _ => None,
+    };
+
     rec_download(
         cid_struct,
         root,
         &url,
         progress_listener,
-        with_decryption,
-        with_decompression,
+        secret,
+        options,
+        manifest.clone(),
     )
     .await?;
 
+    // the links of hidden names are only kept in the manifest
+    for entry in manifest.iter().flat_map(|x| x.symlinks()) {
+        let path = root.join(&entry.path);
+        if fs::symlink_metadata(&path).is_err() {
+            create_symlink(entry.target.as_deref().unwrap_or_default(), &path, root)?;
+        }
+    }
+
     Ok(())
 }
 
+/// Verifies the signature file of a directory uploaded with `with_signing_key`, and returns the signed root.
+///
+/// The signature is checked by the trusted public key like `w3s-ed25519-...`, and the root block served by
+/// the gateway is checked against the signed root, see `writer::signature`.
+/// * `downloaded`: the folder which `download_dir` saved the directory to, whose files are checked against
+///   the signed digests as well, and any file without a digest is reported.
+///   Compressed or encrypted uploads can't be checked this way.
+#[cfg(feature = "signing")]
+pub async fn verify(url: &str, public_key: &str, downloaded: Option<&str>) -> Result<Cid, Error> {
+    let trusted: signature::PublicKey = public_key.parse()?;
+    let url = format!("{}{}", url, if url.ends_with("/") { "" } else { "/" });
+
+    let root_block = fetch_raw_block(&url)
+        .await
+        .ok_or_else(|| Error::NoRootBlock(url.clone()))?;
+
+    let mut buf = vec![];
+    download(
+        format!("{}{}", url, SIGNATURE_NAME),
+        SIGNATURE_NAME,
+        &mut buf,
+        None,
+        &DownloadOptions::default(),
+    )
+    .await?;
+
+    let signed = signature::Signature::from_slice(&buf)?;
+    let root = signed.verify(&trusted, &root_block)?;
+    if let Some(dir) = downloaded {
+        signed.check_dir(dir)?;
+    }
+
+    Ok(root)
+}
+#[cfg(not(feature = "signing"))]
+pub async fn verify(_: &str, _: &str, _: Option<&str>) -> Result<Cid, Error> {
+    Err(Error::FeatureNoSigning)
+}
+
+/// Options of `download`
+#[derive(Debug, Clone, Default)]
+pub struct DownloadOptions {
+    /// the offset in the original file to resume from, which is not supported for compressed files.
+    /// Without a secret, the header is fetched first, and encrypted files are refused instead of written encoded
+    pub start_offset: Option<u64>,
+    /// decrypts the file with the password
+    pub with_decryption: Option<Vec<u8>>,
+    /// decompresses the file without the envelope header
+    pub with_decompression: bool,
+    /// the path of a key file with the raw keys or the X25519 identities to decrypt the files encrypted with them,
+    /// which is used instead of `with_decryption`. Key files of `age-keygen` are accepted with `age`,
+    /// see `writer::raw_key` and `writer::recipient`
+    pub with_key_file: Option<String>,
+    /// stages the decoded bytes in a temporary file, and writes them to `writer` only after every chunk is verified.
+    /// With a wrong password or changed content, `writer` is never written
+    pub with_verification: bool,
+    /// the largest Argon2 costs accepted from the header of an encrypted file, since decryption takes as much
+    /// memory and time as the header asks for. `cipher::MAX_M_COST`, `cipher::MAX_T_COST` and
+    /// `cipher::MAX_P_COST` by default
+    pub argon2_limits: Option<envelope::Argon2Params>,
+}
+
 /// Download a single file with optional decryption and decompression
+///
+/// Files uploaded by this crate with compression or encryption are detected by their envelope headers,
+/// so `with_decompression` only matters for the files without the header.
+/// Files in the age format are detected as well, and decrypted by the identities or the password as the passphrase.
 pub async fn download(
     url: impl AsRef<str>,
     name: impl AsRef<str>,
-    writer: impl io::Write,
+    mut writer: impl io::Write,
     progress_listener: Option<uploader::ProgressListener>,
+    options: &DownloadOptions,
+) -> Result<(), Error> {
+    let secret = read_secret(
+        options.with_decryption.clone(),
+        options.with_key_file.as_deref(),
+    )?;
+    let decoding = Decoding {
+        start_offset: options.start_offset,
+        secret,
+        with_decompression: options.with_decompression,
+        argon2_limits: options.argon2_limits,
+    };
+
+    if options.with_verification {
+        let mut staging = downloader::Staging::new()?;
+        download_decoded(
+            url.as_ref(),
+            name.as_ref(),
+            &mut staging,
+            progress_listener,
+            decoding,
+        )
+        .await?;
+        staging.release_to(&mut writer)?;
+    } else {
+        download_decoded(
+            url.as_ref(),
+            name.as_ref(),
+            writer,
+            progress_listener,
+            decoding,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// How `download_decoded` decodes a file
+#[derive(Default)]
+struct Decoding {
     start_offset: Option<u64>,
-    with_decryption: Option<Vec<u8>>,
+    secret: Option<envelope::Secret>,
     with_decompression: bool,
+    argon2_limits: Option<envelope::Argon2Params>,
+}
+
+/// Downloads the file into `writer` through the decryption and decompression.
+async fn download_decoded(
+    url: &str,
+    name: &str,
+    writer: impl io::Write,
+    progress_listener: Option<uploader::ProgressListener>,
+    decoding: Decoding,
 ) -> Result<(), Error> {
+    let Decoding {
+        start_offset,
+        secret,
+        with_decompression,
+        argon2_limits,
+    } = decoding;
     macro_rules! gen_downloader {
-        ($writer:expr) => {{
+        ($writer:expr, $start_offset:expr) => {{
             let mut downloader = downloader::Downloader::new(progress_listener, $writer);
             downloader
-                .download(name.as_ref().to_owned(), url.as_ref(), start_offset)
+                .download(name.to_owned(), url, $start_offset)
                 .await?;
         }};
     }
 
-    match (with_decompression, with_decryption) {
-        (true, Some(password)) => {
-            let cipher = decrypt_then_decompress(writer, password)?;
-            gen_downloader!(cipher);
-        }
-        (false, Some(password)) => {
-            let cipher = decrypt(writer, password)?;
-            gen_downloader!(cipher);
+    match (secret, start_offset) {
+        (_, Some(_)) if with_decompression => Err(envelope::Error::OffsetOfCompressed)?,
+        (Some(secret), Some(offset)) => {
+            let (cipher, encrypted_offset) =
+                decrypt_from(writer, secret, url, offset, argon2_limits).await?;
+            gen_downloader!(cipher, Some(encrypted_offset));
         }
-        (true, None) => {
-            let decompressor = decompress(writer)?;
-            gen_downloader!(decompressor);
+        (None, Some(offset)) => {
+            // the rest of the file has no header to detect, so it is checked at the beginning
+            let len = cmp::max(envelope::HEADER_SIZE, envelope::AGE_ARMOR_BEGIN.len()) as u64;
+            let start = envelope::plain_start(&downloader::fetch_range(url, 0, len).await?)?;
+            gen_downloader!(writer, Some(start + offset));
         }
-        _ => {
-            gen_downloader!(writer);
+        (secret, None) => {
+            // files with the envelope header are decoded by it
+            let mut opener = envelope::Opener::new(secret, with_decompression, writer);
+            if let Some(max) = argon2_limits {
+                opener = opener.with_max_params(max);
+            }
+            gen_downloader!(opener, None);
         }
     };
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsafe_symlinks() {
+        let root = std::env::temp_dir().join(format!("w3s-symlinks-{}", std::process::id()));
+        fs::create_dir_all(root.join("sub")).unwrap();
+
+        for (target, path) in [
+            ("/etc/passwd", "a"),
+            ("../a", "a"),
+            ("sub/../../a", "b"),
+            ("../../a", "sub/a"),
+        ] {
+            assert!(matches!(
+                create_symlink(target, &root.join(path), &root),
+                Err(Error::UnsafeSymlink(..))
+            ));
+        }
+
+        #[cfg(unix)]
+        for (target, path) in [("a", "b"), ("../a", "sub/a"), ("./sub/../a", "c")] {
+            create_symlink(target, &root.join(path), &root).unwrap();
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn chained_symlinks() {
+        let root = std::env::temp_dir().join(format!("w3s-chained-{}", std::process::id()));
+        fs::create_dir_all(root.join("sub")).unwrap();
+
+        // `up/..` would resolve out of `root` through `sub/up`, so it is normalized first
+        create_symlink("..", &root.join("sub/up"), &root).unwrap();
+        create_symlink("up/..", &root.join("sub/l"), &root).unwrap();
+        assert_eq!(fs::read_link(root.join("sub/l")).unwrap(), Path::new("."));
+        let resolved = root.join("sub/l").canonicalize().unwrap();
+        assert!(resolved.starts_with(root.canonicalize().unwrap()));
+
+        // nothing is created or written through a link
+        assert!(matches!(
+            create_symlink("a", &root.join("sub/up/a"), &root),
+            Err(Error::UnsafeSymlink(..))
+        ));
+        assert!(matches!(
+            check_no_symlink(&root.join("sub/up/sub/file"), &root),
+            Err(Error::SymlinkInPath(..))
+        ));
+        assert!(matches!(
+            check_no_symlink(&root.join("sub/up"), &root),
+            Err(Error::SymlinkInPath(..))
+        ));
+        check_no_symlink(&root.join("sub/file"), &root).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn age_without_secret() {
+        let options = UploadOptions {
+            with_age: Some(false),
+            ..Default::default()
+        };
+        let ret = upload("missing-file", "token", 1, None, &options).await;
+        assert!(matches!(ret, Err(Error::AgeWithoutSecret)));
+    }
+
+    /// Collects every CAR.
+    #[derive(Default)]
+    struct Cars(Vec<Vec<u8>>);
+    impl Write for Cars {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    impl CarWrite for Cars {
+        fn write_car(&mut self, car: car_stream::UploadBody) -> io::Result<usize> {
+            self.0.push(car.to_vec()?);
+            Ok(1)
+        }
+    }
+
+    #[tokio::test]
+    async fn reshard_oversized_car() {
+        use multihash::{Code, MultihashDigest};
+        use quick_protobuf::{MessageWrite, Writer};
+        use std::borrow::Cow;
+        use unixfs_v1::{PBLink, PBNode};
+
+        // a file of 50 leaves, whose root goes last
+        let leaves: Vec<_> = (0..50u8)
+            .map(|i| {
+                let data = vec![i; 1000];
+                (Cid::new_v1(0x55, Code::Sha2_256.digest(&data)), data)
+            })
+            .collect();
+        let node = PBNode {
+            Links: leaves
+                .iter()
+                .map(|(cid, data)| PBLink {
+                    Hash: Some(Cow::from(cid.to_bytes())),
+                    Name: Some(Cow::from("")),
+                    Tsize: Some(data.len() as u64),
+                })
+                .collect(),
+            // UnixFS `Type: File`
+            Data: Some(Cow::from(&[0x08, 0x02][..])),
+        };
+        let mut data = vec![];
+        node.write_message(&mut Writer::new(&mut data)).unwrap();
+        let root = Cid::new_v1(0x70, Code::Sha2_256.digest(&data));
+
+        let mut car = iroh_car::CarWriter::new(CarHeader::new(vec![root]), vec![]);
+        for (cid, data) in leaves.iter() {
+            car.write(*cid, data).unwrap();
+        }
+        car.write(root, &data).unwrap();
+        let car = car.finish().unwrap();
+
+        let max_size = 8 * 1024;
+        let mut reader = CarReader::new(car.as_slice()).await.unwrap();
+        let mut cars = Cars::default();
+        let declared = reshard_car(&mut reader, max_size, &mut cars).await.unwrap();
+        assert!(cars.0.len() > 1);
+        assert_eq!(declared.len(), cars.0.len());
+
+        let mut blocks = vec![];
+        for (car, roots) in cars.0.iter().zip(declared.iter()) {
+            assert!(car.len() <= max_size);
+            let mut reader = CarReader::new(car.as_slice()).await.unwrap();
+            assert_eq!(reader.header().roots(), roots.as_slice());
+
+            // every declared root is a block of the shard
+            let mut cids = vec![];
+            while let Some((cid, _)) = reader.next_block().await.unwrap() {
+                cids.push(cid);
+            }
+            assert!(roots.iter().all(|x| cids.contains(x)));
+            blocks.extend(cids);
+        }
+
+        // only the last shard has the header root, which it declares first
+        assert_eq!(declared.last().unwrap()[0], root);
+        assert!(declared[..declared.len() - 1]
+            .iter()
+            .all(|x| !x.contains(&root)));
+        let expected: Vec<_> = leaves.iter().map(|x| x.0).chain([root]).collect();
+        assert_eq!(blocks, expected);
+    }
+}
@@ -1,20 +1,35 @@
+// the `DagCbor` derive of libipld 0.13 relies on the never type fallback
+#![allow(unknown_lints, dependency_on_unit_never_type_fallback)]
+
 use cid::Cid;
 use ipld::codec::Codec;
 use ipld_cbor::DagCborCodec;
 
 use super::error::Error;
 
+/// The fixed bytes starting a CARv2 file, a CARv1 styled header of `{version: 2}`.
+pub const PRAGMA_V2: [u8; 11] = [
+    0x0a, 0xa1, 0x67, 0x76, 0x65, 0x72, 0x73, 0x69, 0x6f, 0x6e, 0x02,
+];
+/// The size of the fixed CARv2 header following the pragma.
+pub const HEADER_V2_SIZE: usize = 40;
+
 /// A car header.
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[non_exhaustive]
 pub enum CarHeader {
     V1(CarHeaderV1),
+    V2(CarHeaderV2),
 }
 
 impl CarHeader {
     pub fn new(roots: Vec<Cid>) -> Self {
         CarHeader::V1(CarHeaderV1::new(roots, 1))
     }
+
+    pub fn new_v2(roots: Vec<Cid>, data_size: u64, index_size: u64) -> Self {
+        CarHeader::V2(CarHeaderV2::new(roots, data_size, index_size))
+    }
     pub fn decode(buffer: &[u8]) -> Result<Self, Error> {
         let header: CarHeaderV1 = DagCborCodec
             .decode(buffer)
@@ -33,9 +48,13 @@ impl CarHeader {
         Ok(CarHeader::V1(header))
     }
 
+    /// Encodes the CARv1 header. For CARv2, it's the header of the inner CARv1 payload.
     pub fn encode(&self) -> Result<Vec<u8>, Error> {
         match self {
-            CarHeader::V1(ref header) => {
+            CarHeader::V1(ref header)
+            | CarHeader::V2(CarHeaderV2 {
+                inner: ref header, ..
+            }) => {
                 let res = DagCborCodec.encode(header)?;
                 Ok(res)
             }
@@ -45,12 +64,14 @@ impl CarHeader {
     pub fn roots(&self) -> &[Cid] {
         match self {
             CarHeader::V1(header) => &header.roots,
+            CarHeader::V2(header) => &header.inner.roots,
         }
     }
 
     pub fn version(&self) -> u64 {
         match self {
             CarHeader::V1(_) => 1,
+            CarHeader::V2(_) => 2,
         }
     }
 }
@@ -77,6 +98,74 @@ impl From<Vec<Cid>> for CarHeaderV1 {
     }
 }
 
+/// CAR file header version 2. The offsets are counted from the start of the file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CarHeaderV2 {
+    pub characteristics: [u8; 16],
+    pub data_offset: u64,
+    pub data_size: u64,
+    /// `0` means there is no index.
+    pub index_offset: u64,
+    /// The header of the inner CARv1 payload.
+    pub inner: CarHeaderV1,
+}
+
+impl CarHeaderV2 {
+    /// Creates a header for a payload placed right after the header, followed by the index.
+    pub fn new(roots: Vec<Cid>, data_size: u64, index_size: u64) -> Self {
+        let data_offset = (PRAGMA_V2.len() + HEADER_V2_SIZE) as u64;
+        Self {
+            characteristics: [0; 16],
+            data_offset,
+            data_size,
+            index_offset: if index_size == 0 {
+                0
+            } else {
+                data_offset + data_size
+            },
+            inner: CarHeaderV1::from(roots),
+        }
+    }
+
+    /// Encodes the fixed header which follows the pragma.
+    pub fn encode_fixed(&self) -> [u8; HEADER_V2_SIZE] {
+        let mut bytes = [0u8; HEADER_V2_SIZE];
+        bytes[..16].copy_from_slice(&self.characteristics);
+        bytes[16..24].copy_from_slice(&self.data_offset.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.data_size.to_le_bytes());
+        bytes[32..].copy_from_slice(&self.index_offset.to_le_bytes());
+        bytes
+    }
+
+    /// Decodes the fixed header which follows the pragma. The inner header is left empty.
+    pub fn decode_fixed(bytes: &[u8; HEADER_V2_SIZE]) -> Result<Self, Error> {
+        let read_u64 = |i: usize| {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(&bytes[i..i + 8]);
+            u64::from_le_bytes(buf)
+        };
+
+        let mut characteristics = [0u8; 16];
+        characteristics.copy_from_slice(&bytes[..16]);
+
+        let header = Self {
+            characteristics,
+            data_offset: read_u64(16),
+            data_size: read_u64(24),
+            index_offset: read_u64(32),
+            inner: CarHeaderV1::from(vec![]),
+        };
+
+        if header.data_offset < (PRAGMA_V2.len() + HEADER_V2_SIZE) as u64 {
+            return Err(Error::InvalidFile(
+                "CARv2 data offset overlaps the header".to_string(),
+            ));
+        }
+
+        Ok(header)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use ipld::codec::{Decode, Encode};
@@ -100,4 +189,13 @@ mod tests {
             header
         );
     }
+
+    #[test]
+    fn symmetric_header_v2() {
+        let header = CarHeaderV2::new(vec![], 1234, 56);
+        let decoded = CarHeaderV2::decode_fixed(&header.encode_fixed()).unwrap();
+
+        assert_eq!(decoded, header);
+        assert_eq!(decoded.index_offset, 51 + 1234);
+    }
 }
@@ -0,0 +1,227 @@
+use std::collections::BTreeMap;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+use cid::Cid;
+use integer_encoding::{VarInt, VarIntReader};
+
+use super::error::Error;
+
+/// The multicodec of the `MultihashIndexSorted` CARv2 index.
+pub const MULTIHASH_INDEX_SORTED: u64 = 0x0401;
+
+/// Digest and payload offset pairs sorted by digest.
+type Entries = Vec<(Vec<u8>, u64)>;
+
+/// The `MultihashIndexSorted` CARv2 index.
+///
+/// Entries are grouped by the multihash code and the digest width, and sorted by digest.
+/// Each entry points to the start of a block section, relative to the CARv1 payload.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MultihashIndexSorted {
+    buckets: BTreeMap<u64, BTreeMap<u32, Entries>>,
+}
+
+impl MultihashIndexSorted {
+    /// Builds the index by scanning all the sections of a CARv1 payload.
+    pub fn from_v1_payload(payload: &[u8]) -> Result<Self, Error> {
+        let mut index = Self::default();
+        let mut pos = section_len(payload, 0)?.1;
+
+        while pos < payload.len() {
+            let (len, next) = section_len(payload, pos)?;
+            let section = payload
+                .get(next - len..next)
+                .ok_or_else(|| Error::Parsing("truncated block section".to_string()))?;
+            let cid = Cid::read_bytes(&mut Cursor::new(section))?;
+
+            index.insert(&cid, pos as u64);
+            pos = next;
+        }
+
+        Ok(index)
+    }
+
+    /// Builds the index in one pass over the CARv1 payload of `size` bytes at `offset` of `reader`,
+    /// which only reads the CID of every section and seeks over its data.
+    pub fn from_v1_reader<R: Read + Seek>(
+        reader: &mut R,
+        offset: u64,
+        size: u64,
+    ) -> Result<Self, Error> {
+        let mut index = Self::default();
+        reader.seek(SeekFrom::Start(offset))?;
+        let header_len: u64 = reader.read_varint()?;
+        let mut pos = reader.stream_position()? - offset + header_len;
+
+        while pos < size {
+            reader.seek(SeekFrom::Start(offset + pos))?;
+            let len: u64 = reader.read_varint()?;
+            let next = (reader.stream_position()? - offset)
+                .checked_add(len)
+                .filter(|&x| x <= size)
+                .ok_or_else(|| Error::Parsing("truncated block section".to_string()))?;
+            let cid = Cid::read_bytes(reader.by_ref().take(len))?;
+
+            index.insert(&cid, pos);
+            pos = next;
+        }
+
+        Ok(index)
+    }
+
+    pub fn insert(&mut self, cid: &Cid, offset: u64) {
+        let digest = cid.hash().digest().to_vec();
+        let width = digest.len() as u32 + 8;
+        let entries = self
+            .buckets
+            .entry(cid.hash().code())
+            .or_default()
+            .entry(width)
+            .or_default();
+
+        if let Err(i) = entries.binary_search_by(|(x, _)| x.as_slice().cmp(&digest)) {
+            entries.insert(i, (digest, offset));
+        }
+    }
+
+    /// Returns the payload offset of the block section with the same multihash.
+    pub fn get(&self, cid: &Cid) -> Option<u64> {
+        let digest = cid.hash().digest();
+        let entries = self
+            .buckets
+            .get(&cid.hash().code())?
+            .get(&(digest.len() as u32 + 8))?;
+
+        entries
+            .binary_search_by(|(x, _)| x.as_slice().cmp(digest))
+            .ok()
+            .map(|i| entries[i].1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets
+            .values()
+            .flat_map(|x| x.values())
+            .map(|x| x.len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Encodes the index with its leading multicodec.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut ret = MULTIHASH_INDEX_SORTED.encode_var_vec();
+
+        ret.extend((self.buckets.len() as i32).to_le_bytes());
+        for (code, widths) in self.buckets.iter() {
+            ret.extend(code.to_le_bytes());
+            ret.extend((widths.len() as i32).to_le_bytes());
+
+            for (width, entries) in widths.iter() {
+                ret.extend(width.to_le_bytes());
+                ret.extend(((entries.len() * *width as usize) as i64).to_le_bytes());
+                for (digest, offset) in entries {
+                    ret.extend(digest);
+                    ret.extend(offset.to_le_bytes());
+                }
+            }
+        }
+
+        ret
+    }
+
+    /// Decodes an index with its leading multicodec.
+    pub fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        let (codec, mut pos) = u64::decode_var(bytes)
+            .ok_or_else(|| Error::Parsing("failed to parse index codec".to_string()))?;
+        if codec != MULTIHASH_INDEX_SORTED {
+            return Err(Error::InvalidFile(format!(
+                "Only MultihashIndexSorted CARv2 index is supported, got codec: {:#x}",
+                codec
+            )));
+        }
+
+        let mut take = |n: usize| -> Result<&[u8], Error> {
+            let ret = bytes
+                .get(pos..pos + n)
+                .ok_or_else(|| Error::Parsing("truncated CARv2 index".to_string()))?;
+            pos += n;
+            Ok(ret)
+        };
+
+        let mut index = Self::default();
+        let codes_count = i32::from_le_bytes(take(4)?.try_into().unwrap());
+        for _ in 0..codes_count {
+            let code = u64::from_le_bytes(take(8)?.try_into().unwrap());
+            let widths_count = i32::from_le_bytes(take(4)?.try_into().unwrap());
+
+            let widths = index.buckets.entry(code).or_default();
+            for _ in 0..widths_count {
+                let width = u32::from_le_bytes(take(4)?.try_into().unwrap());
+                let size = i64::from_le_bytes(take(8)?.try_into().unwrap());
+                if width <= 8 || size < 0 {
+                    return Err(Error::Parsing("invalid CARv2 index bucket".to_string()));
+                }
+
+                let chunks = take(size as usize)?.chunks_exact(width as usize);
+                if !chunks.remainder().is_empty() {
+                    return Err(Error::Parsing("invalid CARv2 index bucket".to_string()));
+                }
+
+                let entries = chunks
+                    .map(|x| {
+                        let (digest, offset) = x.split_at(width as usize - 8);
+                        (
+                            digest.to_vec(),
+                            u64::from_le_bytes(offset.try_into().unwrap()),
+                        )
+                    })
+                    .collect();
+                widths.insert(width, entries);
+            }
+        }
+
+        Ok(index)
+    }
+}
+
+/// Reads the varint length of the section at `pos`, returns it with the end position of the section.
+fn section_len(payload: &[u8], pos: usize) -> Result<(usize, usize), Error> {
+    let (len, n) = payload
+        .get(pos..)
+        .and_then(usize::decode_var)
+        .ok_or_else(|| Error::Parsing("failed to parse uvarint for section".to_string()))?;
+    Ok((len, pos + n + len))
+}
+
+#[cfg(test)]
+mod tests {
+    use ipld_cbor::DagCborCodec;
+    use multihash::MultihashDigest;
+
+    use super::*;
+
+    #[test]
+    fn symmetric_index() {
+        let mut index = MultihashIndexSorted::default();
+        let cids = [b"a", b"b", b"c"]
+            .map(|x| Cid::new_v1(DagCborCodec.into(), multihash::Code::Sha2_256.digest(x)));
+        let blake = Cid::new_v1(
+            DagCborCodec.into(),
+            multihash::Code::Blake2b256.digest(b"d"),
+        );
+
+        for (i, cid) in cids.iter().enumerate() {
+            index.insert(cid, i as u64 * 100);
+        }
+        index.insert(&blake, 300);
+
+        let decoded = MultihashIndexSorted::decode(&index.encode()).unwrap();
+        assert_eq!(decoded, index);
+        assert_eq!(decoded.len(), 4);
+        assert_eq!(decoded.get(&cids[1]), Some(100));
+        assert_eq!(decoded.get(&blake), Some(300));
+    }
+}
@@ -8,11 +8,15 @@
 
 mod error;
 mod header;
+mod index;
 mod reader;
 mod util;
+mod v2;
 mod writer;
 
 pub use error::Error;
 pub use header::CarHeader;
+pub use index::MultihashIndexSorted;
 pub use reader::CarReader;
+pub use v2::{read_block_at, read_block_v2, read_header_v2, read_index_v2, write_v2};
 pub use writer::CarWriter;
@@ -1,16 +1,18 @@
 use cid::Cid;
 use futures::Stream;
-use tokio::io::AsyncRead;
+use tokio::io::{AsyncRead, AsyncReadExt, Take};
 
 use super::{
     error::Error,
-    header::CarHeader,
+    header::{CarHeader, CarHeaderV2, HEADER_V2_SIZE, PRAGMA_V2},
     util::{ld_read, read_node},
 };
 
 /// Reads CAR files that are in a BufReader
+///
+/// Both CARv1 and CARv2 are accepted. For CARv2, only the blocks of the inner CARv1 payload are read.
 pub struct CarReader<R> {
-    reader: R,
+    reader: Take<R>,
     header: CarHeader,
     buffer: Vec<u8>,
 }
@@ -20,7 +22,8 @@ where
     R: AsyncRead + Send + Unpin,
 {
     /// Creates a new CarReader and parses the CarHeader
-    pub async fn new(mut reader: R) -> Result<Self, Error> {
+    pub async fn new(reader: R) -> Result<Self, Error> {
+        let mut reader = reader.take(u64::MAX);
         let mut buffer = Vec::new();
 
         if !ld_read(&mut reader, &mut buffer).await? {
@@ -29,7 +32,30 @@ where
             ));
         }
 
-        let header = CarHeader::decode(&buffer)?;
+        let header = if buffer == PRAGMA_V2[1..] {
+            let mut bytes = [0u8; HEADER_V2_SIZE];
+            reader.read_exact(&mut bytes).await?;
+            let mut header = CarHeaderV2::decode_fixed(&bytes)?;
+
+            // skips the padding before the payload, then stops at the end of the payload
+            let padding = header.data_offset - (PRAGMA_V2.len() + HEADER_V2_SIZE) as u64;
+            tokio::io::copy(&mut (&mut reader).take(padding), &mut tokio::io::sink()).await?;
+            reader.set_limit(header.data_size);
+
+            if !ld_read(&mut reader, &mut buffer).await? {
+                return Err(Error::Parsing(
+                    "failed to parse uvarint for CARv2 inner header".to_string(),
+                ));
+            }
+            match CarHeader::decode(&buffer)? {
+                CarHeader::V1(inner) => header.inner = inner,
+                _ => unreachable!(),
+            }
+
+            CarHeader::V2(header)
+        } else {
+            CarHeader::decode(&buffer)?
+        };
 
         Ok(CarReader {
             reader,
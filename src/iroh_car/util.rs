@@ -50,7 +50,7 @@ mod tests {
 
     use super::*;
 
-    async fn ld_write<'a, W>(writer: &mut W, bytes: &[u8]) -> Result<(), Error>
+    async fn ld_write<W>(writer: &mut W, bytes: &[u8]) -> Result<(), Error>
     where
         W: AsyncWrite + Send + Unpin,
     {
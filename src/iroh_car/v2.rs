@@ -0,0 +1,117 @@
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+use cid::Cid;
+use integer_encoding::VarIntReader;
+
+use super::{
+    error::Error,
+    header::{CarHeaderV2, HEADER_V2_SIZE, PRAGMA_V2},
+    index::MultihashIndexSorted,
+};
+
+/// Wraps a CARv1 into a CARv2 with a `MultihashIndexSorted` index appended after the payload.
+pub fn write_v2<W: Write>(roots: Vec<Cid>, v1_payload: &[u8], mut writer: W) -> Result<W, Error> {
+    let index = MultihashIndexSorted::from_v1_payload(v1_payload)?.encode();
+    let header = CarHeaderV2::new(roots, v1_payload.len() as u64, index.len() as u64);
+
+    writer.write_all(&PRAGMA_V2)?;
+    writer.write_all(&header.encode_fixed())?;
+    writer.write_all(v1_payload)?;
+    writer.write_all(&index)?;
+    writer.flush()?;
+
+    Ok(writer)
+}
+
+/// Reads the pragma and the fixed header of a CARv2. The inner header is left empty.
+pub fn read_header_v2<R: Read>(reader: &mut R) -> Result<CarHeaderV2, Error> {
+    let mut pragma = [0u8; PRAGMA_V2.len()];
+    reader.read_exact(&mut pragma)?;
+    if pragma != PRAGMA_V2 {
+        return Err(Error::InvalidFile("Not a CAR file version 2".to_string()));
+    }
+
+    let mut bytes = [0u8; HEADER_V2_SIZE];
+    reader.read_exact(&mut bytes)?;
+    CarHeaderV2::decode_fixed(&bytes)
+}
+
+/// Reads the index of a CARv2.
+pub fn read_index_v2<R: Read + Seek>(reader: &mut R) -> Result<MultihashIndexSorted, Error> {
+    reader.seek(SeekFrom::Start(0))?;
+    let header = read_header_v2(reader)?;
+    if header.index_offset == 0 {
+        return Err(Error::InvalidFile(
+            "The CARv2 file has no index".to_string(),
+        ));
+    }
+
+    reader.seek(SeekFrom::Start(header.index_offset))?;
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes)?;
+
+    MultihashIndexSorted::decode(&bytes)
+}
+
+/// Reads a single block of a CARv2 through its index without scanning the payload.
+pub fn read_block_v2<R: Read + Seek>(reader: &mut R, cid: &Cid) -> Result<Option<Vec<u8>>, Error> {
+    let index = read_index_v2(reader)?;
+    let offset = match index.get(cid) {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+
+    reader.seek(SeekFrom::Start(0))?;
+    let header = read_header_v2(reader)?;
+    read_block_at(reader, header.data_offset + offset, cid).map(Some)
+}
+
+/// Reads the block section at `pos` of a CAR file, which is expected to be the block of `cid`.
+///
+/// The length of the section comes from the file, so only the bytes which are there are read.
+pub fn read_block_at<R: Read + Seek>(
+    reader: &mut R,
+    pos: u64,
+    cid: &Cid,
+) -> Result<Vec<u8>, Error> {
+    reader.seek(SeekFrom::Start(pos))?;
+
+    let len: u64 = reader.read_varint()?;
+    let mut section = vec![];
+    reader.take(len).read_to_end(&mut section)?;
+    if section.len() as u64 != len {
+        return Err(Error::InvalidFile(format!(
+            "The block section at {} is truncated",
+            pos
+        )));
+    }
+
+    let mut cursor = Cursor::new(&section);
+    let found = Cid::read_bytes(&mut cursor)?;
+    if found.hash() != cid.hash() {
+        return Err(Error::InvalidFile(format!(
+            "The index entry of {} points to {}",
+            cid, found
+        )));
+    }
+
+    let pos = cursor.position() as usize;
+    Ok(section[pos..].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use integer_encoding::VarIntWriter;
+
+    #[test]
+    fn section_longer_than_file() {
+        let cid = Cid::default();
+        let mut car = vec![];
+        car.write_varint(u64::MAX).unwrap();
+        car.extend(cid.to_bytes());
+
+        let e = read_block_at(&mut Cursor::new(car), 0, &cid).unwrap_err();
+        assert!(matches!(e, Error::InvalidFile(x) if x.contains("truncated")));
+    }
+}
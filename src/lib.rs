@@ -6,16 +6,24 @@
 //! * CAR file uploading is supported.
 //! * Checks uploads though IPFS gateways checker.
 //! * Downloads uploaded file with auto decryption and decompression.
+//! * Packs, inspects, unpacks, extracts and verifies local CAR files without any IPFS gateway.
 //!
 //! ## Feature flags
 //! * `encryption`: Enables encryption during the uploading process and decryption during the downloading process.
 //! * `zstd`: Enables compression during the uploading process and decompression during the downloading process.
+//! * `age`: Enables encryption and decryption in the standard age format, which the `age` CLI can read and write.
+//! * `aes`: Enables AES-256-GCM with PBKDF2 instead of XChaCha20-Poly1305 with Argon2id for the environments
+//!   requiring FIPS-approved algorithms.
+//! * `signing`: Enables detached Ed25519 signatures of directory uploads and their verification.
 //! * `all`: Enables all the features listed above.
 //!
 //! ## Example
 //!
 //! To upload a single file:
-//! ```rust
+//! ```rust,no_run
+//! # use std::sync::{Arc, Mutex};
+//! # use w3s::helper::UploadOptions;
+//! # async fn run(path: &str, auth_token: &str) -> Result<(), w3s::helper::Error> {
 //!  let cid_result = w3s::helper::upload(
 //!     path,  // the file path 
 //!     auth_token,  // the api token created in web3.storage
@@ -23,31 +31,49 @@
 //!     Some(Arc::new(Mutex::new(|name, part, pos, total| {  // the progress listener
 //!         println!("name: {name} part:{part} {pos}/{total}");
 //!     }))),
-//!     Some(None),  // if packed in CAR with custom block size, `Some(None)` means packed in CAR with default 256K block size
-//!     Some(&mut b"abcd1234".to_owned()),  // if use encryption with password
-//!     Some(None),  // if use compression with zstd level, `Some(None)` means uses compression with zstd level at 10
+//!     &UploadOptions {
+//!         with_car: Some(None),  // if packed in CAR with custom block size, `Some(None)` means packed in CAR with default 256K block size
+//!         with_encryption: Some(b"abcd1234".to_vec()),  // if use encryption with password
+//!         with_compression: Some(None),  // if use compression with zstd level, `Some(None)` means uses compression with zstd level at 10
+//!         ..Default::default()  // Argon2id costs, X25519 recipients, the age format, raw keys and AES-256-GCM
+//!     },
 //! )
 //! .await?;
+//! # Ok(())
+//! # }
 //! ```
 //! 
 //! To upload a directory:
-//! ```rust
+//! ```rust,no_run
+//! # use std::sync::{Arc, Mutex};
+//! # use w3s::helper::UploadDirOptions;
+//! # use w3s::writer::car_util::SymlinkPolicy;
+//! # async fn run(path: &str, auth_token: String, key_path: String) -> Result<(), w3s::helper::Error> {
 //! let cid_result = w3s::helper::upload_dir(
 //!     path,  // the folder path
-//!     None,  // file filter which can bypass specific files
 //!     auth_token,  // the api token created in web3.storage
 //!     2,  // max concurrent upload threads
 //!     Some(Arc::new(Mutex::new(|name, part, pos, total| {  // the progress listener
 //!         println!("name: {name} part:{part} {pos}/{total}");
 //!     }))),
-//!     None,  // if use encryption with password
-//!     None,  // if use compression with zstd level
+//!     &UploadDirOptions {
+//!         with_metadata: true,  // if keeps POSIX mode and mtime of files and directories
+//!         symlink_policy: SymlinkPolicy::Store,  // skips, follows or stores symbolic links
+//!         threads: Some(4),  // worker threads to process files, `None` processes them on the current thread
+//!         with_signing_key: Some(key_path),  // key file of an Ed25519 signing key to sign the directory, see `helper::verify`
+//!         ..Default::default()  // file filter, encryption, compression, DAG config, hidden names...
+//!     },
 //! )
 //! .await?;
+//! # Ok(())
+//! # }
 //! ```
 //! 
 //! To download a compressed and encrypted file from IPFS gateway:
-//! ```rust
+//! ```rust,no_run
+//! # use std::sync::{Arc, Mutex};
+//! # use w3s::helper::DownloadOptions;
+//! # async fn run(url: &str, name: &str, mut file: std::fs::File) -> Result<(), w3s::helper::Error> {
 //! w3s::helper::download(
 //!     url,  // the whole url pointing to the file under the IPFS geteway
 //!     name,  // just a label that will later be passed to the progress listener
@@ -55,14 +81,33 @@
 //!     Some(Arc::new(Mutex::new(|name, _, pos, total| {  // the progress listener
 //!         println!("name: {name} {pos}/{total}");
 //!     }))),
-//!     None,  // start offset which should be `None` for compressed or encrypted file
-//!     Some(b"abcd1234".to_vec()),  // use decryption with password
-//!     true,  // use decompression
+//!     &DownloadOptions {
+//!         with_decryption: Some(b"abcd1234".to_vec()),  // use decryption with password
+//!         with_decompression: true,  // use decompression
+//!         with_verification: true,  // if writes the file only after the whole content is decrypted and verified
+//!         ..Default::default()  // start offset to resume from and key file of raw keys or X25519 identities
+//!     },
 //! )
 //! .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! To verify a directory signed by a trusted key:
+//! ```rust,no_run
+//! # async fn run(url: &str, path: &str) -> Result<(), w3s::helper::Error> {
+//! let root = w3s::helper::verify(
+//!     url,  // the whole url pointing to the directory under the IPFS geteway
+//!     "w3s-ed25519-...",  // the trusted public key
+//!     Some(path),  // the folder downloaded by `download_dir` to check the files, or `None` to check the root only
+//! )
+//! .await?;
+//! # Ok(())
+//! # }
 //! ```
 
 pub mod api;
+pub mod car;
 pub mod gateway;
 pub mod helper;
 pub mod writer;
@@ -0,0 +1,982 @@
+//! The age file format v1 (<https://age-encryption.org/v1>), so the files can be decrypted by the `age` CLI
+//! and vice versa
+//!
+//! The header lists the random file key wrapped for every X25519 recipient like `age1...`, or for a passphrase
+//! by scrypt, and is authenticated by HMAC-SHA256. The payload is sealed in 64 KiB chunks by ChaCha20-Poly1305.
+//! `Armor` wraps the whole file in PEM for text channels.
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use base64::Engine;
+use bech32::{FromBase32, ToBase32, Variant};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::prelude::*;
+use sha2::Sha256;
+use thiserror::Error;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+use zeroize::{Zeroize, Zeroizing};
+
+use super::envelope::{Secret, AGE_ARMOR_BEGIN, AGE_INTRO};
+use super::recipient::{Identity, Recipient};
+use super::*;
+use std::{io, mem};
+
+/// Size of the plaintext in every chunk except the last one
+pub const CHUNK_SIZE: usize = 64 * 1024;
+/// The scrypt work factor `log2(N)` of the passphrase, the same as the `age` CLI
+pub const DEFAULT_WORK_FACTOR: u8 = 18;
+/// The largest scrypt work factor accepted for decryption, which takes about 4 GiB of memory
+pub const MAX_WORK_FACTOR: u8 = 22;
+
+pub const ARMOR_END: &[u8] = b"-----END AGE ENCRYPTED FILE-----";
+
+const RECIPIENT_HRP: &str = "age";
+const IDENTITY_HRP: &str = "age-secret-key-";
+
+const FILE_KEY_SIZE: usize = 16;
+const NONCE_SIZE: usize = 16;
+const KEY_SIZE: usize = 32;
+const TAG_SIZE: usize = 16;
+const MAC_SIZE: usize = 32;
+/// Columns of the base64 lines in the header and the armor
+const COLUMNS: usize = 64;
+
+const X25519_INFO: &[u8] = b"age-encryption.org/v1/X25519";
+const SCRYPT_LABEL: &[u8] = b"age-encryption.org/v1/scrypt";
+/// Every wrapping key is used once, so the nonce is fixed
+const WRAP_NONCE: [u8; 12] = [0u8; 12];
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("At least one recipient is required.")]
+    NoRecipient,
+    #[error("Invalid age header: {0}")]
+    InvalidHeader(&'static str),
+    #[error("The age header MAC is invalid. The header has been changed.")]
+    HeaderMacInvalid,
+    #[error("None of the identities can unwrap the file key.")]
+    NoMatchedIdentity,
+    #[error("The file is not encrypted with a passphrase.")]
+    NoPassphrase,
+    #[error("Incorrect passphrase.")]
+    IncorrectPassphrase,
+    #[error("The scrypt work factor {0} is too large.")]
+    WorkFactorTooLarge(u8),
+    #[error("Invalid scrypt work factor: {0}")]
+    InvalidWorkFactor(u8),
+    #[error("The encrypted content is truncated.")]
+    Truncated,
+    #[error("The last chunk is empty after other chunks.")]
+    EmptyLastChunk,
+    #[error("MAC tag error. This could be caused by content being changed.\nchunk: {0}")]
+    MacTagInvalid(u64),
+    #[error("Invalid armor: {0}")]
+    InvalidArmor(&'static str),
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Encodes the recipient as `age1...`, the same as the `age` CLI.
+pub fn recipient_to_string(recipient: &Recipient) -> String {
+    bech32::encode(
+        RECIPIENT_HRP,
+        recipient.0.as_bytes().to_base32(),
+        Variant::Bech32,
+    )
+    .expect("the hrp is valid")
+}
+
+/// Encodes the identity as `AGE-SECRET-KEY-1...`, the same as `age-keygen`.
+pub fn identity_to_string(identity: &Identity) -> String {
+    let mut bytes = identity.0.to_bytes();
+    let encoded =
+        bech32::encode(IDENTITY_HRP, bytes.to_base32(), Variant::Bech32).expect("the hrp is valid");
+    bytes.zeroize();
+    encoded.to_uppercase()
+}
+
+/// Decodes a recipient like `age1...`.
+pub(crate) fn parse_recipient(s: &str) -> Option<[u8; KEY_SIZE]> {
+    decode_bech32(s, RECIPIENT_HRP)
+}
+
+/// Decodes an identity like `AGE-SECRET-KEY-1...`.
+pub(crate) fn parse_identity(s: &str) -> Option<[u8; KEY_SIZE]> {
+    decode_bech32(s, IDENTITY_HRP)
+}
+
+fn decode_bech32(s: &str, hrp: &str) -> Option<[u8; KEY_SIZE]> {
+    let (decoded_hrp, data, variant) = bech32::decode(s).ok()?;
+    if decoded_hrp != hrp || variant != Variant::Bech32 {
+        return None;
+    }
+    let mut bytes = Vec::<u8>::from_base32(&data).ok()?;
+    let key = bytes.as_slice().try_into().ok();
+    bytes.zeroize();
+    key
+}
+
+fn hkdf(salt: &[u8], ikm: &[u8], info: &[u8]) -> [u8; KEY_SIZE] {
+    let mut key = [0u8; KEY_SIZE];
+    Hkdf::<Sha256>::new(Some(salt), ikm)
+        .expand(info, &mut key)
+        .expect("32 bytes is a valid length for HKDF-SHA256");
+    key
+}
+
+fn header_mac(file_key: &[u8], header: &[u8]) -> Hmac<Sha256> {
+    let mut key = hkdf(&[], file_key, b"header");
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key).expect("HMAC takes keys of any size");
+    key.zeroize();
+    mac.update(header);
+    mac
+}
+
+fn find(buf: &[u8], pattern: &[u8]) -> Option<usize> {
+    buf.windows(pattern.len()).position(|x| x == pattern)
+}
+
+/// A recipient stanza of the header, `-> <type> <args>...` followed by the wrapped file key in base64
+struct Stanza {
+    kind: String,
+    args: Vec<String>,
+    body: Vec<u8>,
+}
+
+impl Stanza {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        out.extend(b"-> ");
+        out.extend(self.kind.as_bytes());
+        for arg in self.args.iter() {
+            out.push(b' ');
+            out.extend(arg.as_bytes());
+        }
+        out.push(b'\n');
+
+        // the last line is always shorter than a full one, even if it is empty
+        let body = STANDARD_NO_PAD.encode(&self.body);
+        for line in body.as_bytes().chunks(COLUMNS) {
+            out.extend(line);
+            out.push(b'\n');
+        }
+        if body.len().is_multiple_of(COLUMNS) {
+            out.push(b'\n');
+        }
+    }
+
+    fn decode_arg<const N: usize>(&self, index: usize) -> Result<[u8; N], Error> {
+        STANDARD_NO_PAD
+            .decode(&self.args[index])
+            .ok()
+            .and_then(|x| x.try_into().ok())
+            .ok_or(Error::InvalidHeader("invalid stanza argument"))
+    }
+}
+
+/// Parses the header ending with the MAC line, and returns the stanzas and the MAC.
+fn parse_header(header: &[u8]) -> Result<(Vec<Stanza>, [u8; MAC_SIZE]), Error> {
+    let text = std::str::from_utf8(header).map_err(|_| Error::InvalidHeader("not text"))?;
+    let mut lines = text
+        .strip_prefix(std::str::from_utf8(AGE_INTRO).expect("the intro is text"))
+        .ok_or(Error::InvalidHeader("unsupported version"))?
+        .strip_suffix('\n')
+        .ok_or(Error::InvalidHeader("no MAC"))?
+        .split('\n');
+
+    let mut stanzas = vec![];
+    loop {
+        let line = lines.next().ok_or(Error::InvalidHeader("no MAC"))?;
+        if let Some(mac) = line.strip_prefix("--- ") {
+            let mac = STANDARD_NO_PAD
+                .decode(mac)
+                .ok()
+                .and_then(|x| x.try_into().ok())
+                .ok_or(Error::InvalidHeader("invalid MAC"))?;
+            return Ok((stanzas, mac));
+        }
+
+        let mut args: Vec<String> = line
+            .strip_prefix("-> ")
+            .ok_or(Error::InvalidHeader("invalid stanza"))?
+            .split(' ')
+            .map(str::to_owned)
+            .collect();
+        if args.iter().any(String::is_empty) {
+            return Err(Error::InvalidHeader("empty stanza argument"));
+        }
+        let kind = args.remove(0);
+
+        let mut body = String::new();
+        loop {
+            let line = lines.next().ok_or(Error::InvalidHeader("no MAC"))?;
+            if line.len() > COLUMNS {
+                return Err(Error::InvalidHeader("stanza line too long"));
+            }
+            body.push_str(line);
+            if line.len() < COLUMNS {
+                break;
+            }
+        }
+        let body = STANDARD_NO_PAD
+            .decode(body)
+            .map_err(|_| Error::InvalidHeader("invalid stanza body"))?;
+
+        stanzas.push(Stanza { kind, args, body });
+    }
+}
+
+fn wrap_x25519(file_key: &[u8], recipient: &PublicKey) -> Stanza {
+    let ephemeral = EphemeralSecret::random_from_rng(rand::thread_rng());
+    let share = PublicKey::from(&ephemeral);
+    let shared = ephemeral.diffie_hellman(recipient);
+
+    let salt = [share.as_bytes().as_slice(), recipient.as_bytes()].concat();
+    let mut key = hkdf(&salt, shared.as_bytes(), X25519_INFO);
+    let mut body = file_key.to_vec();
+    cipher::seal_ietf(&key, &WRAP_NONCE, &[], &mut body);
+    key.zeroize();
+
+    Stanza {
+        kind: "X25519".to_owned(),
+        args: vec![STANDARD_NO_PAD.encode(share.as_bytes())],
+        body,
+    }
+}
+
+fn unwrap_x25519(
+    stanza: &Stanza,
+    identities: &[Identity],
+) -> Result<Option<[u8; FILE_KEY_SIZE]>, Error> {
+    if stanza.args.len() != 1 || stanza.body.len() != FILE_KEY_SIZE + TAG_SIZE {
+        return Err(Error::InvalidHeader("invalid X25519 stanza"));
+    }
+    let share = PublicKey::from(stanza.decode_arg::<KEY_SIZE>(0)?);
+
+    for identity in identities {
+        let shared = identity.0.diffie_hellman(&share);
+        // a low order point gives an all-zero secret
+        if !shared.was_contributory() {
+            continue;
+        }
+
+        let salt = [
+            share.as_bytes().as_slice(),
+            identity.to_public().0.as_bytes(),
+        ]
+        .concat();
+        let mut key = hkdf(&salt, shared.as_bytes(), X25519_INFO);
+        let mut body = stanza.body.clone();
+        let is_opened = cipher::open_ietf(&key, &WRAP_NONCE, &[], &mut body);
+        key.zeroize();
+
+        if is_opened {
+            let mut file_key = [0u8; FILE_KEY_SIZE];
+            file_key.copy_from_slice(&body);
+            body.zeroize();
+            return Ok(Some(file_key));
+        }
+    }
+    Ok(None)
+}
+
+fn scrypt_key(passphrase: &[u8], salt: &[u8], work_factor: u8) -> Result<[u8; KEY_SIZE], Error> {
+    let params = scrypt::Params::new(work_factor, 8, 1, KEY_SIZE)
+        .map_err(|_| Error::InvalidWorkFactor(work_factor))?;
+    let mut key = [0u8; KEY_SIZE];
+    scrypt::scrypt(
+        passphrase,
+        &[SCRYPT_LABEL, salt].concat(),
+        &params,
+        &mut key,
+    )
+    .expect("32 bytes is a valid length for scrypt");
+    Ok(key)
+}
+
+fn wrap_scrypt(file_key: &[u8], passphrase: &[u8], work_factor: u8) -> Result<Stanza, Error> {
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+
+    let mut key = scrypt_key(passphrase, &salt, work_factor)?;
+    let mut body = file_key.to_vec();
+    cipher::seal_ietf(&key, &WRAP_NONCE, &[], &mut body);
+    key.zeroize();
+
+    Ok(Stanza {
+        kind: "scrypt".to_owned(),
+        args: vec![STANDARD_NO_PAD.encode(salt), work_factor.to_string()],
+        body,
+    })
+}
+
+fn unwrap_scrypt(
+    stanza: &Stanza,
+    passphrase: &[u8],
+    max_work_factor: u8,
+) -> Result<[u8; FILE_KEY_SIZE], Error> {
+    if stanza.args.len() != 2 || stanza.body.len() != FILE_KEY_SIZE + TAG_SIZE {
+        return Err(Error::InvalidHeader("invalid scrypt stanza"));
+    }
+    let salt = stanza.decode_arg::<16>(0)?;
+    let work_factor = &stanza.args[1];
+    if work_factor.starts_with('0') || !work_factor.bytes().all(|x| x.is_ascii_digit()) {
+        return Err(Error::InvalidHeader("invalid scrypt work factor"));
+    }
+    let work_factor = work_factor
+        .parse::<u8>()
+        .map_err(|_| Error::InvalidHeader("invalid scrypt work factor"))?;
+    if work_factor > max_work_factor {
+        return Err(Error::WorkFactorTooLarge(work_factor));
+    }
+
+    let mut key = scrypt_key(passphrase, &salt, work_factor)?;
+    let mut body = stanza.body.clone();
+    let is_opened = cipher::open_ietf(&key, &WRAP_NONCE, &[], &mut body);
+    key.zeroize();
+
+    if !is_opened {
+        return Err(Error::IncorrectPassphrase);
+    }
+    let mut file_key = [0u8; FILE_KEY_SIZE];
+    file_key.copy_from_slice(&body);
+    body.zeroize();
+    Ok(file_key)
+}
+
+/// What the file key is wrapped for, or unwrapped by
+enum Key {
+    Recipients(Vec<PublicKey>),
+    /// the passphrase with the work factor to encrypt with, or the largest one to decrypt with
+    Passphrase(Zeroizing<Vec<u8>>, u8),
+    Identities(Vec<Identity>),
+}
+
+/// The encryption stage of the age format, which passes `[header][payload nonce: 16]` followed by
+/// `[encrypted chunk][tag]`s to the next writer.
+///
+/// Every chunk holds `CHUNK_SIZE` bytes of plaintext and the last one holds the rest, which is only empty for
+/// an empty file. The nonce of a chunk is `[counter: 11 bytes BE][last flag: u8]`.
+///
+/// After `flush`, the next stream starts with a new file key and header, so every file can be decrypted
+/// individually. For decryption, every chunk is verified before its plaintext is passed to the next writer.
+pub struct Age<W: io::Write> {
+    key: Key,
+    is_decryption: bool,
+    payload_key: Option<[u8; KEY_SIZE]>,
+    counter: u64,
+    buf: Vec<u8>,
+    next_writer: W,
+}
+
+impl<W: io::Write> Age<W> {
+    /// Encrypts for the X25519 recipients, which can be parsed from `age1...` or `w3s-x25519-...`.
+    pub fn new(recipients: Vec<Recipient>, next_writer: W) -> Result<Age<W>, Error> {
+        if recipients.is_empty() {
+            return Err(Error::NoRecipient);
+        }
+        let recipients = recipients.into_iter().map(|x| x.0).collect();
+        Ok(Self::with_key(
+            Key::Recipients(recipients),
+            false,
+            next_writer,
+        ))
+    }
+
+    /// Encrypts with the passphrase by scrypt with `work_factor`, see `DEFAULT_WORK_FACTOR`.
+    pub fn new_with_passphrase(
+        passphrase: Vec<u8>,
+        work_factor: u8,
+        next_writer: W,
+    ) -> Result<Age<W>, Error> {
+        // checks the work factor before any header is written
+        if work_factor == 0 || scrypt::Params::new(work_factor, 8, 1, KEY_SIZE).is_err() {
+            return Err(Error::InvalidWorkFactor(work_factor));
+        }
+        let key = Key::Passphrase(Zeroizing::new(passphrase), work_factor);
+        Ok(Self::with_key(key, false, next_writer))
+    }
+
+    /// Decrypts with the identities, or the password as the passphrase.
+    pub fn new_decryption(secret: Secret, next_writer: W) -> Age<W> {
+        let key = match secret {
+            Secret::Password(password) => {
+                Key::Passphrase(Zeroizing::new(password), MAX_WORK_FACTOR)
+            }
+            Secret::Identities(identities) => Key::Identities(identities),
+            // raw keys have no stanza in the age format, so nothing can be unwrapped
+            Secret::Keys(_) => Key::Identities(vec![]),
+        };
+        Self::with_key(key, true, next_writer)
+    }
+
+    fn with_key(key: Key, is_decryption: bool, next_writer: W) -> Age<W> {
+        Age {
+            key,
+            is_decryption,
+            payload_key: None,
+            counter: 0,
+            buf: vec![],
+            next_writer,
+        }
+    }
+
+    fn reset(&mut self) {
+        if let Some(mut key) = self.payload_key.take() {
+            key.zeroize();
+        }
+        self.counter = 0;
+        self.buf = vec![];
+    }
+
+    fn set_payload_key(&mut self, file_key: &mut [u8; FILE_KEY_SIZE], nonce: &[u8]) {
+        self.payload_key = Some(hkdf(nonce, file_key, b"payload"));
+        file_key.zeroize();
+    }
+
+    fn wrap(&self, file_key: &[u8]) -> Result<Vec<Stanza>, Error> {
+        Ok(match &self.key {
+            Key::Recipients(recipients) => recipients
+                .iter()
+                .map(|x| wrap_x25519(file_key, x))
+                .collect(),
+            Key::Passphrase(passphrase, work_factor) => {
+                vec![wrap_scrypt(file_key, passphrase, *work_factor)?]
+            }
+            Key::Identities(identities) => identities
+                .iter()
+                .map(|x| wrap_x25519(file_key, &x.to_public().0))
+                .collect(),
+        })
+    }
+
+    fn unwrap(&self, stanzas: &[Stanza]) -> Result<[u8; FILE_KEY_SIZE], Error> {
+        // a passphrase must be the only way to decrypt the file
+        let scrypt_stanza = stanzas.iter().find(|x| x.kind == "scrypt");
+        if scrypt_stanza.is_some() && stanzas.len() != 1 {
+            return Err(Error::InvalidHeader("scrypt stanza with other stanzas"));
+        }
+
+        match &self.key {
+            Key::Passphrase(passphrase, max_work_factor) => {
+                let stanza = scrypt_stanza.ok_or(Error::NoPassphrase)?;
+                unwrap_scrypt(stanza, passphrase, *max_work_factor)
+            }
+            Key::Identities(identities) => {
+                // unknown stanzas are skipped
+                for stanza in stanzas.iter().filter(|x| x.kind == "X25519") {
+                    if let Some(file_key) = unwrap_x25519(stanza, identities)? {
+                        return Ok(file_key);
+                    }
+                }
+                Err(Error::NoMatchedIdentity)
+            }
+            Key::Recipients(_) => Err(Error::NoMatchedIdentity),
+        }
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let mut file_key = [0u8; FILE_KEY_SIZE];
+        rand::thread_rng().fill_bytes(&mut file_key);
+
+        let mut header = AGE_INTRO.to_vec();
+        for stanza in self.wrap(&file_key)? {
+            stanza.write_to(&mut header);
+        }
+        header.extend(b"---");
+        let mac = header_mac(&file_key, &header).finalize().into_bytes();
+        header.push(b' ');
+        header.extend(STANDARD_NO_PAD.encode(mac).as_bytes());
+        header.push(b'\n');
+
+        let mut nonce = [0u8; NONCE_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        self.set_payload_key(&mut file_key, &nonce);
+
+        self.next_writer.write_all(&header)?;
+        self.next_writer.write_all(&nonce)
+    }
+
+    /// Reads the header and the payload nonce from the start of the buffer. Returns `false` if they are not
+    /// buffered yet.
+    fn read_header(&mut self) -> Result<bool, Error> {
+        let len = self.buf.len().min(AGE_INTRO.len());
+        if self.buf[..len] != AGE_INTRO[..len] {
+            return Err(Error::InvalidHeader("unsupported version"));
+        }
+
+        // the MAC line is the first line starting with `---`, since stanza lines are in base64 or start with `->`
+        let mac_start = match find(&self.buf, b"\n--- ") {
+            Some(x) => x + 1,
+            None => return Ok(false),
+        };
+        let header_len = match self.buf[mac_start..].iter().position(|&x| x == b'\n') {
+            Some(x) => mac_start + x + 1,
+            None => return Ok(false),
+        };
+        if self.buf.len() < header_len + NONCE_SIZE {
+            return Ok(false);
+        }
+
+        let header: Vec<u8> = self.buf.drain(..header_len).collect();
+        let nonce: Vec<u8> = self.buf.drain(..NONCE_SIZE).collect();
+        let (stanzas, mac) = parse_header(&header)?;
+
+        let mut file_key = self.unwrap(&stanzas)?;
+        // the MAC covers the header up to `---`
+        if header_mac(&file_key, &header[..mac_start + 3])
+            .verify_slice(&mac)
+            .is_err()
+        {
+            file_key.zeroize();
+            return Err(Error::HeaderMacInvalid);
+        }
+        self.set_payload_key(&mut file_key, &nonce);
+
+        Ok(true)
+    }
+
+    /// The nonce of a chunk is `[counter: 11 bytes BE][last flag: u8]`.
+    fn chunk_nonce(&self, is_last: bool) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[3..11].copy_from_slice(&self.counter.to_be_bytes());
+        nonce[11] = is_last as u8;
+        nonce
+    }
+
+    fn seal_chunk(&mut self, len: usize, is_last: bool) -> io::Result<()> {
+        let mut chunk: Vec<u8> = self.buf.drain(..len).collect();
+        let nonce = self.chunk_nonce(is_last);
+        let key = self.payload_key.as_ref().expect("the header is written");
+        cipher::seal_ietf(key, &nonce, &[], &mut chunk);
+        self.counter += 1;
+
+        self.next_writer.write_all(&chunk)
+    }
+
+    fn open_chunk(&mut self, len: usize, is_last: bool) -> io::Result<()> {
+        let mut chunk: Vec<u8> = self.buf.drain(..len).collect();
+        let nonce = self.chunk_nonce(is_last);
+        let key = self.payload_key.as_ref().expect("the header is read");
+
+        if !cipher::open_ietf(key, &nonce, &[], &mut chunk) {
+            Err(Error::MacTagInvalid(self.counter))?;
+        }
+        if is_last && chunk.is_empty() && self.counter > 0 {
+            Err(Error::EmptyLastChunk)?;
+        }
+        self.counter += 1;
+
+        self.next_writer.write_all(&chunk)
+    }
+}
+
+impl<W: io::Write> io::Write for Age<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let buf_size = buf.len();
+        self.buf.extend(buf);
+
+        if self.payload_key.is_none() {
+            if !self.is_decryption {
+                self.write_header()?;
+            } else if !self.read_header()? {
+                return Ok(buf_size);
+            }
+        }
+
+        // a full chunk may be the last one, so it is kept until more bytes come
+        let chunk_size = if self.is_decryption {
+            CHUNK_SIZE + TAG_SIZE
+        } else {
+            CHUNK_SIZE
+        };
+        while self.buf.len() > chunk_size {
+            if self.is_decryption {
+                self.open_chunk(chunk_size, false)?;
+            } else {
+                self.seal_chunk(chunk_size, false)?;
+            }
+        }
+
+        Ok(buf_size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.is_decryption {
+            if self.payload_key.is_none() && !self.read_header()? {
+                Err(Error::Truncated)?;
+            }
+            if self.buf.len() < TAG_SIZE {
+                Err(Error::Truncated)?;
+            }
+            self.open_chunk(self.buf.len(), true)?;
+        } else {
+            if self.payload_key.is_none() {
+                self.write_header()?;
+            }
+            self.seal_chunk(self.buf.len(), true)?;
+        }
+
+        self.next_mut().flush()?;
+
+        self.reset();
+
+        Ok(())
+    }
+}
+
+impl<W: io::Write> ChainWrite<W> for Age<W> {
+    fn next(self) -> W {
+        self.next_writer
+    }
+    fn next_mut(&mut self) -> &mut W {
+        &mut self.next_writer
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArmorState {
+    Begin,
+    Body,
+    /// after the line shorter than a full one
+    LastLine,
+    End,
+}
+
+/// Wraps every stream in the PEM armor of the age format, or unwraps it for decoding.
+///
+/// The base64 lines are 64 columns except the last one.
+pub struct Armor<W: io::Write> {
+    is_decoding: bool,
+    state: ArmorState,
+    buf: Vec<u8>,
+    next_writer: W,
+}
+
+impl<W: io::Write> Armor<W> {
+    pub fn new(next_writer: W) -> Self {
+        Self::with_mode(false, next_writer)
+    }
+
+    pub fn new_decoding(next_writer: W) -> Self {
+        Self::with_mode(true, next_writer)
+    }
+
+    fn with_mode(is_decoding: bool, next_writer: W) -> Self {
+        Armor {
+            is_decoding,
+            state: ArmorState::Begin,
+            buf: vec![],
+            next_writer,
+        }
+    }
+
+    fn write_begin(&mut self) -> io::Result<()> {
+        if self.state == ArmorState::Begin {
+            self.next_writer.write_all(AGE_ARMOR_BEGIN)?;
+            self.next_writer.write_all(b"\n")?;
+            self.state = ArmorState::Body;
+        }
+        Ok(())
+    }
+
+    fn encode_lines(&mut self, len: usize) -> io::Result<()> {
+        let mut text = vec![];
+        for line in self
+            .buf
+            .drain(..len)
+            .collect::<Vec<_>>()
+            .chunks(COLUMNS / 4 * 3)
+        {
+            text.extend(STANDARD.encode(line).as_bytes());
+            text.push(b'\n');
+        }
+        self.next_writer.write_all(&text)
+    }
+
+    /// Decodes the complete lines in the buffer, and the incomplete one at the end if `is_ended`.
+    fn decode_lines(&mut self, is_ended: bool) -> io::Result<()> {
+        loop {
+            let mut line = match self.buf.iter().position(|&x| x == b'\n') {
+                Some(x) => self.buf.drain(..=x).collect::<Vec<_>>(),
+                None if is_ended && !self.buf.is_empty() => mem::take(&mut self.buf),
+                None => return Ok(()),
+            };
+            while matches!(line.last(), Some(b'\n' | b'\r')) {
+                line.pop();
+            }
+            self.decode_line(&line)?;
+        }
+    }
+
+    fn decode_line(&mut self, line: &[u8]) -> io::Result<()> {
+        let is_blank = line.iter().all(u8::is_ascii_whitespace);
+        match self.state {
+            ArmorState::Begin if is_blank => {}
+            ArmorState::Begin if line == AGE_ARMOR_BEGIN => self.state = ArmorState::Body,
+            ArmorState::Begin => Err(Error::InvalidArmor("no begin line"))?,
+            ArmorState::Body | ArmorState::LastLine if line == ARMOR_END => {
+                self.state = ArmorState::End
+            }
+            ArmorState::LastLine => Err(Error::InvalidArmor("short line before the end line"))?,
+            ArmorState::Body => {
+                if line.len() > COLUMNS {
+                    Err(Error::InvalidArmor("line too long"))?;
+                }
+                if line.len() < COLUMNS {
+                    self.state = ArmorState::LastLine;
+                }
+                let data = STANDARD
+                    .decode(line)
+                    .map_err(|_| Error::InvalidArmor("invalid base64"))?;
+                self.next_writer.write_all(&data)?;
+            }
+            ArmorState::End if is_blank => {}
+            ArmorState::End => Err(Error::InvalidArmor("data after the end line"))?,
+        }
+        Ok(())
+    }
+}
+
+impl<W: io::Write> io::Write for Armor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend(buf);
+
+        if self.is_decoding {
+            self.decode_lines(false)?;
+        } else {
+            self.write_begin()?;
+            let full_len = self.buf.len() / (COLUMNS / 4 * 3) * (COLUMNS / 4 * 3);
+            self.encode_lines(full_len)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.is_decoding {
+            self.decode_lines(true)?;
+            if self.state != ArmorState::End {
+                Err(Error::InvalidArmor("no end line"))?;
+            }
+        } else {
+            self.write_begin()?;
+            self.encode_lines(self.buf.len())?;
+            self.next_writer.write_all(ARMOR_END)?;
+            self.next_writer.write_all(b"\n")?;
+        }
+
+        self.next_writer.flush()?;
+        self.state = ArmorState::Begin;
+        self.buf = vec![];
+
+        Ok(())
+    }
+}
+
+impl<W: io::Write> ChainWrite<W> for Armor<W> {
+    fn next(self) -> W {
+        self.next_writer
+    }
+    fn next_mut(&mut self) -> &mut W {
+        &mut self.next_writer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    // the interop vectors are made by an independent implementation of the spec with fixed keys and nonces
+    const IDENTITY: &str =
+        "AGE-SECRET-KEY-1GPQ5YS6YG4RYWJZFFF95CN2WFAG9Z5JN2324V46CT9D9KHZATE0S5FAZ64";
+    const RECIPIENT: &str = "age10xnrrmk7r0uunrcjqvkdatwsu7s8jwv0c7rt3rxggmkgntu955dq25yyzj";
+    const PASSPHRASE: &[u8] = b"interop passphrase";
+    const PLAINTEXT: &[u8] = b"The age interop vector of w3s.\n";
+    const X25519_VECTOR: [&str; 5] = [
+        "YWdlLWVuY3J5cHRpb24ub3JnL3YxCi0+IFgyNTUxOSBaMTNWZE8xM2lURUxQUzUyZ2ZONUMwWnNk",
+        "enNWSWY3UE5sZDVXRGNlcFM4CnNtdjVtcytjNlpwcEczd3pSMU9TY0p6NTMwcldMTHA5cm84Z0R2",
+        "M2pVYzQKLS0tIGJLUHlIa3dTaWprMzJzdTl5MzA2Z0ZaZWxoZlBhZUozTjliMmNGbTE2aEkKICEi",
+        "IyQlJicoKSorLC0uLyfr5MQOpZCOAByLNSVXYvntURgIuoatnYuOjABvxaGxUrqEt6RrsMGbNXYK",
+        "ybFu",
+    ];
+    const SCRYPT_VECTOR: [&str; 4] = [
+        "YWdlLWVuY3J5cHRpb24ub3JnL3YxCi0+IHNjcnlwdCBnSUdDZzRTRmhvZUlpWXFMakkyT2p3IDEw",
+        "ClcvUlhBdWRCS2RzMDZEZ3dvbURoZ3h3enNReU83Q2hnNjV0WmFiNi9xdmcKLS0tIHoxS016Tlo5",
+        "eHpMTmVFN0w2UGdXbWN1M3J2eWRVc1RPNWowT0o5QkpZRmsKICEiIyQlJicoKSorLC0uLyfr5MQO",
+        "pZCOAByLNSVXYvntURgIuoatnYuOjABvxaGxUrqEt6RrsMGbNXYKybFu",
+    ];
+
+    fn vector(lines: &[&str]) -> Vec<u8> {
+        STANDARD.decode(lines.concat()).unwrap()
+    }
+
+    fn identities() -> Secret {
+        Secret::Identities(vec![IDENTITY.parse().unwrap()])
+    }
+
+    fn age_error(e: io::Error) -> Error {
+        *e.into_inner().unwrap().downcast::<Error>().unwrap()
+    }
+
+    fn decrypt(secret: Secret, encrypted: &[u8]) -> io::Result<Vec<u8>> {
+        let mut age = Age::new_decryption(secret, vec![]);
+        // in small writes, like a download
+        for x in encrypted.chunks(1000) {
+            age.write_all(x)?;
+        }
+        age.flush()?;
+        Ok(age.next())
+    }
+
+    fn encrypt(mut age: Age<Vec<u8>>, data: &[u8]) -> Vec<u8> {
+        age.write_all(data).unwrap();
+        age.flush().unwrap();
+        age.next()
+    }
+
+    #[test]
+    fn keys_to_string() {
+        let identity: Identity = IDENTITY.parse().unwrap();
+        assert_eq!(identity_to_string(&identity), IDENTITY);
+        assert_eq!(recipient_to_string(&identity.to_public()), RECIPIENT);
+        assert_eq!(
+            RECIPIENT.parse::<Recipient>().unwrap().0,
+            identity.to_public().0
+        );
+    }
+
+    #[test]
+    fn interop_vectors() {
+        let x25519 = vector(&X25519_VECTOR);
+        assert_eq!(decrypt(identities(), &x25519).unwrap(), PLAINTEXT);
+
+        let scrypt = vector(&SCRYPT_VECTOR);
+        let secret = Secret::Password(PASSPHRASE.to_vec());
+        assert_eq!(decrypt(secret, &scrypt).unwrap(), PLAINTEXT);
+
+        // the armor of the same file
+        let encoded = STANDARD.encode(&x25519);
+        let mut armored = AGE_ARMOR_BEGIN.to_vec();
+        for line in encoded.as_bytes().chunks(COLUMNS) {
+            armored.push(b'\n');
+            armored.extend(line);
+        }
+        armored.push(b'\n');
+        armored.extend(ARMOR_END);
+        armored.push(b'\n');
+
+        let mut armor = Armor::new_decoding(Age::new_decryption(identities(), vec![]));
+        armor.write_all(&armored).unwrap();
+        armor.flush().unwrap();
+        assert_eq!(armor.next().next(), PLAINTEXT);
+    }
+
+    #[test]
+    fn round_trip() {
+        let identity: Identity = IDENTITY.parse().unwrap();
+        for len in [0, 1, CHUNK_SIZE, CHUNK_SIZE * 2 + 100] {
+            let data: Vec<u8> = (0..len).map(|x| x as u8).collect();
+
+            let age = Age::new(vec![identity.to_public()], vec![]).unwrap();
+            let encrypted = encrypt(age, &data);
+            assert_eq!(decrypt(identities(), &encrypted).unwrap(), data);
+
+            let age = Age::new_with_passphrase(PASSPHRASE.to_vec(), 10, vec![]).unwrap();
+            let encrypted = encrypt(age, &data);
+            let secret = Secret::Password(PASSPHRASE.to_vec());
+            assert_eq!(decrypt(secret, &encrypted).unwrap(), data);
+
+            let mut age = Age::new(vec![identity.to_public()], Armor::new(vec![])).unwrap();
+            age.write_all(&data).unwrap();
+            age.flush().unwrap();
+            let armored = age.next().next();
+            assert!(armored.starts_with(AGE_ARMOR_BEGIN));
+
+            let mut armor = Armor::new_decoding(Age::new_decryption(identities(), vec![]));
+            armor.write_all(&armored).unwrap();
+            armor.flush().unwrap();
+            assert_eq!(armor.next().next(), data);
+        }
+    }
+
+    #[test]
+    fn wrong_keys() {
+        let secret = Secret::Identities(vec![Identity::generate()]);
+        let e = decrypt(secret, &vector(&X25519_VECTOR)).unwrap_err();
+        assert!(matches!(age_error(e), Error::NoMatchedIdentity));
+
+        let secret = Secret::Password(b"wrong".to_vec());
+        let e = decrypt(secret, &vector(&SCRYPT_VECTOR)).unwrap_err();
+        assert!(matches!(age_error(e), Error::IncorrectPassphrase));
+
+        let e = decrypt(identities(), &vector(&SCRYPT_VECTOR)).unwrap_err();
+        assert!(matches!(age_error(e), Error::NoMatchedIdentity));
+    }
+
+    #[test]
+    fn tampered() {
+        let encrypted = vector(&X25519_VECTOR);
+        let mac_start = find(&encrypted, b"\n--- ").unwrap() + 5;
+
+        // a stanza, the MAC, the payload nonce and the payload
+        let nonce_start = encrypted.len() - PLAINTEXT.len() - TAG_SIZE - NONCE_SIZE;
+        for i in [40, mac_start, nonce_start, encrypted.len() - 1] {
+            let mut tampered = encrypted.clone();
+            tampered[i] ^= 1;
+            assert!(decrypt(identities(), &tampered).is_err(), "byte {}", i);
+        }
+
+        let mut tampered = encrypted.clone();
+        tampered[mac_start] = if tampered[mac_start] == b'A' {
+            b'B'
+        } else {
+            b'A'
+        };
+        let e = decrypt(identities(), &tampered).unwrap_err();
+        assert!(matches!(age_error(e), Error::HeaderMacInvalid));
+
+        let mut tampered = encrypted;
+        *tampered.last_mut().unwrap() ^= 1;
+        let e = decrypt(identities(), &tampered).unwrap_err();
+        assert!(matches!(age_error(e), Error::MacTagInvalid(0)));
+    }
+
+    #[test]
+    fn truncated() {
+        let encrypted = vector(&X25519_VECTOR);
+        let e = decrypt(
+            identities(),
+            &encrypted[..encrypted.len() - PLAINTEXT.len() - 1],
+        )
+        .unwrap_err();
+        assert!(matches!(age_error(e), Error::Truncated));
+        let e = decrypt(identities(), &encrypted[..40]).unwrap_err();
+        assert!(matches!(age_error(e), Error::Truncated));
+
+        // a full chunk without the last one
+        let identity: Identity = IDENTITY.parse().unwrap();
+        let age = Age::new(vec![identity.to_public()], vec![]).unwrap();
+        let encrypted = encrypt(age, &vec![1u8; CHUNK_SIZE * 2]);
+        let e = decrypt(identities(), &encrypted[..encrypted.len() - TAG_SIZE - 10]).unwrap_err();
+        assert!(matches!(age_error(e), Error::MacTagInvalid(_)));
+    }
+
+    #[test]
+    fn work_factor_limit() {
+        let mut encrypted = vector(&SCRYPT_VECTOR);
+        let at = find(&encrypted, b" 10\n").unwrap();
+        encrypted[at + 1..at + 3].copy_from_slice(b"23");
+        let e = decrypt(Secret::Password(PASSPHRASE.to_vec()), &encrypted).unwrap_err();
+        assert!(matches!(age_error(e), Error::WorkFactorTooLarge(23)));
+
+        assert!(matches!(
+            Age::new_with_passphrase(PASSPHRASE.to_vec(), 0, vec![]),
+            Err(Error::InvalidWorkFactor(0))
+        ));
+    }
+}
@@ -2,6 +2,7 @@
 //!
 use super::super::iroh_car;
 use super::*;
+use car_stream::{CarShard, MemoryBudget};
 use car_util::*;
 use std::cell::RefCell;
 use std::rc::Rc;
@@ -14,6 +15,8 @@ use thiserror::Error;
 enum Error {
     #[error("Car file writing error: {0:?}")]
     CarWriteError(#[from] iroh_car::Error),
+    #[error("IO error")]
+    IoError(#[from] io::Error),
 }
 
 impl From<Error> for io::Error {
@@ -22,147 +25,249 @@ impl From<Error> for io::Error {
     }
 }
 
-pub struct Car<W: io::Write> {
+/// Generates CAR shards under `MAX_CAR_SIZE`, or the size of `with_max_car_size`, from the bytes of files.
+///
+/// The blocks are written into the current shard as soon as they are hashed.
+/// Shards are kept in memory within the memory budget, or spilled to temporary files.
+pub struct Car<W: CarWrite> {
     files_count: usize,
+    flushed_files: usize,
     remote_file_id: Rc<RefCell<u64>>,
     id_map: HashMap<u64, Vec<UnixFsStruct>>,
     dir_items: Rc<Vec<DirectoryItem>>,
     buf: Vec<u8>,
-    blocks: Vec<(Cid, Vec<u8>)>,
+    shard: CarShard,
     block_size: usize,
+    dag_config: DagConfig,
+    max_car_size: usize,
+    root: Option<Cid>,
+    root_meta: FileMeta,
+    #[cfg(feature = "signing")]
+    signing: Option<(signature::SigningKey, bool)>,
+    #[cfg(feature = "signing")]
+    digests: signature::FileDigests,
     next_writer: W,
 }
 
 pub fn single_file_to_directory_item(name: &str, path: Option<&str>) -> DirectoryItem {
-    DirectoryItem::File(name.to_owned(), path.unwrap_or(name).to_owned(), 0)
+    DirectoryItem::File(
+        name.to_owned(),
+        path.unwrap_or(name).to_owned(),
+        0,
+        FileMeta::default(),
+    )
 }
 
-impl<W: io::Write> Car<W> {
+impl<W: CarWrite> Car<W> {
+    /// * `memory_budget`: the bytes of shards kept in memory, including the ones being uploaded.
+    ///   `None` means `car_stream::DEFAULT_MEMORY_BUDGET`.
     pub fn new(
         files_count: usize,
         dir_items: Rc<Vec<DirectoryItem>>,
         remote_file_id: Option<Rc<RefCell<u64>>>,
         custom_block_size: Option<usize>,
+        dag_config: Option<DagConfig>,
+        memory_budget: Option<usize>,
         next_writer: W,
     ) -> Car<W> {
         let block_size = custom_block_size.unwrap_or(256 * 1024);
         let remote_file_id = remote_file_id.unwrap_or_else(|| Rc::new(RefCell::new(0)));
+        let memory_budget = memory_budget.map(MemoryBudget::new).unwrap_or_default();
 
         Car {
             files_count,
+            flushed_files: 0,
             dir_items,
             remote_file_id,
             id_map: HashMap::new(),
-            buf: Vec::with_capacity(block_size + block_size / 10),
-            blocks: vec![],
+            buf: Vec::with_capacity(block_size),
+            shard: CarShard::new(memory_budget),
             block_size,
+            dag_config: dag_config.unwrap_or_default(),
+            max_car_size: MAX_CAR_SIZE,
+            root: None,
+            root_meta: FileMeta::default(),
+            #[cfg(feature = "signing")]
+            signing: None,
+            #[cfg(feature = "signing")]
+            digests: Default::default(),
             next_writer,
         }
     }
 
-    fn check_self_blocks_overflow_index(&self) -> Option<usize> {
-        let mut size = 0;
-        let mut result = None;
-        for (i, (_, block)) in self.blocks.iter().enumerate() {
-            size += block.len();
-            if size >= car_util::MAX_CAR_SIZE {
-                result = Some(i);
-                break;
-            }
-        }
+    /// Signs the root directory and the digests of the written files by `key` at the final flush,
+    /// and adds the signature file to the root directory. `encoded` tells that the files are compressed or encrypted.
+    #[cfg(feature = "signing")]
+    pub fn with_signature(mut self, key: signature::SigningKey, encoded: bool) -> Self {
+        self.signing = Some((key, encoded));
+        self
+    }
 
-        result
+    /// Limits the size of every shard instead of `MAX_CAR_SIZE`. A shard of a single block may still be larger.
+    pub fn with_max_car_size(mut self, size: usize) -> Self {
+        self.max_car_size = size;
+        self
     }
 
-    fn gen_car_from_buf(&mut self, buf: Vec<u8>) -> Result<Option<Vec<u8>>, Error> {
-        let remote_id = *self.remote_file_id.borrow();
-        let mut blocks = gen_blocks(buf, self.block_size);
+    /// Records the mode and mtime of the root directory in its node.
+    pub fn with_root_meta(mut self, meta: FileMeta) -> Self {
+        self.root_meta = meta;
+        self
+    }
 
-        self.blocks
-            .extend(blocks.iter_mut().map(|x| x.rip_data_with_cid()));
+    /// Returns the root CID of the DAG after the final flush.
+    pub fn root(&self) -> Option<Cid> {
+        self.root
+    }
+
+    pub(crate) fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub(crate) fn dag_config(&self) -> &DagConfig {
+        &self.dag_config
+    }
+
+    /// Writes the blocks of file `id` which are already hashed, such as by `parallel`.
+    /// `flush` should still be called at the end of every file.
+    pub(crate) fn write_file_blocks(
+        &mut self,
+        id: u64,
+        mut blocks: Vec<UnixFsStruct>,
+    ) -> io::Result<()> {
+        for block in blocks.iter_mut() {
+            let (cid, data) = block.rip_data_with_cid();
+            #[cfg(feature = "signing")]
+            if self.signing.is_some() {
+                self.digests.update(id, &cid, &data);
+            }
+            self.write_block(cid, data)?;
+        }
 
         // insert blocks into id_map
-        if let Some(struct_lst) = self.id_map.get_mut(&remote_id) {
+        if let Some(struct_lst) = self.id_map.get_mut(&id) {
             struct_lst.extend(blocks);
         } else {
-            self.id_map.insert(remote_id, blocks);
+            self.id_map.insert(id, blocks);
         }
 
-        if let Some(split_index) = self.check_self_blocks_overflow_index() {
-            let mut blocks = mem::take(&mut self.blocks);
-            let remain_blocks = blocks.split_off(split_index);
-            let car = gen_car_by_data(blocks, None)?;
-            self.blocks = remain_blocks;
-            Ok(Some(car))
-        } else {
-            Ok(None)
-        }
+        Ok(())
     }
-    fn buf_extend(&mut self, buf: &[u8]) -> Result<Option<Vec<u8>>, Error> {
-        self.buf.extend(buf);
 
-        let prepared_buf = if self.buf.len() >= MAX_CAR_SIZE {
-            Some(mem::take(&mut self.buf))
-        } else {
-            None
-        };
+    fn write_shard(&mut self, root: Option<Cid>) -> Result<(), Error> {
+        let car = self.shard.finish(root, &self.dag_config)?;
+        if self.next_writer.write_car(car)? == 0 {
+            self.next_writer.flush()?;
+        }
+        Ok(())
+    }
 
-        if let Some(buf) = prepared_buf {
-            self.gen_car_from_buf(buf)
-        } else {
-            Ok(None)
+    fn write_block(&mut self, cid: Cid, data: Vec<u8>) -> Result<(), Error> {
+        if !self.shard.is_empty()
+            && (self.shard.has_max_roots()
+                || self.shard.len_with_block(&cid, data.len()) > self.max_car_size)
+        {
+            // the final root is unknown yet, so the shard declares the roots of its complete subtrees
+            self.write_shard(None)?;
         }
+        self.shard.write_block(cid, &data)?;
+        Ok(())
     }
-}
 
-impl<W: io::Write> io::Write for Car<W> {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let result = self.buf_extend(buf)?;
+    fn write_blocks_from_buf(&mut self, buf: Vec<u8>) -> Result<(), Error> {
+        let remote_id = *self.remote_file_id.borrow();
+        let blocks = gen_blocks(buf, self.block_size, &self.dag_config);
+        self.write_file_blocks(remote_id, blocks)?;
+        Ok(())
+    }
 
-        // this scope is for memory release for car(Vec<u8>)
-        let need_flush = if let Some(car) = result {
-            self.next_mut().write(&car)? == 0
-        } else {
-            false
+    /// Writes the signature file of the root items, and returns them with the signature file.
+    #[cfg(feature = "signing")]
+    fn add_signature(
+        &mut self,
+        mut root_blocks: Vec<UnixFsStruct>,
+        collect_blocks: &mut Vec<UnixFsStruct>,
+    ) -> Result<Vec<UnixFsStruct>, Error> {
+        let (key, encoded) = match self.signing.clone() {
+            Some(x) => x,
+            None => return Ok(root_blocks),
         };
 
-        if need_flush {
-            self.next_mut().flush()?;
+        // the signed root is the root directory without the signature file
+        let signed_root = gen_dir(None, &root_blocks, &self.root_meta, &self.dag_config);
+        let digests = self.digests.by_path(&self.dir_items);
+        let data = signature::Signature::sign(&key, &signed_root.cid(), digests, encoded).to_vec();
+
+        let mut data_blocks = gen_blocks(data, self.block_size, &self.dag_config);
+        for block in data_blocks.iter_mut() {
+            let (cid, data) = block.rip_data_with_cid();
+            self.write_block(cid, data)?;
+        }
+
+        let item = DirectoryItem::File(
+            signature::SIGNATURE_NAME.to_owned(),
+            String::new(),
+            0,
+            FileMeta::default(),
+        );
+        let id_map = HashMap::from([(0, data_blocks)]);
+        root_blocks.push(item.to_unixfs_struct(&id_map, collect_blocks, &self.dag_config));
+        Ok(root_blocks)
+    }
+
+    fn buf_extend(&mut self, buf: &[u8]) -> Result<(), Error> {
+        self.buf.extend(buf);
+
+        // only full blocks are hashed before the file ends
+        let full_len = self.buf.len() / self.block_size * self.block_size;
+        if full_len > 0 {
+            let remain = self.buf.split_off(full_len);
+            let full = mem::replace(&mut self.buf, remain);
+            self.write_blocks_from_buf(full)?;
         }
 
+        Ok(())
+    }
+}
+
+impl<W: CarWrite> io::Write for Car<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf_extend(buf)?;
         Ok(buf.len())
     }
     fn flush(&mut self) -> io::Result<()> {
         if !self.buf.is_empty() {
             let remain_buf = mem::take(&mut self.buf);
-            let car_data = self.gen_car_from_buf(remain_buf)?;
-            if let Some(car) = car_data {
-                if self.next_mut().write(&car)? == 0 {
-                    self.next_mut().flush()?;
-                }
-            }
+            self.write_blocks_from_buf(remain_buf)?;
         }
 
-        // final flush
-        if self.files_count == self.id_map.len() {
+        // every file ends with a flush, including the empty ones which have no blocks in id_map
+        self.flushed_files += 1;
+
+        // final flush, also the only flush of an empty directory
+        if self.flushed_files >= self.files_count && self.root.is_none() {
             // collect dir structure recursively to blocks
             let mut blocks = vec![];
             let root_blocks: Vec<_> = self
                 .dir_items
                 .iter()
-                .map(|item| item.to_unixfs_struct(&self.id_map, &mut blocks))
+                .map(|item| item.to_unixfs_struct(&self.id_map, &mut blocks, &self.dag_config))
                 .collect();
 
-            let root = gen_dir(None, &root_blocks);
+            #[cfg(feature = "signing")]
+            let root_blocks = self.add_signature(root_blocks, &mut blocks)?;
 
-            // merge previous remaining data blocks with deep dir structure blocks
-            self.blocks
-                .extend(blocks.iter_mut().map(|x| x.rip_data_with_cid()));
+            let mut root = gen_dir(None, &root_blocks, &self.root_meta, &self.dag_config);
 
-            let car = gen_car_by_data(mem::take(&mut self.blocks), Some(root))
-                .map_err(|e| io::Error::new(io::ErrorKind::Interrupted, e))?;
+            // the dir structure blocks go after the remaining data blocks, children first
+            for block in blocks.iter_mut().chain([&mut root]) {
+                let (cid, data) = block.rip_data_with_cid();
+                self.write_block(cid, data)?;
+            }
 
-            let _ = self.next_mut().write(&car)?;
+            let (root_cid, _) = root.rip_data_with_cid();
+            self.write_shard(Some(root_cid))?;
+            self.root = Some(root_cid);
             self.next_mut().flush()?;
         }
 
@@ -170,7 +275,7 @@ impl<W: io::Write> io::Write for Car<W> {
     }
 }
 
-impl<W: io::Write> ChainWrite<W> for Car<W> {
+impl<W: CarWrite> ChainWrite<W> for Car<W> {
     fn next(self) -> W {
         self.next_writer
     }
@@ -179,3 +284,127 @@ impl<W: io::Write> ChainWrite<W> for Car<W> {
         &mut self.next_writer
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::io::{Cursor, Write};
+
+    use integer_encoding::VarInt;
+    use ipld_pb::DagPbCodec;
+    use unixfs_v1::PBNode;
+
+    use super::*;
+    use crate::iroh_car::CarHeader;
+
+    /// A shard of the size, the roots and the blocks
+    struct Shard {
+        len: usize,
+        roots: Vec<Cid>,
+        blocks: HashMap<Cid, Vec<u8>>,
+    }
+
+    impl Shard {
+        /// Walks the blocks of the shard from its roots.
+        fn reached(&self) -> HashSet<Cid> {
+            let mut ret = HashSet::new();
+            let mut stack = self.roots.clone();
+            while let Some(cid) = stack.pop() {
+                let data = match self.blocks.get(&cid) {
+                    Some(x) if ret.insert(cid) => x,
+                    _ => continue,
+                };
+                if cid.codec() != u64::from(DagPbCodec) {
+                    continue;
+                }
+                if let Ok(node) = PBNode::try_from(data.as_slice()) {
+                    stack.extend(
+                        node.Links
+                            .iter()
+                            .filter_map(|x| Cid::try_from(x.Hash.as_deref()?).ok()),
+                    );
+                }
+            }
+            ret
+        }
+    }
+
+    /// Collects every shard.
+    #[derive(Default)]
+    struct Shards(Vec<Shard>);
+    impl io::Write for Shards {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    impl CarWrite for Shards {
+        fn write_car(&mut self, car: car_stream::UploadBody) -> io::Result<usize> {
+            let data = car.to_vec()?;
+            let (len, offset) = usize::decode_var(&data).unwrap();
+            let header = CarHeader::decode(&data[offset..offset + len]).unwrap();
+
+            let mut blocks = HashMap::new();
+            let mut pos = offset + len;
+            while pos < data.len() {
+                let (len, offset) = usize::decode_var(&data[pos..]).unwrap();
+                let mut section = Cursor::new(&data[pos + offset..pos + offset + len]);
+                let cid = Cid::read_bytes(&mut section).unwrap();
+                let start = section.position() as usize;
+                blocks.insert(cid, section.into_inner()[start..].to_vec());
+                pos += offset + len;
+            }
+
+            self.0.push(Shard {
+                len: data.len(),
+                roots: header.roots().to_vec(),
+                blocks,
+            });
+            Ok(data.len())
+        }
+    }
+
+    #[test]
+    fn many_tiny_files() {
+        // every leaf is a root until the file nodes are written at the final flush
+        let (dirs, files_per_dir) = (110, 1000);
+        let dir_items: Vec<_> = (0..dirs)
+            .map(|d| {
+                let files = (0..files_per_dir)
+                    .map(|f| {
+                        let id = (d * files_per_dir + f) as u64;
+                        DirectoryItem::File(f.to_string(), String::new(), id, FileMeta::default())
+                    })
+                    .collect();
+                DirectoryItem::Directory(d.to_string(), files, FileMeta::default())
+            })
+            .collect();
+
+        let file_id = Rc::new(RefCell::new(0));
+        let mut car = Car::new(
+            dirs * files_per_dir,
+            Rc::new(dir_items),
+            Some(file_id.clone()),
+            None,
+            None,
+            None,
+            Shards::default(),
+        );
+        for id in 0..(dirs * files_per_dir) as u64 {
+            *file_id.borrow_mut() = id;
+            car.write_all(&id.to_le_bytes().repeat(125)).unwrap();
+            car.flush().unwrap();
+        }
+
+        let shards = car.next().0;
+        assert!(shards.len() > 1);
+        for shard in shards {
+            assert!(shard.len <= MAX_CAR_SIZE);
+            assert!((1..=car_stream::MAX_SHARD_ROOTS).contains(&shard.roots.len()));
+            // no block is left out by the roots
+            assert_eq!(shard.reached().len(), shard.blocks.len());
+        }
+    }
+}
@@ -0,0 +1,408 @@
+//! Streams CAR shards with bounded memory by spilling them to temporary files
+//!
+use super::super::iroh_car::CarHeader;
+use super::car_util::{empty_item, is_inlined, SubtreeRoots};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::{cmp, mem};
+
+use cid::Cid;
+use integer_encoding::VarInt;
+
+use super::car_util::DagConfig;
+
+pub const DEFAULT_MEMORY_BUDGET: usize = 256 * 1024 * 1024; // 256mb
+/// The most roots declared by a shard without a given root, which keeps its header small.
+/// A shard is finished once it has as many roots, so none of them is left out.
+pub const MAX_SHARD_ROOTS: usize = 1024;
+
+/// Size of a CAR header without any root, with the length varint of at most 3 bytes:
+/// `{"roots": [...], "version": 1}` as the map, its two keys, the array header of at most 3 bytes and the version
+const HEADER_OVERHEAD: usize = 3 + 1 + 6 + 3 + 8 + 1;
+/// Size of a root in the header besides the CID bytes: tag 42, the byte string header and the `0x00` prefix
+const ROOT_OVERHEAD: usize = 2 + 2 + 1;
+/// Room for the block of the empty item, which is written when the shard has no complete subtree
+const EMPTY_ITEM_RESERVE: usize = 128;
+
+/// Limits the bytes of shards kept in memory, including the ones still being uploaded.
+/// It is shared by the clones.
+#[derive(Debug, Clone)]
+pub struct MemoryBudget {
+    limit: usize,
+    used: Arc<AtomicUsize>,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Self {
+        MemoryBudget {
+            limit,
+            used: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Acquire)
+    }
+
+    fn reserve(&self) -> Reservation {
+        Reservation {
+            size: 0,
+            limit: self.limit,
+            used: self.used.clone(),
+        }
+    }
+}
+
+impl Default for MemoryBudget {
+    fn default() -> Self {
+        Self::new(DEFAULT_MEMORY_BUDGET)
+    }
+}
+
+/// Bytes taken from a `MemoryBudget`, given back on drop
+#[derive(Debug)]
+struct Reservation {
+    size: usize,
+    limit: usize,
+    used: Arc<AtomicUsize>,
+}
+
+impl Reservation {
+    fn grow(&mut self, n: usize) -> bool {
+        let result = self
+            .used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |x| {
+                (x + n <= self.limit).then_some(x + n)
+            });
+        if result.is_ok() {
+            self.size += n;
+        }
+        result.is_ok()
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.used.fetch_sub(self.size, Ordering::AcqRel);
+    }
+}
+
+/// A temporary file removed on drop unless it is persisted
+#[derive(Debug)]
+pub(crate) struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// Creates the file in the temporary directory, which only the owner can read and write on Unix.
+    pub(crate) fn create(extension: &str) -> io::Result<(Self, File)> {
+        Self::create_at(&env::temp_dir(), extension, true)
+    }
+
+    /// Creates the file in `dir` with the permissions of any new file there, to be persisted in `dir` later.
+    pub(crate) fn create_in(dir: &Path, extension: &str) -> io::Result<(Self, File)> {
+        Self::create_at(dir, extension, false)
+    }
+
+    fn create_at(dir: &Path, extension: &str, is_private: bool) -> io::Result<(Self, File)> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let name = format!(
+            "w3s-{}-{}.{}",
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed),
+            extension
+        );
+        let path = dir.join(name);
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create_new(true);
+        #[cfg(unix)]
+        if is_private {
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        }
+        #[cfg(not(unix))]
+        let _ = is_private;
+        let file = options.open(&path)?;
+
+        Ok((TempFile { path }, file))
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Moves the file to `path`, which should be on the same file system, instead of removing it.
+    pub(crate) fn persist(mut self, path: &Path) -> io::Result<()> {
+        fs::rename(&self.path, path)?;
+        self.path = PathBuf::new();
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.path.as_os_str().is_empty() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Tail {
+    // the reservation is given back when the body is dropped
+    Memory(Vec<u8>, #[allow(dead_code)] Option<Reservation>),
+    File(TempFile, usize),
+}
+
+/// Data of one upload, kept in memory or backed by a temporary file
+#[derive(Debug)]
+pub struct UploadBody {
+    head: Vec<u8>,
+    tail: Tail,
+}
+
+impl UploadBody {
+    pub fn len(&self) -> usize {
+        self.head.len()
+            + match &self.tail {
+                Tail::Memory(data, _) => data.len(),
+                Tail::File(_, len) => *len,
+            }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_file_backed(&self) -> bool {
+        matches!(self.tail, Tail::File(..))
+    }
+
+    pub fn to_vec(&self) -> io::Result<Vec<u8>> {
+        let mut ret = Vec::with_capacity(self.len());
+        self.write_to(&mut ret)?;
+        Ok(ret)
+    }
+
+    /// Copies the whole body to `writer` in chunks.
+    pub fn write_to(&self, mut writer: impl Write) -> io::Result<()> {
+        let mut file = None;
+        let mut pos = 0;
+        while pos < self.len() {
+            let chunk = self.read_chunk(&mut file, pos, 1024 * 1024)?;
+            writer.write_all(&chunk)?;
+            pos += chunk.len();
+        }
+        Ok(())
+    }
+
+    /// Whether the bytes from `pos` are kept in memory, so they can be read without blocking.
+    pub(crate) fn is_in_memory(&self, pos: usize) -> bool {
+        pos < self.head.len() || matches!(self.tail, Tail::Memory(..))
+    }
+
+    /// Reads at most `max_len` bytes from `pos`. The file is opened at the first read of the tail and
+    /// kept in `file`, so the reads should be sequential.
+    pub(crate) fn read_chunk(
+        &self,
+        file: &mut Option<File>,
+        pos: usize,
+        max_len: usize,
+    ) -> io::Result<Vec<u8>> {
+        if pos < self.head.len() {
+            let end = cmp::min(self.head.len(), pos + max_len);
+            return Ok(self.head[pos..end].to_vec());
+        }
+
+        let pos = pos - self.head.len();
+        match &self.tail {
+            Tail::Memory(data, _) => {
+                let end = cmp::min(data.len(), pos + max_len);
+                Ok(data[pos..end].to_vec())
+            }
+            Tail::File(temp, len) => {
+                if file.is_none() {
+                    let mut f = File::open(&temp.path)?;
+                    f.seek(SeekFrom::Start(pos as u64))?;
+                    *file = Some(f);
+                }
+
+                let mut ret = vec![0u8; cmp::min(len - pos, max_len)];
+                file.as_mut().unwrap().read_exact(&mut ret)?;
+                Ok(ret)
+            }
+        }
+    }
+}
+
+impl From<Vec<u8>> for UploadBody {
+    fn from(data: Vec<u8>) -> Self {
+        UploadBody {
+            head: vec![],
+            tail: Tail::Memory(data, None),
+        }
+    }
+}
+
+enum Sections {
+    Memory(Vec<u8>, Reservation),
+    File(TempFile, BufWriter<File>),
+}
+
+/// A CAR shard whose block sections are written as soon as the blocks are hashed.
+/// The header is generated at the end, since the roots are only known by then.
+pub struct CarShard {
+    sections: Sections,
+    sections_len: usize,
+    cids: Vec<Cid>,
+    subtrees: SubtreeRoots,
+    max_root_len: usize,
+    budget: MemoryBudget,
+}
+
+impl CarShard {
+    pub fn new(budget: MemoryBudget) -> Self {
+        CarShard {
+            sections: Sections::Memory(vec![], budget.reserve()),
+            sections_len: 0,
+            cids: vec![],
+            subtrees: SubtreeRoots::default(),
+            max_root_len: 0,
+            budget,
+        }
+    }
+
+    /// The size of the CAR with the largest possible header.
+    pub fn len(&self) -> usize {
+        self.sections_len + self.header_len(self.cids.len(), self.max_root_len)
+    }
+
+    /// The size of the CAR with the largest possible header after writing a block of `data_len` bytes.
+    pub fn len_with_block(&self, cid: &Cid, data_len: usize) -> usize {
+        if is_inlined(cid) {
+            return self.len();
+        }
+        let cid_len = cid.to_bytes().len();
+        let section_len = (cid_len + data_len).required_space() + cid_len + data_len;
+        let max_root_len = cmp::max(self.max_root_len, cid_len + ROOT_OVERHEAD);
+        self.sections_len + section_len + self.header_len(self.cids.len() + 1, max_root_len)
+    }
+
+    /// Every block may be a root, but no more than `MAX_SHARD_ROOTS` are kept in a shard.
+    fn header_len(&self, blocks: usize, max_root_len: usize) -> usize {
+        HEADER_OVERHEAD + EMPTY_ITEM_RESERVE + cmp::min(blocks, MAX_SHARD_ROOTS) * max_root_len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cids.is_empty()
+    }
+
+    /// Whether the shard has `MAX_SHARD_ROOTS` roots, so it should be finished before the next block.
+    pub fn has_max_roots(&self) -> bool {
+        self.subtrees.count() >= MAX_SHARD_ROOTS
+    }
+
+    /// The size of the block sections without the header.
+    pub fn sections_len(&self) -> usize {
+        self.sections_len
+    }
+
+    /// Writes a block section. Inlined blocks are skipped.
+    pub fn write_block(&mut self, cid: Cid, data: &[u8]) -> io::Result<()> {
+        if is_inlined(&cid) {
+            return Ok(());
+        }
+
+        self.subtrees
+            .push(cid, data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let cid_bytes = cid.to_bytes();
+        let mut section = (cid_bytes.len() + data.len()).encode_var_vec();
+        section.reserve(cid_bytes.len() + data.len());
+        section.extend(&cid_bytes);
+        section.extend(data);
+
+        if let Sections::Memory(buf, reservation) = &mut self.sections {
+            if reservation.grow(section.len()) {
+                buf.extend(&section);
+            } else {
+                // spills to a temporary file when out of the memory budget
+                let (temp, file) = TempFile::create("car")?;
+                let mut writer = BufWriter::new(file);
+                writer.write_all(buf)?;
+                writer.write_all(&section)?;
+                self.sections = Sections::File(temp, writer);
+            }
+        } else if let Sections::File(_, writer) = &mut self.sections {
+            writer.write_all(&section)?;
+        }
+
+        self.sections_len += section.len();
+        self.max_root_len = cmp::max(self.max_root_len, cid_bytes.len() + ROOT_OVERHEAD);
+        self.cids.push(cid);
+        Ok(())
+    }
+
+    /// Ends the shard with the header and starts a new one.
+    ///
+    /// Without a root, the header declares the roots of the complete subtrees in the shard,
+    /// which reach every block of them. A parent whose children are in earlier shards isn't complete,
+    /// so it is only reached from the roots of a later shard.
+    pub fn finish(&mut self, root: Option<Cid>, config: &DagConfig) -> io::Result<UploadBody> {
+        let roots = match root {
+            Some(x) => vec![x],
+            None => self.declared_roots(vec![], config)?,
+        };
+        self.finish_with_roots(roots)
+    }
+
+    /// Returns `roots` followed by the roots of the complete subtrees in the shard.
+    /// Without any, the empty item is written as the root.
+    pub fn declared_roots(
+        &mut self,
+        mut roots: Vec<Cid>,
+        config: &DagConfig,
+    ) -> io::Result<Vec<Cid>> {
+        for x in self.subtrees.roots() {
+            if !roots.contains(&x) {
+                roots.push(x);
+            }
+        }
+        if roots.is_empty() {
+            let (cid, data) = empty_item(config).rip_data_with_cid();
+            self.write_block(cid, &data)?;
+            roots.push(cid);
+        }
+        Ok(roots)
+    }
+
+    /// Ends the shard with a header of the given roots and starts a new one.
+    pub fn finish_with_roots(&mut self, roots: Vec<Cid>) -> io::Result<UploadBody> {
+        let header = CarHeader::new(roots)
+            .encode()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut head = header.len().encode_var_vec();
+        head.extend(header);
+
+        let shard = mem::replace(self, CarShard::new(self.budget.clone()));
+        let tail = match shard.sections {
+            Sections::Memory(data, reservation) => Tail::Memory(data, Some(reservation)),
+            Sections::File(temp, writer) => {
+                writer.into_inner().map_err(|e| e.into_error())?;
+                Tail::File(temp, shard.sections_len)
+            }
+        };
+
+        Ok(UploadBody { head, tail })
+    }
+}
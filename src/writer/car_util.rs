@@ -2,22 +2,130 @@
 //!
 use super::super::iroh_car;
 use super::*;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
-use std::fs;
+use std::fs::{self, File};
+use std::io::{Read, Seek};
 use std::path::{Path, PathBuf};
-use std::{borrow::Cow, collections::HashMap, mem};
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+use std::{borrow::Cow, mem};
 
 use cid::Cid;
+use cid::Version;
+use integer_encoding::VarInt;
 use ipld_pb::DagPbCodec;
 use iroh_car::CarHeader;
 use iroh_car::*;
-use multihash::{Code::Sha2_256, MultihashDigest};
+use multihash::{Code, Multihash, MultihashDigest};
 use quick_protobuf::message::MessageWrite;
-use quick_protobuf::Writer;
+use quick_protobuf::sizeofs::sizeof_varint;
+use quick_protobuf::{Writer, WriterBackend};
+use serde::{Deserialize, Serialize};
 use unixfs_v1::{PBLink, PBNode, UnixFs, UnixFsType};
 
 pub const MAX_CAR_SIZE: usize = 104752742; // 99.9mb
 
+/// The largest block which can be inlined, limited by the 64 bytes digest size of `Cid`
+pub const MAX_INLINE_SIZE: usize = 64;
+
+/// The file of signed uploads in the root directory, which is never encrypted or compressed,
+/// see `writer::signature`
+pub const SIGNATURE_NAME: &str = "w3s-signature.json";
+
+/// The permission bits which are captured and restored. Setuid, setgid and sticky bits are dropped,
+/// since the mode of a downloaded DAG is untrusted.
+const PERMISSION_BITS: u32 = 0o777;
+
+const IDENTITY: u64 = 0x00;
+const RAW: u64 = 0x55;
+
+/// Hash functions for the generated blocks
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DagHash {
+    #[default]
+    Sha2_256,
+    Blake2b256,
+    Blake3,
+}
+
+impl From<DagHash> for Code {
+    fn from(hash: DagHash) -> Self {
+        match hash {
+            DagHash::Sha2_256 => Code::Sha2_256,
+            DagHash::Blake2b256 => Code::Blake2b256,
+            DagHash::Blake3 => Code::Blake3_256,
+        }
+    }
+}
+
+/// Options of the DAG generation
+///
+/// The default config generates the CIDs of earlier versions, except for a directory with an empty file,
+/// whose link is named now instead of being left without a name.
+#[derive(Debug, Clone, Copy)]
+pub struct DagConfig {
+    /// The hash function of all the blocks and the CAR roots.
+    pub hash: DagHash,
+    /// `Version::V0` is only applied to dag-pb blocks hashed by sha2-256, others fall back to CIDv1.
+    pub cid_version: Version,
+    /// Blocks up to this size (at most `MAX_INLINE_SIZE`) are inlined into identity CIDs
+    /// and are not written to the CAR.
+    pub inline_limit: Option<usize>,
+    /// Uses raw leaves (codec 0x55). Otherwise the leaves are dag-pb UnixFS `File` nodes.
+    pub raw_leaves: bool,
+    /// Links a single-block file to its leaf directly instead of a wrapping `File` node.
+    pub unwrap_single_block: bool,
+}
+
+impl Default for DagConfig {
+    fn default() -> Self {
+        DagConfig {
+            hash: DagHash::Sha2_256,
+            cid_version: Version::V1,
+            inline_limit: None,
+            raw_leaves: true,
+            unwrap_single_block: false,
+        }
+    }
+}
+
+impl DagConfig {
+    fn gen_cid(&self, codec: u64, data: &[u8]) -> Cid {
+        let inline_limit = self.inline_limit.unwrap_or(0).min(MAX_INLINE_SIZE);
+
+        if data.len() <= inline_limit {
+            return Cid::new_v1(codec, Multihash::wrap(IDENTITY, data).unwrap());
+        }
+
+        let digest = Code::from(self.hash).digest(data);
+        match (self.cid_version, self.hash) {
+            (Version::V0, DagHash::Sha2_256) if codec == u64::from(DagPbCodec) => {
+                Cid::new_v0(digest).unwrap()
+            }
+            _ => Cid::new_v1(codec, digest),
+        }
+    }
+
+    /// The leaf which can replace the wrapping `File` node of a single-block file.
+    fn unwrapped_leaf<'a>(
+        &self,
+        blocks: &'a [UnixFsStruct],
+        meta: &FileMeta,
+    ) -> Option<&'a UnixFsStruct> {
+        // raw leaves can't carry the metadata
+        match blocks {
+            [leaf] if self.unwrap_single_block && meta.is_empty() => Some(leaf),
+            _ => None,
+        }
+    }
+}
+
+/// Detects if the data of a block is inlined in its CID
+pub fn is_inlined(cid: &Cid) -> bool {
+    cid.hash().code() == IDENTITY
+}
+
 trait ToVec {
     fn to_vec(&self) -> Vec<u8>;
 }
@@ -38,22 +146,189 @@ impl<'a> ToVec for UnixFs<'a> {
     }
 }
 
+/// POSIX mode and modification time stored in the UnixFS 1.5 `mode` and `mtime` fields.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileMeta {
+    /// permission bits, e.g. `0o755`
+    pub mode: Option<u32>,
+    /// seconds and nanoseconds since the unix epoch
+    pub mtime: Option<(i64, u32)>,
+}
+
+impl FileMeta {
+    /// Captures mode and mtime from filesystem metadata. The mode is only available on unix.
+    pub fn from_fs(metadata: &fs::Metadata) -> Self {
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(metadata.permissions().mode() & PERMISSION_BITS)
+        };
+        #[cfg(not(unix))]
+        let mode = None;
+
+        let mtime = metadata
+            .modified()
+            .ok()
+            .map(|t| match t.duration_since(UNIX_EPOCH) {
+                Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+                // the nanoseconds always count forward, so -1.5s is (-2, 500_000_000)
+                Err(e) => match e.duration().subsec_nanos() {
+                    0 => (-(e.duration().as_secs() as i64), 0),
+                    n => (-(e.duration().as_secs() as i64) - 1, 1_000_000_000 - n),
+                },
+            });
+
+        FileMeta { mode, mtime }
+    }
+
+    /// Reads mode and mtime from an encoded dag-pb UnixFS node.
+    pub fn from_block(block: &[u8]) -> Option<Self> {
+        let node = PBNode::try_from(block).ok()?;
+        let data = UnixFs::try_from(&node).ok()?;
+        let meta = unixfs_v1::Metadata::from(&data);
+
+        let result = FileMeta {
+            mode: meta.mode(),
+            mtime: meta.mtime(),
+        };
+        (!result.is_empty()).then_some(result)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mode.is_none() && self.mtime.is_none()
+    }
+
+    /// Restores the recorded mtime and mode on a local file or directory.
+    /// Only the permission bits of the mode are restored.
+    pub fn apply(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+
+        // mtime goes first since a restored mode may remove the read permission
+        if let Some((secs, nanos)) = self.mtime {
+            let time = if secs >= 0 {
+                UNIX_EPOCH + Duration::new(secs as u64, nanos)
+            } else {
+                UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
+                    + Duration::from_nanos(nanos as u64)
+            };
+            open_for_times(path)?.set_modified(time)?;
+        }
+
+        #[cfg(unix)]
+        if let Some(mode) = self.mode {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(path, fs::Permissions::from_mode(mode & PERMISSION_BITS))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Opens a file or directory to set its mtime.
+#[cfg(not(windows))]
+fn open_for_times(path: &Path) -> io::Result<File> {
+    File::open(path)
+}
+
+/// Windows only opens a directory with the backup semantics, and sets the times with the access to write attributes.
+#[cfg(windows)]
+fn open_for_times(path: &Path) -> io::Result<File> {
+    use std::os::windows::fs::OpenOptionsExt;
+    const FILE_WRITE_ATTRIBUTES: u32 = 0x0100;
+    const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x0200_0000;
+
+    fs::OpenOptions::new()
+        .access_mode(FILE_WRITE_ATTRIBUTES)
+        .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+        .open(path)
+}
+
+/// `UnixTime` of unixfs-v1 is not exported, so the `mtime` field is written by this one.
+struct UnixTimeWrite(i64, u32);
+impl MessageWrite for UnixTimeWrite {
+    fn get_size(&self) -> usize {
+        1 + sizeof_varint(self.0 as u64) + if self.1 == 0 { 0 } else { 1 + 4 }
+    }
+    fn write_message<W: WriterBackend>(&self, w: &mut Writer<W>) -> quick_protobuf::Result<()> {
+        w.write_with_tag(8, |w| w.write_int64(self.0))?;
+        if self.1 != 0 {
+            w.write_with_tag(21, |w| w.write_fixed32(self.1))?;
+        }
+        Ok(())
+    }
+}
+
+fn unixfs_to_vec(data: UnixFs, meta: &FileMeta) -> Vec<u8> {
+    let mut ret = UnixFs {
+        mode: meta.mode,
+        ..data
+    }
+    .to_vec();
+
+    // `mtime` is the last field of the message, so appending it keeps the field order
+    if let Some((secs, nanos)) = meta.mtime {
+        let mut writer = Writer::new(&mut ret);
+        writer
+            .write_with_tag(66, |w| w.write_message(&UnixTimeWrite(secs, nanos)))
+            .unwrap();
+    }
+    ret
+}
+
+/// Reads the target path of an encoded dag-pb UnixFS `Symlink` node.
+pub fn read_symlink_block(block: &[u8]) -> Option<String> {
+    let node = PBNode::try_from(block).ok()?;
+    let data = UnixFs::try_from(&node).ok()?;
+    if data.Type != UnixFsType::Symlink {
+        return None;
+    }
+
+    data.Data.map(|x| String::from_utf8_lossy(&x).to_string())
+}
+
+/// How symbolic links are handled when collecting items from the disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Ignores the links.
+    #[default]
+    Skip,
+    /// Collects what the links point to. Links looping back to an ancestor directory are skipped.
+    Follow,
+    /// Keeps the links as UnixFS `Symlink` nodes.
+    Store,
+}
+
 #[derive(Debug)]
 pub enum DirectoryItem {
-    /// name, path, id
-    File(String, String, u64),
-    /// name, sub_items
-    Directory(String, Vec<DirectoryItem>),
+    /// name, path, id, meta
+    File(String, String, u64, FileMeta),
+    /// name, sub_items, meta
+    Directory(String, Vec<DirectoryItem>, FileMeta),
+    /// name, target, meta
+    Symlink(String, String, FileMeta),
 }
 
 impl DirectoryItem {
+    /// Collects the items under `path`.
+    /// * `with_metadata`: captures mode and mtime of every file and directory.
+    /// * `symlink_policy`: decides what to do with symbolic links.
     pub fn from_path(
         path: &str,
         filter: Option<fn(name: &str, is_file: bool) -> bool>,
+        with_metadata: bool,
+        symlink_policy: SymlinkPolicy,
     ) -> io::Result<(Vec<Self>, u64)> {
         let path_buf = Path::new(path).to_path_buf();
+        let mut ancestors = vec![fs::canonicalize(&path_buf)?];
         let mut id = 0;
-        let result = Self::from_path_buf(path_buf, &mut id, filter.unwrap_or(|_, _| true))?;
+        let result = Self::from_path_buf(
+            path_buf,
+            &mut id,
+            filter.unwrap_or(|_, _| true),
+            with_metadata,
+            symlink_policy,
+            &mut ancestors,
+        )?;
         Ok((result, id))
     }
 
@@ -61,26 +336,69 @@ impl DirectoryItem {
         path: PathBuf,
         id: &mut u64,
         filter: fn(&str, bool) -> bool,
+        with_metadata: bool,
+        symlink_policy: SymlinkPolicy,
+        ancestors: &mut Vec<PathBuf>,
     ) -> io::Result<Vec<Self>> {
         let dir = fs::read_dir(path)?;
         let mut result = vec![];
         for item in dir {
             let entry = item?;
-            let metadata = entry.metadata()?;
+            let mut metadata = entry.metadata()?;
             let name = entry.file_name().to_string_lossy().to_string();
+
+            let is_symlink = metadata.is_symlink();
+            if is_symlink {
+                match symlink_policy {
+                    SymlinkPolicy::Skip => continue,
+                    SymlinkPolicy::Follow => match fs::metadata(entry.path()) {
+                        Ok(x) => metadata = x,
+                        // dangling link
+                        Err(_) => continue,
+                    },
+                    SymlinkPolicy::Store => {}
+                }
+            }
+
             if !filter(&name, metadata.is_file()) {
                 continue;
             }
 
-            if metadata.is_dir() {
-                result.push(Self::Directory(
+            let meta = if with_metadata {
+                FileMeta::from_fs(&metadata)
+            } else {
+                FileMeta::default()
+            };
+
+            if is_symlink && symlink_policy == SymlinkPolicy::Store {
+                let target = fs::read_link(entry.path())?;
+                result.push(Self::Symlink(
                     name,
-                    Self::from_path_buf(entry.path(), id, filter)?,
+                    target.to_string_lossy().to_string(),
+                    meta,
                 ));
+            } else if metadata.is_dir() {
+                let canonical = fs::canonicalize(entry.path())?;
+                if ancestors.contains(&canonical) {
+                    continue;
+                }
+
+                ancestors.push(canonical);
+                let sub_items = Self::from_path_buf(
+                    entry.path(),
+                    id,
+                    filter,
+                    with_metadata,
+                    symlink_policy,
+                    ancestors,
+                )?;
+                ancestors.pop();
+
+                result.push(Self::Directory(name, sub_items, meta));
             } else if metadata.is_file() {
                 let path = entry.path().to_string_lossy().to_string();
                 *id += 1;
-                result.push(Self::File(name, path, *id));
+                result.push(Self::File(name, path, *id, meta));
             }
         }
 
@@ -91,22 +409,37 @@ impl DirectoryItem {
         &self,
         id_map: &HashMap<u64, Vec<UnixFsStruct>>,
         collect_blocks: &mut Vec<UnixFsStruct>,
+        config: &DagConfig,
     ) -> UnixFsStruct {
         let block = match self {
-            Self::File(name, _, id) => {
+            Self::File(name, _, id, meta) => {
                 if let Some(blocks) = id_map.get(id) {
-                    gen_pbnode_from_blocks(name.clone(), blocks)
+                    if let Some(leaf) = config.unwrapped_leaf(blocks, meta) {
+                        // the leaf has already been collected with the file data
+                        return UnixFsStruct {
+                            name: Some(name.clone()),
+                            ..leaf.clone()
+                        };
+                    }
+                    gen_pbnode_from_blocks(name.clone(), blocks, meta, config)
+                } else if !meta.is_empty() {
+                    gen_pbnode_from_blocks(name.clone(), &[], meta, config)
                 } else {
-                    empty_item()
+                    // an empty file has no blocks, and its link is named like any other file
+                    UnixFsStruct {
+                        name: Some(name.clone()),
+                        ..empty_item(config)
+                    }
                 }
             }
-            Self::Directory(name, sub_items) => {
+            Self::Directory(name, sub_items, meta) => {
                 let items: Vec<UnixFsStruct> = sub_items
                     .iter()
-                    .map(|x| x.to_unixfs_struct(id_map, collect_blocks))
+                    .map(|x| x.to_unixfs_struct(id_map, collect_blocks, config))
                     .collect();
-                gen_dir(Some(name.clone()), &items)
+                gen_dir(Some(name.clone()), &items, meta, config)
             }
+            Self::Symlink(name, target, meta) => gen_symlink(name.clone(), target, meta, config),
         };
 
         collect_blocks.push(block.clone());
@@ -122,11 +455,15 @@ pub struct UnixFsStruct {
     size: u64,
 }
 impl UnixFsStruct {
+    pub fn cid(&self) -> Cid {
+        self.cid
+    }
+
     pub fn rip_data_with_cid(&mut self) -> (Cid, Vec<u8>) {
         (self.cid, mem::take(&mut self.data))
     }
 
-    pub fn to_link(&self) -> PBLink {
+    pub fn to_link(&self) -> PBLink<'_> {
         PBLink {
             Name: None,
             Hash: Some(self.cid.to_bytes().into()),
@@ -147,9 +484,9 @@ impl Display for UnixFsStruct {
 }
 
 /// Detect if a list of Cids contains one empty item
-pub fn find_empty_item(lst: &[Cid]) -> Option<Cid> {
-    let cid = empty_item().cid;
-    if lst.iter().any(|x| *x == cid) {
+pub fn find_empty_item(lst: &[Cid], config: &DagConfig) -> Option<Cid> {
+    let cid = empty_item(config).cid;
+    if lst.contains(&cid) {
         Some(cid)
     } else {
         None
@@ -158,7 +495,7 @@ pub fn find_empty_item(lst: &[Cid]) -> Option<Cid> {
 
 /// blake2b: bafykbzacebrixudpac7a56ypc7lxhwqe5nyvvmyc6mhurq4pc3zmsmymr2cum
 /// sha2-256: bafybeih5bpd2dhazdwycgx5czs4xj3k7g7qtgatxotbi5enslwjbz7nrfe
-fn empty_item() -> UnixFsStruct {
+pub(crate) fn empty_item(config: &DagConfig) -> UnixFsStruct {
     let data_bytes = UnixFs {
         Type: UnixFsType::Raw,
         Data: None,
@@ -177,8 +514,7 @@ fn empty_item() -> UnixFsStruct {
     }
     .to_vec();
 
-    let digest = Sha2_256.digest(&node_bytes);
-    let cid = Cid::new_v1(DagPbCodec.into(), digest);
+    let cid = config.gen_cid(DagPbCodec.into(), &node_bytes);
     UnixFsStruct {
         name: None,
         cid,
@@ -187,72 +523,260 @@ fn empty_item() -> UnixFsStruct {
     }
 }
 
+/// Tracks the complete subtrees of blocks written in order, the children before their parents.
+///
+/// A block is complete when every block it links to is inlined or complete in the same list,
+/// so a file whose leaves are in another shard is not. The roots are the complete blocks which
+/// are not linked by any other complete block.
+#[derive(Default)]
+pub struct SubtreeRoots {
+    cids: Vec<Cid>,
+    complete: HashSet<Cid>,
+    linked: HashSet<Cid>,
+    count: usize,
+}
+
+impl SubtreeRoots {
+    pub fn push(&mut self, cid: Cid, data: &[u8]) -> Result<(), quick_protobuf::Error> {
+        if is_inlined(&cid) || self.complete.contains(&cid) {
+            return Ok(());
+        }
+
+        if cid.codec() == u64::from(DagPbCodec) {
+            let links = PBNode::try_from(data)?
+                .Links
+                .into_iter()
+                .filter_map(|x| x.Hash.and_then(|h| Cid::try_from(h.as_ref()).ok()))
+                .collect::<Vec<_>>();
+            if !links
+                .iter()
+                .all(|x| is_inlined(x) || self.complete.contains(x))
+            {
+                return Ok(());
+            }
+            for x in links {
+                // a complete child stops being a root once it is linked
+                if self.linked.insert(x) && self.complete.contains(&x) {
+                    self.count -= 1;
+                }
+            }
+        }
+
+        self.complete.insert(cid);
+        self.cids.push(cid);
+        self.count += 1;
+        Ok(())
+    }
+
+    /// The number of roots, without collecting them
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn roots(&self) -> Vec<Cid> {
+        self.cids
+            .iter()
+            .filter(|x| !self.linked.contains(x))
+            .copied()
+            .collect()
+    }
+}
+
+/// Finds the roots of the complete subtrees in the list, whose descendants are all in it.
+/// Inlined blocks are never stored, so they are left out.
+pub fn find_subtree_roots<'a>(blocks: impl Iterator<Item = (&'a Cid, &'a [u8])>) -> Vec<Cid> {
+    let mut roots = SubtreeRoots::default();
+    for (cid, data) in blocks {
+        // a node which can't be decoded is never complete
+        let _ = roots.push(*cid, data);
+    }
+    roots.roots()
+}
+
+/// Roots of a CAR without a given root. Falls back to the empty item when every block is inlined.
+fn subtree_roots_or_empty<'a>(
+    blocks: impl Iterator<Item = (&'a Cid, &'a [u8])>,
+    config: &DagConfig,
+) -> (Vec<Cid>, Option<UnixFsStruct>) {
+    let roots = find_subtree_roots(blocks);
+    if roots.is_empty() {
+        let empty = empty_item(config);
+        (vec![empty.cid], Some(empty))
+    } else {
+        (roots, None)
+    }
+}
+
+/// Writes the blocks in the given order followed by the root, so the children always go before their parents.
+///
+/// Without a root, the header declares the roots of the complete subtrees in `blocks`.
+/// This makes every shard of a large DAG an independently importable CAR.
 pub fn gen_car_by_data(
     blocks: Vec<(Cid, Vec<u8>)>,
     root_struct: Option<UnixFsStruct>,
+    config: &DagConfig,
 ) -> Result<Vec<u8>, iroh_car::Error> {
-    let root = root_struct.unwrap_or_else(empty_item);
-    let header = CarHeader::new(vec![root.cid]);
+    let (roots, root_struct) = match root_struct {
+        Some(x) => (vec![x.cid], Some(x)),
+        None => subtree_roots_or_empty(blocks.iter().map(|(c, d)| (c, d.as_slice())), config),
+    };
+    let header = CarHeader::new(roots);
 
-    let mut buffer = Vec::with_capacity(MAX_CAR_SIZE);
+    let size = blocks.iter().map(|(_, x)| x.len() + 64).sum::<usize>();
+    let mut buffer = Vec::with_capacity(size);
     let mut writer = CarWriter::new(header, &mut buffer);
 
     for (cid, data) in blocks {
-        writer.write(cid, data)?;
+        if !is_inlined(&cid) {
+            writer.write(cid, data)?;
+        }
+    }
+    if let Some(root) = root_struct.filter(|x| !is_inlined(&x.cid)) {
+        writer.write(root.cid, root.data)?;
     }
-    writer.write(root.cid, root.data)?;
     writer.flush()?;
 
     Ok(buffer)
 }
 
+/// The same as `gen_car_by_data` but takes the data out of `UnixFsStruct` blocks.
 pub fn gen_car(
     blocks: &mut [UnixFsStruct],
     root_struct: Option<UnixFsStruct>,
+    config: &DagConfig,
 ) -> Result<Vec<u8>, iroh_car::Error> {
-    let root = root_struct.unwrap_or_else(empty_item);
-    let header = CarHeader::new(vec![root.cid]);
+    let (roots, root_struct) = match root_struct {
+        Some(x) => (vec![x.cid], Some(x)),
+        None => subtree_roots_or_empty(blocks.iter().map(|x| (&x.cid, x.data.as_slice())), config),
+    };
+    let header = CarHeader::new(roots);
 
-    let mut buffer = Vec::with_capacity(MAX_CAR_SIZE);
+    let size = blocks.iter().map(|x| x.data.len() + 64).sum::<usize>();
+    let mut buffer = Vec::with_capacity(size);
     let mut writer = CarWriter::new(header, &mut buffer);
 
     for block in blocks {
-        let data = mem::take(&mut block.data);
-        writer.write(block.cid, data)?;
+        if !is_inlined(&block.cid) {
+            let data = mem::take(&mut block.data);
+            writer.write(block.cid, data)?;
+        }
+    }
+    if let Some(root) = root_struct.filter(|x| !is_inlined(&x.cid)) {
+        writer.write(root.cid, root.data)?;
     }
-    writer.write(root.cid, root.data)?;
     writer.flush()?;
 
     Ok(buffer)
 }
 
-pub fn gen_blocks(buf: Vec<u8>, block_size: usize) -> Vec<UnixFsStruct> {
+/// Wraps a CAR from `gen_car` or `gen_car_by_data` into a CARv2 with a `MultihashIndexSorted` index.
+pub fn car_v1_to_v2(car: &[u8]) -> Result<Vec<u8>, iroh_car::Error> {
+    let header = usize::decode_var(car)
+        .and_then(|(len, n)| car.get(n..n + len))
+        .ok_or_else(|| {
+            iroh_car::Error::Parsing("failed to parse uvarint for header".to_string())
+        })?;
+    let roots = CarHeader::decode(header)?.roots().to_vec();
+
+    write_v2(roots, car, Vec::with_capacity(car.len() + car.len() / 100))
+}
+
+/// Reads a single block of a CARv2 through its index without scanning the data.
+/// Returns `None` if the CID is not in the index.
+pub fn read_block_from_car_v2<R: Read + Seek>(
+    reader: &mut R,
+    cid: &Cid,
+) -> Result<Option<Vec<u8>>, iroh_car::Error> {
+    read_block_v2(reader, cid)
+}
+
+pub fn gen_blocks(buf: Vec<u8>, block_size: usize, config: &DagConfig) -> Vec<UnixFsStruct> {
+    gen_blocks_from_slice(&buf, block_size, config)
+}
+
+/// Same as `gen_blocks`, but the chunks are hashed on at most `threads` scoped threads.
+pub fn gen_blocks_parallel(
+    buf: &[u8],
+    block_size: usize,
+    config: &DagConfig,
+    threads: usize,
+) -> Vec<UnixFsStruct> {
+    let chunks_count = buf.len().div_ceil(block_size);
+    if threads <= 1 || chunks_count <= 1 {
+        return gen_blocks_from_slice(buf, block_size, config);
+    }
+
+    // every part holds whole chunks, so the blocks are the same as the sequential ones
+    let part_size = chunks_count.div_ceil(threads) * block_size;
+    thread::scope(|s| {
+        let handles: Vec<_> = buf
+            .chunks(part_size)
+            .map(|part| s.spawn(move || gen_blocks_from_slice(part, block_size, config)))
+            .collect();
+
+        handles
+            .into_iter()
+            .flat_map(|x| x.join().expect("block hashing thread panicked"))
+            .collect()
+    })
+}
+
+fn gen_blocks_from_slice(buf: &[u8], block_size: usize, config: &DagConfig) -> Vec<UnixFsStruct> {
     buf.chunks(block_size)
         .map(|chunk| {
-            let digest = Sha2_256.digest(chunk);
-            let cid = Cid::new_v1(0x55, digest);
+            let (cid, data) = if config.raw_leaves {
+                (config.gen_cid(RAW, chunk), chunk.to_vec())
+            } else {
+                let data_bytes = UnixFs {
+                    Type: UnixFsType::File,
+                    Data: Some(Cow::from(chunk)),
+                    filesize: Some(chunk.len() as u64),
+                    blocksizes: vec![],
+                    hashType: None,
+                    fanout: None,
+                    mode: None,
+                    mtime: None,
+                }
+                .to_vec();
+
+                let node_bytes = PBNode {
+                    Links: vec![],
+                    Data: Some(Cow::from(data_bytes)),
+                }
+                .to_vec();
+
+                (config.gen_cid(DagPbCodec.into(), &node_bytes), node_bytes)
+            };
+
             UnixFsStruct {
                 name: None,
                 cid,
-                data: chunk.to_vec(),
+                data,
                 size: chunk.len() as u64,
             }
         })
         .collect()
 }
 
-pub fn gen_dir(name: Option<String>, items: &[UnixFsStruct]) -> UnixFsStruct {
-    let data_bytes = UnixFs {
-        Type: UnixFsType::Directory,
-        Data: None,
-        filesize: None,
-        blocksizes: vec![],
-        hashType: None,
-        fanout: None,
-        mode: None,
-        mtime: None,
-    }
-    .to_vec();
+pub fn gen_dir(
+    name: Option<String>,
+    items: &[UnixFsStruct],
+    meta: &FileMeta,
+    config: &DagConfig,
+) -> UnixFsStruct {
+    let data_bytes = unixfs_to_vec(
+        UnixFs {
+            Type: UnixFsType::Directory,
+            Data: None,
+            filesize: None,
+            blocksizes: vec![],
+            hashType: None,
+            fanout: None,
+            mode: None,
+            mtime: None,
+        },
+        meta,
+    );
 
     let mut dir_size = 0;
     let links = items
@@ -273,8 +797,7 @@ pub fn gen_dir(name: Option<String>, items: &[UnixFsStruct]) -> UnixFsStruct {
     }
     .to_vec();
 
-    let digest = Sha2_256.digest(&node_bytes);
-    let cid = Cid::new_v1(DagPbCodec.into(), digest);
+    let cid = config.gen_cid(DagPbCodec.into(), &node_bytes);
 
     UnixFsStruct {
         name,
@@ -284,7 +807,47 @@ pub fn gen_dir(name: Option<String>, items: &[UnixFsStruct]) -> UnixFsStruct {
     }
 }
 
-pub fn gen_pbnode_from_blocks(name: String, blocks: &[UnixFsStruct]) -> UnixFsStruct {
+pub fn gen_symlink(
+    name: String,
+    target: &str,
+    meta: &FileMeta,
+    config: &DagConfig,
+) -> UnixFsStruct {
+    let data_bytes = unixfs_to_vec(
+        UnixFs {
+            Type: UnixFsType::Symlink,
+            Data: Some(Cow::from(target.as_bytes())),
+            filesize: None,
+            blocksizes: vec![],
+            hashType: None,
+            fanout: None,
+            mode: None,
+            mtime: None,
+        },
+        meta,
+    );
+
+    let node_bytes = PBNode {
+        Links: vec![],
+        Data: Some(Cow::from(data_bytes)),
+    }
+    .to_vec();
+
+    let cid = config.gen_cid(DagPbCodec.into(), &node_bytes);
+    UnixFsStruct {
+        name: Some(name),
+        cid,
+        size: node_bytes.len() as u64,
+        data: node_bytes,
+    }
+}
+
+pub fn gen_pbnode_from_blocks(
+    name: String,
+    blocks: &[UnixFsStruct],
+    meta: &FileMeta,
+    config: &DagConfig,
+) -> UnixFsStruct {
     let mut filesize = 0u64;
     let (links, blocksizes) = blocks
         .iter()
@@ -294,17 +857,19 @@ pub fn gen_pbnode_from_blocks(name: String, blocks: &[UnixFsStruct]) -> UnixFsSt
         })
         .unzip();
 
-    let data_bytes = UnixFs {
-        Type: UnixFsType::File,
-        Data: None,
-        filesize: Some(filesize),
-        blocksizes,
-        hashType: None,
-        fanout: None,
-        mode: None,
-        mtime: None,
-    }
-    .to_vec();
+    let data_bytes = unixfs_to_vec(
+        UnixFs {
+            Type: UnixFsType::File,
+            Data: None,
+            filesize: Some(filesize),
+            blocksizes,
+            hashType: None,
+            fanout: None,
+            mode: None,
+            mtime: None,
+        },
+        meta,
+    );
 
     let node_bytes = PBNode {
         Links: links,
@@ -312,8 +877,7 @@ pub fn gen_pbnode_from_blocks(name: String, blocks: &[UnixFsStruct]) -> UnixFsSt
     }
     .to_vec();
 
-    let digest = Sha2_256.digest(&node_bytes);
-    let cid = Cid::new_v1(DagPbCodec.into(), digest);
+    let cid = config.gen_cid(DagPbCodec.into(), &node_bytes);
     UnixFsStruct {
         name: Some(name),
         cid,
@@ -321,3 +885,206 @@ pub fn gen_pbnode_from_blocks(name: String, blocks: &[UnixFsStruct]) -> UnixFsSt
         size: filesize,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roots_of(blocks: &[&UnixFsStruct]) -> Vec<Cid> {
+        find_subtree_roots(blocks.iter().map(|x| (&x.cid, x.data.as_slice())))
+    }
+
+    #[test]
+    fn subtree_roots() {
+        let config = DagConfig::default();
+        let meta = FileMeta::default();
+        let leaves = gen_blocks((0..30).collect(), 10, &config);
+        let file = gen_pbnode_from_blocks("a".to_owned(), &leaves, &meta, &config);
+        let dir = gen_dir(None, std::slice::from_ref(&file), &meta, &config);
+
+        // the whole DAG is one subtree
+        let all: Vec<_> = leaves.iter().chain([&file, &dir]).collect();
+        assert_eq!(roots_of(&all), vec![dir.cid]);
+
+        // the leaves without their parent are separate subtrees
+        let first: Vec<_> = leaves[..2].iter().collect();
+        assert_eq!(roots_of(&first), vec![leaves[0].cid, leaves[1].cid]);
+
+        // the file and the directory miss the leaves of an earlier shard
+        let rest = [&leaves[2], &file, &dir];
+        assert_eq!(roots_of(&rest), vec![leaves[2].cid]);
+    }
+
+    #[test]
+    fn inlined_blocks() {
+        let config = DagConfig {
+            inline_limit: Some(16),
+            ..Default::default()
+        };
+        let blocks = gen_blocks((0..20).collect(), 16, &config);
+        assert!(is_inlined(&blocks[0].cid));
+        assert_eq!(blocks[0].cid.hash().digest(), &blocks[0].data[..]);
+        // the last chunk of 4 bytes is inlined too
+        assert!(is_inlined(&blocks[1].cid));
+
+        let blocks = gen_blocks((0..20).collect(), 20, &config);
+        assert!(!is_inlined(&blocks[0].cid));
+
+        // the limit is capped by `MAX_INLINE_SIZE`
+        let config = DagConfig {
+            inline_limit: Some(1000),
+            ..Default::default()
+        };
+        let blocks = gen_blocks(vec![0; MAX_INLINE_SIZE + 1], 1000, &config);
+        assert!(!is_inlined(&blocks[0].cid));
+        assert!(gen_blocks(vec![0; 10], 1000, &DagConfig::default())
+            .iter()
+            .all(|x| !is_inlined(&x.cid)));
+    }
+
+    #[test]
+    fn leaf_types() {
+        let data: Vec<u8> = (0..10).collect();
+        let raw = gen_blocks(data.clone(), 10, &DagConfig::default());
+        assert_eq!(raw[0].cid.codec(), RAW);
+        assert_eq!(raw[0].data, data);
+
+        let config = DagConfig {
+            raw_leaves: false,
+            ..Default::default()
+        };
+        let leaf = &gen_blocks(data.clone(), 10, &config)[0];
+        assert_eq!(leaf.cid.codec(), u64::from(DagPbCodec));
+        let node = unixfs_v1::FlatUnixFs::try_from(leaf.data.as_slice()).unwrap();
+        assert_eq!(node.data.Type, UnixFsType::File);
+        assert_eq!(node.data.Data.as_deref(), Some(data.as_slice()));
+    }
+
+    #[test]
+    fn unwrap_single_block() {
+        let config = DagConfig {
+            unwrap_single_block: true,
+            ..Default::default()
+        };
+        let id_map = HashMap::from([
+            (0, gen_blocks(vec![1; 10], 10, &config)),
+            (1, gen_blocks(vec![1; 20], 10, &config)),
+        ]);
+        let file = |id, meta| DirectoryItem::File("a".to_owned(), String::new(), id, meta);
+        let to_struct = |item: DirectoryItem, config: &DagConfig| {
+            item.to_unixfs_struct(&id_map, &mut vec![], config)
+        };
+        let leaf = id_map[&0][0].cid;
+
+        assert_eq!(to_struct(file(0, FileMeta::default()), &config).cid, leaf);
+        let wrapped = to_struct(file(0, FileMeta::default()), &DagConfig::default());
+        assert_eq!(wrapped.cid.codec(), u64::from(DagPbCodec));
+        assert_ne!(wrapped.cid, leaf);
+
+        // the metadata and more blocks need the `File` node
+        let meta = FileMeta {
+            mode: Some(0o644),
+            mtime: None,
+        };
+        assert_ne!(to_struct(file(0, meta), &config).cid, leaf);
+        let two = to_struct(file(1, FileMeta::default()), &config);
+        assert_eq!(two.cid.codec(), u64::from(DagPbCodec));
+    }
+
+    #[tokio::test]
+    async fn hash_and_version_round_trip() {
+        let hashes = [
+            (DagHash::Sha2_256, Code::Sha2_256),
+            (DagHash::Blake2b256, Code::Blake2b256),
+            (DagHash::Blake3, Code::Blake3_256),
+        ];
+        for (hash, code) in hashes {
+            for cid_version in [Version::V0, Version::V1] {
+                let config = DagConfig {
+                    hash,
+                    cid_version,
+                    ..Default::default()
+                };
+                let meta = FileMeta::default();
+                let mut blocks = gen_blocks((0..30).collect(), 10, &config);
+                let file = gen_pbnode_from_blocks("a".to_owned(), &blocks, &meta, &config);
+                let dir = gen_dir(None, std::slice::from_ref(&file), &meta, &config);
+                blocks.push(file);
+
+                let cids: Vec<_> = blocks.iter().chain([&dir]).map(|x| x.cid).collect();
+                let data = blocks.iter_mut().map(|x| x.rip_data_with_cid()).collect();
+                let car = gen_car_by_data(data, Some(dir), &config).unwrap();
+
+                // every block is read back and checked against its CID
+                let store = crate::car::BlockStore::from_reader(car.as_slice())
+                    .await
+                    .unwrap();
+                assert_eq!(store.roots(), &cids[cids.len() - 1..]);
+                for cid in cids.iter() {
+                    store.get(cid).unwrap();
+                    assert_eq!(cid.hash().code(), u64::from(code));
+                }
+
+                // raw leaves never get CIDv0, and dag-pb nodes only with sha2-256
+                assert!(cids[..3]
+                    .iter()
+                    .all(|x| x.codec() == RAW && x.version() == Version::V1));
+                let is_v0 = cid_version == Version::V0 && hash == DagHash::Sha2_256;
+                for cid in cids[3..].iter() {
+                    assert_eq!(cid.codec(), u64::from(DagPbCodec));
+                    assert_eq!(cid.version() == Version::V0, is_v0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn negative_mtime() {
+        let meta = FileMeta {
+            mode: Some(0o644),
+            mtime: Some((-2, 500_000_000)),
+        };
+        let (_, block) = gen_dir(None, &[], &meta, &DagConfig::default()).rip_data_with_cid();
+        assert_eq!(FileMeta::from_block(&block), Some(meta));
+
+        let path = std::env::temp_dir().join(format!("w3s-mtime-{}", std::process::id()));
+        File::create(&path).unwrap();
+        meta.apply(&path).unwrap();
+        let restored = FileMeta::from_fs(&fs::metadata(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(restored.mtime, meta.mtime);
+    }
+
+    #[test]
+    fn directory_meta() {
+        let meta = FileMeta {
+            mode: Some(0o750),
+            mtime: Some((1_600_000_000, 0)),
+        };
+        let path = std::env::temp_dir().join(format!("w3s-dir-meta-{}", std::process::id()));
+        fs::create_dir_all(&path).unwrap();
+        meta.apply(&path).unwrap();
+        let restored = FileMeta::from_fs(&fs::metadata(&path).unwrap());
+        fs::remove_dir_all(&path).unwrap();
+        assert_eq!(restored.mtime, meta.mtime);
+        #[cfg(unix)]
+        assert_eq!(restored.mode, meta.mode);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn special_mode_bits() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let meta = FileMeta {
+            mode: Some(0o6755),
+            mtime: None,
+        };
+        let path = std::env::temp_dir().join(format!("w3s-mode-{}", std::process::id()));
+        File::create(&path).unwrap();
+        meta.apply(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o7777, 0o755);
+    }
+}
@@ -1,6 +1,10 @@
 use aead::generic_array::GenericArray;
+#[cfg(feature = "aes")]
+use aead::{AeadInPlace, NewAead};
+#[cfg(feature = "aes")]
+use aes_gcm::Aes256Gcm;
 use argon2::password_hash::Output;
-use argon2::{password_hash::SaltString, Argon2, PasswordHasher};
+use argon2::{password_hash::SaltString, Algorithm, Argon2, Params, PasswordHasher, Version};
 use chacha20::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
 use chacha20::XChaCha20;
 use poly1305::universal_hash::{NewUniversalHash, UniversalHash};
@@ -9,12 +13,57 @@ use rand::prelude::*;
 use thiserror::Error;
 use zeroize::Zeroize;
 
+use super::downloader::Staging;
+use super::envelope::{Argon2Params, Encryption};
 use super::*;
+use std::io::Write;
 use std::{io, mem};
 
-const SALT_SIZE: usize = 8;
-const NONCE_SIZE: usize = 24;
+/// Version of the encrypted stream format
+pub const VERSION: u8 = 2;
+/// Version of the header with the cipher suite after the version, which is written for AES-256-GCM
+pub const SUITE_VERSION: u8 = 3;
+/// Size of the plaintext in every chunk except the last one
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The largest Argon2 memory cost in KiB accepted from a header by default, which is 256 MiB
+pub const MAX_M_COST: u32 = 256 * 1024;
+/// The largest Argon2 number of iterations accepted from a header by default
+pub const MAX_T_COST: u32 = 10;
+/// The largest Argon2 degree of parallelism accepted from a header by default
+pub const MAX_P_COST: u32 = 16;
+/// PBKDF2-HMAC-SHA256 iterations to derive the key from the password for AES-256-GCM
+pub const PBKDF2_ITERATIONS: u32 = 600_000;
+/// The largest PBKDF2 iterations accepted from a header
+pub const MAX_PBKDF2_ITERATIONS: u32 = 10_000_000;
+
+const MAX_PARAMS: Argon2Params = Argon2Params {
+    m_cost: MAX_M_COST,
+    t_cost: MAX_T_COST,
+    p_cost: MAX_P_COST,
+};
+
+/// The suite of XChaCha20-Poly1305 with Argon2id
+const SUITE_XCHACHA20_POLY1305: u8 = 0;
+/// The suite of AES-256-GCM with PBKDF2-HMAC-SHA256
+const SUITE_AES_256_GCM: u8 = 1;
+
+const PARAMS_SIZE: usize = 12;
+const SALT_SIZE: usize = 16;
+const NONCE_PREFIX_SIZE: usize = 19;
+/// Size of `[version][m_cost][t_cost][p_cost][salt][nonce prefix]` at the start of the encrypted stream
+pub const HEADER_SIZE: usize = 1 + PARAMS_SIZE + SALT_SIZE + NONCE_PREFIX_SIZE;
+/// Size of the header of `SUITE_VERSION`, which is the largest one
+pub const MAX_HEADER_SIZE: usize = HEADER_SIZE + 1;
+/// Version 1 has the default Argon2 costs and an 8 bytes salt, as `[1][salt][nonce prefix]`
+const V1_SALT_SIZE: usize = 8;
+const V1_HEADER_SIZE: usize = 1 + V1_SALT_SIZE + NONCE_PREFIX_SIZE;
+/// Size of `[salt][nonce]` at the start of the legacy format, see `Legacy`
+const LEGACY_PREFIX_SIZE: usize = V1_SALT_SIZE + 24;
+const TAG_SIZE: usize = 16;
 const BLOCK_SIZE: usize = 64;
+#[cfg(feature = "aes")]
+const AES_GCM_INFO: &[u8] = b"w3s-aes-256-gcm";
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -22,119 +71,340 @@ pub enum Error {
     HashResultError,
     #[error("Argon2 password hash error: {0:?}")]
     PasswordHashError(argon2::password_hash::errors::Error),
-    #[error("No enough bytes for salt.")]
-    TooShortForSalt,
-    #[error("No enough bytes for nonce.")]
-    TooShortForNonce,
+    #[error("Invalid Argon2 parameters: {0}")]
+    InvalidParams(argon2::Error),
+    #[error("The Argon2 costs {0:?} exceed the limits of decryption.")]
+    ParamsTooLarge(Argon2Params),
+    #[error("The PBKDF2 iterations {0} are out of the range of 1 to `MAX_PBKDF2_ITERATIONS`.")]
+    InvalidIterations(u32),
+    #[error("Unknown cipher suite: {0}")]
+    UnknownSuite(u8),
+    #[error("The stream is sealed by {0:?}, which doesn't match the envelope header.")]
+    SuiteMismatch(Encryption),
+    #[error("No enough bytes for the header.")]
+    TooShortForHeader,
+    #[error("Unsupported encryption format version: {0}")]
+    UnsupportedVersion(u8),
+    #[error("The encrypted content is truncated.")]
+    Truncated,
+    #[error("The stream exceeds the 2^32 chunks which a nonce prefix can count.")]
+    TooManyChunks,
     #[error("MAC tag error. This could be caused by an incorrect password or content being changed.\n{0}")]
     MacTagInvalid(String),
 }
 
 impl From<Error> for io::Error {
     fn from(e: Error) -> Self {
-        io::Error::new(io::ErrorKind::Interrupted, e)
+        // not `Interrupted`, which `write_all` would retry after a chunk failed
+        io::Error::new(io::ErrorKind::InvalidData, e)
     }
 }
 
-/// The cipher will pass `[salt][nonce][encrypted data][mac]` to the next writer.
-/// All the `[salt][nonce][encrypted data]` parts will be updated into MAC.
+/// Returns the size of the header starting with `version`.
+pub fn header_size(version: u8) -> Result<usize, Error> {
+    match version {
+        1 => Ok(V1_HEADER_SIZE),
+        VERSION => Ok(HEADER_SIZE),
+        SUITE_VERSION => Ok(MAX_HEADER_SIZE),
+        x => Err(Error::UnsupportedVersion(x)),
+    }
+}
+
+/// Returns the offset in the encrypted stream of the chunk holding the plaintext `offset`,
+/// for the stream of the header `version`.
+pub fn encrypted_offset(version: u8, offset: u64) -> Result<u64, Error> {
+    let header_size = header_size(version)? as u64;
+    Ok(header_size + offset / CHUNK_SIZE as u64 * (CHUNK_SIZE + TAG_SIZE) as u64)
+}
+
+/// Returns the counter of the chunk holding the plaintext `offset`.
+fn chunk_counter(offset: u64) -> Result<u32, Error> {
+    u32::try_from(offset / CHUNK_SIZE as u64).map_err(|_| Error::TooManyChunks)
+}
+
+/// The cipher will pass `[version][m_cost][t_cost][p_cost][salt][nonce prefix]` followed by `[encrypted chunk][tag]`s
+/// to the next writer.
+///
+/// The key is derived by Argon2id with the costs in the header, so decryption needs nothing but the password.
+/// Every chunk holds `CHUNK_SIZE` bytes of plaintext except the last one, which is shorter or even empty.
+/// Chunks are sealed by XChaCha20-Poly1305 with the header as associated data.
+/// The nonce of a chunk is `[nonce prefix][counter: u32 BE][last flag: u8]`,
+/// so chunks can't be reordered, and a truncated stream is detected without the last flag.
 ///
-/// This stream cipher is compatible to the `chacha20poly1305` crate.
-/// But if you are using `chacha20poly1305` crate to decrypt the file,
-/// you have to use `cipher.decrypt_in_place(nonce, salt + nonce, &mut buffer).unwrap()` to
-/// get the correct result.
+/// With `with_aes_gcm` or `new_with_pbkdf2`, chunks are sealed by AES-256-GCM instead, which should be recorded
+/// in the envelope header too. The header is `[3][suite: 1][params][salt][nonce prefix]` then, so the suite is
+/// authenticated with every chunk, and the params of a password are `[iterations: u32 LE][0][0]` of PBKDF2-HMAC-SHA256.
+/// Every stream has its own key derived by HKDF-SHA256 from the key with the nonce prefix as the salt,
+/// and the nonce of a chunk is the last 12 bytes of the one above.
+///
+/// With `with_envelope`, the envelope header and stanzas in front of the stream are authenticated too,
+/// as `[envelope header][stanzas][header]` of the associated data, so none of their codes or costs can be changed.
+///
+/// After `flush`, the next stream starts with a new header and a random nonce prefix, so every file of a directory
+/// can be decrypted individually.
+///
+/// For decryption, every chunk is verified before its plaintext is passed to the next writer.
+/// Streams of version 1 are decrypted too, whose header is `[1][salt][nonce prefix]` with the default costs.
+/// The costs of a header are limited by `MAX_M_COST`, `MAX_T_COST` and `MAX_P_COST` unless `with_max_params`
+/// is given, and the iterations by `MAX_PBKDF2_ITERATIONS`. The suite of a header must be the one of the cipher.
+/// With `with_legacy`, the single-tag format before the versioned header is decrypted too,
+/// whose plaintext is held in a temporary file until its tag is verified.
 pub struct Cipher<W: io::Write> {
-    cipher: XChaCha20,
-    mac: Poly1305,
     kept_key: Output,
-    decryption: Option<Vec<u8>>, // Some(password)
-    is_prefix_written: bool,
-    remnant2mac: Vec<u8>,
-    content_len: usize,
+    decryption: Option<Vec<u8>>, // Some(password) before the header is read
+    is_decryption: bool,
+    accepts_legacy: bool,
+    legacy: Option<Box<Legacy>>,
+    envelope: Vec<u8>, // the envelope header and stanzas in front of every stream
+    header: Vec<u8>,
+    aad: Vec<u8>,
+    counter: u32,
+    buf: Vec<u8>,
+    skip: usize,
+    params: Argon2Params,
+    max_params: Argon2Params, // the limits of the costs in a header
+    iterations: u32,
     salt: [u8; SALT_SIZE],
-    nonce: [u8; NONCE_SIZE],
+    nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+    encryption: Encryption,
     next_writer: W,
 }
 
 impl<W: io::Write> Cipher<W> {
     pub fn new_decryption(pwd: Vec<u8>, next_writer: W) -> Result<Cipher<W>, Error> {
-        let salt = [0u8; SALT_SIZE];
-        let nonce = [0u8; NONCE_SIZE];
-        let (cipher, mac, kept_key) = Self::gen_cipher_and_mac(&mut [], &salt, &nonce)?;
+        Self::new_decryption_at(pwd, 0, next_writer)
+    }
+
+    /// Decrypts from the plaintext `offset`. The header should be written first,
+    /// then the encrypted stream from `encrypted_offset(version, offset)`.
+    pub fn new_decryption_at(
+        pwd: Vec<u8>,
+        offset: u64,
+        next_writer: W,
+    ) -> Result<Cipher<W>, Error> {
+        // the key is hashed with the salt in the header
+        let mut cipher = Self::new_decryption_with_key([0u8; 32], offset, next_writer)?;
+        cipher.decryption = Some(pwd);
+        Ok(cipher)
+    }
+
+    /// Decrypts the stream encrypted by `new_with_key` from the plaintext `offset`, like `new_decryption_at`.
+    pub fn new_decryption_with_key(
+        mut key: [u8; 32],
+        offset: u64,
+        next_writer: W,
+    ) -> Result<Cipher<W>, Error> {
+        let kept_key = Output::new(&key).map_err(Error::PasswordHashError)?;
+        key.zeroize();
 
         Ok(Cipher {
-            cipher,
-            mac,
             kept_key,
-            decryption: Some(pwd),
-            is_prefix_written: false,
-            remnant2mac: vec![],
-            content_len: 0,
-            salt,
-            nonce,
+            decryption: None,
+            is_decryption: true,
+            accepts_legacy: false,
+            legacy: None,
+            envelope: vec![],
+            header: vec![],
+            aad: vec![],
+            counter: chunk_counter(offset)?,
+            buf: vec![],
+            skip: (offset % CHUNK_SIZE as u64) as usize,
+            params: Argon2Params::default(),
+            max_params: MAX_PARAMS,
+            iterations: 0,
+            salt: [0u8; SALT_SIZE],
+            nonce_prefix: [0u8; NONCE_PREFIX_SIZE],
+            encryption: Encryption::XChaCha20Poly1305,
             next_writer,
         })
     }
 
+    /// Encrypts with the default Argon2 costs.
     pub fn new(pwd: &mut [u8], next_writer: W) -> Result<Cipher<W>, Error> {
-        let mut rng = rand::thread_rng();
+        Self::new_with_params(pwd, Argon2Params::default(), next_writer)
+    }
+
+    pub fn new_with_params(
+        pwd: &mut [u8],
+        params: Argon2Params,
+        next_writer: W,
+    ) -> Result<Cipher<W>, Error> {
+        let mut salt = [0u8; SALT_SIZE];
+        rand::thread_rng().fill_bytes(&mut salt);
+
+        Ok(Cipher {
+            kept_key: Self::hash_password(pwd, &salt, &params)?,
+            decryption: None,
+            is_decryption: false,
+            accepts_legacy: false,
+            legacy: None,
+            envelope: vec![],
+            header: vec![],
+            aad: vec![],
+            counter: 0,
+            buf: vec![],
+            skip: 0,
+            params,
+            max_params: MAX_PARAMS,
+            iterations: 0,
+            salt,
+            nonce_prefix: [0u8; NONCE_PREFIX_SIZE],
+            encryption: Encryption::XChaCha20Poly1305,
+            next_writer,
+        })
+    }
+
+    /// Encrypts by AES-256-GCM with the key derived from the password by PBKDF2-HMAC-SHA256,
+    /// which are both FIPS-approved unlike XChaCha20-Poly1305 and Argon2id.
+    #[cfg(feature = "aes")]
+    pub fn new_with_pbkdf2(
+        pwd: &mut [u8],
+        iterations: u32,
+        next_writer: W,
+    ) -> Result<Cipher<W>, Error> {
         let mut salt = [0u8; SALT_SIZE];
-        let mut nonce = [0u8; NONCE_SIZE];
-        rng.fill_bytes(&mut salt);
-        rng.fill_bytes(&mut nonce);
+        rand::thread_rng().fill_bytes(&mut salt);
+
+        Ok(Cipher {
+            kept_key: pbkdf2_password(pwd, &salt, iterations)?,
+            decryption: None,
+            is_decryption: false,
+            accepts_legacy: false,
+            legacy: None,
+            envelope: vec![],
+            header: vec![],
+            aad: vec![],
+            counter: 0,
+            buf: vec![],
+            skip: 0,
+            params: Argon2Params {
+                m_cost: 0,
+                t_cost: 0,
+                p_cost: 0,
+            },
+            max_params: MAX_PARAMS,
+            iterations,
+            salt,
+            nonce_prefix: [0u8; NONCE_PREFIX_SIZE],
+            encryption: Encryption::Aes256Gcm,
+            next_writer,
+        })
+    }
 
-        let (cipher, mac, kept_key) = Self::gen_cipher_and_mac(pwd, &salt, &nonce)?;
+    /// Encrypts with a random 32 bytes key instead of a password, such as a file key wrapped for recipients.
+    ///
+    /// The header has zero Argon2 costs and an empty salt, since nothing is derived.
+    pub fn new_with_key(mut key: [u8; 32], next_writer: W) -> Result<Cipher<W>, Error> {
+        let kept_key = Output::new(&key).map_err(Error::PasswordHashError)?;
+        key.zeroize();
 
         Ok(Cipher {
-            cipher,
-            mac,
             kept_key,
             decryption: None,
-            is_prefix_written: false,
-            remnant2mac: vec![],
-            content_len: 0,
-            salt,
-            nonce,
+            is_decryption: false,
+            accepts_legacy: false,
+            legacy: None,
+            envelope: vec![],
+            header: vec![],
+            aad: vec![],
+            counter: 0,
+            buf: vec![],
+            skip: 0,
+            params: Argon2Params {
+                m_cost: 0,
+                t_cost: 0,
+                p_cost: 0,
+            },
+            max_params: MAX_PARAMS,
+            iterations: 0,
+            salt: [0u8; SALT_SIZE],
+            nonce_prefix: [0u8; NONCE_PREFIX_SIZE],
+            encryption: Encryption::XChaCha20Poly1305,
             next_writer,
         })
     }
 
-    fn reset(&mut self) {
-        let (cipher, mac, _) = Self::gen_cipher_and_mac_with_hashed_pwd(self.kept_key, &self.nonce);
+    /// Creates an encryption cipher with the same key and salt for another writer,
+    /// so the password is not hashed again for every file processed on other threads.
+    pub(crate) fn fork<W2: io::Write>(&self, next_writer: W2) -> Cipher<W2> {
+        Cipher {
+            kept_key: self.kept_key,
+            decryption: None,
+            is_decryption: false,
+            accepts_legacy: false,
+            legacy: None,
+            envelope: self.envelope.clone(),
+            header: vec![],
+            aad: vec![],
+            counter: 0,
+            buf: vec![],
+            skip: 0,
+            params: self.params,
+            max_params: self.max_params,
+            iterations: self.iterations,
+            salt: self.salt,
+            nonce_prefix: [0u8; NONCE_PREFIX_SIZE],
+            encryption: self.encryption,
+            next_writer,
+        }
+    }
+
+    /// Also decrypts the legacy format of a password, for streams without the envelope.
+    ///
+    /// A stream whose header parses is versioned, and it is never taken for legacy after it fails to open.
+    /// A legacy stream starts with a random salt instead, so about one in 256 of them, whose salt starts with 1,
+    /// look versioned and can't be decrypted.
+    /// Only versioned streams can be decrypted from an offset.
+    pub fn with_legacy(mut self) -> Self {
+        self.accepts_legacy = self.decryption.is_some() && self.counter == 0 && self.skip == 0;
+        self
+    }
 
-        self.mac = mac;
-        self.cipher = cipher;
-        self.is_prefix_written = false;
-        self.remnant2mac = vec![];
-        self.content_len = 0;
+    /// Accepts the Argon2 costs up to `max` from a header instead of `MAX_M_COST`, `MAX_T_COST` and `MAX_P_COST`,
+    /// since decryption takes as much memory and time as the header asks for.
+    pub fn with_max_params(mut self, max: Argon2Params) -> Self {
+        self.max_params = max;
+        self
     }
 
-    fn gen_cipher_and_mac_with_hashed_pwd(
-        hashed_pwd: Output,
-        nonce: &[u8],
-    ) -> (XChaCha20, Poly1305, Output) {
-        let mut cipher = XChaCha20::new(hashed_pwd.as_bytes().into(), nonce.into());
+    /// Authenticates `head`, the envelope header and stanzas written or read before every stream,
+    /// with every chunk. The same bytes must be given for decryption.
+    pub fn with_envelope(mut self, head: Vec<u8>) -> Self {
+        self.envelope = head;
+        self
+    }
 
-        // init for mac
-        let mut mac_key = poly1305::Key::default();
-        cipher.apply_keystream(&mut *mac_key);
+    /// Seals or opens the chunks by AES-256-GCM instead of XChaCha20-Poly1305.
+    #[cfg(feature = "aes")]
+    pub fn with_aes_gcm(mut self) -> Self {
+        self.encryption = Encryption::Aes256Gcm;
+        self
+    }
 
-        let mac = Poly1305::new(GenericArray::from_slice(&*mac_key));
-        mac_key.zeroize();
+    /// The Argon2 costs of the key
+    pub fn params(&self) -> Argon2Params {
+        self.params
+    }
 
-        cipher.seek(BLOCK_SIZE);
+    /// The algorithm which seals the chunks
+    pub fn encryption(&self) -> Encryption {
+        self.encryption
+    }
 
-        (cipher, mac, hashed_pwd)
+    fn reset(&mut self) {
+        self.legacy = None;
+        self.header = vec![];
+        self.counter = 0;
+        self.buf = vec![];
+        self.skip = 0;
     }
 
-    fn gen_cipher_and_mac(
-        pwd: &mut [u8],
-        salt: &[u8],
-        nonce: &[u8],
-    ) -> Result<(XChaCha20, Poly1305, Output), Error> {
+    fn hash_password(pwd: &mut [u8], salt: &[u8], params: &Argon2Params) -> Result<Output, Error> {
         let salt_string = SaltString::b64_encode(salt).map_err(Error::PasswordHashError)?;
+        let params = Params::new(params.m_cost, params.t_cost, params.p_cost, None)
+            .map_err(Error::InvalidParams)?;
 
-        let hashed_pwd = Argon2::default()
+        let hashed_pwd = Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
             .hash_password(pwd, &salt_string)
             .map_err(Error::PasswordHashError)?
             .hash
@@ -142,133 +412,489 @@ impl<W: io::Write> Cipher<W> {
 
         pwd.zeroize();
 
-        Ok(Self::gen_cipher_and_mac_with_hashed_pwd(hashed_pwd, nonce))
+        Ok(hashed_pwd)
     }
 
-    fn update_mac(&mut self, buf: &[u8], keep_remnant: bool) {
-        let mut buf2mac = mem::take(&mut self.remnant2mac);
-        buf2mac.extend(buf);
+    fn gen_header(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(MAX_HEADER_SIZE);
+        match self.encryption {
+            Encryption::Aes256Gcm => {
+                header.push(SUITE_VERSION);
+                header.push(SUITE_AES_256_GCM);
+                header.extend(self.iterations.to_le_bytes());
+                header.extend([0u8; PARAMS_SIZE - 4]);
+            }
+            _ => {
+                header.push(VERSION);
+                header.extend(self.params.m_cost.to_le_bytes());
+                header.extend(self.params.t_cost.to_le_bytes());
+                header.extend(self.params.p_cost.to_le_bytes());
+            }
+        }
+        header.extend(self.salt);
+        header.extend(self.nonce_prefix);
+        header
+    }
+
+    /// Parses a versioned header into its Argon2 costs, PBKDF2 iterations and salt, without deriving the key.
+    ///
+    /// The costs or iterations are checked against the limits only if the key is derived from a password.
+    fn parse_header<'a>(&self, header: &'a [u8]) -> Result<(Argon2Params, u32, &'a [u8]), Error> {
+        let read_u32 =
+            |i: usize| u32::from_le_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]);
+        let (encryption, params_at) = match header[0] {
+            SUITE_VERSION => match header[1] {
+                SUITE_XCHACHA20_POLY1305 => (Encryption::XChaCha20Poly1305, 2),
+                SUITE_AES_256_GCM => (Encryption::Aes256Gcm, 2),
+                x => return Err(Error::UnknownSuite(x)),
+            },
+            _ => (Encryption::XChaCha20Poly1305, 1),
+        };
+        if encryption != self.encryption {
+            return Err(Error::SuiteMismatch(encryption));
+        }
 
-        if keep_remnant {
-            match buf2mac.len() / BLOCK_SIZE {
-                0 => self.remnant2mac = buf2mac,
-                x => {
-                    let index = x * BLOCK_SIZE;
-                    self.mac.update_padded(&buf2mac[..index]);
-                    self.content_len += index;
-                    self.remnant2mac = buf2mac[index..].to_vec();
+        if header[0] == 1 {
+            return Ok((Argon2Params::default(), 0, &header[1..1 + V1_SALT_SIZE]));
+        }
+        let (params, iterations) = if encryption == Encryption::Aes256Gcm {
+            (self.params, read_u32(params_at))
+        } else {
+            let params = Argon2Params {
+                m_cost: read_u32(params_at),
+                t_cost: read_u32(params_at + 4),
+                p_cost: read_u32(params_at + 8),
+            };
+            (params, self.iterations)
+        };
+        if self.decryption.is_some() {
+            if encryption == Encryption::Aes256Gcm {
+                if !(1..=MAX_PBKDF2_ITERATIONS).contains(&iterations) {
+                    return Err(Error::InvalidIterations(iterations));
                 }
+            } else if params.m_cost > self.max_params.m_cost
+                || params.t_cost > self.max_params.t_cost
+                || params.p_cost > self.max_params.p_cost
+            {
+                return Err(Error::ParamsTooLarge(params));
             }
-        } else {
-            self.mac.update_padded(&buf2mac);
-            self.content_len += buf2mac.len();
         }
+        let salt = &header[params_at + PARAMS_SIZE..params_at + PARAMS_SIZE + SALT_SIZE];
+        Ok((params, iterations, salt))
     }
 
-    fn encrypt(&mut self, bytes: &[u8]) -> Vec<u8> {
-        let mut buffer = bytes.to_vec();
+    /// Reads the header from the start of the buffer. Returns `false` if it isn't buffered yet.
+    fn read_header(&mut self) -> Result<bool, Error> {
+        let header_size = match self.buf.first() {
+            Some(&version) => header_size(version)?,
+            None => return Ok(false),
+        };
+        if self.buf.len() < header_size {
+            return Ok(false);
+        }
+
+        let header: Vec<_> = self.buf.drain(..header_size).collect();
+        let (params, iterations, salt) = self.parse_header(&header)?;
+        self.params = params;
+        self.iterations = iterations;
+        self.nonce_prefix
+            .copy_from_slice(&header[header_size - NONCE_PREFIX_SIZE..]);
 
-        self.cipher.apply_keystream(&mut buffer);
-        self.update_mac(&buffer, true);
+        if let Some(mut pwd) = self.decryption.take() {
+            self.kept_key = match self.encryption {
+                #[cfg(feature = "aes")]
+                Encryption::Aes256Gcm => pbkdf2_password(&mut pwd, salt, iterations)?,
+                _ => Self::hash_password(&mut pwd, salt, &params)?,
+            };
+        }
+        self.aad = [self.envelope.as_slice(), &header].concat();
+        self.header = header;
 
-        buffer
+        Ok(true)
     }
-    fn decrypt(&mut self, bytes: &[u8]) -> Vec<u8> {
-        let mut buffer = bytes.to_vec();
 
-        self.update_mac(&buffer, true);
-        self.cipher.apply_keystream(&mut buffer);
+    /// Tells the legacy format from a versioned header. Returns `false` if more bytes are needed.
+    ///
+    /// Only the header is parsed, so no key is derived before the format is known.
+    fn detect_legacy(&mut self, is_end: bool) -> io::Result<bool> {
+        let parsed = match self.buf.first().map(|&x| header_size(x)) {
+            None if !is_end => return Ok(false),
+            Some(Ok(size)) if self.buf.len() < size && !is_end => return Ok(false),
+            Some(Ok(size)) if self.buf.len() >= size => {
+                self.parse_header(&self.buf[..size]).map(|_| ())
+            }
+            Some(Err(e)) => Err(e),
+            _ => Err(Error::TooShortForHeader),
+        };
+        self.accepts_legacy = false;
+        let probe = match parsed {
+            Ok(()) => return Ok(true),
+            // a header over the limits is reported if the stream isn't legacy either
+            Err(e @ (Error::ParamsTooLarge(_) | Error::InvalidIterations(_))) => Some(e),
+            Err(_) => None,
+        };
 
-        buffer
+        let mut pwd = self
+            .decryption
+            .take()
+            .expect("legacy is only accepted for a password");
+        if self.buf.len() < LEGACY_PREFIX_SIZE {
+            pwd.zeroize();
+            Err(Error::TooShortForHeader)?;
+        }
+        let prefix: Vec<_> = self.buf.drain(..LEGACY_PREFIX_SIZE).collect();
+        self.legacy = Some(Box::new(Legacy::new(&mut pwd, &prefix, probe)?));
+        Ok(true)
     }
 
-    pub fn finalize_mac(&mut self) -> Vec<u8> {
-        let mut block = GenericArray::default();
-        // for associated_data
-        block[..8].copy_from_slice(&((SALT_SIZE + NONCE_SIZE) as u64).to_le_bytes());
-        // for content data
-        block[8..].copy_from_slice(&(self.content_len as u64).to_le_bytes());
+    fn write_header(&mut self) -> io::Result<()> {
+        // every stream has a fresh nonce, so files encrypted by the same key never share a keystream
+        rand::thread_rng().fill_bytes(&mut self.nonce_prefix);
+        self.header = self.gen_header();
+        self.aad = [self.envelope.as_slice(), &self.header].concat();
+        self.next_writer.write_all(&self.header)
+    }
 
-        self.mac.update(&block);
-        self.mac.to_owned().finalize().into_bytes().to_vec()
+    /// The nonce of a chunk is `[nonce prefix][counter: u32 BE][last flag: u8]`.
+    fn chunk_nonce(&self, is_last: bool) -> [u8; 24] {
+        let mut nonce = [0u8; 24];
+        nonce[..NONCE_PREFIX_SIZE].copy_from_slice(&self.nonce_prefix);
+        nonce[NONCE_PREFIX_SIZE..NONCE_PREFIX_SIZE + 4]
+            .copy_from_slice(&self.counter.to_be_bytes());
+        nonce[23] = is_last as u8;
+        nonce
     }
-}
 
-impl<W: io::Write> io::Write for Cipher<W> {
-    fn write(&mut self, mut buf: &[u8]) -> io::Result<usize> {
-        let buf_size = buf.len();
+    /// The counter of the chunk after the current one, which never wraps around to reuse a nonce.
+    fn next_counter(&self) -> io::Result<u32> {
+        self.counter
+            .checked_add(1)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, Error::TooManyChunks))
+    }
+
+    fn seal_chunk(&mut self, len: usize, is_last: bool) -> io::Result<()> {
+        let nonce = self.chunk_nonce(is_last);
+        self.counter = self.next_counter()?;
+        let mut chunk: Vec<u8> = self.buf.drain(..len).collect();
+        match self.encryption {
+            #[cfg(feature = "aes")]
+            Encryption::Aes256Gcm => {
+                seal_aes_gcm(self.kept_key.as_bytes(), &nonce, &self.aad, &mut chunk)
+            }
+            _ => seal(self.kept_key.as_bytes(), &nonce, &self.aad, &mut chunk),
+        }
+
+        // since the Upload writer shouldn't be the next one, there is no needs to handle the 0 written length condition.
+        self.next_writer.write_all(&chunk)
+    }
 
-        if !self.is_prefix_written {
-            // add salt and nonce to the start to enable streaming decryption when downloading the file
-            let mut prefix = Vec::with_capacity(SALT_SIZE + NONCE_SIZE);
-
-            if let Some(pwd) = self.decryption.as_mut() {
-                // this branch is for decryption
-                let salt = buf.get(0..SALT_SIZE).ok_or(Error::TooShortForSalt)?;
-                let nonce = buf
-                    .get(SALT_SIZE..SALT_SIZE + NONCE_SIZE)
-                    .ok_or(Error::TooShortForNonce)?;
-                let (cipher, mac, _) = Self::gen_cipher_and_mac(pwd, salt, nonce)?;
-                pwd.clear();
-
-                self.cipher = cipher;
-                self.mac = mac;
-
-                prefix.extend(salt);
-                prefix.extend(nonce);
-
-                buf = buf
-                    .get(SALT_SIZE + NONCE_SIZE..)
-                    .ok_or(Error::TooShortForNonce)?;
-            } else {
-                prefix.extend(self.salt);
-                prefix.extend(self.nonce);
-
-                self.next_mut().write_all(&prefix)?;
+    fn open_chunk(&mut self, len: usize, is_last: bool) -> io::Result<()> {
+        let nonce = self.chunk_nonce(is_last);
+        let next_counter = self.next_counter()?;
+        let mut chunk: Vec<u8> = self.buf.drain(..len).collect();
+        let is_valid = match self.encryption {
+            #[cfg(feature = "aes")]
+            Encryption::Aes256Gcm => {
+                open_aes_gcm(self.kept_key.as_bytes(), &nonce, &self.aad, &mut chunk)
             }
+            _ => open(self.kept_key.as_bytes(), &nonce, &self.aad, &mut chunk),
+        };
 
-            // use salt + nonce as associated_data to update the mac
-            self.mac.update_padded(&prefix);
+        if !is_valid {
+            Err(Error::MacTagInvalid(format!(
+                "chunk: {}{}",
+                self.counter,
+                if is_last { " (last)" } else { "" }
+            )))?;
+        }
+        self.counter = next_counter;
+
+        let skip = mem::take(&mut self.skip).min(chunk.len());
+        self.next_writer.write_all(&chunk[skip..])
+    }
 
-            self.is_prefix_written = true;
+    /// Decrypts the buffered chunks, and the last one at the end of the stream.
+    fn decrypt(&mut self, is_end: bool) -> io::Result<()> {
+        if self.accepts_legacy && !self.detect_legacy(is_end)? {
+            return Ok(());
+        }
+        if let Some(legacy) = self.legacy.as_mut() {
+            legacy.decrypt(&mut self.buf)?;
+            if is_end {
+                let legacy = self.legacy.take().expect("legacy is decrypting");
+                legacy.finish(&mem::take(&mut self.buf), &mut self.next_writer)?;
+            }
+            return Ok(());
         }
 
-        if let Some(raw) = self.decryption.take() {
-            if !raw.is_empty() {
-                let decrypted = self.decrypt(&raw);
-                self.next_mut().write_all(&decrypted)?;
+        if self.header.is_empty() && !self.read_header()? {
+            if is_end {
+                Err(Error::TooShortForHeader)?;
             }
-            self.decryption = Some(buf.to_vec())
-        } else {
-            let encrypted = self.encrypt(buf);
-            // since the Upload writer shouldn't be the next one, there is no needs to handle the 0 written length condition.
-            self.next_mut().write_all(&encrypted)?;
+            return Ok(());
         }
 
-        Ok(buf_size)
+        // the last chunk is always shorter than a full one, so full chunks are never the last
+        while self.buf.len() >= CHUNK_SIZE + TAG_SIZE {
+            self.open_chunk(CHUNK_SIZE + TAG_SIZE, false)?;
+        }
+        if is_end {
+            if self.buf.len() < TAG_SIZE {
+                Err(Error::Truncated)?;
+            }
+            self.open_chunk(self.buf.len(), true)?;
+        }
+        Ok(())
     }
+}
 
-    fn flush(&mut self) -> io::Result<()> {
-        if let Some(raw) = self.decryption.take() {
-            let (buf, tag) = raw.split_at(raw.len() - 16);
-            let decrypted = self.decrypt(buf);
-            self.next_mut().write_all(&decrypted)?;
-            self.decryption = Some(tag.to_vec());
+/// Decrypts `[salt][nonce][encrypted data][mac]`, the format before the versioned header.
+///
+/// The key is derived by Argon2id with the default costs, and the whole stream is sealed by XChaCha20-Poly1305
+/// with `[salt][nonce]` as associated data. Since the single tag is at the end,
+/// the plaintext is staged in a temporary file and passed to the next writer after it is verified.
+struct Legacy {
+    cipher: XChaCha20,
+    mac: Poly1305,
+    remnant: Vec<u8>, // the encrypted bytes shorter than a block of Poly1305
+    content_len: u64,
+    staged: Staging,
+    probe: Option<Error>, // why the header didn't parse, if it was over the limits
+}
+
+impl Legacy {
+    fn new(pwd: &mut [u8], prefix: &[u8], probe: Option<Error>) -> io::Result<Legacy> {
+        let (salt, nonce) = prefix.split_at(V1_SALT_SIZE);
+        let key = Cipher::<io::Sink>::hash_password(pwd, salt, &Argon2Params::default())?;
+        let (cipher, mut mac) = gen_cipher_and_mac::<XChaCha20>(key.as_bytes(), nonce);
+        mac.update_padded(prefix);
+
+        Ok(Legacy {
+            cipher,
+            mac,
+            remnant: vec![],
+            content_len: 0,
+            staged: Staging::new()?,
+            probe,
+        })
+    }
+
+    /// Decrypts `buf` except the last `TAG_SIZE` bytes, which may be the tag, to the staging file.
+    fn decrypt(&mut self, buf: &mut Vec<u8>) -> io::Result<()> {
+        let len = buf.len().saturating_sub(TAG_SIZE);
+        let mut decrypted: Vec<_> = buf.drain(..len).collect();
+
+        self.content_len += len as u64;
+        self.remnant.extend(&decrypted);
+        let blocks_size = self.remnant.len() / poly1305::BLOCK_SIZE * poly1305::BLOCK_SIZE;
+        self.mac.update_padded(&self.remnant[..blocks_size]);
+        self.remnant.drain(..blocks_size);
+
+        self.cipher.apply_keystream(&mut decrypted);
+        self.staged.write_all(&decrypted)
+    }
+
+    /// Verifies the tag after all the other bytes are decrypted, then passes the plaintext to `writer`.
+    fn finish(mut self, tag: &[u8], writer: &mut impl io::Write) -> io::Result<()> {
+        if let Err(e) = self.verify(tag) {
+            Err(self.probe.take().unwrap_or(e))?;
         }
+        self.staged.release_to(writer)
+    }
 
-        self.update_mac(&[], false);
+    fn verify(&mut self, tag: &[u8]) -> Result<(), Error> {
+        if tag.len() < TAG_SIZE {
+            return Err(Error::Truncated);
+        }
+        let mut mac = self.mac.clone();
+        mac.update_padded(&self.remnant);
 
-        let mac = self.finalize_mac();
+        let mut block = GenericArray::default();
+        block[..8].copy_from_slice(&(LEGACY_PREFIX_SIZE as u64).to_le_bytes());
+        block[8..].copy_from_slice(&self.content_len.to_le_bytes());
+        mac.update(&block);
 
-        if let Some(tag) = self.decryption.as_ref() {
-            if &mac != tag {
-                Err(Error::MacTagInvalid(format!(
-                    "calculated: {:02x?}\n from file: {:02x?}",
-                    mac, tag
-                )))?;
-            }
+        if mac.finalize() != poly1305::Tag::new(*GenericArray::from_slice(tag)) {
+            return Err(Error::MacTagInvalid("legacy format".to_owned()));
+        }
+        Ok(())
+    }
+}
+
+/// Generates the stream cipher and Poly1305 of ChaCha20-Poly1305 or XChaCha20-Poly1305.
+fn gen_cipher_and_mac<C>(key: &[u8], nonce: &[u8]) -> (C, Poly1305)
+where
+    C: KeyIvInit + StreamCipher + StreamCipherSeek,
+{
+    let mut cipher = C::new(
+        GenericArray::from_slice(key),
+        GenericArray::from_slice(nonce),
+    );
+
+    let mut mac_key = poly1305::Key::default();
+    cipher.apply_keystream(&mut mac_key);
+    let mac = Poly1305::new(GenericArray::from_slice(&mac_key));
+    mac_key.zeroize();
+
+    cipher.seek(BLOCK_SIZE);
+
+    (cipher, mac)
+}
+
+fn compute_tag(mut mac: Poly1305, aad: &[u8], encrypted: &[u8]) -> poly1305::Tag {
+    mac.update_padded(aad);
+    mac.update_padded(encrypted);
+
+    let mut block = GenericArray::default();
+    block[..8].copy_from_slice(&(aad.len() as u64).to_le_bytes());
+    block[8..].copy_from_slice(&(encrypted.len() as u64).to_le_bytes());
+    mac.update(&block);
+
+    mac.finalize()
+}
+
+fn seal_with<C>(key: &[u8], nonce: &[u8], aad: &[u8], buf: &mut Vec<u8>)
+where
+    C: KeyIvInit + StreamCipher + StreamCipherSeek,
+{
+    let (mut cipher, mac) = gen_cipher_and_mac::<C>(key, nonce);
+    cipher.apply_keystream(buf);
+    let tag = compute_tag(mac, aad, buf);
+    buf.extend(tag.into_bytes());
+}
+
+fn open_with<C>(key: &[u8], nonce: &[u8], aad: &[u8], buf: &mut Vec<u8>) -> bool
+where
+    C: KeyIvInit + StreamCipher + StreamCipherSeek,
+{
+    if buf.len() < TAG_SIZE {
+        return false;
+    }
+    let tag = buf.split_off(buf.len() - TAG_SIZE);
+    let (mut cipher, mac) = gen_cipher_and_mac::<C>(key, nonce);
+
+    // `Tag` compares in constant time
+    if compute_tag(mac, aad, buf) != poly1305::Tag::new(*GenericArray::from_slice(&tag)) {
+        return false;
+    }
+
+    cipher.apply_keystream(buf);
+    true
+}
+
+/// Encrypts `buf` in place by XChaCha20-Poly1305 with a 32 bytes `key`, and appends the tag.
+pub(crate) fn seal(key: &[u8], nonce: &[u8; 24], aad: &[u8], buf: &mut Vec<u8>) {
+    seal_with::<XChaCha20>(key, nonce, aad, buf)
+}
+
+/// Verifies the tag at the end of `buf` and decrypts the rest in place. Returns `false` without decryption
+/// if `buf` is shorter than a tag or the tag is invalid.
+pub(crate) fn open(key: &[u8], nonce: &[u8; 24], aad: &[u8], buf: &mut Vec<u8>) -> bool {
+    open_with::<XChaCha20>(key, nonce, aad, buf)
+}
+
+/// Derives the key from the password by PBKDF2-HMAC-SHA256.
+#[cfg(feature = "aes")]
+fn pbkdf2_password(pwd: &mut [u8], salt: &[u8], iterations: u32) -> Result<Output, Error> {
+    if !(1..=MAX_PBKDF2_ITERATIONS).contains(&iterations) {
+        pwd.zeroize();
+        return Err(Error::InvalidIterations(iterations));
+    }
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2::<hmac::Hmac<sha2::Sha256>>(pwd, salt, iterations, &mut key);
+    pwd.zeroize();
+
+    let ret = Output::new(&key).map_err(Error::PasswordHashError);
+    key.zeroize();
+    ret
+}
+
+/// Creates AES-256-GCM with the key of the stream, and returns it with the nonce of the chunk.
+#[cfg(feature = "aes")]
+fn aes_gcm_of_chunk(key: &[u8], nonce: &[u8; 24]) -> (Aes256Gcm, [u8; 12]) {
+    // a key for every stream, so the shorter nonces never repeat under the same key
+    let mut stream_key = [0u8; 32];
+    hkdf::Hkdf::<sha2::Sha256>::new(Some(&nonce[..NONCE_PREFIX_SIZE]), key)
+        .expand(AES_GCM_INFO, &mut stream_key)
+        .expect("32 bytes is a valid length for HKDF-SHA256");
+    let cipher = Aes256Gcm::new(GenericArray::from_slice(&stream_key));
+    stream_key.zeroize();
+
+    let mut chunk_nonce = [0u8; 12];
+    chunk_nonce.copy_from_slice(&nonce[12..]);
+    (cipher, chunk_nonce)
+}
+
+/// The same as `seal` by AES-256-GCM, see `Cipher`.
+#[cfg(feature = "aes")]
+fn seal_aes_gcm(key: &[u8], nonce: &[u8; 24], aad: &[u8], buf: &mut Vec<u8>) {
+    let (cipher, nonce) = aes_gcm_of_chunk(key, nonce);
+    let tag = cipher
+        .encrypt_in_place_detached(GenericArray::from_slice(&nonce), aad, buf)
+        .expect("chunks are shorter than the limit of AES-256-GCM");
+    buf.extend(tag);
+}
+
+/// The same as `open` by AES-256-GCM, see `Cipher`.
+#[cfg(feature = "aes")]
+fn open_aes_gcm(key: &[u8], nonce: &[u8; 24], aad: &[u8], buf: &mut Vec<u8>) -> bool {
+    if buf.len() < TAG_SIZE {
+        return false;
+    }
+    let tag = buf.split_off(buf.len() - TAG_SIZE);
+    let (cipher, nonce) = aes_gcm_of_chunk(key, nonce);
+    cipher
+        .decrypt_in_place_detached(
+            GenericArray::from_slice(&nonce),
+            aad,
+            buf,
+            GenericArray::from_slice(&tag),
+        )
+        .is_ok()
+}
+
+/// The same as `seal` by ChaCha20-Poly1305 of RFC 8439 with a 12 bytes nonce.
+#[cfg(feature = "age")]
+pub(crate) fn seal_ietf(key: &[u8], nonce: &[u8; 12], aad: &[u8], buf: &mut Vec<u8>) {
+    seal_with::<chacha20::ChaCha20>(key, nonce, aad, buf)
+}
+
+/// The same as `open` by ChaCha20-Poly1305 of RFC 8439 with a 12 bytes nonce.
+#[cfg(feature = "age")]
+pub(crate) fn open_ietf(key: &[u8], nonce: &[u8; 12], aad: &[u8], buf: &mut Vec<u8>) -> bool {
+    open_with::<chacha20::ChaCha20>(key, nonce, aad, buf)
+}
+
+impl<W: io::Write> io::Write for Cipher<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let buf_size = buf.len();
+        self.buf.extend(buf);
+
+        if self.is_decryption {
+            self.decrypt(false)?;
+            return Ok(buf_size);
+        }
+
+        if self.header.is_empty() {
+            // add the header to the start to enable streaming decryption when downloading the file
+            self.write_header()?;
+        }
+        // the last chunk is always shorter than a full one, so full chunks are never the last
+        while self.buf.len() >= CHUNK_SIZE {
+            self.seal_chunk(CHUNK_SIZE, false)?;
+        }
+
+        Ok(buf_size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.is_decryption {
+            self.decrypt(true)?;
         } else {
-            self.next_mut().write_all(&mac)?;
+            if self.header.is_empty() {
+                self.write_header()?;
+            }
+            self.seal_chunk(self.buf.len(), true)?;
         }
 
         self.next_mut().flush()?;
@@ -287,3 +913,397 @@ impl<W: io::Write> ChainWrite<W> for Cipher<W> {
         &mut self.next_writer
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn encrypt(params: Argon2Params, data: &[u8]) -> Vec<u8> {
+        let mut cipher =
+            Cipher::new_with_params(&mut b"password".to_vec(), params, vec![]).unwrap();
+        cipher.write_all(data).unwrap();
+        cipher.flush().unwrap();
+        cipher.next()
+    }
+
+    fn decrypt(pwd: &[u8], encrypted: &[u8]) -> io::Result<Vec<u8>> {
+        let mut cipher = Cipher::new_decryption(pwd.to_vec(), vec![]).unwrap();
+        cipher.write_all(encrypted)?;
+        cipher.flush()?;
+        Ok(cipher.next())
+    }
+
+    fn cipher_error(e: io::Error) -> Error {
+        *e.into_inner().unwrap().downcast::<Error>().unwrap()
+    }
+
+    /// Encrypts in the legacy format, the same as the cipher before the versioned header.
+    fn encrypt_legacy(salt: [u8; V1_SALT_SIZE], data: &[u8]) -> Vec<u8> {
+        let nonce = [5u8; 24];
+        let mut prefix = salt.to_vec();
+        prefix.extend(nonce);
+
+        let key = Cipher::<io::Sink>::hash_password(
+            &mut b"password".to_vec(),
+            &salt,
+            &Argon2Params::default(),
+        )
+        .unwrap();
+        let (mut cipher, mac) = gen_cipher_and_mac::<XChaCha20>(key.as_bytes(), &nonce);
+        let mut encrypted = data.to_vec();
+        cipher.apply_keystream(&mut encrypted);
+        let tag = compute_tag(mac, &prefix, &encrypted);
+
+        prefix.extend(encrypted);
+        prefix.extend(tag.into_bytes());
+        prefix
+    }
+
+    fn decrypt_legacy(encrypted: &[u8]) -> io::Result<Vec<u8>> {
+        let mut cipher = Cipher::new_decryption(b"password".to_vec(), vec![])
+            .unwrap()
+            .with_legacy();
+        // in small writes, like a download
+        for x in encrypted.chunks(1000) {
+            cipher.write_all(x)?;
+        }
+        cipher.flush()?;
+        Ok(cipher.next())
+    }
+
+    #[test]
+    fn round_trip() {
+        for len in [0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, CHUNK_SIZE * 2 + 100] {
+            let data: Vec<u8> = (0..len).map(|x| x as u8).collect();
+            let encrypted = encrypt(Argon2Params::default(), &data);
+            let chunks = len / CHUNK_SIZE + 1;
+            assert_eq!(encrypted.len(), HEADER_SIZE + len + chunks * TAG_SIZE);
+            assert_eq!(decrypt(b"password", &encrypted).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn header_per_file() {
+        let mut cipher = Cipher::new(&mut b"password".to_vec(), vec![]).unwrap();
+        let mut files = vec![];
+        for data in [&b"first"[..], b"second"] {
+            cipher.write_all(data).unwrap();
+            cipher.flush().unwrap();
+            files.push(mem::take(cipher.next_mut()));
+        }
+        // a forked cipher of another thread has its own nonce prefix too
+        let mut forked = cipher.fork(vec![]);
+        forked.write_all(b"third").unwrap();
+        forked.flush().unwrap();
+        files.push(forked.next());
+
+        let nonce_prefixes: Vec<_> = files
+            .iter()
+            .map(|x| &x[HEADER_SIZE - NONCE_PREFIX_SIZE..HEADER_SIZE])
+            .collect();
+        for i in 0..files.len() {
+            for j in i + 1..files.len() {
+                assert_ne!(files[i][..HEADER_SIZE], files[j][..HEADER_SIZE]);
+                assert_ne!(nonce_prefixes[i], nonce_prefixes[j]);
+            }
+        }
+        for (x, data) in files.iter().zip([&b"first"[..], b"second", b"third"]) {
+            assert_eq!(decrypt(b"password", x).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn wrong_password() {
+        let encrypted = encrypt(Argon2Params::default(), b"data");
+        let e = cipher_error(decrypt(b"wrong", &encrypted).unwrap_err());
+        assert!(matches!(e, Error::MacTagInvalid(_)));
+    }
+
+    #[test]
+    fn tampered() {
+        let data = vec![1u8; CHUNK_SIZE + 10];
+        let encrypted = encrypt(Argon2Params::default(), &data);
+        for i in [
+            0,
+            20,
+            HEADER_SIZE,
+            HEADER_SIZE + CHUNK_SIZE + 5,
+            encrypted.len() - 1,
+        ] {
+            let mut tampered = encrypted.clone();
+            tampered[i] ^= 1;
+            assert!(decrypt(b"password", &tampered).is_err(), "byte {}", i);
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let data = vec![1u8; CHUNK_SIZE * 2];
+        let encrypted = encrypt(Argon2Params::default(), &data);
+
+        // without the empty last chunk, the stream ends at a full chunk
+        let e = decrypt(b"password", &encrypted[..encrypted.len() - TAG_SIZE]).unwrap_err();
+        assert!(matches!(cipher_error(e), Error::Truncated));
+        // a full chunk taken for the last one
+        let e = decrypt(b"password", &encrypted[..encrypted.len() - TAG_SIZE - 10]).unwrap_err();
+        assert!(matches!(cipher_error(e), Error::MacTagInvalid(_)));
+        let e = decrypt(b"password", &encrypted[..HEADER_SIZE + 10]).unwrap_err();
+        assert!(matches!(cipher_error(e), Error::Truncated));
+        let e = decrypt(b"password", &encrypted[..HEADER_SIZE - 1]).unwrap_err();
+        assert!(matches!(cipher_error(e), Error::TooShortForHeader));
+    }
+
+    #[test]
+    fn from_offset() {
+        let data: Vec<u8> = (0..CHUNK_SIZE * 3).map(|x| (x / 7) as u8).collect();
+        let encrypted = encrypt(Argon2Params::default(), &data);
+        let offset = CHUNK_SIZE as u64 + 123;
+
+        let mut cipher = Cipher::new_decryption_at(b"password".to_vec(), offset, vec![]).unwrap();
+        cipher.write_all(&encrypted[..HEADER_SIZE]).unwrap();
+        let start = encrypted_offset(VERSION, offset).unwrap() as usize;
+        cipher.write_all(&encrypted[start..]).unwrap();
+        cipher.flush().unwrap();
+        assert_eq!(cipher.next(), &data[offset as usize..]);
+    }
+
+    #[test]
+    fn too_many_chunks() {
+        let key = [9u8; 32];
+        let mut cipher = Cipher::new_with_key(key, vec![]).unwrap();
+        cipher.counter = u32::MAX;
+        let e = cipher.write_all(&vec![0u8; CHUNK_SIZE]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(matches!(cipher_error(e), Error::TooManyChunks));
+
+        let mut cipher = Cipher::new_with_key(key, vec![]).unwrap();
+        cipher.write_all(b"data").unwrap();
+        cipher.flush().unwrap();
+        let encrypted = cipher.next();
+        let header_size = header_size(encrypted[0]).unwrap();
+
+        let offset = u32::MAX as u64 * CHUNK_SIZE as u64;
+        assert!(matches!(
+            Cipher::new_decryption_with_key(key, offset + CHUNK_SIZE as u64, vec![]),
+            Err(Error::TooManyChunks)
+        ));
+        let mut cipher = Cipher::new_decryption_with_key(key, offset, vec![]).unwrap();
+        cipher.write_all(&encrypted[..header_size]).unwrap();
+        cipher.write_all(&encrypted[header_size..]).unwrap();
+        let e = cipher.flush().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(matches!(cipher_error(e), Error::TooManyChunks));
+    }
+
+    #[test]
+    fn legacy() {
+        let data = vec![3u8; CHUNK_SIZE + 100];
+        // salts which start with a version but don't parse as a header
+        for salt in [
+            [0xa0; V1_SALT_SIZE],
+            [VERSION; V1_SALT_SIZE],
+            [SUITE_VERSION; V1_SALT_SIZE],
+        ] {
+            let encrypted = encrypt_legacy(salt, &data);
+            assert_eq!(decrypt_legacy(&encrypted).unwrap(), data);
+        }
+        let encrypted = encrypt_legacy([VERSION; V1_SALT_SIZE], b"");
+        assert_eq!(decrypt_legacy(&encrypted).unwrap(), b"");
+
+        // a salt starting with 1 parses as a header of version 1, which is never tried as legacy
+        let encrypted = encrypt_legacy([1; V1_SALT_SIZE], &data);
+        let e = cipher_error(decrypt_legacy(&encrypted).unwrap_err());
+        assert!(matches!(e, Error::MacTagInvalid(x) if x.starts_with("chunk")));
+
+        // nothing is passed on before the tag is verified
+        let mut tampered = encrypt_legacy([0xa0; V1_SALT_SIZE], &data);
+        tampered[100] ^= 1;
+        let mut cipher = Cipher::new_decryption(b"password".to_vec(), vec![])
+            .unwrap()
+            .with_legacy();
+        cipher.write_all(&tampered).unwrap();
+        let e = cipher_error(cipher.flush().unwrap_err());
+        assert!(matches!(e, Error::MacTagInvalid(_)));
+        assert!(cipher.next().is_empty());
+
+        // not without `with_legacy`, like a stream in the envelope
+        let encrypted = encrypt_legacy([0xa0; V1_SALT_SIZE], &data);
+        let e = cipher_error(decrypt(b"password", &encrypted).unwrap_err());
+        assert!(matches!(e, Error::UnsupportedVersion(0xa0)));
+    }
+
+    #[test]
+    fn versioned_with_legacy() {
+        for len in [0, 10, CHUNK_SIZE, CHUNK_SIZE + 10] {
+            let data = vec![4u8; len];
+            let encrypted = encrypt(Argon2Params::default(), &data);
+            assert_eq!(decrypt_legacy(&encrypted).unwrap(), data);
+        }
+
+        // a versioned stream which fails to open is never taken for legacy
+        let mut tampered = encrypt(Argon2Params::default(), b"data");
+        tampered[HEADER_SIZE] ^= 1;
+        let e = cipher_error(decrypt_legacy(&tampered).unwrap_err());
+        assert!(matches!(e, Error::MacTagInvalid(x) if x.starts_with("chunk")));
+
+        // a header over the limits is reported after it isn't legacy either
+        let mut encrypted = encrypt(Argon2Params::default(), b"data");
+        encrypted[5..9].copy_from_slice(&(MAX_T_COST + 1).to_le_bytes());
+        let e = cipher_error(decrypt_legacy(&encrypted).unwrap_err());
+        assert!(matches!(e, Error::ParamsTooLarge(x) if x.t_cost == MAX_T_COST + 1));
+    }
+
+    #[test]
+    fn costs_in_header() {
+        let params = Argon2Params {
+            m_cost: 1024,
+            t_cost: 2,
+            p_cost: 2,
+        };
+        let encrypted = encrypt(params, b"data");
+        assert_eq!(encrypted[0], VERSION);
+        assert_eq!(decrypt(b"password", &encrypted).unwrap(), b"data");
+    }
+
+    #[test]
+    fn costs_over_limits() {
+        let mut encrypted = encrypt(Argon2Params::default(), b"data");
+        encrypted[5..9].copy_from_slice(&(MAX_T_COST + 1).to_le_bytes());
+
+        let e = cipher_error(decrypt(b"password", &encrypted).unwrap_err());
+        assert!(matches!(e, Error::ParamsTooLarge(x) if x.t_cost == MAX_T_COST + 1));
+
+        // the limits are only raised on purpose
+        let params = Argon2Params {
+            t_cost: MAX_T_COST + 1,
+            ..Argon2Params::default()
+        };
+        let encrypted = encrypt(params, b"data");
+        let mut cipher = Cipher::new_decryption(b"password".to_vec(), vec![])
+            .unwrap()
+            .with_max_params(params);
+        cipher.write_all(&encrypted).unwrap();
+        cipher.flush().unwrap();
+        assert_eq!(cipher.next(), b"data");
+    }
+
+    #[test]
+    fn version_1() {
+        let salt = [7u8; V1_SALT_SIZE];
+        let mut header = vec![1];
+        header.extend(salt);
+        header.extend([9u8; NONCE_PREFIX_SIZE]);
+
+        let key = Cipher::<Vec<u8>>::hash_password(
+            &mut b"password".to_vec(),
+            &salt,
+            &Argon2Params::default(),
+        )
+        .unwrap();
+        let mut nonce = [9u8; 24];
+        nonce[NONCE_PREFIX_SIZE..23].copy_from_slice(&0u32.to_be_bytes());
+        nonce[23] = 1;
+        let mut chunk = b"data".to_vec();
+        seal(key.as_bytes(), &nonce, &header, &mut chunk);
+
+        let mut encrypted = header;
+        encrypted.extend(chunk);
+        assert_eq!(decrypt(b"password", &encrypted).unwrap(), b"data");
+        assert_eq!(
+            encrypted_offset(1, CHUNK_SIZE as u64).unwrap(),
+            (V1_HEADER_SIZE + CHUNK_SIZE + TAG_SIZE) as u64
+        );
+    }
+
+    #[cfg(feature = "aes")]
+    fn encrypt_aes_gcm(data: &[u8]) -> Vec<u8> {
+        let mut cipher = Cipher::new_with_pbkdf2(&mut b"password".to_vec(), 1000, vec![]).unwrap();
+        cipher.write_all(data).unwrap();
+        cipher.flush().unwrap();
+        cipher.next()
+    }
+
+    #[cfg(feature = "aes")]
+    fn decrypt_aes_gcm(pwd: &[u8], encrypted: &[u8]) -> io::Result<Vec<u8>> {
+        let mut cipher = Cipher::new_decryption(pwd.to_vec(), vec![])
+            .unwrap()
+            .with_aes_gcm();
+        cipher.write_all(encrypted)?;
+        cipher.flush()?;
+        Ok(cipher.next())
+    }
+
+    #[cfg(feature = "aes")]
+    #[test]
+    fn aes_gcm_round_trip() {
+        for len in [0, 1, CHUNK_SIZE, CHUNK_SIZE * 2 + 100] {
+            let data: Vec<u8> = (0..len).map(|x| x as u8).collect();
+            let encrypted = encrypt_aes_gcm(&data);
+            assert_eq!(encrypted[..2], [SUITE_VERSION, SUITE_AES_256_GCM]);
+            assert_eq!(encrypted[2..6], 1000u32.to_le_bytes());
+            let chunks = len / CHUNK_SIZE + 1;
+            assert_eq!(encrypted.len(), MAX_HEADER_SIZE + len + chunks * TAG_SIZE);
+            assert_eq!(decrypt_aes_gcm(b"password", &encrypted).unwrap(), data);
+        }
+
+        let e = cipher_error(decrypt_aes_gcm(b"wrong", &encrypt_aes_gcm(b"data")).unwrap_err());
+        assert!(matches!(e, Error::MacTagInvalid(_)));
+
+        // a raw key needs no derivation
+        let mut cipher = Cipher::new_with_key([3u8; 32], vec![])
+            .unwrap()
+            .with_aes_gcm();
+        cipher.write_all(b"data").unwrap();
+        cipher.flush().unwrap();
+        let mut decryption = Cipher::new_decryption_with_key([3u8; 32], 0, vec![])
+            .unwrap()
+            .with_aes_gcm();
+        decryption.write_all(&cipher.next()).unwrap();
+        decryption.flush().unwrap();
+        assert_eq!(decryption.next(), b"data");
+    }
+
+    #[cfg(feature = "aes")]
+    #[test]
+    fn aes_gcm_suite_bound() {
+        let encrypted = encrypt_aes_gcm(b"data");
+
+        // the suite of the header must be the one of the envelope
+        let e = cipher_error(decrypt(b"password", &encrypted).unwrap_err());
+        assert!(matches!(e, Error::SuiteMismatch(Encryption::Aes256Gcm)));
+        let e = cipher_error(
+            decrypt_aes_gcm(b"password", &encrypt(Argon2Params::default(), b"data")).unwrap_err(),
+        );
+        assert!(matches!(
+            e,
+            Error::SuiteMismatch(Encryption::XChaCha20Poly1305)
+        ));
+
+        // and the header is authenticated with the chunks
+        let mut cipher = Cipher::new_with_key([3u8; 32], vec![])
+            .unwrap()
+            .with_aes_gcm();
+        cipher.write_all(b"data").unwrap();
+        cipher.flush().unwrap();
+        let mut changed = cipher.next();
+        changed[10] = 1;
+        let mut decryption = Cipher::new_decryption_with_key([3u8; 32], 0, vec![])
+            .unwrap()
+            .with_aes_gcm();
+        decryption.write_all(&changed).unwrap();
+        let e = cipher_error(decryption.flush().unwrap_err());
+        assert!(matches!(e, Error::MacTagInvalid(_)));
+
+        let mut changed = encrypted.clone();
+        changed[1] = 9;
+        let e = cipher_error(decrypt_aes_gcm(b"password", &changed).unwrap_err());
+        assert!(matches!(e, Error::UnknownSuite(9)));
+
+        let mut changed = encrypted;
+        changed[2..6].copy_from_slice(&(MAX_PBKDF2_ITERATIONS + 1).to_le_bytes());
+        let e = cipher_error(decrypt_aes_gcm(b"password", &changed).unwrap_err());
+        assert!(matches!(e, Error::InvalidIterations(_)));
+    }
+}
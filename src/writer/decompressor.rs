@@ -1,5 +1,5 @@
 use super::*;
-use std::io::{self};
+use std::io::{self, Write};
 use zstd::stream::write::Decoder;
 
 pub struct Decompressor<'a, W: io::Write> {
@@ -14,17 +14,29 @@ impl<'a, W: io::Write> Decompressor<'a, W> {
             next_writer: Decoder::new(next_writer)?,
         })
     }
+
+    /// The decoder may take only part of the input in one write.
+    fn write_cache(&mut self) -> io::Result<()> {
+        let mut pos = 0;
+        while pos < self.cache.len() {
+            match self.next_writer.write(&self.cache[pos..])? {
+                0 => break,
+                len => pos += len,
+            }
+        }
+        self.cache.drain(0..pos);
+        Ok(())
+    }
 }
 impl<'a, W: io::Write> io::Write for Decompressor<'a, W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         self.cache.extend(buf);
-
-        let len = self.next_writer.write(&self.cache)?;
-        self.cache.drain(0..len);
+        self.write_cache()?;
 
         Ok(buf.len())
     }
     fn flush(&mut self) -> io::Result<()> {
+        self.write_cache()?;
         self.next_writer.flush()
     }
 }
@@ -23,15 +23,16 @@ impl<W: io::Write> Dir<W> {
     pub fn walk_write(&mut self, dir_items: &[DirectoryItem]) -> io::Result<()> {
         for item in dir_items {
             match item {
-                DirectoryItem::File(_, path, id) => {
+                DirectoryItem::File(_, path, id, _) => {
                     *self.curr_file_id.borrow_mut() = *id;
                     let mut file = File::open(path)?;
                     io::copy(&mut file, &mut self.next_writer)?;
                     self.next_writer.flush()?;
                 }
-                DirectoryItem::Directory(_, sub_dir_items) => {
+                DirectoryItem::Directory(_, sub_dir_items, _) => {
                     self.walk_write(sub_dir_items)?;
                 }
+                DirectoryItem::Symlink(..) => {}
             }
         }
 
@@ -46,7 +47,7 @@ impl<W: io::Write> Dir<W> {
     ) -> io::Result<()> {
         for item in dir_items {
             match item {
-                DirectoryItem::File(_, path, id) => {
+                DirectoryItem::File(_, path, id, _) => {
                     *self.curr_file_id.borrow_mut() = *id;
                     let mut file = File::open(path)?;
 
@@ -57,9 +58,10 @@ impl<W: io::Write> Dir<W> {
                     compressor.finish()?;
                     self.next_writer.flush()?;
                 }
-                DirectoryItem::Directory(_, sub_dir_items) => {
+                DirectoryItem::Directory(_, sub_dir_items, _) => {
                     self.walk_write_with_compression(sub_dir_items, level)?;
                 }
+                DirectoryItem::Symlink(..) => {}
             }
         }
 
@@ -1,9 +1,13 @@
 //! Handles cid file downloading
+use super::car_stream::TempFile;
 use super::{uploader::ProgressListener, ChainWrite};
-use reqwest::Client;
+use reqwest::{Client, StatusCode};
 use thiserror::Error;
 
-use std::{io, sync::Arc};
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom};
+use std::path::Path;
+use std::{cmp, io, sync::Arc};
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -29,6 +33,62 @@ impl<W: io::Write> Downloader<W> {
     }
 }
 
+/// Keeps the decoded bytes in a temporary file until they are released,
+/// so nothing reaches the destination before the whole file is decrypted and verified.
+///
+/// `flush` doesn't release the bytes, since the writers before it flush at the end of the stream.
+pub struct Staging {
+    file: BufWriter<File>,
+    temp: TempFile,
+}
+
+impl Staging {
+    pub fn new() -> io::Result<Self> {
+        Self::with_temp(TempFile::create("part")?)
+    }
+
+    /// Stages the bytes in `dir`, where the file is going to be persisted.
+    pub fn new_in(dir: &Path) -> io::Result<Self> {
+        Self::with_temp(TempFile::create_in(dir, "part")?)
+    }
+
+    fn with_temp((temp, file): (TempFile, File)) -> io::Result<Self> {
+        Ok(Staging {
+            file: BufWriter::new(file),
+            temp,
+        })
+    }
+
+    /// Copies the staged bytes to `writer`, which should be called after the stream is verified.
+    pub fn release_to(self, writer: &mut impl io::Write) -> io::Result<()> {
+        let mut file = self.file.into_inner().map_err(|e| e.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+        io::copy(&mut file, writer)?;
+        writer.flush()
+    }
+
+    /// Moves the staged file to `path` without copying, which should be called after the stream is verified.
+    /// The staging should be created by `new_in` with the folder of `path`.
+    pub fn persist(self, path: &Path) -> io::Result<()> {
+        let file = self.file.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        // the file can't be renamed on Windows while it is open
+        drop(file);
+        self.temp.persist(path)
+    }
+}
+
+impl io::Write for Staging {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Fetches the last 16 bytes of the file, which are the tag of the legacy encrypted format.
+#[deprecated(note = "the legacy tag is verified by `cipher::Cipher` while decrypting")]
 pub async fn fetch_mac(url: &str) -> Result<Vec<u8>, Error> {
     let size = Client::new()
         .head(url)
@@ -41,7 +101,10 @@ pub async fn fetch_mac(url: &str) -> Result<Vec<u8>, Error> {
 
     let resp = Client::new()
         .get(url)
-        .header("Range", format!("bytes={}-{}", size - 16, size))
+        .header(
+            "Range",
+            format!("bytes={}-{}", size.saturating_sub(16), size),
+        )
         .send()
         .await?
         .bytes()
@@ -50,6 +113,36 @@ pub async fn fetch_mac(url: &str) -> Result<Vec<u8>, Error> {
     Ok(resp.to_vec())
 }
 
+/// Fetches `len` bytes from `start` with a range request, or fewer at the end of the file.
+pub async fn fetch_range(url: &str, start: u64, len: u64) -> Result<Vec<u8>, Error> {
+    let mut resp = Client::new()
+        .get(url)
+        .header("Range", format!("bytes={}-{}", start, start + len - 1))
+        .send()
+        .await?
+        .error_for_status()?;
+
+    // the whole body is returned if the server ignores the range, so it is read only up to the range
+    let mut skip = if resp.status() == StatusCode::PARTIAL_CONTENT {
+        0
+    } else {
+        start
+    };
+    let mut ret = Vec::with_capacity(len as usize);
+    while (ret.len() as u64) < len {
+        let chunk = match resp.chunk().await? {
+            Some(x) => x,
+            None => break,
+        };
+        let from = cmp::min(skip, chunk.len() as u64) as usize;
+        skip -= from as u64;
+        let to = cmp::min(chunk.len(), from + (len as usize - ret.len()));
+        ret.extend_from_slice(&chunk[from..to]);
+    }
+
+    Ok(ret)
+}
+
 impl<W: io::Write> Downloader<W> {
     pub async fn download(
         &mut self,
@@ -70,8 +163,7 @@ impl<W: io::Write> Downloader<W> {
             if let Ok(content_range_str) = content_range.to_str() {
                 content_range_str
                     .split('/')
-                    .into_iter()
-                    .last()
+                    .next_back()
                     .and_then(|x| x.parse::<u64>().ok())
             } else {
                 resp.content_length()
@@ -119,3 +211,29 @@ impl<W: io::Write> ChainWrite<W> for Downloader<W> {
         &mut self.next_writer
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+
+    #[test]
+    fn persist_staging() {
+        let dir = std::env::temp_dir().join(format!("w3s-staging-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file");
+
+        let mut staging = Staging::new_in(&dir).unwrap();
+        staging.write_all(b"verified").unwrap();
+        staging.flush().unwrap();
+        // nothing is at the destination before the staging is persisted
+        assert!(!path.exists());
+        staging.persist(&path).unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"verified");
+        // only the persisted file is left
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,911 @@
+//! Self-describing header in front of compressed or encrypted files
+//!
+//! `[magic: 4][version: u8][compression: u8][encryption: u8][kdf: u8][m_cost: u32 LE][t_cost: u32 LE][p_cost: u32 LE]`
+//!
+//! The chunks of `cipher::Cipher` are sealed by XChaCha20-Poly1305 if the encryption is `1`, or AES-256-GCM if `2`.
+//! For the files encrypted for X25519 recipients, the three costs are `[recipients: u32 LE][0][0]`,
+//! and the stanzas of `recipient` follow the header.
+//! For the files encrypted with a raw key, they are `[key ID: u64 LE][0]`.
+//! For the passwords of AES-256-GCM derived by PBKDF2-HMAC-SHA256, they are `[iterations: u32 LE][0][0]`.
+//!
+//! AES-256-GCM only goes with PBKDF2 or raw keys, so a file of it uses nothing but FIPS-approved algorithms.
+//! Argon2id and X25519 recipients only go with XChaCha20-Poly1305.
+//! The header and stanzas of an encrypted file are authenticated with every chunk of the cipher,
+//! see `cipher::Cipher::with_envelope`.
+//!
+//! Files in the age format have no header, and are detected by their first line instead.
+use super::*;
+use std::{cmp, io, mem};
+use thiserror::Error;
+
+/// The leading `0x89` keeps the header from being taken as text
+pub const MAGIC: [u8; 4] = [0x89, b'W', b'3', b'S'];
+pub const VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 20;
+/// Size of `[ephemeral public key][wrapped file key][tag]` of every recipient
+pub const STANZA_SIZE: usize = 32 + 32 + 16;
+/// The largest number of recipients, which keeps the stanzas buffered before decryption small
+pub const MAX_RECIPIENTS: u32 = 1024;
+/// The first line of the age format
+pub const AGE_INTRO: &[u8] = b"age-encryption.org/v1\n";
+/// The first line of the armored age format
+pub const AGE_ARMOR_BEGIN: &[u8] = b"-----BEGIN AGE ENCRYPTED FILE-----";
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("No enough bytes for the envelope header.")]
+    TooShortForHeader,
+    #[error("Unsupported envelope version: {0}")]
+    UnsupportedVersion(u8),
+    #[error("Unknown {0} code in the envelope header: {1}")]
+    UnknownCode(&'static str, u8),
+    #[error("Too many recipients in the envelope header: {0}")]
+    TooManyRecipients(u32),
+    #[error("The encryption {0:?} can't be used with the key derivation {1:?}.")]
+    UnsupportedSuite(Encryption, Kdf),
+    #[error("The file is encrypted, but no password is given.")]
+    NoPassword,
+    #[error("The file is encrypted for recipients, but no identity is given.")]
+    NoIdentity,
+    #[error("The file is encrypted with a raw key, but no key is given.")]
+    NoKey,
+    #[error("The file is in the age format, but no identity or passphrase is given.")]
+    NoAgeSecret,
+    #[error("The file isn't encrypted, but a secret is given to decrypt it.")]
+    NotEncrypted,
+    #[error("Files in the age format can't be decrypted with raw keys.")]
+    RawKeyOfAge,
+    #[error("Resuming from an offset is not supported for compressed files.")]
+    OffsetOfCompressed,
+    #[error("Resuming from an offset is not supported for files in the age format.")]
+    OffsetOfAge,
+    #[error("The feature:\"encryption\" is required.")]
+    FeatureNoCipher,
+    #[error("The feature:\"zstd\" is required.")]
+    FeatureNoZstd,
+    #[error("The feature:\"age\" is required.")]
+    FeatureNoAge,
+    #[error("The feature:\"aes\" is required.")]
+    FeatureNoAes,
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Zstd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encryption {
+    None,
+    /// the chunked format of `cipher::Cipher`
+    XChaCha20Poly1305,
+    /// the same chunked format sealed by AES-256-GCM, see `cipher::Cipher::with_aes_gcm`
+    Aes256Gcm,
+}
+
+/// Costs of Argon2id which derives the key from the password
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Argon2Params {
+    /// memory size in KiB
+    pub m_cost: u32,
+    /// number of iterations
+    pub t_cost: u32,
+    /// degree of parallelism
+    pub p_cost: u32,
+}
+
+impl Default for Argon2Params {
+    /// The same as `argon2::Params::default()`
+    fn default() -> Self {
+        Argon2Params {
+            m_cost: 4096,
+            t_cost: 3,
+            p_cost: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kdf {
+    None,
+    Argon2id(Argon2Params),
+    /// a random file key wrapped for the recipients
+    X25519 {
+        recipients: u32,
+    },
+    /// a raw key used without derivation, see `raw_key`
+    RawKey {
+        key_id: u64,
+    },
+    /// PBKDF2-HMAC-SHA256 which derives the key of AES-256-GCM from the password
+    Pbkdf2 {
+        iterations: u32,
+    },
+}
+
+/// What decrypts the files
+#[derive(Debug, Clone)]
+pub enum Secret {
+    Password(Vec<u8>),
+    #[cfg(feature = "encryption")]
+    Identities(Vec<recipient::Identity>),
+    /// raw keys, one of which has the key ID in the header
+    Keys(Vec<raw_key::RawKey>),
+}
+
+/// Returns `Some(is_armored)` if `buf` starts with the first line of the age format.
+pub fn age_format(buf: &[u8]) -> Option<bool> {
+    if buf.starts_with(AGE_INTRO) {
+        Some(false)
+    } else if buf.starts_with(AGE_ARMOR_BEGIN) {
+        Some(true)
+    } else {
+        None
+    }
+}
+
+/// Describes how the file was processed. Compression is applied before encryption.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub compression: Compression,
+    pub encryption: Encryption,
+    pub kdf: Kdf,
+}
+
+impl Header {
+    /// The file is encrypted unless `kdf` is `Kdf::None`.
+    pub fn new(is_compressed: bool, kdf: Kdf) -> Self {
+        Header {
+            compression: if is_compressed {
+                Compression::Zstd
+            } else {
+                Compression::None
+            },
+            encryption: if kdf == Kdf::None {
+                Encryption::None
+            } else {
+                Encryption::XChaCha20Poly1305
+            },
+            kdf,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut ret = [0u8; HEADER_SIZE];
+        ret[..4].copy_from_slice(&MAGIC);
+        ret[4] = VERSION;
+        ret[5] = match self.compression {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+        };
+        ret[6] = match self.encryption {
+            Encryption::None => 0,
+            Encryption::XChaCha20Poly1305 => 1,
+            Encryption::Aes256Gcm => 2,
+        };
+        match self.kdf {
+            Kdf::None => {}
+            Kdf::Argon2id(params) => {
+                ret[7] = 1;
+                ret[8..12].copy_from_slice(&params.m_cost.to_le_bytes());
+                ret[12..16].copy_from_slice(&params.t_cost.to_le_bytes());
+                ret[16..20].copy_from_slice(&params.p_cost.to_le_bytes());
+            }
+            Kdf::X25519 { recipients } => {
+                ret[7] = 2;
+                ret[8..12].copy_from_slice(&recipients.to_le_bytes());
+            }
+            Kdf::RawKey { key_id } => {
+                ret[7] = 3;
+                ret[8..16].copy_from_slice(&key_id.to_le_bytes());
+            }
+            Kdf::Pbkdf2 { iterations } => {
+                ret[7] = 4;
+                ret[8..12].copy_from_slice(&iterations.to_le_bytes());
+            }
+        }
+        ret
+    }
+
+    /// Returns `None` if `buf` doesn't start with `MAGIC`.
+    pub fn parse(buf: &[u8]) -> Result<Option<Self>, Error> {
+        if !buf.starts_with(&MAGIC) {
+            return Ok(None);
+        }
+        if buf.len() < HEADER_SIZE {
+            return Err(Error::TooShortForHeader);
+        }
+        if buf[4] != VERSION {
+            return Err(Error::UnsupportedVersion(buf[4]));
+        }
+
+        let read_u32 = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+        let header = Header {
+            compression: match buf[5] {
+                0 => Compression::None,
+                1 => Compression::Zstd,
+                x => return Err(Error::UnknownCode("compression", x)),
+            },
+            encryption: match buf[6] {
+                0 => Encryption::None,
+                1 => Encryption::XChaCha20Poly1305,
+                2 => Encryption::Aes256Gcm,
+                x => return Err(Error::UnknownCode("encryption", x)),
+            },
+            kdf: match buf[7] {
+                0 => Kdf::None,
+                1 => Kdf::Argon2id(Argon2Params {
+                    m_cost: read_u32(8),
+                    t_cost: read_u32(12),
+                    p_cost: read_u32(16),
+                }),
+                2 => Kdf::X25519 {
+                    recipients: read_u32(8),
+                },
+                3 => Kdf::RawKey {
+                    key_id: u64::from_le_bytes(buf[8..16].try_into().expect("8 bytes")),
+                },
+                4 => Kdf::Pbkdf2 {
+                    iterations: read_u32(8),
+                },
+                x => return Err(Error::UnknownCode("kdf", x)),
+            },
+        };
+        header.stanzas_size()?;
+        Ok(Some(header))
+    }
+
+    /// Size of the recipient stanzas after the header, which are at most `MAX_RECIPIENTS`
+    pub fn stanzas_size(&self) -> Result<usize, Error> {
+        match self.kdf {
+            Kdf::X25519 { recipients } if recipients <= MAX_RECIPIENTS => (recipients as usize)
+                .checked_mul(STANZA_SIZE)
+                .ok_or(Error::TooManyRecipients(recipients)),
+            Kdf::X25519 { recipients } => Err(Error::TooManyRecipients(recipients)),
+            _ => Ok(0),
+        }
+    }
+
+    /// Checks the header can be decoded by this build, and returns if it is encrypted.
+    ///
+    /// The cipher reads the Argon2 costs from its own header, and authenticates both headers with every chunk.
+    fn check(&self) -> Result<bool, Error> {
+        if self.compression == Compression::Zstd && !cfg!(feature = "zstd") {
+            return Err(Error::FeatureNoZstd);
+        }
+        if self.encryption == Encryption::Aes256Gcm && !cfg!(feature = "aes") {
+            return Err(Error::FeatureNoAes);
+        }
+        match (self.encryption, self.kdf) {
+            (Encryption::None, _) => Ok(false),
+            (_, Kdf::None) => Err(Error::UnknownCode("kdf", 0)),
+            (Encryption::XChaCha20Poly1305, Kdf::Pbkdf2 { .. })
+            | (Encryption::Aes256Gcm, Kdf::Argon2id(_) | Kdf::X25519 { .. }) => {
+                Err(Error::UnsupportedSuite(self.encryption, self.kdf))
+            }
+            _ if cfg!(feature = "encryption") => Ok(true),
+            _ => Err(Error::FeatureNoCipher),
+        }
+    }
+
+    /// The error for decrypting the file without the secret of its kdf
+    fn missing_secret(&self) -> Error {
+        match self.kdf {
+            Kdf::X25519 { .. } => Error::NoIdentity,
+            Kdf::RawKey { .. } => Error::NoKey,
+            _ => Error::NoPassword,
+        }
+    }
+}
+
+/// Returns where the content starts in a file beginning with `head`, to resume its download without a secret.
+///
+/// The rest of a compressed or encrypted file can't be decoded without its beginning, so they are refused.
+pub fn plain_start(head: &[u8]) -> Result<u64, Error> {
+    if age_format(head).is_some() {
+        return Err(Error::OffsetOfAge);
+    }
+    match Header::parse(head)? {
+        Some(x) if x.compression != Compression::None => Err(Error::OffsetOfCompressed),
+        Some(x) if x.check()? => Err(x.missing_secret()),
+        Some(x) => Ok((HEADER_SIZE + x.stanzas_size()?) as u64),
+        None => Ok(0),
+    }
+}
+
+/// Writes the header and the recipient stanzas before every stream, and passes the bytes through.
+pub struct Envelope<W: io::Write> {
+    header: Header,
+    stanzas: Vec<u8>,
+    is_header_written: bool,
+    next_writer: W,
+}
+
+impl<W: io::Write> Envelope<W> {
+    pub fn new(header: Header, next_writer: W) -> Self {
+        Self::with_stanzas(header, vec![], next_writer)
+    }
+
+    /// `stanzas` are the file key wrapped by `recipient::wrap`.
+    pub fn with_stanzas(header: Header, stanzas: Vec<u8>, next_writer: W) -> Self {
+        Envelope {
+            header,
+            stanzas,
+            is_header_written: false,
+            next_writer,
+        }
+    }
+
+    /// The header and stanzas written before every stream
+    pub fn head(&self) -> Vec<u8> {
+        [&self.header.to_bytes()[..], &self.stanzas].concat()
+    }
+
+    /// Creates an envelope with the same header and stanzas for another writer.
+    pub(crate) fn fork<W2: io::Write>(&self, next_writer: W2) -> Envelope<W2> {
+        Envelope::with_stanzas(self.header, self.stanzas.clone(), next_writer)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        if !self.is_header_written {
+            self.next_writer.write_all(&self.header.to_bytes())?;
+            self.next_writer.write_all(&self.stanzas)?;
+            self.is_header_written = true;
+        }
+        Ok(())
+    }
+}
+
+impl<W: io::Write> io::Write for Envelope<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_header()?;
+        self.next_writer.write_all(buf)?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        // an empty stream still has the header
+        self.write_header()?;
+        self.next_writer.flush()?;
+        self.is_header_written = false;
+        Ok(())
+    }
+}
+
+impl<W: io::Write> ChainWrite<W> for Envelope<W> {
+    fn next(self) -> W {
+        self.next_writer
+    }
+    fn next_mut(&mut self) -> &mut W {
+        &mut self.next_writer
+    }
+}
+
+/// Detects the header and decodes the rest of the stream by it.
+///
+/// Streams without the header are decoded by `secret` and `with_decompression`, the same as before the header.
+/// Streams with an unencrypted header are refused with `secret`, since their content isn't authenticated.
+/// Streams in the age format are decrypted by `secret` as identities or a passphrase, and decompressed by
+/// `with_decompression`.
+/// Without `secret` and `with_decompression`, streams without the header are passed through unchanged.
+pub struct Opener<'a, W: io::Write + 'a> {
+    buf: Vec<u8>,
+    secret: Option<Secret>,
+    with_decompression: bool,
+    max_params: Option<Argon2Params>,
+    next_writer: Option<W>,
+    body: Option<Box<dyn io::Write + 'a>>,
+}
+
+impl<'a, W: io::Write + 'a> Opener<'a, W> {
+    pub fn new(secret: Option<Secret>, with_decompression: bool, next_writer: W) -> Self {
+        Opener {
+            buf: vec![],
+            secret,
+            with_decompression,
+            max_params: None,
+            next_writer: Some(next_writer),
+            body: None,
+        }
+    }
+
+    /// Accepts the Argon2 costs up to `max` from the cipher header, see `cipher::Cipher::with_max_params`.
+    pub fn with_max_params(mut self, max: Argon2Params) -> Self {
+        self.max_params = Some(max);
+        self
+    }
+
+    /// Returns if the header and stanzas are buffered, or the stream has no header.
+    fn is_head_buffered(&self) -> Result<bool, Error> {
+        // the first line of the age format is longer than the magic
+        for intro in [AGE_INTRO, AGE_ARMOR_BEGIN] {
+            if self.buf.len() < intro.len() && intro.starts_with(&self.buf) {
+                return Ok(false);
+            }
+        }
+
+        let len = cmp::min(self.buf.len(), MAGIC.len());
+        if self.buf[..len] != MAGIC[..len] {
+            return Ok(true);
+        }
+        if self.buf.len() < HEADER_SIZE {
+            return Ok(false);
+        }
+        let header = Header::parse(&self.buf)?.expect("the magic is checked");
+        Ok(self.buf.len() >= HEADER_SIZE + header.stanzas_size()?)
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        if let Some(is_armored) = age_format(&self.buf) {
+            let secret = match self.secret.take() {
+                Some(Secret::Keys(_)) => Err(Error::RawKeyOfAge)?,
+                x => x.ok_or(Error::NoAgeSecret)?,
+            };
+            let next_writer = self.next_writer.take().expect("the stream is opened once");
+            let mut body = decode_age(next_writer, is_armored, self.with_decompression, secret)?;
+            body.write_all(&mem::take(&mut self.buf))?;
+            self.body = Some(body);
+            return Ok(());
+        }
+
+        let header = Header::parse(&self.buf)?;
+        let (is_compressed, is_encrypted, head_size) = match header.as_ref() {
+            Some(x) => (
+                x.compression == Compression::Zstd,
+                x.check()?,
+                HEADER_SIZE + x.stanzas_size()?,
+            ),
+            None => (self.with_decompression, self.secret.is_some(), 0),
+        };
+        // the content of an unencrypted file isn't authenticated, so it must not pass for decrypted content
+        if !is_encrypted && self.secret.is_some() {
+            Err(Error::NotEncrypted)?;
+        }
+        if self.buf.len() < head_size {
+            Err(Error::TooShortForHeader)?;
+        }
+        let head: Vec<u8> = self.buf.drain(..head_size).collect();
+
+        let secret = if is_encrypted {
+            let missing = header.map_or(Error::NoPassword, |x| x.missing_secret());
+            Some(self.secret.take().ok_or(missing)?)
+        } else {
+            None
+        };
+
+        let next_writer = self.next_writer.take().expect("the stream is opened once");
+        let mut body = decode_chain(
+            next_writer,
+            is_compressed,
+            header,
+            &head,
+            secret,
+            self.max_params,
+        )?;
+        body.write_all(&mem::take(&mut self.buf))?;
+        self.body = Some(body);
+
+        Ok(())
+    }
+}
+
+impl<'a, W: io::Write + 'a> io::Write for Opener<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(body) = self.body.as_mut() {
+            body.write_all(buf)?;
+            return Ok(buf.len());
+        }
+
+        self.buf.extend(buf);
+        if self.is_head_buffered()? {
+            self.open()?;
+        }
+
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        if self.body.is_none() {
+            self.open()?;
+        }
+        self.body.as_mut().map_or(Ok(()), |x| x.flush())
+    }
+}
+
+/// Creates the decryption cipher from the plaintext `offset` for a file with `header`.
+///
+/// `head` is the envelope header and stanzas read before the stream, which the cipher authenticates.
+/// Files without the header can only be decrypted by a password, and may be in the legacy format of the cipher.
+/// The Argon2 costs of the cipher header are limited by `max_params`, or the defaults of the cipher without it.
+#[cfg(feature = "encryption")]
+pub fn decryption_cipher<W: io::Write>(
+    header: Option<Header>,
+    head: &[u8],
+    secret: Secret,
+    offset: u64,
+    max_params: Option<Argon2Params>,
+    writer: W,
+) -> io::Result<cipher::Cipher<W>> {
+    if matches!(header, Some(x) if x.encryption == Encryption::None) {
+        Err(Error::NotEncrypted)?;
+    }
+    let kdf = header.map_or(Kdf::None, |x| x.kdf);
+    let mut cipher = match (kdf, secret) {
+        (Kdf::X25519 { .. }, Secret::Identities(identities)) => {
+            let stanzas = head.get(HEADER_SIZE..).unwrap_or_default();
+            let file_key = recipient::unwrap(stanzas, &identities)?;
+            cipher::Cipher::new_decryption_with_key(file_key, offset, writer)?
+        }
+        (Kdf::RawKey { key_id }, Secret::Keys(keys)) => {
+            let key = keys
+                .iter()
+                .find(|x| x.id() == key_id)
+                .ok_or(raw_key::Error::NoMatchedKey(key_id))?;
+            cipher::Cipher::new_decryption_with_key(*key.as_bytes(), offset, writer)?
+        }
+        (Kdf::X25519 { .. }, _) => Err(Error::NoIdentity)?,
+        (Kdf::RawKey { .. }, _) => Err(Error::NoKey)?,
+        (_, Secret::Password(password)) if header.is_none() => {
+            cipher::Cipher::new_decryption_at(password, offset, writer)?.with_legacy()
+        }
+        (_, Secret::Password(password)) => {
+            cipher::Cipher::new_decryption_at(password, offset, writer)?
+        }
+        (_, _) => Err(Error::NoPassword)?,
+    };
+    if let Some(max) = max_params {
+        cipher = cipher.with_max_params(max);
+    }
+    let cipher = cipher.with_envelope(head.to_vec());
+
+    match header.map(|x| x.encryption) {
+        #[cfg(feature = "aes")]
+        Some(Encryption::Aes256Gcm) => Ok(cipher.with_aes_gcm()),
+        #[cfg(not(feature = "aes"))]
+        Some(Encryption::Aes256Gcm) => Err(Error::FeatureNoAes)?,
+        _ => Ok(cipher),
+    }
+}
+
+fn decode_chain<'a, W: io::Write + 'a>(
+    writer: W,
+    is_compressed: bool,
+    header: Option<Header>,
+    head: &[u8],
+    secret: Option<Secret>,
+    max_params: Option<Argon2Params>,
+) -> io::Result<Box<dyn io::Write + 'a>> {
+    Ok(match (is_compressed, secret) {
+        #[cfg(all(feature = "zstd", feature = "encryption"))]
+        (true, Some(secret)) => Box::new(decryption_cipher(
+            header,
+            head,
+            secret,
+            0,
+            max_params,
+            decompressor::Decompressor::new(writer)?,
+        )?),
+        #[cfg(feature = "encryption")]
+        (false, Some(secret)) => Box::new(decryption_cipher(
+            header, head, secret, 0, max_params, writer,
+        )?),
+        #[cfg(feature = "zstd")]
+        (true, None) => Box::new(decompressor::Decompressor::new(writer)?),
+        (false, None) => Box::new(writer),
+        #[cfg(not(feature = "zstd"))]
+        (true, _) => Err(Error::FeatureNoZstd)?,
+        #[cfg(not(feature = "encryption"))]
+        (_, Some(_)) => {
+            let _ = (header, head, max_params);
+            Err(Error::FeatureNoCipher)?
+        }
+    })
+}
+
+#[cfg(feature = "age")]
+fn decode_age<'a, W: io::Write + 'a>(
+    writer: W,
+    is_armored: bool,
+    is_compressed: bool,
+    secret: Secret,
+) -> io::Result<Box<dyn io::Write + 'a>> {
+    let writer = decode_chain(writer, is_compressed, None, &[], None, None)?;
+    let age = age::Age::new_decryption(secret, writer);
+    Ok(if is_armored {
+        Box::new(age::Armor::new_decoding(age))
+    } else {
+        Box::new(age)
+    })
+}
+#[cfg(not(feature = "age"))]
+fn decode_age<'a, W: io::Write + 'a>(
+    _: W,
+    _: bool,
+    _: bool,
+    _: Secret,
+) -> io::Result<Box<dyn io::Write + 'a>> {
+    Err(Error::FeatureNoAge)?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn open(secret: Option<Secret>, with_decompression: bool, buf: &[u8]) -> io::Result<Vec<u8>> {
+        let mut decoded = vec![];
+        let mut opener = Opener::new(secret, with_decompression, &mut decoded);
+        // in small writes, like a download
+        for x in buf.chunks(7) {
+            opener.write_all(x)?;
+        }
+        opener.flush()?;
+        drop(opener);
+        Ok(decoded)
+    }
+
+    /// Encrypts `data` in the envelope, like the helper does.
+    #[cfg(feature = "encryption")]
+    fn seal(
+        header: Header,
+        stanzas: Vec<u8>,
+        key: cipher::Cipher<Vec<u8>>,
+        data: &[u8],
+    ) -> Vec<u8> {
+        let mut ret = vec![];
+        let envelope = Envelope::with_stanzas(header, stanzas, &mut ret);
+        let head = envelope.head();
+        let mut cipher = key.fork(envelope).with_envelope(head);
+        cipher.write_all(data).unwrap();
+        cipher.flush().unwrap();
+        drop(cipher);
+        ret
+    }
+
+    #[test]
+    fn header_to_bytes() {
+        let kdfs = [
+            Kdf::Argon2id(Argon2Params::default()),
+            Kdf::X25519 { recipients: 3 },
+            Kdf::RawKey {
+                key_id: u64::MAX - 1,
+            },
+            Kdf::Pbkdf2 { iterations: 1000 },
+        ];
+        for kdf in kdfs {
+            let header = Header::new(true, kdf);
+            let parsed = Header::parse(&header.to_bytes()).unwrap().unwrap();
+            assert_eq!(parsed, header);
+        }
+        assert!(Header::parse(b"not a header").unwrap().is_none());
+        assert!(matches!(
+            Header::parse(&MAGIC),
+            Err(Error::TooShortForHeader)
+        ));
+    }
+
+    #[test]
+    fn recipients_limit() {
+        let header = Header::new(
+            false,
+            Kdf::X25519 {
+                recipients: MAX_RECIPIENTS,
+            },
+        );
+        assert_eq!(
+            header.stanzas_size().unwrap(),
+            MAX_RECIPIENTS as usize * STANZA_SIZE
+        );
+
+        // the count is read from the file, so it can be anything
+        let mut buf = header.to_bytes();
+        buf[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        let e = Header::parse(&buf).unwrap_err();
+        assert!(matches!(e, Error::TooManyRecipients(u32::MAX)));
+
+        let mut opener = Opener::new(Some(Secret::Password(vec![])), false, vec![]);
+        assert!(opener.write(&buf).is_err());
+    }
+
+    #[test]
+    fn detect_without_flags() {
+        // streams without the header are passed through unchanged
+        for input in [&b"data"[..], &MAGIC[..2], b""] {
+            assert_eq!(open(None, false, input).unwrap(), input);
+        }
+        assert!(open(None, false, AGE_INTRO).is_err());
+
+        // compression-only uploads are decompressed by their header
+        let header = Header::new(true, Kdf::None).to_bytes();
+        #[cfg(feature = "zstd")]
+        {
+            let mut enveloped = header.to_vec();
+            enveloped.extend(zstd::encode_all(&b"data"[..], 0).unwrap());
+            assert_eq!(open(None, false, &enveloped).unwrap(), b"data");
+        }
+        #[cfg(not(feature = "zstd"))]
+        assert!(open(None, false, &header).is_err());
+    }
+
+    #[test]
+    fn resume_without_secret() {
+        assert_eq!(plain_start(b"data").unwrap(), 0);
+        let plain = Header::new(false, Kdf::None).to_bytes();
+        assert_eq!(plain_start(&plain).unwrap(), HEADER_SIZE as u64);
+
+        let compressed = Header::new(true, Kdf::None).to_bytes();
+        assert!(matches!(
+            plain_start(&compressed),
+            Err(Error::OffsetOfCompressed)
+        ));
+        assert!(matches!(plain_start(AGE_INTRO), Err(Error::OffsetOfAge)));
+        #[cfg(feature = "encryption")]
+        {
+            let encrypted = Header::new(false, Kdf::RawKey { key_id: 1 }).to_bytes();
+            assert!(matches!(plain_start(&encrypted), Err(Error::NoKey)));
+        }
+    }
+
+    #[test]
+    fn plaintext_with_secret() {
+        let mut enveloped = Header::new(false, Kdf::None).to_bytes().to_vec();
+        enveloped.extend(b"data");
+        let secret = Some(Secret::Password(b"password".to_vec()));
+        let e = open(secret, false, &enveloped).unwrap_err();
+        let e = e.into_inner().unwrap().downcast::<Error>().unwrap();
+        assert!(matches!(*e, Error::NotEncrypted));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn round_trip() {
+        let data = vec![7u8; cipher::CHUNK_SIZE + 10];
+
+        let params = Argon2Params::default();
+        let key =
+            cipher::Cipher::new_with_params(&mut b"password".to_vec(), params, vec![]).unwrap();
+        let sealed = seal(
+            Header::new(false, Kdf::Argon2id(params)),
+            vec![],
+            key,
+            &data,
+        );
+        let password = Secret::Password(b"password".to_vec());
+        assert_eq!(open(Some(password), false, &sealed).unwrap(), data);
+        let e = open(None, true, &sealed).unwrap_err();
+        assert!(e.to_string().contains("no password"));
+
+        let identity = recipient::Identity::generate();
+        let file_key = recipient::gen_file_key();
+        let stanzas = recipient::wrap(&file_key, &[identity.to_public()]).unwrap();
+        let key = cipher::Cipher::new_with_key(file_key, vec![]).unwrap();
+        let sealed = seal(
+            Header::new(false, Kdf::X25519 { recipients: 1 }),
+            stanzas,
+            key,
+            &data,
+        );
+        let identities = Secret::Identities(vec![identity]);
+        assert_eq!(open(Some(identities), false, &sealed).unwrap(), data);
+        let others = Secret::Identities(vec![recipient::Identity::generate()]);
+        assert!(open(Some(others), false, &sealed).is_err());
+
+        let raw_key = raw_key::RawKey::generate(9);
+        let key = cipher::Cipher::new_with_key(*raw_key.as_bytes(), vec![]).unwrap();
+        let sealed = seal(
+            Header::new(false, Kdf::RawKey { key_id: 9 }),
+            vec![],
+            key,
+            &data,
+        );
+        let keys = Secret::Keys(vec![raw_key::RawKey::generate(8), raw_key]);
+        assert_eq!(open(Some(keys), false, &sealed).unwrap(), data);
+        let others = Secret::Keys(vec![raw_key::RawKey::generate(9)]);
+        assert!(open(Some(others), false, &sealed).is_err());
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn header_authenticated() {
+        let raw_key = raw_key::RawKey::generate(9);
+        let key = cipher::Cipher::new_with_key(*raw_key.as_bytes(), vec![]).unwrap();
+        let sealed = seal(
+            Header::new(false, Kdf::RawKey { key_id: 9 }),
+            vec![],
+            key,
+            b"data",
+        );
+        let params = Argon2Params::default();
+        let key =
+            cipher::Cipher::new_with_params(&mut b"password".to_vec(), params, vec![]).unwrap();
+        let sealed_by_password = seal(
+            Header::new(false, Kdf::Argon2id(params)),
+            vec![],
+            key,
+            b"data",
+        );
+
+        // the costs of the envelope, the bytes which the kdf ignores, and the compression code
+        let mut changes = vec![(&sealed_by_password, 12), (&sealed, 19)];
+        if cfg!(feature = "zstd") {
+            changes.push((&sealed, 5));
+        }
+        for (sealed, i) in changes {
+            let mut changed = sealed.clone();
+            changed[i] ^= 1;
+            let secret = match Header::parse(sealed).unwrap().unwrap().kdf {
+                Kdf::RawKey { .. } => Secret::Keys(vec![raw_key.clone()]),
+                _ => Secret::Password(b"password".to_vec()),
+            };
+            let e = open(Some(secret), false, &changed).unwrap_err();
+            assert!(e.to_string().contains("MAC tag"), "byte {}: {}", i, e);
+        }
+    }
+
+    #[cfg(feature = "aes")]
+    #[test]
+    fn round_trip_aes_gcm() {
+        let data = vec![7u8; cipher::CHUNK_SIZE + 10];
+        let aes_gcm = |kdf| Header {
+            encryption: Encryption::Aes256Gcm,
+            ..Header::new(false, kdf)
+        };
+
+        let key = cipher::Cipher::new_with_pbkdf2(&mut b"password".to_vec(), 1000, vec![]).unwrap();
+        let sealed = seal(
+            aes_gcm(Kdf::Pbkdf2 { iterations: 1000 }),
+            vec![],
+            key,
+            &data,
+        );
+        let password = Secret::Password(b"password".to_vec());
+        assert_eq!(open(Some(password), false, &sealed).unwrap(), data);
+
+        let raw_key = raw_key::RawKey::generate(9);
+        let key = cipher::Cipher::new_with_key(*raw_key.as_bytes(), vec![])
+            .unwrap()
+            .with_aes_gcm();
+        let sealed = seal(aes_gcm(Kdf::RawKey { key_id: 9 }), vec![], key, &data);
+        let keys = Secret::Keys(vec![raw_key.clone()]);
+        assert_eq!(open(Some(keys), false, &sealed).unwrap(), data);
+
+        // the envelope can't claim another suite than the cipher header
+        let key = cipher::Cipher::new_with_key(*raw_key.as_bytes(), vec![])
+            .unwrap()
+            .with_aes_gcm();
+        let sealed = seal(
+            Header::new(false, Kdf::RawKey { key_id: 9 }),
+            vec![],
+            key,
+            &data,
+        );
+        let keys = Secret::Keys(vec![raw_key]);
+        assert!(open(Some(keys), false, &sealed).is_err());
+    }
+
+    #[cfg(feature = "aes")]
+    #[test]
+    fn unsupported_suites() {
+        let headers = [
+            Header {
+                encryption: Encryption::Aes256Gcm,
+                ..Header::new(false, Kdf::Argon2id(Argon2Params::default()))
+            },
+            Header {
+                encryption: Encryption::Aes256Gcm,
+                ..Header::new(false, Kdf::X25519 { recipients: 1 })
+            },
+            Header::new(false, Kdf::Pbkdf2 { iterations: 1000 }),
+        ];
+        for header in headers {
+            let e = header.check().unwrap_err();
+            assert!(matches!(e, Error::UnsupportedSuite(..)));
+        }
+    }
+}
@@ -0,0 +1,270 @@
+//! Hides the names of files and directories in directory uploads
+//!
+//! Every item is renamed to an opaque ID, and its metadata is removed from the UnixFS nodes.
+//! A manifest mapping the IDs back to the real names, sizes and metadata is added to the root directory
+//! as `MANIFEST_NAME`, and encrypted like the other files.
+//! Symbolic links are only kept in the manifest, since their targets would be readable in the nodes.
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::car_stream::TempFile;
+use super::car_util::{DirectoryItem, FileMeta};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::{Component, Path};
+
+pub const MANIFEST_NAME: &str = "w3s-manifest.json";
+const VERSION: u8 = 1;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Invalid manifest: {0}")]
+    InvalidManifest(#[from] serde_json::Error),
+    #[error("Unsupported manifest version: {0}")]
+    UnsupportedVersion(u8),
+    #[error("Invalid path in the manifest: {0}")]
+    InvalidPath(String),
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+/// The real path and metadata of an item
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub kind: EntryKind,
+    /// path relative to the root directory
+    pub path: String,
+    /// bytes of the original file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(default)]
+    pub meta: FileMeta,
+    /// target of the symbolic link
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+/// Entries of all the items by their opaque paths
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    version: u8,
+    entries: BTreeMap<String, Entry>,
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+impl Manifest {
+    /// Renames the items to opaque IDs, and adds the manifest of them to the root items as the file `manifest_id`.
+    ///
+    /// The manifest is written to a temporary file, which is removed when the returned `TempFile` is dropped.
+    pub(crate) fn hide(
+        dir_items: Vec<DirectoryItem>,
+        manifest_id: u64,
+    ) -> io::Result<(Vec<DirectoryItem>, TempFile)> {
+        let mut manifest = Manifest {
+            version: VERSION,
+            entries: BTreeMap::new(),
+        };
+        let mut counter = 0;
+        let mut dir_items = manifest.hide_items(dir_items, "", "", &mut counter)?;
+
+        let (temp, mut file) = TempFile::create("json")?;
+        serde_json::to_writer(&mut file, &manifest).map_err(Error::from)?;
+        file.flush()?;
+
+        dir_items.insert(
+            0,
+            DirectoryItem::File(
+                MANIFEST_NAME.to_owned(),
+                temp.path().to_string_lossy().to_string(),
+                manifest_id,
+                FileMeta::default(),
+            ),
+        );
+        Ok((dir_items, temp))
+    }
+
+    fn hide_items(
+        &mut self,
+        dir_items: Vec<DirectoryItem>,
+        real_dir: &str,
+        opaque_dir: &str,
+        counter: &mut u64,
+    ) -> io::Result<Vec<DirectoryItem>> {
+        let mut result = vec![];
+        for item in dir_items {
+            *counter += 1;
+            let id = format!("{:x}", counter);
+            let opaque = join(opaque_dir, &id);
+
+            let entry = match item {
+                DirectoryItem::File(name, path, file_id, meta) => {
+                    let size = std::fs::metadata(&path)?.len();
+                    result.push(DirectoryItem::File(id, path, file_id, FileMeta::default()));
+                    Entry {
+                        kind: EntryKind::File,
+                        path: join(real_dir, &name),
+                        size: Some(size),
+                        meta,
+                        target: None,
+                    }
+                }
+                DirectoryItem::Directory(name, sub_items, meta) => {
+                    let path = join(real_dir, &name);
+                    let sub_items = self.hide_items(sub_items, &path, &opaque, counter)?;
+                    result.push(DirectoryItem::Directory(id, sub_items, FileMeta::default()));
+                    Entry {
+                        kind: EntryKind::Directory,
+                        path,
+                        size: None,
+                        meta,
+                        target: None,
+                    }
+                }
+                DirectoryItem::Symlink(name, target, meta) => Entry {
+                    kind: EntryKind::Symlink,
+                    path: join(real_dir, &name),
+                    size: None,
+                    meta,
+                    target: Some(target),
+                },
+            };
+            self.entries.insert(opaque, entry);
+        }
+
+        Ok(result)
+    }
+
+    /// Parses the decrypted manifest. Empty paths and paths leaving the root directory are rejected.
+    pub fn from_slice(buf: &[u8]) -> Result<Manifest, Error> {
+        let manifest: Manifest = serde_json::from_slice(buf)?;
+        if manifest.version != VERSION {
+            return Err(Error::UnsupportedVersion(manifest.version));
+        }
+
+        if let Some(entry) = manifest.entries.values().find(|x| {
+            x.path.is_empty()
+                || !Path::new(&x.path)
+                    .components()
+                    .all(|x| matches!(x, Component::Normal(_)))
+        }) {
+            return Err(Error::InvalidPath(entry.path.clone()));
+        }
+        Ok(manifest)
+    }
+
+    /// Returns the entry of the item with the opaque path.
+    pub fn entry(&self, opaque_path: &str) -> Option<&Entry> {
+        self.entries.get(opaque_path)
+    }
+
+    /// Returns the symbolic links, which are not in the DAG.
+    pub fn symlinks(&self) -> impl Iterator<Item = &Entry> {
+        self.entries
+            .values()
+            .filter(|x| x.kind == EntryKind::Symlink)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn hide_and_parse() {
+        let dir = std::env::temp_dir().join(format!("w3s-manifest-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("secret.txt").to_string_lossy().to_string();
+        fs::write(&path, b"12345").unwrap();
+
+        let meta = FileMeta {
+            mode: Some(0o600),
+            mtime: Some((1, 0)),
+        };
+        let items = vec![
+            DirectoryItem::Directory(
+                "private".to_owned(),
+                vec![DirectoryItem::File("secret.txt".to_owned(), path, 0, meta)],
+                meta,
+            ),
+            DirectoryItem::Symlink("link".to_owned(), "private/secret.txt".to_owned(), meta),
+        ];
+        let (hidden, temp) = Manifest::hide(items, 7).unwrap();
+        let manifest = Manifest::from_slice(&fs::read(temp.path()).unwrap()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // the manifest goes first, and the link is only kept in it
+        assert_eq!(hidden.len(), 2);
+        assert!(matches!(&hidden[0], DirectoryItem::File(name, _, 7, _) if name == MANIFEST_NAME));
+        let (dir_id, sub_items) = match &hidden[1] {
+            DirectoryItem::Directory(id, sub_items, x) if x.is_empty() => (id, sub_items),
+            x => panic!("unexpected item {:?}", x),
+        };
+        let file_id = match &sub_items[..] {
+            [DirectoryItem::File(id, _, 0, x)] if x.is_empty() => id,
+            x => panic!("unexpected items {:?}", x),
+        };
+
+        let entry = manifest.entry(dir_id).unwrap();
+        assert_eq!(
+            (entry.kind, entry.path.as_str()),
+            (EntryKind::Directory, "private")
+        );
+        let entry = manifest.entry(&format!("{}/{}", dir_id, file_id)).unwrap();
+        assert_eq!(entry.kind, EntryKind::File);
+        assert_eq!(entry.path, "private/secret.txt");
+        assert_eq!((entry.size, entry.meta), (Some(5), meta));
+
+        let links: Vec<_> = manifest.symlinks().collect();
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].path, "link");
+        assert_eq!(links[0].target.as_deref(), Some("private/secret.txt"));
+    }
+
+    #[test]
+    fn invalid_manifests() {
+        for path in ["../escape", "/etc/passwd", "a/../../b", ""] {
+            let json = format!(
+                r#"{{"version":1,"entries":{{"1":{{"kind":"file","path":"{}"}}}}}}"#,
+                path
+            );
+            assert!(
+                matches!(
+                    Manifest::from_slice(json.as_bytes()),
+                    Err(Error::InvalidPath(_))
+                ),
+                "{}",
+                path
+            );
+        }
+
+        let json = r#"{"version":2,"entries":{}}"#;
+        assert!(matches!(
+            Manifest::from_slice(json.as_bytes()),
+            Err(Error::UnsupportedVersion(2))
+        ));
+        assert!(matches!(
+            Manifest::from_slice(b"not json"),
+            Err(Error::InvalidManifest(_))
+        ));
+    }
+}
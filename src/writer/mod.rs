@@ -4,7 +4,12 @@ use std::io;
 
 pub mod dir;
 pub mod car_util;
+pub mod car_stream;
 pub mod car;
+pub mod parallel;
+pub mod envelope;
+pub mod raw_key;
+pub mod manifest;
 
 pub mod splitter;
 pub mod uploader;
@@ -12,6 +17,12 @@ pub mod downloader;
 
 #[cfg(feature = "encryption")]
 pub mod cipher;
+#[cfg(feature = "encryption")]
+pub mod recipient;
+#[cfg(feature = "age")]
+pub mod age;
+#[cfg(feature = "signing")]
+pub mod signature;
 
 #[cfg(feature = "zstd")]
 pub mod decompressor;
@@ -21,3 +32,14 @@ pub trait ChainWrite<W: io::Write>: io::Write {
     fn next_mut(&mut self) -> &mut W;
     fn next(self) -> W;
 }
+
+/// Describe the trait of writers which can take a whole CAR file at once
+pub trait CarWrite: io::Write {
+    /// Returns the same as `io::Write::write`, 0 means `flush` should be called after.
+    fn write_car(&mut self, car: car_stream::UploadBody) -> io::Result<usize> {
+        let data = car.to_vec()?;
+        self.write(&data)
+    }
+}
+impl CarWrite for Vec<u8> {}
+impl CarWrite for std::fs::File {}
@@ -0,0 +1,332 @@
+//! Processes files on worker threads and feeds the hashed blocks to `Car` in the order of a sequential walk
+//!
+use super::*;
+use car::Car;
+use car_util::{gen_blocks_parallel, DagConfig, DirectoryItem, UnixFsStruct};
+use std::fs::File;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Mutex;
+use std::{mem, thread};
+
+/// Hashed batches kept for every file before its worker waits for the writing
+const CHANNEL_CAPACITY: usize = 4;
+
+type Batch = io::Result<Vec<UnixFsStruct>>;
+
+/// Returns the number of threads the current machine can run in parallel.
+pub fn available_threads() -> usize {
+    thread::available_parallelism()
+        .map(|x| x.get())
+        .unwrap_or(1)
+}
+
+/// How the bytes of every file are transformed before hashing
+#[derive(Default)]
+pub struct FileEncoding {
+    /// zstd level, `Some(None)` means level 10
+    pub compression: Option<Option<i32>>,
+    /// the header and recipient stanzas written before every compressed or encrypted file
+    pub envelope: Option<envelope::Envelope<io::Sink>>,
+    /// a cipher whose key, salt and Argon2 costs are used for every file
+    #[cfg(feature = "encryption")]
+    pub encryption: Option<cipher::Cipher<io::Sink>>,
+}
+
+/// Splits bytes into blocks and hashes them in batches, then sends the blocks to the writing thread.
+struct BlockSender {
+    buf: Vec<u8>,
+    block_size: usize,
+    hash_threads: usize,
+    dag_config: DagConfig,
+    sender: SyncSender<Batch>,
+}
+
+impl BlockSender {
+    fn send(&mut self, buf: &[u8]) -> io::Result<()> {
+        let blocks = gen_blocks_parallel(buf, self.block_size, &self.dag_config, self.hash_threads);
+        self.sender
+            .send(Ok(blocks))
+            .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e))
+    }
+}
+
+impl io::Write for BlockSender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend(buf);
+
+        // only full blocks are hashed before the file ends, the same as `Car`
+        let batch_size = self.block_size * self.hash_threads;
+        if self.buf.len() >= batch_size {
+            let full_len = self.buf.len() / self.block_size * self.block_size;
+            let remain = self.buf.split_off(full_len);
+            let full = mem::replace(&mut self.buf, remain);
+            self.send(&full)?;
+        }
+
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        let remain = mem::take(&mut self.buf);
+        if !remain.is_empty() {
+            self.send(&remain)?;
+        }
+        Ok(())
+    }
+}
+
+fn collect_files<'a>(dir_items: &'a [DirectoryItem], files: &mut Vec<(u64, &'a str)>) {
+    for item in dir_items {
+        match item {
+            DirectoryItem::File(_, path, id, _) => files.push((*id, path)),
+            DirectoryItem::Directory(_, sub_dir_items, _) => collect_files(sub_dir_items, files),
+            DirectoryItem::Symlink(..) => {}
+        }
+    }
+}
+
+/// Copies the file into `writer` with optional compression, the same as the `Dir` walks.
+fn copy_file(
+    file: &mut File,
+    writer: &mut impl io::Write,
+    encoding: &FileEncoding,
+) -> io::Result<()> {
+    match encoding.compression {
+        #[cfg(feature = "zstd")]
+        Some(level) => {
+            let mut compressor = zstd::stream::Encoder::new(writer, level.unwrap_or(10))?;
+            io::copy(file, &mut compressor)?;
+            compressor.finish()?;
+        }
+        #[cfg(not(feature = "zstd"))]
+        Some(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "The feature:\"zstd\" is required.",
+            ))
+        }
+        None => {
+            io::copy(file, writer)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes one file through the encoding and the envelope into `sender`. The file ends with a flush, like the `Dir` walks.
+fn write_file(path: &str, encoding: &FileEncoding, mut sender: BlockSender) -> io::Result<()> {
+    let mut file = File::open(path)?;
+
+    let mut envelope = match encoding.envelope.as_ref() {
+        Some(x) => x.fork(sender),
+        None => {
+            copy_file(&mut file, &mut sender, encoding)?;
+            return io::Write::flush(&mut sender);
+        }
+    };
+
+    #[cfg(feature = "encryption")]
+    if let Some(template) = encoding.encryption.as_ref() {
+        let mut cipher = template.fork(envelope);
+        copy_file(&mut file, &mut cipher, encoding)?;
+        return io::Write::flush(&mut cipher);
+    }
+
+    copy_file(&mut file, &mut envelope, encoding)?;
+    io::Write::flush(&mut envelope)
+}
+
+/// Walks the directory items with `threads` workers and writes all the files into `car`.
+///
+/// Files are read, compressed, encrypted and hashed concurrently, while `car` receives the blocks file by file
+/// in the order of `Dir::walk_write`, so the CAR files are the same as the sequential ones.
+/// At most `CHANNEL_CAPACITY` hashed batches are kept for every file being processed.
+pub fn write_dir_parallel<W: CarWrite>(
+    dir_items: &[DirectoryItem],
+    car: &mut Car<W>,
+    encoding: &FileEncoding,
+    threads: usize,
+) -> io::Result<()> {
+    let mut files = vec![];
+    collect_files(dir_items, &mut files);
+
+    let workers = threads.clamp(1, files.len().max(1));
+    let hash_threads = (threads / workers).max(1);
+    let block_size = car.block_size();
+    let dag_config = *car.dag_config();
+
+    let (senders, receivers): (Vec<_>, Vec<_>) = files
+        .iter()
+        .map(|_| {
+            let (sender, receiver) = mpsc::sync_channel::<Batch>(CHANNEL_CAPACITY);
+            (Mutex::new(Some(sender)), receiver)
+        })
+        .unzip();
+    let next_file = AtomicUsize::new(0);
+
+    thread::scope(|s| {
+        for _ in 0..workers {
+            s.spawn(|| loop {
+                let index = next_file.fetch_add(1, Ordering::Relaxed);
+                let (_, path) = match files.get(index) {
+                    Some(x) => x,
+                    None => break,
+                };
+                let sender = match senders[index].lock().unwrap().take() {
+                    Some(x) => x,
+                    None => break,
+                };
+
+                let block_sender = BlockSender {
+                    buf: Vec::with_capacity(block_size * hash_threads),
+                    block_size,
+                    hash_threads,
+                    dag_config,
+                    sender: sender.clone(),
+                };
+                if let Err(e) = write_file(path, encoding, block_sender) {
+                    // the writing thread has stopped if the error can't be sent
+                    if sender.send(Err(e)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        let result = write_batches(car, &files, receivers);
+        if result.is_err() {
+            // the workers stop taking files, and the ones waiting on the dropped channels return
+            next_file.store(files.len(), Ordering::Relaxed);
+        }
+        result
+    })
+}
+
+fn write_batches<W: CarWrite>(
+    car: &mut Car<W>,
+    files: &[(u64, &str)],
+    receivers: Vec<Receiver<Batch>>,
+) -> io::Result<()> {
+    for ((id, _), receiver) in files.iter().zip(receivers) {
+        for blocks in receiver {
+            car.write_file_blocks(*id, blocks?)?;
+        }
+        // ends the file like the sequential walks
+        io::Write::flush(car)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::fs;
+    use std::rc::Rc;
+
+    use super::*;
+    use car_stream::UploadBody;
+    use car_util::SymlinkPolicy;
+    use cid::Cid;
+
+    /// Collects the bytes of every CAR.
+    #[derive(Default)]
+    struct Cars(Vec<Vec<u8>>);
+    impl io::Write for Cars {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.push(buf.to_vec());
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    impl CarWrite for Cars {
+        fn write_car(&mut self, car: UploadBody) -> io::Result<usize> {
+            let data = car.to_vec()?;
+            let len = data.len();
+            self.0.push(data);
+            Ok(len)
+        }
+    }
+
+    fn new_car(dir_items: &Rc<Vec<DirectoryItem>>, count: u64) -> (Car<Cars>, Rc<RefCell<u64>>) {
+        let file_id = Rc::new(RefCell::new(0));
+        let car = Car::new(
+            count as usize,
+            dir_items.clone(),
+            Some(file_id.clone()),
+            Some(1024),
+            None,
+            None,
+            Cars::default(),
+        );
+        (car, file_id)
+    }
+
+    fn pack(
+        dir_items: &Rc<Vec<DirectoryItem>>,
+        count: u64,
+        encoding: &FileEncoding,
+        threads: usize,
+    ) -> (Option<Cid>, Vec<Vec<u8>>) {
+        let (mut car, _) = new_car(dir_items, count);
+        write_dir_parallel(dir_items, &mut car, encoding, threads).unwrap();
+        (car.root(), car.next().0)
+    }
+
+    #[test]
+    fn same_car_on_any_threads() {
+        let root = std::env::temp_dir().join(format!("w3s-parallel-{}", std::process::id()));
+        fs::create_dir_all(root.join("sub/deeper")).unwrap();
+        // empty, single block, several blocks and a batch of blocks
+        for (name, len) in [
+            ("empty", 0),
+            ("small", 100),
+            ("sub/blocks", 5000),
+            ("sub/deeper/batch", 40_000),
+            ("sub/same", 5000),
+        ] {
+            let data: Vec<u8> = (0..len).map(|x| (x % 251) as u8).collect();
+            fs::write(root.join(name), data).unwrap();
+        }
+
+        let (dir_items, count) = DirectoryItem::from_path(
+            root.to_str().unwrap(),
+            None,
+            false,
+            SymlinkPolicy::default(),
+        )
+        .unwrap();
+        let dir_items = Rc::new(dir_items);
+
+        let (car, file_id) = new_car(&dir_items, count);
+        let mut dir = dir::Dir::new(file_id, car);
+        dir.walk_write(&dir_items).unwrap();
+        let car = dir.next();
+        let sequential = (car.root(), car.next().0);
+        assert!(sequential.0.is_some() && !sequential.1.is_empty());
+
+        let encoding = FileEncoding::default();
+        for threads in [1, 4] {
+            assert_eq!(
+                pack(&dir_items, count, &encoding, threads),
+                sequential,
+                "threads: {}",
+                threads
+            );
+        }
+
+        #[cfg(feature = "zstd")]
+        {
+            let encoding = FileEncoding {
+                compression: Some(Some(3)),
+                ..Default::default()
+            };
+            let single = pack(&dir_items, count, &encoding, 1);
+            assert_ne!(single, sequential);
+            assert_eq!(pack(&dir_items, count, &encoding, 4), single);
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
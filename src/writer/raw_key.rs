@@ -0,0 +1,199 @@
+//! Raw 256-bit keys with an ID, which replace the password when no slow KDF is wanted, such as machine-to-machine
+//! backups with keys kept in a secrets store
+//!
+//! A key is saved as `W3S-KEY-<id: 16 hex digits>-<key: 64 hex digits>` in a key file, one per line.
+//! The ID is recorded in the envelope header, so the matching key of a key file is picked for decryption.
+use thiserror::Error;
+
+use std::path::Path;
+use std::{fmt, fs, io, str::FromStr};
+
+pub const KEY_PREFIX: &str = "W3S-KEY-";
+pub const KEY_SIZE: usize = 32;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Invalid raw key in the key file.")]
+    InvalidKey,
+    #[error("No raw key in the key file.")]
+    NoKey,
+    #[error("No raw key with the ID {0:016x} in the key file.")]
+    NoMatchedKey(u64),
+    #[error("IO error: {0}")]
+    IoError(#[from] io::Error),
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// A 32 bytes key used directly by the cipher, and the ID to find it
+#[derive(Clone, PartialEq, Eq)]
+pub struct RawKey {
+    id: u64,
+    key: [u8; KEY_SIZE],
+}
+
+impl RawKey {
+    pub fn new(id: u64, key: [u8; KEY_SIZE]) -> Self {
+        RawKey { id, key }
+    }
+
+    /// Generates a random key with the ID.
+    #[cfg(feature = "encryption")]
+    pub fn generate(id: u64) -> Self {
+        use rand::RngCore;
+
+        let mut key = [0u8; KEY_SIZE];
+        rand::thread_rng().fill_bytes(&mut key);
+        RawKey { id, key }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_SIZE] {
+        &self.key
+    }
+
+    /// Reads the keys of a key file, one per line. Empty lines and lines starting with `#` are skipped.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Vec<RawKey>, Error> {
+        Self::parse_lines(&fs::read_to_string(path)?)
+    }
+
+    /// Reads the key with `id` from a key file, or the first one if `id` is `None`.
+    pub fn load(path: impl AsRef<Path>, id: Option<u64>) -> Result<RawKey, Error> {
+        let mut keys = Self::from_file(path)?;
+        match id {
+            Some(id) => keys
+                .into_iter()
+                .find(|x| x.id == id)
+                .ok_or(Error::NoMatchedKey(id)),
+            None => Ok(keys.swap_remove(0)),
+        }
+    }
+
+    /// Parses the keys of the key file content, the same as `from_file`.
+    pub fn parse_lines(text: &str) -> Result<Vec<RawKey>, Error> {
+        let keys = text
+            .lines()
+            .map(str::trim)
+            .filter(|x| !x.is_empty() && !x.starts_with('#'))
+            .map(RawKey::from_str)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if keys.is_empty() {
+            return Err(Error::NoKey);
+        }
+        Ok(keys)
+    }
+}
+
+impl fmt::Display for RawKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{:016x}-", KEY_PREFIX, self.id)?;
+        self.key.iter().try_for_each(|x| write!(f, "{:02x}", x))
+    }
+}
+
+impl fmt::Debug for RawKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RawKey")
+            .field("id", &format_args!("{:016x}", self.id))
+            .finish()
+    }
+}
+
+impl FromStr for RawKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (id, key) = s
+            .trim()
+            .strip_prefix(KEY_PREFIX)
+            .and_then(|x| x.split_once('-'))
+            .ok_or(Error::InvalidKey)?;
+        let is_hex =
+            |x: &str, len: usize| x.len() == len && x.bytes().all(|x| x.is_ascii_hexdigit());
+        if !is_hex(id, 16) || !is_hex(key, KEY_SIZE * 2) {
+            return Err(Error::InvalidKey);
+        }
+
+        let id = u64::from_str_radix(id, 16).map_err(|_| Error::InvalidKey)?;
+        let mut bytes = [0u8; KEY_SIZE];
+        for (i, x) in bytes.iter_mut().enumerate() {
+            *x = u8::from_str_radix(&key[i * 2..i * 2 + 2], 16).map_err(|_| Error::InvalidKey)?;
+        }
+        Ok(RawKey { id, key: bytes })
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl Drop for RawKey {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(&mut self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str =
+        "W3S-KEY-00000000000000ff-000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    #[test]
+    fn key_to_string() {
+        let key: RawKey = KEY.parse().unwrap();
+        assert_eq!(key.id(), 0xff);
+        assert_eq!(key.as_bytes()[31], 0x1f);
+        assert_eq!(key.to_string(), KEY);
+        // the key is never printed by `Debug`
+        assert_eq!(format!("{:?}", key), "RawKey { id: 00000000000000ff }");
+
+        let invalid = [
+            "W3S-KEY-ff-000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+            "W3S-KEY-00000000000000ff-000102030405060708090a0b0c0d0e0f",
+            "W3S-KEY-00000000000000fg-000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+            "W3S-KEY-+0000000000000ff-000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+            &KEY[1..],
+        ];
+        for x in invalid {
+            assert!(
+                matches!(x.parse::<RawKey>(), Err(Error::InvalidKey)),
+                "{}",
+                x
+            );
+        }
+    }
+
+    #[test]
+    fn key_file() {
+        let other = RawKey::new(1, [9u8; KEY_SIZE]);
+        let text = format!("# backup keys\n\n{}\n  {}  \n", other, KEY);
+        let keys = RawKey::parse_lines(&text).unwrap();
+        assert_eq!(keys, vec![other.clone(), KEY.parse().unwrap()]);
+
+        let path = std::env::temp_dir().join(format!("w3s-raw-key-{}", std::process::id()));
+        fs::write(&path, &text).unwrap();
+        assert_eq!(RawKey::load(&path, None).unwrap(), other);
+        assert_eq!(RawKey::load(&path, Some(0xff)).unwrap().id(), 0xff);
+        assert!(matches!(
+            RawKey::load(&path, Some(2)),
+            Err(Error::NoMatchedKey(2))
+        ));
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            RawKey::parse_lines("# no key\n"),
+            Err(Error::NoKey)
+        ));
+        assert!(matches!(
+            RawKey::parse_lines(&format!("{}\nnot a key\n", KEY)),
+            Err(Error::InvalidKey)
+        ));
+    }
+}
@@ -0,0 +1,283 @@
+//! X25519 recipients which the random file key is wrapped for, in the style of age
+//!
+//! Every recipient has a stanza `[ephemeral public key: 32][wrapped file key: 32][tag: 16]` after the envelope header.
+//! The wrapping key is derived by HKDF-SHA256 from the X25519 shared secret, salted with both public keys.
+//!
+//! With the feature `age`, the `age1...` recipients and `AGE-SECRET-KEY-1...` identities of the `age` CLI
+//! are accepted as well.
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hkdf::Hkdf;
+use rand::prelude::*;
+use sha2::Sha256;
+use thiserror::Error;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+use zeroize::Zeroize;
+
+use super::envelope::{MAX_RECIPIENTS, STANZA_SIZE};
+use std::path::Path;
+use std::{fmt, fs, io, str::FromStr};
+
+pub const RECIPIENT_PREFIX: &str = "w3s-x25519-";
+pub const IDENTITY_PREFIX: &str = "W3S-X25519-SECRET-KEY-";
+
+const KEY_SIZE: usize = 32;
+const WRAP_INFO: &[u8] = b"w3s-x25519-wrap";
+/// Every wrapping key is used once, so the nonce is fixed
+const WRAP_NONCE: [u8; 24] = [0u8; 24];
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Invalid recipient: {0}")]
+    InvalidRecipient(String),
+    #[error("Invalid identity in the key file.")]
+    InvalidIdentity,
+    #[error("No identity in the key file.")]
+    NoIdentity,
+    #[error("At least one recipient is required.")]
+    NoRecipient,
+    #[error("Too many recipients: {0}, the limit is {}", MAX_RECIPIENTS)]
+    TooManyRecipients(usize),
+    #[error("None of the identities can unwrap the file key.")]
+    NoMatchedIdentity,
+    #[error("IO error: {0}")]
+    IoError(#[from] io::Error),
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// The public key to encrypt for, shown as `w3s-x25519-<base64url>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Recipient(pub(crate) PublicKey);
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}",
+            RECIPIENT_PREFIX,
+            URL_SAFE_NO_PAD.encode(self.0.as_bytes())
+        )
+    }
+}
+
+impl FromStr for Recipient {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let trimmed = s.trim();
+        let key = trimmed.strip_prefix(RECIPIENT_PREFIX).and_then(decode_key);
+        #[cfg(feature = "age")]
+        let key = key.or_else(|| super::age::parse_recipient(trimmed));
+
+        key.map(|x| Recipient(PublicKey::from(x)))
+            .ok_or_else(|| Error::InvalidRecipient(s.to_owned()))
+    }
+}
+
+/// The secret key to decrypt with, saved as `W3S-X25519-SECRET-KEY-<base64url>` in a key file
+#[derive(Clone)]
+pub struct Identity(pub(crate) StaticSecret);
+
+impl Identity {
+    pub fn generate() -> Self {
+        Identity(StaticSecret::random_from_rng(rand::thread_rng()))
+    }
+
+    pub fn to_public(&self) -> Recipient {
+        Recipient(PublicKey::from(&self.0))
+    }
+
+    /// Reads the identities of a key file, one per line. Empty lines and lines starting with `#` are skipped.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Vec<Identity>, Error> {
+        let identities = fs::read_to_string(path)?
+            .lines()
+            .map(str::trim)
+            .filter(|x| !x.is_empty() && !x.starts_with('#'))
+            .map(Identity::from_str)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if identities.is_empty() {
+            return Err(Error::NoIdentity);
+        }
+        Ok(identities)
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}",
+            IDENTITY_PREFIX,
+            URL_SAFE_NO_PAD.encode(self.0.as_bytes())
+        )
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Identity").field(&self.to_public()).finish()
+    }
+}
+
+impl FromStr for Identity {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let trimmed = s.trim();
+        let key = trimmed.strip_prefix(IDENTITY_PREFIX).and_then(decode_key);
+        #[cfg(feature = "age")]
+        let key = key.or_else(|| super::age::parse_identity(trimmed));
+
+        key.map(|x| Identity(StaticSecret::from(x)))
+            .ok_or(Error::InvalidIdentity)
+    }
+}
+
+fn decode_key(s: &str) -> Option<[u8; KEY_SIZE]> {
+    let mut bytes = URL_SAFE_NO_PAD.decode(s).ok()?;
+    let key = bytes.as_slice().try_into().ok();
+    bytes.zeroize();
+    key
+}
+
+fn wrapping_key(shared: &[u8], ephemeral: &PublicKey, recipient: &PublicKey) -> [u8; KEY_SIZE] {
+    let salt = [ephemeral.as_bytes().as_slice(), recipient.as_bytes()].concat();
+    let mut key = [0u8; KEY_SIZE];
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(WRAP_INFO, &mut key)
+        .expect("32 bytes is a valid length for HKDF-SHA256");
+    key
+}
+
+/// Generates a random file key.
+pub fn gen_file_key() -> [u8; KEY_SIZE] {
+    let mut key = [0u8; KEY_SIZE];
+    rand::thread_rng().fill_bytes(&mut key);
+    key
+}
+
+/// Wraps `file_key` for every recipient, and returns the stanzas.
+pub fn wrap(file_key: &[u8; KEY_SIZE], recipients: &[Recipient]) -> Result<Vec<u8>, Error> {
+    if recipients.is_empty() {
+        return Err(Error::NoRecipient);
+    }
+    if recipients.len() > MAX_RECIPIENTS as usize {
+        return Err(Error::TooManyRecipients(recipients.len()));
+    }
+
+    let mut stanzas = Vec::with_capacity(recipients.len() * STANZA_SIZE);
+    for recipient in recipients {
+        let ephemeral = EphemeralSecret::random_from_rng(rand::thread_rng());
+        let ephemeral_public = PublicKey::from(&ephemeral);
+        let shared = ephemeral.diffie_hellman(&recipient.0);
+
+        let mut key = wrapping_key(shared.as_bytes(), &ephemeral_public, &recipient.0);
+        let mut wrapped = file_key.to_vec();
+        super::cipher::seal(&key, &WRAP_NONCE, &[], &mut wrapped);
+        key.zeroize();
+
+        stanzas.extend(ephemeral_public.as_bytes());
+        stanzas.extend(wrapped);
+    }
+
+    Ok(stanzas)
+}
+
+/// Unwraps the file key from the stanzas with any of the identities.
+pub fn unwrap(stanzas: &[u8], identities: &[Identity]) -> Result<[u8; KEY_SIZE], Error> {
+    for stanza in stanzas.chunks_exact(STANZA_SIZE) {
+        let mut ephemeral_public = [0u8; KEY_SIZE];
+        ephemeral_public.copy_from_slice(&stanza[..KEY_SIZE]);
+        let ephemeral_public = PublicKey::from(ephemeral_public);
+
+        for identity in identities {
+            let shared = identity.0.diffie_hellman(&ephemeral_public);
+            // a low order point gives an all-zero secret
+            if !shared.was_contributory() {
+                continue;
+            }
+
+            let public = PublicKey::from(&identity.0);
+            let mut key = wrapping_key(shared.as_bytes(), &ephemeral_public, &public);
+            let mut wrapped = stanza[KEY_SIZE..].to_vec();
+            let is_opened = super::cipher::open(&key, &WRAP_NONCE, &[], &mut wrapped);
+            key.zeroize();
+
+            if is_opened {
+                let mut file_key = [0u8; KEY_SIZE];
+                file_key.copy_from_slice(&wrapped);
+                wrapped.zeroize();
+                return Ok(file_key);
+            }
+        }
+    }
+
+    Err(Error::NoMatchedIdentity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_to_string() {
+        let identity = Identity::generate();
+        let parsed: Identity = identity.to_string().parse().unwrap();
+        assert_eq!(parsed.to_public(), identity.to_public());
+
+        let recipient = identity.to_public();
+        assert!(recipient.to_string().starts_with(RECIPIENT_PREFIX));
+        assert_eq!(
+            recipient.to_string().parse::<Recipient>().unwrap(),
+            recipient
+        );
+        assert!("w3s-x25519-abc".parse::<Recipient>().is_err());
+    }
+
+    #[test]
+    fn wrap_and_unwrap() {
+        let identities = [Identity::generate(), Identity::generate()];
+        let recipients: Vec<_> = identities.iter().map(|x| x.to_public()).collect();
+        let file_key = gen_file_key();
+        let stanzas = wrap(&file_key, &recipients).unwrap();
+        assert_eq!(stanzas.len(), 2 * STANZA_SIZE);
+
+        for identity in identities {
+            assert_eq!(unwrap(&stanzas, &[identity]).unwrap(), file_key);
+        }
+        let e = unwrap(&stanzas, &[Identity::generate()]).unwrap_err();
+        assert!(matches!(e, Error::NoMatchedIdentity));
+    }
+
+    #[test]
+    fn tampered_stanza() {
+        let identity = Identity::generate();
+        let stanzas = wrap(&gen_file_key(), &[identity.to_public()]).unwrap();
+        for i in [0, KEY_SIZE, STANZA_SIZE - 1] {
+            let mut tampered = stanzas.clone();
+            tampered[i] ^= 1;
+            assert!(
+                unwrap(&tampered, std::slice::from_ref(&identity)).is_err(),
+                "byte {}",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn recipients_limit() {
+        assert!(matches!(
+            wrap(&gen_file_key(), &[]),
+            Err(Error::NoRecipient)
+        ));
+        let recipients = vec![Identity::generate().to_public(); MAX_RECIPIENTS as usize + 1];
+        let e = wrap(&gen_file_key(), &recipients).unwrap_err();
+        assert!(matches!(e, Error::TooManyRecipients(_)));
+    }
+}
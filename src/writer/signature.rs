@@ -0,0 +1,574 @@
+//! Detached Ed25519 signatures of directory uploads, which prove that an upload comes from the holder of a key
+//!
+//! The signature file is added to the root directory as `SIGNATURE_NAME` after all the other items.
+//! It signs the CID of the root directory without itself, and the SHA-256 digests of the uploaded files by their paths.
+//! The signed root is recomputed from the root block for verification, so everything under it is covered by the CIDs.
+//! Only a flat root directory, which is what the writer generates, can link the signature file,
+//! so a HAMT-sharded root is refused.
+//!
+//! The digests are of the uploaded content, which is hashed while the leaves are written.
+//! For compressed or encrypted uploads, that is the encoded content, so the signature file
+//! doesn't reveal digests of the plaintext, and the payload is marked as `encoded`.
+//!
+//! A signing key is saved as `W3S-ED25519-SECRET-KEY-<base64url>` in a key file,
+//! and its public key is shown as `w3s-ed25519-<base64url>`.
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use cid::Cid;
+use ed25519_dalek::{Signer, Verifier};
+use multihash::{Code, MultihashDigest};
+use quick_protobuf::message::MessageWrite;
+use quick_protobuf::Writer;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use unixfs_v1::{PBNode, UnixFs, UnixFsType};
+
+use super::car_util::DirectoryItem;
+pub use super::car_util::SIGNATURE_NAME;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::path::Path;
+use std::{fmt, fs, io, str::FromStr};
+
+pub const PUBLIC_KEY_PREFIX: &str = "w3s-ed25519-";
+pub const SECRET_KEY_PREFIX: &str = "W3S-ED25519-SECRET-KEY-";
+
+const VERSION: u8 = 1;
+const KEY_SIZE: usize = 32;
+const IDENTITY: u64 = 0x00;
+const DAG_PB: u64 = 0x70;
+const RAW: u64 = 0x55;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Invalid public key: {0}")]
+    InvalidPublicKey(String),
+    #[error("Invalid signing key in the key file.")]
+    InvalidSigningKey,
+    #[error("No signing key in the key file.")]
+    NoSigningKey,
+    #[error("Invalid signature file: {0}")]
+    InvalidSignatureFile(#[from] serde_json::Error),
+    #[error("Unsupported signature version: {0}")]
+    UnsupportedVersion(u8),
+    #[error("The upload is signed by an untrusted key: {0}")]
+    UntrustedKey(String),
+    #[error("The signature doesn't match the signed content.")]
+    InvalidSignature,
+    #[error("The root block doesn't match the signed root {0}")]
+    RootMismatch(String),
+    #[error("The digest of {0} doesn't match the signature.")]
+    DigestMismatch(String),
+    #[error("The signed digests are of the encoded content, which can't be checked against the decoded files.")]
+    EncodedDigests,
+    #[error("The root directory is HAMT-sharded, which can't be signed.")]
+    ShardedRoot,
+    #[error("{0} has no signed digest.")]
+    UnsignedFile(String),
+    #[error("IO error: {0}")]
+    IoError(#[from] io::Error),
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+fn decode_key(s: &str) -> Option<[u8; KEY_SIZE]> {
+    URL_SAFE_NO_PAD.decode(s).ok()?.try_into().ok()
+}
+
+/// The public key to verify with, shown as `w3s-ed25519-<base64url>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PublicKey(ed25519_dalek::VerifyingKey);
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}",
+            PUBLIC_KEY_PREFIX,
+            URL_SAFE_NO_PAD.encode(self.0.as_bytes())
+        )
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.trim()
+            .strip_prefix(PUBLIC_KEY_PREFIX)
+            .and_then(decode_key)
+            .and_then(|x| ed25519_dalek::VerifyingKey::from_bytes(&x).ok())
+            .map(PublicKey)
+            .ok_or_else(|| Error::InvalidPublicKey(s.to_owned()))
+    }
+}
+
+/// The secret key to sign with, saved as `W3S-ED25519-SECRET-KEY-<base64url>` in a key file
+#[derive(Clone)]
+pub struct SigningKey(ed25519_dalek::SigningKey);
+
+impl SigningKey {
+    pub fn generate() -> Self {
+        SigningKey(ed25519_dalek::SigningKey::generate(&mut rand::rngs::OsRng))
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.0.verifying_key())
+    }
+
+    /// Reads the first signing key of a key file. Empty lines and lines starting with `#` are skipped.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<SigningKey, Error> {
+        fs::read_to_string(path)?
+            .lines()
+            .map(str::trim)
+            .find(|x| !x.is_empty() && !x.starts_with('#'))
+            .ok_or(Error::NoSigningKey)?
+            .parse()
+    }
+}
+
+impl fmt::Display for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}",
+            SECRET_KEY_PREFIX,
+            URL_SAFE_NO_PAD.encode(self.0.as_bytes())
+        )
+    }
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SigningKey")
+            .field(&self.public_key().to_string())
+            .finish()
+    }
+}
+
+impl FromStr for SigningKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.trim()
+            .strip_prefix(SECRET_KEY_PREFIX)
+            .and_then(decode_key)
+            .map(|x| SigningKey(ed25519_dalek::SigningKey::from_bytes(&x)))
+            .ok_or(Error::InvalidSigningKey)
+    }
+}
+
+fn to_hex(hasher: Sha256) -> String {
+    hasher
+        .finalize()
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect()
+}
+
+fn sha256_hex(path: impl AsRef<Path>) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(to_hex(hasher))
+}
+
+/// The digests of the uploaded files, which are hashed leaf by leaf as the files are written
+#[derive(Debug, Clone, Default)]
+pub struct FileDigests(HashMap<u64, Sha256>);
+
+impl FileDigests {
+    /// Hashes the content of a leaf block of file `id`, which is a raw block or a UnixFS `File` node.
+    pub fn update(&mut self, id: u64, cid: &Cid, block: &[u8]) {
+        let hasher = self.0.entry(id).or_default();
+        match cid.codec() {
+            RAW => hasher.update(block),
+            DAG_PB => {
+                if let Ok(node) = PBNode::try_from(block) {
+                    if let Some(data) = UnixFs::try_from(&node).ok().and_then(|x| x.Data) {
+                        hasher.update(data);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns the digests by the paths relative to the root directory.
+    /// A file without any leaf is empty.
+    pub fn by_path(&self, dir_items: &[DirectoryItem]) -> BTreeMap<String, String> {
+        fn rec(
+            items: &[DirectoryItem],
+            dir: &str,
+            hashers: &HashMap<u64, Sha256>,
+            digests: &mut BTreeMap<String, String>,
+        ) {
+            for item in items {
+                match item {
+                    DirectoryItem::File(name, _, id, _) => {
+                        let hasher = hashers.get(id).cloned().unwrap_or_default();
+                        digests.insert(format!("{}{}", dir, name), to_hex(hasher));
+                    }
+                    DirectoryItem::Directory(name, sub_items, _) => {
+                        rec(sub_items, &format!("{}{}/", dir, name), hashers, digests);
+                    }
+                    DirectoryItem::Symlink(..) => {}
+                }
+            }
+        }
+
+        let mut digests = BTreeMap::new();
+        rec(dir_items, "", &self.0, &mut digests);
+        digests
+    }
+}
+
+/// The signed part of the signature file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Payload {
+    version: u8,
+    root: String,
+    digests: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    encoded: bool,
+}
+
+/// The content of the signature file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Signature {
+    #[serde(flatten)]
+    payload: Payload,
+    public_key: String,
+    signature: String,
+}
+
+impl Signature {
+    /// Signs the root directory `root` without the signature file, and the digests of `FileDigests::by_path`.
+    /// `encoded` tells that the files are compressed or encrypted.
+    pub fn sign(
+        key: &SigningKey,
+        root: &Cid,
+        digests: BTreeMap<String, String>,
+        encoded: bool,
+    ) -> Signature {
+        let payload = Payload {
+            version: VERSION,
+            root: root.to_string(),
+            digests,
+            encoded,
+        };
+        // the maps are sorted, so the same payload is always serialized to the same bytes
+        let signature = key.0.sign(&serde_json::to_vec(&payload).unwrap());
+
+        Signature {
+            payload,
+            public_key: key.public_key().to_string(),
+            signature: URL_SAFE_NO_PAD.encode(signature.to_bytes()),
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        serde_json::to_vec_pretty(self).unwrap()
+    }
+
+    pub fn from_slice(buf: &[u8]) -> Result<Signature, Error> {
+        let signature: Signature = serde_json::from_slice(buf)?;
+        if signature.payload.version != VERSION {
+            return Err(Error::UnsupportedVersion(signature.payload.version));
+        }
+        Ok(signature)
+    }
+
+    /// The public key which made the signature, which is not trusted before `verify`.
+    pub fn public_key(&self) -> &str {
+        &self.public_key
+    }
+
+    pub fn digests(&self) -> &BTreeMap<String, String> {
+        &self.payload.digests
+    }
+
+    /// Whether the digests are of compressed or encrypted content.
+    pub fn is_encoded(&self) -> bool {
+        self.payload.encoded
+    }
+
+    /// Verifies the signature by the trusted key, and that `root_block` is the signed root directory
+    /// with the signature file. Returns the signed root.
+    pub fn verify(&self, trusted: &PublicKey, root_block: &[u8]) -> Result<Cid, Error> {
+        if self.public_key.parse::<PublicKey>().ok().as_ref() != Some(trusted) {
+            return Err(Error::UntrustedKey(self.public_key.clone()));
+        }
+
+        let signature = URL_SAFE_NO_PAD
+            .decode(&self.signature)
+            .ok()
+            .and_then(|x| ed25519_dalek::Signature::from_slice(&x).ok())
+            .ok_or(Error::InvalidSignature)?;
+        let message = serde_json::to_vec(&self.payload)?;
+        trusted
+            .0
+            .verify(&message, &signature)
+            .map_err(|_| Error::InvalidSignature)?;
+
+        let root = Cid::from_str(&self.payload.root)
+            .map_err(|_| Error::RootMismatch(self.payload.root.clone()))?;
+        if is_sharded(root_block) {
+            return Err(Error::ShardedRoot);
+        }
+        if !is_signed_root(&root, root_block) {
+            return Err(Error::RootMismatch(self.payload.root.clone()));
+        }
+        Ok(root)
+    }
+
+    /// Checks the files downloaded to `dir` against the signed digests, after `verify`.
+    /// Returns `Error::UnsignedFile` for a file without a signed digest, besides the signature file.
+    /// Symlinks have no digest, so they are skipped.
+    /// The digests of an encoded upload can't be checked, as the downloaded files are decoded.
+    pub fn check_dir(&self, dir: impl AsRef<Path>) -> Result<(), Error> {
+        if self.payload.encoded {
+            return Err(Error::EncodedDigests);
+        }
+        for (path, digest) in self.payload.digests.iter() {
+            match sha256_hex(dir.as_ref().join(path)) {
+                Ok(x) if &x == digest => {}
+                _ => return Err(Error::DigestMismatch(path.clone())),
+            }
+        }
+
+        let mut files = vec![];
+        list_files(dir.as_ref(), "", &mut files)?;
+        match files
+            .into_iter()
+            .find(|x| x != SIGNATURE_NAME && !self.payload.digests.contains_key(x))
+        {
+            Some(x) => Err(Error::UnsignedFile(x)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Lists the paths of the regular files under `dir` relative to the root directory, like the signed digests.
+fn list_files(dir: &Path, prefix: &str, files: &mut Vec<String>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            list_files(&entry.path(), &format!("{}/", path), files)?;
+        } else if file_type.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Whether the root block is a HAMT shard of UnixFS
+fn is_sharded(root_block: &[u8]) -> bool {
+    match PBNode::try_from(root_block) {
+        Ok(node) => matches!(UnixFs::try_from(&node), Ok(x) if x.Type == UnixFsType::HAMTShard),
+        Err(_) => false,
+    }
+}
+
+/// Removes the link of the signature file from the root block, and checks that the rest is hashed to `root`.
+fn is_signed_root(root: &Cid, root_block: &[u8]) -> bool {
+    let mut node = match PBNode::try_from(root_block) {
+        Ok(x) if root.codec() == DAG_PB => x,
+        _ => return false,
+    };
+    let len = node.Links.len();
+    node.Links
+        .retain(|x| x.Name.as_deref() != Some(SIGNATURE_NAME));
+    if node.Links.len() + 1 != len {
+        return false;
+    }
+
+    let mut data = vec![];
+    if node.write_message(&mut Writer::new(&mut data)).is_err() {
+        return false;
+    }
+
+    let expected = root.hash();
+    if expected.code() == IDENTITY {
+        return expected.digest() == data;
+    }
+    match Code::try_from(expected.code()) {
+        Ok(code) => code.digest(&data).digest() == expected.digest(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::car_util::*;
+    use super::*;
+    use std::env;
+
+    const CONTENT: &[u8] = b"signed content of the file";
+
+    fn digest_of(buf: &[u8]) -> String {
+        to_hex(Sha256::new_with_prefix(buf))
+    }
+
+    fn file_item(name: &str, id: u64) -> DirectoryItem {
+        DirectoryItem::File(name.to_owned(), String::new(), id, FileMeta::default())
+    }
+
+    /// Returns the signature of a directory with file `a`, and the root block with the signature file.
+    fn signed_dir(key: &SigningKey) -> (Signature, Vec<u8>) {
+        let config = DagConfig::default();
+        let meta = FileMeta::default();
+        let leaves = gen_blocks(CONTENT.to_vec(), 8, &config);
+        let file = gen_pbnode_from_blocks("a".to_owned(), &leaves, &meta, &config);
+        let signed_root = gen_dir(None, std::slice::from_ref(&file), &meta, &config);
+
+        let digests = BTreeMap::from([("a".to_owned(), digest_of(CONTENT))]);
+        let signature = Signature::sign(key, &signed_root.cid(), digests, false);
+
+        let blocks = gen_blocks(signature.to_vec(), 1024, &config);
+        let signature_file =
+            gen_pbnode_from_blocks(SIGNATURE_NAME.to_owned(), &blocks, &meta, &config);
+        let (_, root_block) =
+            gen_dir(None, &[file, signature_file], &meta, &config).rip_data_with_cid();
+        (signature, root_block)
+    }
+
+    #[test]
+    fn key_strings() {
+        let key = SigningKey::generate();
+        let parsed: SigningKey = key.to_string().parse().unwrap();
+        assert_eq!(parsed.public_key(), key.public_key());
+
+        let public_key = key.public_key().to_string();
+        assert!(public_key.starts_with(PUBLIC_KEY_PREFIX));
+        assert_eq!(public_key.parse::<PublicKey>().unwrap(), key.public_key());
+
+        assert!(matches!(
+            "w3s-ed25519-abc".parse::<PublicKey>(),
+            Err(Error::InvalidPublicKey(_))
+        ));
+        assert!(matches!(
+            "W3S-ED25519-SECRET-KEY-abc".parse::<SigningKey>(),
+            Err(Error::InvalidSigningKey)
+        ));
+    }
+
+    #[test]
+    fn digests_of_leaves() {
+        for raw_leaves in [false, true] {
+            let config = DagConfig {
+                raw_leaves,
+                ..Default::default()
+            };
+            let mut digests = FileDigests::default();
+            for block in gen_blocks(CONTENT.to_vec(), 8, &config).iter_mut() {
+                let (cid, data) = block.rip_data_with_cid();
+                digests.update(1, &cid, &data);
+            }
+
+            let dir = DirectoryItem::Directory(
+                "d".to_owned(),
+                vec![file_item("a", 1), file_item("empty", 2)],
+                FileMeta::default(),
+            );
+            let expected = BTreeMap::from([
+                ("d/a".to_owned(), digest_of(CONTENT)),
+                ("d/empty".to_owned(), digest_of(b"")),
+            ]);
+            assert_eq!(digests.by_path(&[dir]), expected);
+        }
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let key = SigningKey::generate();
+        let (signature, root_block) = signed_dir(&key);
+        let signature = Signature::from_slice(&signature.to_vec()).unwrap();
+        assert_eq!(signature.public_key(), key.public_key().to_string());
+        assert!(!signature.is_encoded());
+
+        let root = signature.verify(&key.public_key(), &root_block).unwrap();
+        assert_eq!(root.to_string(), signature.payload.root);
+
+        // another trusted key
+        let other = SigningKey::generate().public_key();
+        assert!(matches!(
+            signature.verify(&other, &root_block),
+            Err(Error::UntrustedKey(_))
+        ));
+
+        // a tampered payload
+        let mut tampered = signature.clone();
+        tampered
+            .payload
+            .digests
+            .insert("a".to_owned(), digest_of(b"other content"));
+        assert!(matches!(
+            tampered.verify(&key.public_key(), &root_block),
+            Err(Error::InvalidSignature)
+        ));
+
+        // a root block of another directory
+        let (_, mut empty_block) =
+            gen_dir(None, &[], &FileMeta::default(), &DagConfig::default()).rip_data_with_cid();
+        assert!(matches!(
+            signature.verify(&key.public_key(), &empty_block),
+            Err(Error::RootMismatch(_))
+        ));
+        empty_block.clear();
+        assert!(signature.verify(&key.public_key(), &empty_block).is_err());
+
+        // a HAMT-sharded root, whose UnixFS type is 5
+        let node = PBNode {
+            Links: vec![],
+            Data: Some(std::borrow::Cow::from(&[0x08, 0x05][..])),
+        };
+        let mut sharded = vec![];
+        node.write_message(&mut Writer::new(&mut sharded)).unwrap();
+        assert!(matches!(
+            signature.verify(&key.public_key(), &sharded),
+            Err(Error::ShardedRoot)
+        ));
+    }
+
+    #[test]
+    fn check_downloaded_dir() {
+        let key = SigningKey::generate();
+        let (signature, _) = signed_dir(&key);
+
+        let dir = env::temp_dir().join(format!("w3s-signature-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a"), CONTENT).unwrap();
+        fs::write(dir.join(SIGNATURE_NAME), b"{}").unwrap();
+        assert!(signature.check_dir(&dir).is_ok());
+
+        // a file which isn't signed
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/b"), b"").unwrap();
+        assert!(matches!(
+            signature.check_dir(&dir),
+            Err(Error::UnsignedFile(x)) if x == "sub/b"
+        ));
+        fs::remove_dir_all(dir.join("sub")).unwrap();
+
+        fs::write(dir.join("a"), b"other content").unwrap();
+        assert!(matches!(
+            signature.check_dir(&dir),
+            Err(Error::DigestMismatch(_))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut encoded = signature.clone();
+        encoded.payload.encoded = true;
+        assert!(matches!(
+            encoded.check_dir(&dir),
+            Err(Error::EncodedDigests)
+        ));
+    }
+}
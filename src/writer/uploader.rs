@@ -1,11 +1,14 @@
 //! Handles upload tasks
+use super::car_stream::UploadBody;
+use super::CarWrite;
 use cid::Cid;
 use core::task::Poll;
-use futures::TryFutureExt;
+use futures::{FutureExt, TryFutureExt};
 use reqwest::{Body, Client};
 use serde::Deserialize;
 use std::{
-    cmp, fmt,
+    fmt,
+    fs::File,
     io, mem,
     str::FromStr,
     sync::{Arc, Mutex},
@@ -108,12 +111,35 @@ impl Uploader {
         result
     }
 
+    fn spawn_upload(&mut self, body: UploadBody) -> io::Result<usize> {
+        let len = body.len();
+        let upload_future = Uploader::upload(
+            self.upload_type,
+            self.w3s_name.clone(),
+            self.tasks.len() + self.results.len(),
+            self.auth_token.clone(),
+            Arc::new(body),
+            self.progress_listener.clone(),
+        );
+        let handler = tokio::spawn(upload_future);
+        self.tasks.push(handler);
+
+        if self.tasks.len() == self.max_concurrent {
+            // abnormal written len can tell the parent Writer to call `flush` after this `write` function.
+            // you shouldn't call `self.flush()` directly here because it can't drop the outside Vec to release memory
+            // when pause the thread to await async uploading jobs.
+            Ok(0)
+        } else {
+            Ok(len)
+        }
+    }
+
     pub async fn upload(
         upload_type: UploadType,
         w3s_name: Arc<String>,
         part: usize,
         auth_token: Arc<String>,
-        data: Arc<Vec<u8>>,
+        data: Arc<UploadBody>,
         progress_listener: Option<ProgressListener>,
     ) -> Result<Cid, Error> {
         let api = Arc::new(format!("https://api.web3.storage/{}", upload_type));
@@ -124,6 +150,8 @@ impl Uploader {
                 part,
                 data: data.clone(),
                 cursor: 0,
+                file: None,
+                reading: None,
                 progress_listener: progress_listener.clone(),
             });
 
@@ -151,25 +179,7 @@ impl Uploader {
 
 impl io::Write for Uploader {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let upload_future = Uploader::upload(
-            self.upload_type,
-            self.w3s_name.clone(),
-            self.tasks.len() + self.results.len(),
-            self.auth_token.clone(),
-            Arc::new(buf.to_vec()),
-            self.progress_listener.clone(),
-        );
-        let handler = tokio::spawn(upload_future);
-        self.tasks.push(handler);
-
-        if self.tasks.len() == self.max_concurrent {
-            // abnormal written len can tell the parent Writer to call `flush` after this `write` function.
-            // you shouldn't call `self.flush()` directly here because it can't drop the outside Vec to release memory
-            // when pause the thread to await async uploading jobs.
-            Ok(0)
-        } else {
-            Ok(buf.len())
-        }
+        self.spawn_upload(buf.to_vec().into())
     }
 
     // this `flush` function is to complete concurrent uploading connections by blocking current thread.
@@ -185,39 +195,116 @@ struct Response {
     cid: String,
 }
 
-#[derive(Clone)]
+impl CarWrite for Uploader {
+    /// Uploads the CAR without copying it, so a file-backed body stays on the disk.
+    fn write_car(&mut self, car: UploadBody) -> io::Result<usize> {
+        self.spawn_upload(car)
+    }
+}
+
+const CHUNK_SIZE: usize = 1024 * 32;
+
+type ReadResult = (Option<File>, io::Result<Vec<u8>>);
+
 pub struct ProgressStream {
     name: Arc<String>,
     part: usize,
-    data: Arc<Vec<u8>>,
+    data: Arc<UploadBody>,
     cursor: usize,
+    file: Option<File>,
+    reading: Option<JoinHandle<ReadResult>>,
     progress_listener: Option<ProgressListener>,
 }
+
+impl ProgressStream {
+    fn advance(&mut self, chunk: Vec<u8>) -> Vec<u8> {
+        self.cursor += chunk.len();
+
+        if let Some(pl) = self.progress_listener.as_ref() {
+            if let Ok(mut f) = pl.lock() {
+                f(self.name.clone(), self.part, self.cursor, self.data.len());
+            }
+        }
+
+        chunk
+    }
+}
+
 impl futures::Stream for ProgressStream {
     type Item = io::Result<Vec<u8>>;
 
     fn poll_next(
         mut self: std::pin::Pin<&mut Self>,
-        _: &mut std::task::Context<'_>,
+        cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        let total_len = self.data.len();
-        let remain_len = total_len - self.cursor;
+        let this = &mut *self;
 
-        if remain_len == 0 {
-            Poll::Ready(None)
-        } else {
-            let mut result = vec![0u8; cmp::min(remain_len, 1024 * 32)];
-            let start_index = self.cursor;
-            self.cursor += result.len();
-            result.copy_from_slice(&self.data[start_index..self.cursor]);
-
-            if let Some(pl) = self.progress_listener.as_ref() {
-                if let Ok(mut f) = pl.lock() {
-                    f(self.name.clone(), self.part, self.cursor, total_len);
-                }
+        if this.reading.is_none() {
+            if this.cursor == this.data.len() {
+                return Poll::Ready(None);
+            }
+
+            if this.data.is_in_memory(this.cursor) {
+                let chunk = this
+                    .data
+                    .read_chunk(&mut this.file, this.cursor, CHUNK_SIZE)?;
+                return Poll::Ready(Some(Ok(this.advance(chunk))));
             }
 
-            Poll::Ready(Some(Ok(result)))
+            // a shard spilled to the disk is read on the blocking threads not to stall the runtime
+            let (data, mut file, cursor) = (this.data.clone(), this.file.take(), this.cursor);
+            this.reading = Some(tokio::task::spawn_blocking(move || {
+                let result = data.read_chunk(&mut file, cursor, CHUNK_SIZE);
+                (file, result)
+            }));
+        }
+
+        let (file, result) = futures::ready!(this.reading.as_mut().unwrap().poll_unpin(cx))
+            .map_err(io::Error::other)?;
+        this.reading = None;
+        this.file = file;
+
+        Poll::Ready(Some(Ok(this.advance(result?))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::car_stream::{CarShard, MemoryBudget};
+    use super::*;
+    use futures::TryStreamExt;
+    use multihash::{Code, MultihashDigest};
+
+    fn stream(body: UploadBody) -> ProgressStream {
+        ProgressStream {
+            name: Arc::new("test".to_owned()),
+            part: 0,
+            data: Arc::new(body),
+            cursor: 0,
+            file: None,
+            reading: None,
+            progress_listener: None,
+        }
+    }
+
+    fn shard(budget: usize) -> UploadBody {
+        let mut shard = CarShard::new(MemoryBudget::new(budget));
+        for i in 0..10u8 {
+            let data = vec![i; CHUNK_SIZE + 7];
+            let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(&data));
+            shard.write_block(cid, &data).unwrap();
         }
+        shard.finish_with_roots(vec![]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn stream_spilled_shard() {
+        let in_memory: Vec<Vec<u8>> = stream(shard(usize::MAX)).try_collect().await.unwrap();
+        let body = shard(0);
+        assert!(!body.is_in_memory(body.len() - 1));
+        let spilled: Vec<Vec<u8>> = stream(body).try_collect().await.unwrap();
+
+        assert_eq!(in_memory.concat().len(), shard(0).len());
+        assert_eq!(in_memory.concat(), spilled.concat());
     }
 }